@@ -0,0 +1,62 @@
+//! Measures how long inserts take while the keyspace grows, where a table that
+//! resizes all at once shows up as a few very slow inserts. A `HashMap` behind
+//! a single lock, like the keyspace used to be, is measured for comparison.
+//!
+//! Run with `cargo bench -p dkv_db --bench resize_latency`. The number of keys
+//! can be changed with `DKV_BENCH_KEYS`.
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use dkv_db::{Value, DB};
+
+fn report(name: &str, mut latencies: Vec<Duration>) {
+    latencies.sort();
+    let percentile = |p: f64| {
+        latencies[((latencies.len() as f64 * p) as usize).min(latencies.len() - 1)].as_micros()
+    };
+    println!(
+        "{:>10} {:>10} {:>10} {:>10} {:>10}",
+        name,
+        percentile(0.5),
+        percentile(0.999),
+        percentile(0.99999),
+        latencies.last().unwrap().as_micros()
+    );
+}
+
+fn main() {
+    let keys: usize = std::env::var("DKV_BENCH_KEYS")
+        .map(|keys| keys.parse().expect("DKV_BENCH_KEYS must be a number"))
+        .unwrap_or(4_000_000);
+    println!(
+        "{:>10} {:>10} {:>10} {:>10} {:>10}",
+        "keyspace", "p50 us", "p99.9 us", "p99.999 us", "max us"
+    );
+
+    let map = Mutex::new(HashMap::new());
+    let latencies = (0..keys)
+        .map(|i| {
+            let start = Instant::now();
+            map.lock()
+                .unwrap()
+                .insert(format!("key:{}", i), Value::from("value"));
+            start.elapsed()
+        })
+        .collect();
+    report("HashMap", latencies);
+    drop(map);
+
+    // A single shard, so that every insert resizes the same table
+    let db = DB::with_shards(1);
+    let latencies = (0..keys)
+        .map(|i| {
+            let start = Instant::now();
+            db.set(format!("key:{}", i), Value::from("value"));
+            start.elapsed()
+        })
+        .collect();
+    report("DB", latencies);
+}
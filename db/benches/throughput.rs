@@ -0,0 +1,90 @@
+//! Measures how many GETs and SETs threads get through together, with the
+//! keyspace behind a single lock and split into [DEFAULT_SHARDS] shards.
+//! Every thread works on keys picked at random from the same keyspace,
+//! doing one SET for every 9 GETs.
+//!
+//! Run with `cargo bench -p dkv_db --bench throughput`. The thread counts
+//! (comma separated), the number of keys and how long each run takes can be
+//! changed with `DKV_BENCH_THREADS`, `DKV_BENCH_KEYS` and `DKV_BENCH_SECONDS`.
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread::sleep,
+    time::{Duration, Instant},
+};
+
+use dkv_db::{Value, DB, DEFAULT_SHARDS};
+
+fn env_or(name: &str, default: &str) -> String {
+    std::env::var(name).unwrap_or_else(|_| default.to_owned())
+}
+
+/// Runs GETs and SETs until `stop` is set, returning how many it did
+fn run_thread(db: DB, keys: Arc<Vec<String>>, seed: u64, stop: Arc<AtomicBool>) -> usize {
+    // xorshift, good enough to pick keys
+    let mut state = seed | 1;
+    let mut operations = 0;
+    while !stop.load(Ordering::Relaxed) {
+        for _ in 0..100 {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            let key = &keys[state as usize % keys.len()];
+            if state.is_multiple_of(10) {
+                db.set(key.clone(), Value::from("value"));
+            } else {
+                db.view(key, |value| value.is_some());
+            }
+        }
+        operations += 100;
+    }
+    operations
+}
+
+fn main() {
+    let threads: Vec<usize> = env_or("DKV_BENCH_THREADS", "1,2,4,8")
+        .split(',')
+        .map(|threads| threads.parse().expect("DKV_BENCH_THREADS must be numbers"))
+        .collect();
+    let key_count: usize = env_or("DKV_BENCH_KEYS", "100000")
+        .parse()
+        .expect("DKV_BENCH_KEYS must be a number");
+    let duration = Duration::from_secs(
+        env_or("DKV_BENCH_SECONDS", "2")
+            .parse()
+            .expect("DKV_BENCH_SECONDS must be a number"),
+    );
+    let keys: Arc<Vec<String>> = Arc::new((0..key_count).map(|i| format!("key:{}", i)).collect());
+
+    println!("{:>8} {:>8} {:>14}", "shards", "threads", "ops/sec");
+    for shards in [1, DEFAULT_SHARDS] {
+        for &thread_count in &threads {
+            let db = DB::with_shards(shards);
+            for key in keys.iter() {
+                db.set(key.clone(), Value::from("value"));
+            }
+            let stop = Arc::new(AtomicBool::new(false));
+            let start = Instant::now();
+            let handles: Vec<_> = (0..thread_count)
+                .map(|i| {
+                    let (db, keys, stop) = (db.clone(), keys.clone(), stop.clone());
+                    std::thread::spawn(move || run_thread(db, keys, i as u64 + 1, stop))
+                })
+                .collect();
+            sleep(duration);
+            stop.store(true, Ordering::Relaxed);
+            let operations: usize = handles
+                .into_iter()
+                .map(|handle| handle.join().unwrap())
+                .sum();
+            println!(
+                "{:>8} {:>8} {:>14.0}",
+                shards,
+                thread_count,
+                operations as f64 / start.elapsed().as_secs_f64()
+            );
+        }
+    }
+}
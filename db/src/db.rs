@@ -1,66 +1,427 @@
 pub use crate::value::*;
 use std::{
     collections::HashMap,
-    sync::{Arc, Mutex},
+    hash::{BuildHasher, RandomState},
+    mem::size_of,
+    sync::{
+        atomic::{AtomicU16, AtomicUsize, Ordering},
+        Arc, Mutex, MutexGuard, RwLock, RwLockReadGuard, RwLockWriteGuard, TryLockError,
+    },
+    time::{Duration, Instant},
 };
+
+use crate::{
+    dict::Dict,
+    evict::{self, Access, EvictionPolicy, EvictionPool, MaxMemory},
+    KeyspaceEvents,
+};
+
+/// How many shards [DB::new] splits the keyspace into
+pub const DEFAULT_SHARDS: usize = 64;
+
 #[derive(Clone)]
 pub struct DB {
-    db_impl: Arc<Mutex<DBImpl>>,
+    db_impl: Arc<DBImpl>,
+}
+
+impl Default for DB {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl DB {
     pub fn new() -> DB {
+        DB::with_shards(DEFAULT_SHARDS)
+    }
+
+    /// Splits the keyspace into `shards` parts, rounded up to a power of two,
+    /// that each have their own lock. Keys are spread over them by hash.
+    pub fn with_shards(shards: usize) -> DB {
+        let shards = shards.max(1).next_power_of_two();
         DB {
-            db_impl: Arc::new(Mutex::new(DBImpl {
-                map: HashMap::new(),
-                subscribers: HashMap::new(),
-                next_subscriber_id: 0,
-            })),
+            db_impl: Arc::new(DBImpl {
+                shards: (0..shards).map(|_| RwLock::default()).collect(),
+                hasher: RandomState::new(),
+                subscribers: RwLock::default(),
+                next_subscriber_id: AtomicUsize::new(0),
+                keyspace_events: AtomicU16::new(KeyspaceEvents::NONE.bits()),
+                created: Instant::now(),
+                used_memory: AtomicUsize::new(0),
+                peak_memory: AtomicUsize::new(0),
+                maxmemory: AtomicUsize::new(0),
+                eviction: Mutex::default(),
+            }),
         }
     }
 
     pub fn get_optional(&self, key: &str) -> Option<Value> {
-        self.with_lock(|m| m.map.get(key).cloned())
+        self.view(key, |value| value.cloned())
     }
     pub fn exists(&self, key: &str) -> bool {
-        self.with_lock(|m| m.map.contains_key(key))
+        self.read_shard(key).map.contains_key(key)
     }
     pub fn flush_all(&self) {
-        self.with_lock(|m| m.map.clear())
+        // Every shard is locked first, so that nobody sees some of them flushed
+        let mut shards: Vec<_> = self.db_impl.shards.iter().map(write).collect();
+        for shard in &mut shards {
+            shard.map.clear();
+            shard.table_memory = 0;
+        }
+        self.db_impl.used_memory.store(0, Ordering::Relaxed);
     }
 
     pub fn set(&self, key: String, value: Value) {
-        self.with_lock(|m| m.map.insert(key, value));
+        let now = self.now();
+        let new_key = {
+            let mut shard = self.write_shard(&key);
+            if let Some(entry) = shard.map.get_mut(&key) {
+                // Like redis' `dbOverwrite`, the key keeps how often it was used,
+                // so that rewriting a hot key doesn't make it look cold
+                entry.access.touch(now);
+                entry.value = value;
+                let size = Entry::size(&key, &entry.value, MEMORY_SAMPLES);
+                let previous = std::mem::replace(&mut entry.size, size);
+                self.account(previous, size);
+                return;
+            }
+            let notify_new = self.keyspace_events().should_notify(KeyspaceEvents::NEW);
+            let new_key = notify_new.then(|| key.clone());
+            let entry = Entry::new(&key, value, now);
+            self.insert(&mut shard, key, entry);
+            new_key
+        };
+        if let Some(key) = new_key {
+            self.notify_keyspace_event(KeyspaceEvents::NEW, "new", &key);
+        }
     }
 
     pub fn del(&self, key: &str) -> u64 {
-        self.with_lock(|m| m.map.remove(key).is_some() as u64)
+        self.remove(&mut self.write_shard(key), key).is_some() as u64
+    }
+
+    /// Moves the value of `key` to `new_key`, replacing what `new_key` had.
+    /// Returns false if `key` doesn't exist.
+    pub fn rename(&self, key: &str, new_key: String) -> bool {
+        let mut shards = self.write_shards(&[key, &new_key]);
+        let Some(mut entry) = self.remove(shards.get(key), key) else {
+            return false;
+        };
+        entry.size = Entry::size(&new_key, &entry.value, MEMORY_SAMPLES);
+        self.insert(shards.get(&new_key), new_key, entry);
+        true
     }
 
     pub fn view<T>(&self, key: &str, f: impl FnOnce(Option<&Value>) -> T) -> T {
-        self.with_lock(|m| f(m.map.get(key)))
+        let shard = self.read_shard(key);
+        let entry = shard.map.get(key);
+        if let Some(entry) = entry {
+            entry.access.touch(self.now());
+        }
+        f(entry.map(|entry| &entry.value))
     }
 
     pub fn mutate<T>(&self, key: &str, f: impl FnOnce(Option<&mut Value>) -> T) -> T {
-        self.with_lock(|m| {
-            let value = m.map.get_mut(key);
-            f(value)
+        let now = self.now();
+        let mut shard = self.write_shard(key);
+        let Some(entry) = shard.map.get_mut(key) else {
+            return f(None);
+        };
+        entry.access.touch(now);
+        let result = f(Some(&mut entry.value));
+        let size = Entry::size(key, &entry.value, MEMORY_SAMPLES);
+        let previous = std::mem::replace(&mut entry.size, size);
+        self.account(previous, size);
+        self.account_tables(&mut shard);
+        result
+    }
+
+    /// An estimate of the bytes used by the keyspace, which is what `maxmemory` limits.
+    /// Values are only looked at when they change, so big lists and hashes are
+    /// estimated from a few of their elements, see [Value::memory_usage].
+    pub fn used_memory(&self) -> usize {
+        self.db_impl.used_memory.load(Ordering::Relaxed)
+    }
+
+    /// An estimate of the bytes `key` uses, with its value, like redis' `MEMORY USAGE`.
+    /// Looks at `samples` elements of lists and hashes, or at all of them if it's 0.
+    pub fn memory_usage(&self, key: &str, samples: usize) -> Option<usize> {
+        let shard = self.read_shard(key);
+        let entry = shard.map.get(key)?;
+        Some(Entry::size(key, &entry.value, samples))
+    }
+
+    /// How `key` is encoded, and how recently and often it was accessed, like redis'
+    /// `OBJECT`. Looking at it doesn't count as an access. Both the idle time and the
+    /// frequency are always tracked, whatever the [EvictionPolicy].
+    pub fn object_info(&self, key: &str) -> Option<ObjectInfo> {
+        let now = self.now();
+        let shard = self.read_shard(key);
+        let entry = shard.map.get(key)?;
+        Some(ObjectInfo {
+            encoding: entry.value.encoding(),
+            idle: Duration::from_millis(entry.access.idle(now)),
+            frequency: entry.access.frequency(now),
         })
     }
 
-    fn with_lock<T>(&self, f: impl FnOnce(&mut DBImpl) -> T) -> T {
-        let mut db_impl = self.db_impl.lock().unwrap();
-        f(&mut db_impl)
+    /// Where [DB::used_memory] goes, like redis' `MEMORY STATS`
+    pub fn memory_stats(&self) -> MemoryStats {
+        let mut stats = MemoryStats {
+            used_memory: self.used_memory(),
+            peak_memory: self.db_impl.peak_memory.load(Ordering::Relaxed),
+            ..MemoryStats::default()
+        };
+        for shard in self.db_impl.shards.iter() {
+            let shard = read(shard);
+            stats.keys += shard.map.len();
+            stats.table_overhead += shard.table_memory;
+        }
+        stats.entry_overhead = stats.keys * ENTRY_OVERHEAD;
+        stats
     }
 
-    pub fn publish(&self, channel: &str, value: &str) {
-        let subscribers = self.with_lock(|db| {
-            db.subscribers
-                .values()
-                .filter(|it| it.channel == channel)
-                .cloned()
-                .collect::<Vec<Subscriber>>()
+    /// Sets how much memory the keyspace may use, and how [DB::evict] makes room
+    pub fn set_maxmemory(&self, maxmemory: MaxMemory) {
+        let mut eviction = lock(&self.db_impl.eviction);
+        if eviction.maxmemory.policy != maxmemory.policy {
+            // Scores of different policies can't be compared
+            eviction.pool.clear();
+        }
+        eviction.maxmemory = maxmemory;
+        self.db_impl
+            .maxmemory
+            .store(maxmemory.limit, Ordering::Relaxed);
+    }
+
+    /// Evicts keys the way [MaxMemory::policy] says until the keyspace uses no more
+    /// than [MaxMemory::limit], calling `on_evicted` with each of them. Returns false
+    /// if it still uses more, e.g. because the policy is [EvictionPolicy::NoEviction].
+    pub fn evict(&self, mut on_evicted: impl FnMut(&str)) -> bool {
+        let over_limit = || {
+            let limit = self.db_impl.maxmemory.load(Ordering::Relaxed);
+            limit > 0 && self.used_memory() > limit
+        };
+        if !over_limit() {
+            return true;
+        }
+        // Only one thread evicts at a time, so that they don't all evict for the same bytes
+        let mut eviction = lock(&self.db_impl.eviction);
+        while over_limit() {
+            let Some(key) = self.eviction_candidate(&mut eviction) else {
+                return false;
+            };
+            // Candidates may have been deleted since they were sampled
+            if self.del(&key) > 0 {
+                self.notify_keyspace_event(KeyspaceEvents::EVICTED, "evicted", &key);
+                on_evicted(&key);
+            }
+        }
+        true
+    }
+
+    fn eviction_candidate(&self, eviction: &mut Eviction) -> Option<String> {
+        let MaxMemory {
+            policy, samples, ..
+        } = eviction.maxmemory;
+        // dkv has no expires yet, so there are no keys that volatile policies may evict
+        if policy == EvictionPolicy::NoEviction || policy.is_volatile() {
+            return None;
+        }
+        if policy == EvictionPolicy::AllKeysRandom {
+            let mut key = None;
+            self.sample(1, |sampled, _| key = Some(sampled.to_owned()));
+            return key;
+        }
+        let now = self.now();
+        self.sample(samples.max(1), |key, entry| {
+            let score = match policy {
+                EvictionPolicy::AllKeysLfu => (u8::MAX - entry.access.frequency(now)) as u64,
+                _ => entry.access.idle(now),
+            };
+            eviction.pool.offer(score, key);
         });
+        eviction.pool.pop()
+    }
+
+    /// Calls `f` with up to `count` random entries of a shard that has some
+    fn sample(&self, count: usize, mut f: impl FnMut(&str, &Entry)) {
+        let shards = &self.db_impl.shards;
+        let first = evict::random() as usize;
+        for index in (0..shards.len()).map(|i| first.wrapping_add(i) & (shards.len() - 1)) {
+            let shard = read(&shards[index]);
+            if shard.map.len() > 0 {
+                shard.map.sample(evict::random(), count, &mut f);
+                return;
+            }
+        }
+    }
+
+    /// Inserts `entry`, counting the memory it uses instead of what it replaces
+    fn insert(&self, shard: &mut Shard, key: String, entry: Entry) {
+        let size = entry.size;
+        let previous = shard.map.insert(key, entry).map_or(0, |entry| entry.size);
+        self.account(previous, size);
+        self.account_tables(shard);
+    }
+
+    fn remove(&self, shard: &mut Shard, key: &str) -> Option<Entry> {
+        let entry = shard.map.remove(key);
+        if let Some(entry) = &entry {
+            self.account(entry.size, 0);
+        }
+        // Removing may also move entries along, when the shard is resizing
+        self.account_tables(shard);
+        entry
+    }
+
+    /// Counts the memory of the shard's tables, which change size as it resizes
+    fn account_tables(&self, shard: &mut Shard) {
+        let size = shard.map.table_memory();
+        let previous = std::mem::replace(&mut shard.table_memory, size);
+        self.account(previous, size);
+    }
+
+    fn account(&self, previous: usize, size: usize) {
+        let used_memory = &self.db_impl.used_memory;
+        if size > previous {
+            let used = used_memory.fetch_add(size - previous, Ordering::Relaxed) + size - previous;
+            self.db_impl.peak_memory.fetch_max(used, Ordering::Relaxed);
+        } else {
+            used_memory.fetch_sub(previous - size, Ordering::Relaxed);
+        }
+    }
+
+    /// Milliseconds since the DB was created, for access times
+    fn now(&self) -> u64 {
+        self.db_impl.created.elapsed().as_millis() as u64
+    }
+
+    /// Calls `f` with some of the keys, starting at `cursor`, until it was called
+    /// about `count` times. Returns the cursor to continue from, 0 once every key
+    /// was seen. Like redis' `SCAN`, keys that exist from start to end are seen at
+    /// least once, even when the keyspace resizes in between, but may be seen more
+    /// than once.
+    pub fn scan(&self, cursor: u64, count: usize, mut f: impl FnMut(&str, &Value)) -> u64 {
+        let shards = &self.db_impl.shards;
+        // The shard is in the cursor's low bits, and the shard's own cursor above them
+        let shard_bits = shards.len().trailing_zeros();
+        let mut index = (cursor & (shards.len() as u64 - 1)) as usize;
+        let mut shard_cursor = cursor >> shard_bits;
+        let mut seen = 0;
+        // Like redis, doesn't go through too many empty buckets in a single call
+        let mut visits = count.max(1) * 10;
+        loop {
+            {
+                let shard = read(&shards[index]);
+                loop {
+                    shard_cursor = shard.map.scan(shard_cursor, |key, entry| {
+                        seen += 1;
+                        f(key, &entry.value)
+                    });
+                    visits -= 1;
+                    if shard_cursor == 0 || seen >= count || visits == 0 {
+                        break;
+                    }
+                }
+            }
+            if shard_cursor == 0 {
+                index += 1;
+                if index == shards.len() {
+                    return 0;
+                }
+            }
+            if seen >= count || visits == 0 {
+                return shard_cursor << shard_bits | index as u64;
+            }
+        }
+    }
+
+    /// Moves entries of resizing shards over to their new table for about
+    /// `budget`, like redis' `activerehashing` does when the server is idle.
+    /// Returns true if some shards are still resizing.
+    pub fn rehash(&self, budget: Duration) -> bool {
+        let deadline = Instant::now() + budget;
+        let mut resizing = false;
+        for shard in self.db_impl.shards.iter() {
+            let mut shard = match shard.try_write() {
+                Ok(shard) => shard,
+                Err(TryLockError::Poisoned(e)) => e.into_inner(),
+                // Operations on the shard move entries along themselves
+                Err(TryLockError::WouldBlock) => {
+                    resizing = true;
+                    continue;
+                }
+            };
+            while shard.map.rehash(100) {
+                if Instant::now() >= deadline {
+                    self.account_tables(&mut shard);
+                    return true;
+                }
+            }
+            self.account_tables(&mut shard);
+        }
+        resizing
+    }
+
+    fn shard_index(&self, key: &str) -> usize {
+        self.db_impl.hasher.hash_one(key) as usize & (self.db_impl.shards.len() - 1)
+    }
+
+    fn read_shard(&self, key: &str) -> RwLockReadGuard<'_, Shard> {
+        read(&self.db_impl.shards[self.shard_index(key)])
+    }
+
+    fn write_shard(&self, key: &str) -> RwLockWriteGuard<'_, Shard> {
+        write(&self.db_impl.shards[self.shard_index(key)])
+    }
+
+    /// Locks the shards of every key, always in the order of their index
+    /// so that threads locking several of them can't deadlock each other
+    fn write_shards(&self, keys: &[&str]) -> LockedShards<'_> {
+        let mut indexes: Vec<usize> = keys.iter().map(|key| self.shard_index(key)).collect();
+        indexes.sort_unstable();
+        indexes.dedup();
+        LockedShards {
+            db: self,
+            shards: indexes
+                .into_iter()
+                .map(|index| (index, write(&self.db_impl.shards[index])))
+                .collect(),
+        }
+    }
+
+    pub fn keyspace_events(&self) -> KeyspaceEvents {
+        KeyspaceEvents::from_bits(self.db_impl.keyspace_events.load(Ordering::Relaxed))
+    }
+
+    pub fn set_keyspace_events(&self, events: KeyspaceEvents) {
+        self.db_impl
+            .keyspace_events
+            .store(events.bits(), Ordering::Relaxed)
+    }
+
+    /// Publishes `event` for `key` to `__keyspace@0__:<key>` and/or `__keyevent@0__:<event>`,
+    /// if notifications for `class` are enabled through [DB::set_keyspace_events].
+    pub fn notify_keyspace_event(&self, class: KeyspaceEvents, event: &str, key: &str) {
+        let events = self.keyspace_events();
+        if !events.should_notify(class) {
+            return;
+        }
+        if events.contains(KeyspaceEvents::KEYSPACE) {
+            self.publish(&format!("__keyspace@0__:{}", key), event);
+        }
+        if events.contains(KeyspaceEvents::KEYEVENT) {
+            self.publish(&format!("__keyevent@0__:{}", event), key);
+        }
+    }
+
+    pub fn publish(&self, channel: &str, value: &str) {
+        let subscribers = read(&self.db_impl.subscribers)
+            .values()
+            .filter(|it| it.channel == channel)
+            .cloned()
+            .collect::<Vec<Subscriber>>();
         // Subscriber functions may run for a long time, so we don't want to hold the lock
         // while they run, so we copy them out of the lock and then call them.
         for subscriber in subscribers {
@@ -73,32 +434,151 @@ impl DB {
         channel: &str,
         f: impl Fn(Message) + Send + Sync + 'static,
     ) -> SubscriberId {
-        self.with_lock(move |db| {
-            db.next_subscriber_id += 1;
-            let id = SubscriberId(db.next_subscriber_id);
-            db.subscribers.insert(
-                id,
-                Subscriber {
-                    callback: Arc::new(f),
-                    channel: channel.to_string(),
-                },
-            );
-            id
-        })
+        let id = SubscriberId(
+            self.db_impl
+                .next_subscriber_id
+                .fetch_add(1, Ordering::Relaxed)
+                + 1,
+        );
+        write(&self.db_impl.subscribers).insert(
+            id,
+            Subscriber {
+                callback: Arc::new(f),
+                channel: channel.to_string(),
+            },
+        );
+        id
     }
 
     pub fn unsubscribe(&self, id: SubscriberId) {
-        self.with_lock(|db| {
-            db.subscribers.remove(&id);
-        });
+        write(&self.db_impl.subscribers).remove(&id);
     }
 }
 
 struct DBImpl {
-    map: HashMap<String, Value>,
-    next_subscriber_id: usize,
-    subscribers: HashMap<SubscriberId, Subscriber>,
+    shards: Box<[RwLock<Shard>]>,
+    /// Picks the shard of a key
+    hasher: RandomState,
+    next_subscriber_id: AtomicUsize,
+    subscribers: RwLock<HashMap<SubscriberId, Subscriber>>,
+    keyspace_events: AtomicU16,
+    /// When access times count from
+    created: Instant,
+    /// The sum of every entry's [Entry::size] and shard's [Shard::table_memory]
+    used_memory: AtomicUsize,
+    /// The highest `used_memory` so far
+    peak_memory: AtomicUsize,
+    /// Same as [MaxMemory::limit], so that checking it doesn't need a lock
+    maxmemory: AtomicUsize,
+    eviction: Mutex<Eviction>,
+}
+
+#[derive(Default)]
+struct Shard {
+    map: Dict<Entry>,
+    /// What the map's tables add to [DB::used_memory], as of its last change
+    table_memory: usize,
 }
+
+struct Entry {
+    value: Value,
+    access: Access,
+    /// What the entry adds to [DB::used_memory], as of its last change
+    size: usize,
+}
+
+/// What an entry uses besides its key's and value's allocations:
+/// itself, and the key and link to the next entry of its node in the [Dict]
+const ENTRY_OVERHEAD: usize = size_of::<Entry>() + size_of::<String>() + size_of::<usize>();
+
+impl Entry {
+    fn new(key: &str, value: Value, now: u64) -> Entry {
+        Entry {
+            size: Entry::size(key, &value, MEMORY_SAMPLES),
+            value,
+            access: Access::new(now),
+        }
+    }
+
+    fn size(key: &str, value: &Value, samples: usize) -> usize {
+        ENTRY_OVERHEAD + key.len() + value.memory_usage(samples)
+    }
+}
+
+/// What [DB::object_info] tells about a key
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ObjectInfo {
+    /// Same as [Value::encoding]
+    pub encoding: &'static str,
+    /// Since the key was last accessed
+    pub idle: Duration,
+    /// A logarithmic counter of accesses, like redis' LFU counter. It starts at 5
+    /// for new keys, and goes down by one for every minute without accesses.
+    pub frequency: u8,
+}
+
+/// Where the memory of a [DB] goes, in bytes
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MemoryStats {
+    /// Same as [DB::used_memory]
+    pub used_memory: usize,
+    /// The highest `used_memory` since the DB was created
+    pub peak_memory: usize,
+    pub keys: usize,
+    /// The buckets of the hash tables holding the keys
+    pub table_overhead: usize,
+    /// What each entry uses besides its key and value, for all of them
+    pub entry_overhead: usize,
+}
+
+impl MemoryStats {
+    /// Bytes used by keys and values themselves
+    pub fn dataset(&self) -> usize {
+        self.used_memory
+            .saturating_sub(self.table_overhead + self.entry_overhead)
+    }
+}
+
+#[derive(Default)]
+struct Eviction {
+    maxmemory: MaxMemory,
+    pool: EvictionPool,
+}
+
+/// Write locks on the shards of several keys
+struct LockedShards<'a> {
+    db: &'a DB,
+    shards: Vec<(usize, RwLockWriteGuard<'a, Shard>)>,
+}
+
+impl LockedShards<'_> {
+    fn get(&mut self, key: &str) -> &mut Shard {
+        let index = self.db.shard_index(key);
+        let (_, shard) = self
+            .shards
+            .iter_mut()
+            .find(|(locked, _)| *locked == index)
+            .expect("The key's shard is locked");
+        shard
+    }
+}
+
+// A panic while a lock is held, e.g. in a callback given to [DB::mutate], leaves
+// the lock poisoned. The keyspace is still usable, so it keeps being served
+// instead of taking every other client down with it.
+
+fn read<T>(lock: &RwLock<T>) -> RwLockReadGuard<'_, T> {
+    lock.read().unwrap_or_else(|e| e.into_inner())
+}
+
+fn write<T>(lock: &RwLock<T>) -> RwLockWriteGuard<'_, T> {
+    lock.write().unwrap_or_else(|e| e.into_inner())
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|e| e.into_inner())
+}
+
 #[derive(Copy, Clone, PartialEq, Eq, Hash)]
 pub struct SubscriberId(usize);
 
@@ -116,14 +596,14 @@ pub struct Message<'a> {
 
 #[cfg(test)]
 mod test {
-    use std::thread::spawn;
+    use std::{sync::Mutex, thread::spawn};
 
     use super::*;
 
     #[test]
     fn should_allow_reading_without_copying() {
         let db = DB::new();
-        db.set("key".to_string(), Value::String("value".to_string()));
+        db.set("key".to_string(), Value::from("value"));
         let len = db.view("key", |value: Option<&Value>| match value {
             Some(Value::String(value)) => value.len(),
             _ => panic!(),
@@ -179,4 +659,285 @@ mod test {
         publisher.join().unwrap();
         assert_eq!(1, *count.lock().unwrap());
     }
+
+    #[test]
+    fn should_publish_keyspace_events() {
+        let db = DB::new();
+        db.set_keyspace_events(KeyspaceEvents::parse("KEn$").unwrap());
+        let messages = Arc::new(Mutex::new(vec![]));
+        for channel in ["__keyspace@0__:key", "__keyevent@0__:new"] {
+            let messages = messages.clone();
+            db.subscribe(channel, move |m| {
+                messages
+                    .lock()
+                    .unwrap()
+                    .push(format!("{} {}", m.channel, m.value));
+            });
+        }
+        db.set("key".to_string(), Value::from("1"));
+        db.set("key".to_string(), Value::from("2"));
+        db.notify_keyspace_event(KeyspaceEvents::STRING, "set", "key");
+        db.notify_keyspace_event(KeyspaceEvents::HASH, "hset", "key");
+
+        assert_eq!(
+            *messages.lock().unwrap(),
+            vec![
+                "__keyspace@0__:key new",
+                "__keyevent@0__:new key",
+                "__keyspace@0__:key set",
+            ]
+        );
+    }
+
+    #[test]
+    fn renames_keys_across_shards() {
+        let db = DB::with_shards(4);
+        for i in 0..100 {
+            db.set(format!("key{}", i), Value::from(i.to_string()));
+        }
+        for i in 0..100 {
+            assert!(db.rename(&format!("key{}", i), format!("renamed{}", i)));
+        }
+        assert!(!db.rename("key0", "other".to_string()));
+        assert!(db.rename("renamed1", "renamed1".to_string()));
+        for i in 0..100 {
+            assert!(!db.exists(&format!("key{}", i)));
+            let value = db.get_optional(&format!("renamed{}", i));
+            assert!(matches!(value, Some(Value::String(s)) if s == i.to_string().as_bytes()));
+        }
+        db.flush_all();
+        assert!(!db.exists("renamed1"));
+    }
+
+    #[test]
+    fn locks_shards_in_the_same_order() {
+        let db = DB::with_shards(2);
+        let keys: Vec<String> = (0..16).map(|i| format!("key{}", i)).collect();
+        let threads: Vec<_> = (0..4)
+            .map(|thread| {
+                let db = db.clone();
+                let keys = keys.clone();
+                spawn(move || {
+                    for round in 0..2_000 {
+                        let from = &keys[(thread + round) % keys.len()];
+                        let to = &keys[(thread * 3 + round * 7) % keys.len()];
+                        db.set(from.clone(), Value::from("value"));
+                        db.rename(from, to.clone());
+                    }
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+    }
+
+    #[test]
+    fn keeps_serving_after_a_panic() {
+        let db = DB::with_shards(1);
+        db.set("key".to_string(), Value::from("value"));
+        let result = std::panic::catch_unwind(|| db.mutate("key", |_| panic!("failing command")));
+        assert!(result.is_err());
+        assert!(db.exists("key"));
+        db.set("other".to_string(), Value::from("value"));
+        assert!(db.exists("other"));
+    }
+
+    #[test]
+    fn scans_every_key_while_the_keyspace_grows() {
+        let db = DB::with_shards(4);
+        for i in 0..1000 {
+            db.set(format!("key{}", i), Value::from("value"));
+        }
+        let mut seen = std::collections::HashSet::new();
+        let mut cursor = 0;
+        let mut calls = 0;
+        loop {
+            cursor = db.scan(cursor, 10, |key, _| {
+                seen.insert(key.to_owned());
+            });
+            calls += 1;
+            for i in 0..50 {
+                db.set(format!("new{}_{}", calls, i), Value::from("value"));
+            }
+            db.rehash(Duration::from_millis(1));
+            if cursor == 0 {
+                break;
+            }
+        }
+        assert!(calls > 10);
+        assert!((0..1000).all(|i| seen.contains(&format!("key{}", i))));
+
+        db.flush_all();
+        assert_eq!(db.scan(0, 10, |key, _| panic!("{} was flushed", key)), 0);
+    }
+
+    #[test]
+    fn accounts_memory_of_every_change() {
+        // A single shard, so that renaming doesn't allocate another shard's table
+        let db = DB::with_shards(1);
+        assert_eq!(db.used_memory(), 0);
+        db.set("key".to_string(), Value::from("a".repeat(1000)));
+        let used = db.used_memory();
+        assert!(used > 1000 && used < 1200, "{}", used);
+        db.mutate("key", |value| match value {
+            Some(Value::String(s)) => s.extend_from_slice(&[b'a'; 9000]),
+            _ => unreachable!(),
+        });
+        assert!(db.used_memory() > 10_000);
+        db.set("key".to_string(), Value::from("a".repeat(1000)));
+        assert_eq!(db.used_memory(), used);
+        db.rename("key", "renamed".to_string());
+        assert_eq!(db.used_memory(), used + "renamed".len() - "key".len());
+        db.set("other".to_string(), Value::from("value"));
+        db.del("renamed");
+        assert!(db.used_memory() < 200);
+        db.flush_all();
+        assert_eq!(db.used_memory(), 0);
+    }
+
+    #[test]
+    fn reports_memory_usage() {
+        let db = DB::with_shards(4);
+        assert_eq!(db.memory_stats(), MemoryStats::default());
+        let mut list = vec!["a".to_string(); 100];
+        list.push("a".repeat(10_000));
+        db.set("list".to_string(), Value::from(list));
+        let sampled = db.memory_usage("list", MEMORY_SAMPLES).unwrap();
+        let exact = db.memory_usage("list", 0).unwrap();
+        assert!(exact > sampled + 9_000, "{} {}", sampled, exact);
+        assert_eq!(db.memory_usage("nope", 0), None);
+        for i in 0..100 {
+            db.set(format!("key{}", i), Value::from("value"));
+        }
+        let stats = db.memory_stats();
+        assert_eq!(stats.keys, 101);
+        assert_eq!(stats.used_memory, db.used_memory());
+        assert!(stats.peak_memory >= db.used_memory());
+        assert!(stats.table_overhead >= 101 * size_of::<usize>());
+        assert_eq!(stats.entry_overhead, 101 * ENTRY_OVERHEAD);
+        // Keys, values and the list's slots
+        assert!(stats.dataset() > 100 * ("keyNN".len() + "value".len()) + 101 * 24);
+
+        db.flush_all();
+        let stats = db.memory_stats();
+        assert_eq!((stats.used_memory, stats.keys, stats.dataset()), (0, 0, 0));
+        assert!(stats.peak_memory > 0);
+    }
+
+    #[test]
+    fn reports_object_info_without_touching() {
+        let db = DB::new();
+        assert_eq!(db.object_info("key"), None);
+        db.set("key".to_string(), Value::from("12"));
+        std::thread::sleep(Duration::from_millis(20));
+        let info = db.object_info("key").unwrap();
+        assert_eq!((info.encoding, info.frequency), ("int", 5));
+        assert!(info.idle >= Duration::from_millis(20));
+        assert!(db.object_info("key").unwrap().idle >= info.idle);
+        for _ in 0..100 {
+            db.get_optional("key");
+        }
+        let info = db.object_info("key").unwrap();
+        assert!(info.idle < Duration::from_millis(20));
+        assert!(info.frequency > 5);
+    }
+
+    /// Evicts about `evicted` of the `keys` keys of `db`, which use the same memory
+    fn evict_from(db: &DB, policy: EvictionPolicy, keys: usize, evicted: usize) -> Vec<String> {
+        let limit = db.used_memory() / keys * (keys - evicted);
+        db.set_maxmemory(MaxMemory {
+            limit,
+            policy,
+            samples: 5,
+        });
+        let mut evicted = vec![];
+        assert!(db.evict(|key| evicted.push(key.to_owned())));
+        assert!(db.used_memory() <= limit);
+        evicted
+    }
+
+    #[test]
+    fn evicts_least_recently_used_keys() {
+        let db = DB::with_shards(4);
+        for i in 0..200 {
+            db.set(format!("key{}", i), Value::from("value"));
+        }
+        std::thread::sleep(Duration::from_millis(20));
+        for i in 100..200 {
+            db.get_optional(&format!("key{}", i));
+        }
+        let evicted = evict_from(&db, EvictionPolicy::AllKeysLru, 200, 50);
+        assert!(evicted.len() >= 50);
+        let recent = (100..200)
+            .filter(|i| evicted.contains(&format!("key{}", i)))
+            .count();
+        assert!(recent < 10, "{} recently used keys were evicted", recent);
+    }
+
+    #[test]
+    fn evicts_least_frequently_used_keys() {
+        let db = DB::with_shards(4);
+        for i in 0..200 {
+            db.set(format!("key{}", i), Value::from("value"));
+        }
+        for _ in 0..100 {
+            for i in 100..200 {
+                db.view(&format!("key{}", i), |_| {});
+            }
+        }
+        let evicted = evict_from(&db, EvictionPolicy::AllKeysLfu, 200, 50);
+        let frequent = (100..200)
+            .filter(|i| evicted.contains(&format!("key{}", i)))
+            .count();
+        assert!(
+            frequent < 10,
+            "{} frequently used keys were evicted",
+            frequent
+        );
+
+        // Rewriting keys doesn't reset how often they were used
+        for i in 100..150 {
+            db.set(format!("key{}", i), Value::from("value"));
+        }
+        let evicted = evict_from(&db, EvictionPolicy::AllKeysLfu, 150, 25);
+        let rewritten = (100..150)
+            .filter(|i| evicted.contains(&format!("key{}", i)))
+            .count();
+        assert!(
+            rewritten < 5,
+            "{} frequently used keys were evicted after being rewritten",
+            rewritten
+        );
+
+        let evicted = evict_from(&db, EvictionPolicy::AllKeysRandom, 125, 50);
+        assert!(evicted.len() >= 50);
+    }
+
+    #[test]
+    fn fails_to_evict_without_candidates() {
+        let db = DB::new();
+        for i in 0..10 {
+            db.set(format!("key{}", i), Value::from("value"));
+        }
+        for policy in [EvictionPolicy::NoEviction, EvictionPolicy::VolatileLru] {
+            db.set_maxmemory(MaxMemory {
+                limit: 1,
+                policy,
+                samples: 5,
+            });
+            assert!(!db.evict(|key| panic!("{} was evicted", key)));
+        }
+        db.set_maxmemory(MaxMemory {
+            limit: 1,
+            policy: EvictionPolicy::AllKeysLru,
+            samples: 5,
+        });
+        // Every key gets evicted, but the shards' empty tables still don't fit
+        assert!(!db.evict(|_| {}));
+        assert_eq!(db.memory_stats().keys, 0);
+        db.set_maxmemory(MaxMemory::default());
+        db.set("key".to_string(), Value::from("value"));
+        assert!(db.evict(|key| panic!("{} was evicted", key)));
+    }
 }
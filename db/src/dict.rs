@@ -0,0 +1,437 @@
+//! A hash table that resizes a few buckets at a time, like redis' `dict`, so
+//! that no single insert has to move every entry at once.
+//!
+//! While resizing, entries live in two tables. Every operation that has mutable
+//! access moves a bucket from the old table to the new one, and
+//! [Dict::rehash] moves more of them while the server is idle. Lookups check
+//! both tables until the old one is empty.
+use std::{
+    hash::{BuildHasher, RandomState},
+    iter,
+};
+
+const INITIAL_SIZE: usize = 4;
+/// The table shrinks once fewer than this percentage of its buckets are used
+const MIN_FILL: usize = 10;
+/// How many empty buckets a rehash step skips for each bucket it was asked to move
+const EMPTY_VISITS: usize = 10;
+
+pub(crate) struct Dict<V> {
+    tables: [Table<V>; 2],
+    /// While resizing, the next bucket of `tables[0]` to move to `tables[1]`
+    rehash_index: Option<usize>,
+    hasher: RandomState,
+}
+
+/// A power of two number of buckets, each a chain of entries
+struct Table<V> {
+    buckets: Vec<Bucket<V>>,
+    used: usize,
+}
+
+type Bucket<V> = Option<Box<Node<V>>>;
+
+struct Node<V> {
+    key: String,
+    value: V,
+    next: Bucket<V>,
+}
+
+impl<V> Default for Dict<V> {
+    fn default() -> Self {
+        Dict {
+            tables: [Table::with_size(0), Table::with_size(0)],
+            rehash_index: None,
+            hasher: RandomState::new(),
+        }
+    }
+}
+
+impl<V> Dict<V> {
+    pub fn len(&self) -> usize {
+        self.tables[0].used + self.tables[1].used
+    }
+
+    /// Bytes allocated for the buckets of the tables, but not for the entries in them
+    pub fn table_memory(&self) -> usize {
+        self.tables
+            .iter()
+            .map(|table| table.buckets.capacity() * std::mem::size_of::<Bucket<V>>())
+            .sum()
+    }
+
+    pub fn is_rehashing(&self) -> bool {
+        self.rehash_index.is_some()
+    }
+
+    pub fn get(&self, key: &str) -> Option<&V> {
+        let hash = self.hasher.hash_one(key);
+        self.tables()
+            .iter()
+            .find_map(|table| table.find(hash, key))
+            .map(|node| &node.value)
+    }
+
+    pub fn contains_key(&self, key: &str) -> bool {
+        self.get(key).is_some()
+    }
+
+    pub fn get_mut(&mut self, key: &str) -> Option<&mut V> {
+        self.rehash(1);
+        self.find_mut(key)
+    }
+
+    /// Returns the value `key` had before, if any
+    pub fn insert(&mut self, key: String, value: V) -> Option<V> {
+        self.rehash(1);
+        if let Some(existing) = self.find_mut(&key) {
+            return Some(std::mem::replace(existing, value));
+        }
+        self.expand_if_needed();
+        let hash = self.hasher.hash_one(&key);
+        // New entries go to the new table, so that the old one only empties
+        let table = &mut self.tables[self.is_rehashing() as usize];
+        table.push(
+            hash,
+            Box::new(Node {
+                key,
+                value,
+                next: None,
+            }),
+        );
+        None
+    }
+
+    pub fn remove(&mut self, key: &str) -> Option<V> {
+        self.rehash(1);
+        let hash = self.hasher.hash_one(key);
+        let tables = if self.is_rehashing() { 2 } else { 1 };
+        let node = self.tables[..tables]
+            .iter_mut()
+            .find_map(|table| table.remove(hash, key))?;
+        self.shrink_if_needed();
+        Some(node.value)
+    }
+
+    pub fn clear(&mut self) {
+        *self = Dict {
+            hasher: std::mem::take(&mut self.hasher),
+            ..Dict::default()
+        };
+    }
+
+    /// Calls `f` with the entries of a few buckets, starting at `cursor`, and returns
+    /// the cursor to continue from, 0 once every bucket was visited.
+    ///
+    /// Like redis, the cursor's bits are incremented starting from the highest one,
+    /// so that buckets that were already visited map to buckets that were too once
+    /// the table resized. Entries that exist from start to end are seen at least
+    /// once, but may be seen more than once.
+    pub fn scan(&self, mut cursor: u64, mut f: impl FnMut(&str, &V)) -> u64 {
+        if self.len() == 0 {
+            return 0;
+        }
+        let next = |cursor: u64, mask: u64| {
+            // Sets the bits outside of the mask, so that incrementing
+            // the reversed cursor carries over into the mask's bits
+            (cursor | !mask)
+                .reverse_bits()
+                .wrapping_add(1)
+                .reverse_bits()
+        };
+        if !self.is_rehashing() {
+            let table = &self.tables[0];
+            let mask = table.mask();
+            table.visit(cursor & mask, &mut f);
+            return next(cursor, mask);
+        }
+        let (small, big) = if self.tables[0].buckets.len() <= self.tables[1].buckets.len() {
+            (&self.tables[0], &self.tables[1])
+        } else {
+            (&self.tables[1], &self.tables[0])
+        };
+        let (small_mask, big_mask) = (small.mask(), big.mask());
+        small.visit(cursor & small_mask, &mut f);
+        // Then every bucket of the bigger table that the smaller table's bucket expands to
+        loop {
+            big.visit(cursor & big_mask, &mut f);
+            cursor = next(cursor, big_mask);
+            if cursor & (small_mask ^ big_mask) == 0 {
+                return cursor;
+            }
+        }
+    }
+
+    /// Calls `f` with up to `count` entries, from consecutive buckets starting
+    /// at the one `start` maps to, like redis' `dictGetSomeKeys`. Picking a
+    /// random `start` gives random entries, but not evenly distributed ones.
+    pub fn sample(&self, start: u64, count: usize, mut f: impl FnMut(&str, &V)) {
+        let buckets = self.tables().iter().map(|table| table.buckets.len() as u64);
+        let mut seen = 0;
+        for index in (0..buckets.max().unwrap_or(0)).map(|i| start.wrapping_add(i)) {
+            for table in self.tables() {
+                if table.buckets.is_empty() {
+                    continue;
+                }
+                table.visit(index & table.mask(), &mut |key, value| {
+                    if seen < count {
+                        seen += 1;
+                        f(key, value);
+                    }
+                });
+            }
+            if seen >= count {
+                return;
+            }
+        }
+    }
+
+    /// Moves up to `buckets` buckets to the resized table.
+    /// Returns true if there are more to move.
+    pub fn rehash(&mut self, buckets: usize) -> bool {
+        let Some(mut index) = self.rehash_index else {
+            return false;
+        };
+        let mut empty_visits = buckets * EMPTY_VISITS;
+        let [old, new] = &mut self.tables;
+        for _ in 0..buckets {
+            if old.used == 0 {
+                break;
+            }
+            while old.buckets[index].is_none() {
+                index += 1;
+                empty_visits -= 1;
+                if empty_visits == 0 {
+                    self.rehash_index = Some(index);
+                    return true;
+                }
+            }
+            let mut next = old.buckets[index].take();
+            while let Some(mut node) = next {
+                next = node.next.take();
+                old.used -= 1;
+                new.push(self.hasher.hash_one(&node.key), node);
+            }
+            index += 1;
+        }
+        if old.used > 0 {
+            self.rehash_index = Some(index);
+            return true;
+        }
+        self.tables[0] = std::mem::replace(&mut self.tables[1], Table::with_size(0));
+        self.rehash_index = None;
+        // Entries may have been removed faster than they were moved
+        self.shrink_if_needed();
+        self.is_rehashing()
+    }
+
+    /// The tables holding entries
+    fn tables(&self) -> &[Table<V>] {
+        &self.tables[..if self.is_rehashing() { 2 } else { 1 }]
+    }
+
+    fn find_mut(&mut self, key: &str) -> Option<&mut V> {
+        let hash = self.hasher.hash_one(key);
+        let tables = if self.is_rehashing() { 2 } else { 1 };
+        self.tables[..tables]
+            .iter_mut()
+            .find_map(|table| table.find_mut(hash, key))
+            .map(|node| &mut node.value)
+    }
+
+    fn expand_if_needed(&mut self) {
+        if self.is_rehashing() {
+            return;
+        }
+        let table = &self.tables[0];
+        if table.buckets.is_empty() {
+            self.tables[0] = Table::with_size(INITIAL_SIZE);
+        } else if table.used >= table.buckets.len() {
+            self.resize((table.used + 1).next_power_of_two());
+        }
+    }
+
+    fn shrink_if_needed(&mut self) {
+        let table = &self.tables[0];
+        if self.is_rehashing()
+            || table.buckets.len() <= INITIAL_SIZE
+            || table.used * 100 >= table.buckets.len() * MIN_FILL
+        {
+            return;
+        }
+        self.resize(table.used.next_power_of_two().max(INITIAL_SIZE));
+    }
+
+    fn resize(&mut self, size: usize) {
+        self.tables[1] = Table::with_size(size);
+        self.rehash_index = Some(0);
+    }
+}
+
+impl<V> Table<V> {
+    fn with_size(size: usize) -> Table<V> {
+        Table {
+            buckets: iter::repeat_with(|| None).take(size).collect(),
+            used: 0,
+        }
+    }
+
+    fn mask(&self) -> u64 {
+        self.buckets.len() as u64 - 1
+    }
+
+    fn index(&self, hash: u64) -> usize {
+        (hash & self.mask()) as usize
+    }
+
+    fn find(&self, hash: u64, key: &str) -> Option<&Node<V>> {
+        if self.buckets.is_empty() {
+            return None;
+        }
+        iter::successors(self.buckets[self.index(hash)].as_deref(), |node| {
+            node.next.as_deref()
+        })
+        .find(|node| node.key == key)
+    }
+
+    fn find_mut(&mut self, hash: u64, key: &str) -> Option<&mut Node<V>> {
+        if self.buckets.is_empty() {
+            return None;
+        }
+        let index = self.index(hash);
+        let mut next = self.buckets[index].as_deref_mut();
+        while let Some(node) = next {
+            if node.key == key {
+                return Some(node);
+            }
+            next = node.next.as_deref_mut();
+        }
+        None
+    }
+
+    fn push(&mut self, hash: u64, mut node: Box<Node<V>>) {
+        let index = self.index(hash);
+        node.next = self.buckets[index].take();
+        self.buckets[index] = Some(node);
+        self.used += 1;
+    }
+
+    fn remove(&mut self, hash: u64, key: &str) -> Option<Box<Node<V>>> {
+        if self.buckets.is_empty() {
+            return None;
+        }
+        let index = self.index(hash);
+        let mut link = &mut self.buckets[index];
+        while link.as_ref().is_some_and(|node| node.key != key) {
+            link = &mut link.as_mut().unwrap().next;
+        }
+        let mut node = link.take()?;
+        *link = node.next.take();
+        self.used -= 1;
+        Some(node)
+    }
+
+    fn visit(&self, index: u64, f: &mut impl FnMut(&str, &V)) {
+        let bucket = self.buckets[index as usize].as_deref();
+        for node in iter::successors(bucket, |node| node.next.as_deref()) {
+            f(&node.key, &node.value);
+        }
+    }
+}
+
+impl<V> Drop for Table<V> {
+    /// Drops chains one node at a time, instead of recursing through them
+    fn drop(&mut self) {
+        for bucket in &mut self.buckets {
+            let mut next = bucket.take();
+            while let Some(mut node) = next {
+                next = node.next.take();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashSet;
+
+    use super::*;
+
+    #[test]
+    fn resizes_incrementally() {
+        let mut dict = Dict::default();
+        for i in 0..1000 {
+            assert_eq!(dict.insert(i.to_string(), i), None);
+            // Every entry stays reachable while it's moved to the bigger table
+            assert_eq!(dict.get("0"), Some(&0));
+            assert_eq!(dict.get(&i.to_string()), Some(&i));
+        }
+        assert_eq!(dict.insert("1".to_string(), -1), Some(1));
+        assert_eq!(dict.len(), 1000);
+        while dict.rehash(1) {}
+        assert!(dict.tables[0].buckets.len() >= 1000);
+
+        assert_eq!(dict.remove("1"), Some(-1));
+        assert_eq!(dict.remove("1"), None);
+        for i in (0..990).filter(|i| *i != 1) {
+            assert_eq!(dict.remove(&i.to_string()), Some(i));
+        }
+        while dict.rehash(1) {}
+        assert!(dict.tables[0].buckets.len() <= 64);
+        assert_eq!(dict.len(), 10);
+        *dict.get_mut("999").unwrap() += 1;
+        assert_eq!(dict.get("999"), Some(&1000));
+    }
+
+    #[test]
+    fn scan_sees_every_key_across_resizes() {
+        let mut dict = Dict::default();
+        for i in 0..500 {
+            dict.insert(format!("key{}", i), ());
+        }
+        let mut seen = HashSet::new();
+        let mut cursor = 0;
+        let mut step = 0;
+        loop {
+            cursor = dict.scan(cursor, |key, _| {
+                seen.insert(key.to_owned());
+            });
+            // Grows the table, then shrinks it, in the middle of the scan
+            step += 1;
+            if step < 20 {
+                for i in 0..200 {
+                    dict.insert(format!("new{}_{}", step, i), ());
+                }
+            } else if step < 40 {
+                for i in 0..200 {
+                    dict.remove(&format!("new{}_{}", step - 19, i));
+                }
+            }
+            if cursor == 0 {
+                break;
+            }
+        }
+        for i in 0..500 {
+            assert!(seen.contains(&format!("key{}", i)), "key{} wasn't seen", i);
+        }
+    }
+
+    #[test]
+    fn samples_entries_from_any_bucket() {
+        let mut dict = Dict::default();
+        dict.sample(0, 5, |_, _: &()| panic!("the dict is empty"));
+        for i in 0..100 {
+            dict.insert(i.to_string(), ());
+        }
+        let mut seen = HashSet::new();
+        for start in 0..1000 {
+            let mut sampled = 0;
+            dict.sample(start * 7919, 20, |key, _| {
+                sampled += 1;
+                seen.insert(key.to_owned());
+            });
+            assert_eq!(sampled, 20);
+        }
+        assert_eq!(seen.len(), 100);
+    }
+}
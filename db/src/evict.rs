@@ -0,0 +1,259 @@
+//! Approximated LRU and LFU eviction, like redis' `maxmemory-policy`.
+//!
+//! Rather than keeping every key sorted by when it was last used, a few random
+//! keys are sampled each time one has to be evicted. The best candidates seen
+//! so far are kept in a small pool, so that eviction gets closer to a true LRU
+//! or LFU the more keys it evicts.
+use std::{
+    cell::Cell,
+    hash::{BuildHasher, RandomState},
+    sync::atomic::{AtomicU32, AtomicU64, Ordering},
+};
+
+/// Candidates kept between evictions, same as redis' `EVPOOL_SIZE`
+const POOL_SIZE: usize = 16;
+/// The LFU counter of new keys, so that they aren't evicted right away
+const LFU_INIT_VAL: u8 = 5;
+/// How hard it gets to increment the LFU counter, like redis' `lfu-log-factor`.
+/// With 10, the counter reaches 255 after about a million accesses.
+const LFU_LOG_FACTOR: u32 = 10;
+/// Minutes without accesses after which the LFU counter is decremented, like redis' `lfu-decay-time`
+const LFU_DECAY_MINUTES: u16 = 1;
+
+/// How keys are picked once the keyspace uses more than `maxmemory`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EvictionPolicy {
+    /// Nothing is evicted, and commands that need more memory fail
+    NoEviction,
+    AllKeysLru,
+    AllKeysLfu,
+    AllKeysRandom,
+    /// Like the `allkeys` policies, but only for keys with an expire
+    VolatileLru,
+    VolatileLfu,
+    VolatileRandom,
+    /// Evicts the keys that expire the soonest
+    VolatileTtl,
+}
+
+impl EvictionPolicy {
+    const NAMES: [(&'static str, EvictionPolicy); 8] = [
+        ("noeviction", Self::NoEviction),
+        ("allkeys-lru", Self::AllKeysLru),
+        ("allkeys-lfu", Self::AllKeysLfu),
+        ("allkeys-random", Self::AllKeysRandom),
+        ("volatile-lru", Self::VolatileLru),
+        ("volatile-lfu", Self::VolatileLfu),
+        ("volatile-random", Self::VolatileRandom),
+        ("volatile-ttl", Self::VolatileTtl),
+    ];
+
+    pub fn parse(s: &str) -> Option<EvictionPolicy> {
+        Self::NAMES
+            .iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case(s))
+            .map(|(_, policy)| *policy)
+    }
+
+    pub fn name(self) -> &'static str {
+        Self::NAMES
+            .iter()
+            .find(|(_, policy)| *policy == self)
+            .map(|(name, _)| *name)
+            .unwrap()
+    }
+
+    /// Every policy's name, comma separated
+    pub fn names() -> String {
+        Self::NAMES.map(|(name, _)| name).join(", ")
+    }
+
+    /// Only keys with an expire can be evicted
+    pub fn is_volatile(self) -> bool {
+        matches!(
+            self,
+            Self::VolatileLru | Self::VolatileLfu | Self::VolatileRandom | Self::VolatileTtl
+        )
+    }
+}
+
+/// How much memory the keyspace may use, and what happens when it uses more,
+/// as configured by redis' `maxmemory`, `maxmemory-policy` and `maxmemory-samples`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MaxMemory {
+    /// Bytes, 0 for no limit
+    pub limit: usize,
+    pub policy: EvictionPolicy,
+    /// Keys sampled each time one is evicted. More is closer to a true LRU
+    /// or LFU, but slower.
+    pub samples: usize,
+}
+
+impl Default for MaxMemory {
+    fn default() -> Self {
+        MaxMemory {
+            limit: 0,
+            policy: EvictionPolicy::NoEviction,
+            samples: 5,
+        }
+    }
+}
+
+/// When an entry was last accessed and how often, updated
+/// through shared references so that reads can do it too
+pub(crate) struct Access {
+    /// Milliseconds since the DB was created
+    last: AtomicU64,
+    /// The LFU counter in the low 8 bits, and above them the
+    /// minute it was last decremented, like redis' `lru` field
+    lfu: AtomicU32,
+}
+
+impl Access {
+    pub fn new(now: u64) -> Access {
+        Access {
+            last: AtomicU64::new(now),
+            lfu: AtomicU32::new(pack_lfu(now, LFU_INIT_VAL)),
+        }
+    }
+
+    pub fn touch(&self, now: u64) {
+        self.last.store(now, Ordering::Relaxed);
+        let counter = log_incr(self.frequency(now));
+        self.lfu.store(pack_lfu(now, counter), Ordering::Relaxed);
+    }
+
+    /// Milliseconds since the entry was last accessed
+    pub fn idle(&self, now: u64) -> u64 {
+        now.saturating_sub(self.last.load(Ordering::Relaxed))
+    }
+
+    /// The LFU counter, after decrementing it for every
+    /// `LFU_DECAY_MINUTES` the entry wasn't accessed
+    pub fn frequency(&self, now: u64) -> u8 {
+        let lfu = self.lfu.load(Ordering::Relaxed);
+        let (decremented, counter) = ((lfu >> 8) as u16, lfu as u8);
+        let periods = minutes(now).wrapping_sub(decremented) / LFU_DECAY_MINUTES;
+        counter.saturating_sub(periods.min(u8::MAX as u16) as u8)
+    }
+}
+
+fn minutes(now: u64) -> u16 {
+    (now / 60_000) as u16
+}
+
+fn pack_lfu(now: u64, counter: u8) -> u32 {
+    (minutes(now) as u32) << 8 | counter as u32
+}
+
+/// Increments an LFU counter with a probability that gets lower as it grows
+fn log_incr(counter: u8) -> u8 {
+    if counter == u8::MAX {
+        return counter;
+    }
+    let base = counter.saturating_sub(LFU_INIT_VAL) as u32;
+    // Same as comparing a random float to 1 / (base * LFU_LOG_FACTOR + 1)
+    if random().is_multiple_of((base * LFU_LOG_FACTOR + 1) as u64) {
+        counter + 1
+    } else {
+        counter
+    }
+}
+
+/// The best candidates for eviction seen so far, by how long they've been idle
+/// or how rarely they're used, with the best one last
+#[derive(Default)]
+pub(crate) struct EvictionPool {
+    candidates: Vec<(u64, String)>,
+}
+
+impl EvictionPool {
+    /// Keeps `key` if its `score` is higher than one of the candidates',
+    /// or if there's still room for it
+    pub fn offer(&mut self, score: u64, key: &str) {
+        if let Some(index) = self.candidates.iter().position(|(_, it)| it == key) {
+            self.candidates.remove(index);
+        }
+        if self.candidates.len() == POOL_SIZE {
+            if score <= self.candidates[0].0 {
+                return;
+            }
+            self.candidates.remove(0);
+        }
+        let index = self.candidates.partition_point(|(it, _)| *it <= score);
+        self.candidates.insert(index, (score, key.to_owned()));
+    }
+
+    pub fn pop(&mut self) -> Option<String> {
+        self.candidates.pop().map(|(_, key)| key)
+    }
+
+    pub fn clear(&mut self) {
+        self.candidates.clear();
+    }
+}
+
+/// A fast, non cryptographic random number
+pub(crate) fn random() -> u64 {
+    thread_local! {
+        static STATE: Cell<u64> = Cell::new(RandomState::new().hash_one(0) | 1);
+    }
+    STATE.with(|state| {
+        // xorshift64*
+        let mut x = state.get();
+        x ^= x >> 12;
+        x ^= x << 25;
+        x ^= x >> 27;
+        state.set(x);
+        x.wrapping_mul(0x2545_F491_4F6C_DD1D)
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parses_policies() {
+        assert_eq!(
+            EvictionPolicy::parse("AllKeys-LRU"),
+            Some(EvictionPolicy::AllKeysLru)
+        );
+        assert_eq!(EvictionPolicy::parse("lru"), None);
+        for (name, policy) in EvictionPolicy::NAMES {
+            assert_eq!(policy.name(), name);
+        }
+    }
+
+    #[test]
+    fn counts_accesses_logarithmically() {
+        let access = Access::new(0);
+        assert_eq!(access.frequency(0), LFU_INIT_VAL);
+        for _ in 0..100 {
+            access.touch(0);
+        }
+        let frequency = access.frequency(0);
+        assert!(
+            frequency > LFU_INIT_VAL + 1 && frequency < 20,
+            "{}",
+            frequency
+        );
+        // Decremented once per minute without accesses
+        assert_eq!(access.frequency(3 * 60_000), frequency - 3);
+        assert_eq!(access.frequency(1000 * 60_000), 0);
+        assert_eq!(access.idle(1500), 1500);
+    }
+
+    #[test]
+    fn pool_keeps_the_best_candidates() {
+        let mut pool = EvictionPool::default();
+        for score in 0..100 {
+            pool.offer(score % 50, &format!("key{}", score));
+        }
+        pool.offer(49, "key49");
+        assert_eq!(pool.candidates.len(), POOL_SIZE);
+        assert_eq!(pool.pop().as_deref(), Some("key49"));
+        assert_eq!(pool.pop().as_deref(), Some("key99"));
+        assert_eq!(pool.pop().as_deref(), Some("key98"));
+    }
+}
@@ -1,3 +1,8 @@
 mod db;
+mod dict;
+mod evict;
+mod notify;
 mod value;
 pub use db::*;
+pub use evict::{EvictionPolicy, MaxMemory};
+pub use notify::*;
@@ -0,0 +1,151 @@
+use std::fmt::Display;
+
+/// Which keyspace notifications get published, as configured by
+/// redis' `notify-keyspace-events` flag string.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct KeyspaceEvents(u16);
+
+impl KeyspaceEvents {
+    pub const NONE: KeyspaceEvents = KeyspaceEvents(0);
+    /// `K`: publish on `__keyspace@<db>__:<key>`
+    pub const KEYSPACE: KeyspaceEvents = KeyspaceEvents(1 << 0);
+    /// `E`: publish on `__keyevent@<db>__:<event>`
+    pub const KEYEVENT: KeyspaceEvents = KeyspaceEvents(1 << 1);
+    /// `g`: generic commands like DEL and RENAME
+    pub const GENERIC: KeyspaceEvents = KeyspaceEvents(1 << 2);
+    /// `$`: string commands
+    pub const STRING: KeyspaceEvents = KeyspaceEvents(1 << 3);
+    /// `l`: list commands
+    pub const LIST: KeyspaceEvents = KeyspaceEvents(1 << 4);
+    /// `s`: set commands
+    pub const SET: KeyspaceEvents = KeyspaceEvents(1 << 5);
+    /// `h`: hash commands
+    pub const HASH: KeyspaceEvents = KeyspaceEvents(1 << 6);
+    /// `z`: sorted set commands
+    pub const ZSET: KeyspaceEvents = KeyspaceEvents(1 << 7);
+    /// `x`: a key expired
+    pub const EXPIRED: KeyspaceEvents = KeyspaceEvents(1 << 8);
+    /// `e`: a key was evicted because of maxmemory
+    pub const EVICTED: KeyspaceEvents = KeyspaceEvents(1 << 9);
+    /// `t`: stream commands
+    pub const STREAM: KeyspaceEvents = KeyspaceEvents(1 << 10);
+    /// `m`: a read command didn't find its key
+    pub const KEY_MISS: KeyspaceEvents = KeyspaceEvents(1 << 11);
+    /// `n`: a new key was created
+    pub const NEW: KeyspaceEvents = KeyspaceEvents(1 << 12);
+    /// `A`: alias for `g$lshzxet`. Like in redis, it doesn't include `m` and `n`.
+    pub const ALL: KeyspaceEvents = KeyspaceEvents(
+        Self::GENERIC.0
+            | Self::STRING.0
+            | Self::LIST.0
+            | Self::SET.0
+            | Self::HASH.0
+            | Self::ZSET.0
+            | Self::EXPIRED.0
+            | Self::EVICTED.0
+            | Self::STREAM.0,
+    );
+
+    const FLAGS: [(char, KeyspaceEvents); 11] = [
+        ('g', Self::GENERIC),
+        ('$', Self::STRING),
+        ('l', Self::LIST),
+        ('s', Self::SET),
+        ('h', Self::HASH),
+        ('z', Self::ZSET),
+        ('x', Self::EXPIRED),
+        ('e', Self::EVICTED),
+        ('t', Self::STREAM),
+        ('m', Self::KEY_MISS),
+        ('n', Self::NEW),
+    ];
+
+    /// Parses a `notify-keyspace-events` string. Returns None if it
+    /// contains an unknown flag.
+    pub fn parse(s: &str) -> Option<KeyspaceEvents> {
+        let mut events = KeyspaceEvents::NONE;
+        for c in s.chars() {
+            events = events
+                | match c {
+                    'A' => Self::ALL,
+                    'K' => Self::KEYSPACE,
+                    'E' => Self::KEYEVENT,
+                    c => Self::FLAGS.iter().find(|(flag, _)| *flag == c)?.1,
+                };
+        }
+        Some(events)
+    }
+
+    pub(crate) const fn from_bits(bits: u16) -> KeyspaceEvents {
+        KeyspaceEvents(bits)
+    }
+
+    pub(crate) const fn bits(self) -> u16 {
+        self.0
+    }
+
+    pub fn contains(self, other: KeyspaceEvents) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    /// Whether an event of the given class would be published at all
+    pub fn should_notify(self, class: KeyspaceEvents) -> bool {
+        self.0 & class.0 != 0 && (self.contains(Self::KEYSPACE) || self.contains(Self::KEYEVENT))
+    }
+}
+
+impl std::ops::BitOr for KeyspaceEvents {
+    type Output = KeyspaceEvents;
+    fn bitor(self, rhs: Self) -> Self::Output {
+        KeyspaceEvents(self.0 | rhs.0)
+    }
+}
+
+impl Display for KeyspaceEvents {
+    /// Formats the flags the same way redis reports them in `CONFIG GET`
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.contains(Self::ALL) {
+            write!(f, "A")?;
+        }
+        for (flag, class) in Self::FLAGS {
+            let included_in_all = Self::ALL.contains(class);
+            if self.contains(class) && !(included_in_all && self.contains(Self::ALL)) {
+                write!(f, "{}", flag)?;
+            }
+        }
+        if self.contains(Self::KEYSPACE) {
+            write!(f, "K")?;
+        }
+        if self.contains(Self::KEYEVENT) {
+            write!(f, "E")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parses_and_formats_flags() {
+        assert_eq!(KeyspaceEvents::parse(""), Some(KeyspaceEvents::NONE));
+        assert_eq!(
+            KeyspaceEvents::parse("Kg$"),
+            Some(KeyspaceEvents::KEYSPACE | KeyspaceEvents::GENERIC | KeyspaceEvents::STRING)
+        );
+        assert_eq!(KeyspaceEvents::parse("KQ"), None);
+        assert_eq!(KeyspaceEvents::parse("KEA").unwrap().to_string(), "AKE");
+        assert_eq!(KeyspaceEvents::parse("EhmA").unwrap().to_string(), "AmE");
+        assert_eq!(KeyspaceEvents::parse("Exg").unwrap().to_string(), "gxE");
+    }
+
+    #[test]
+    fn requires_keyspace_or_keyevent_to_notify() {
+        let events = KeyspaceEvents::parse("g").unwrap();
+        assert!(!events.should_notify(KeyspaceEvents::GENERIC));
+        let events = KeyspaceEvents::parse("Kg").unwrap();
+        assert!(events.should_notify(KeyspaceEvents::GENERIC));
+        assert!(!events.should_notify(KeyspaceEvents::HASH));
+    }
+}
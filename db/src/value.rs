@@ -1,19 +1,172 @@
-use std::collections::HashMap;
+use std::{any::Any, collections::HashMap, fmt::Debug, mem::size_of};
 
-#[derive(Debug, Clone)]
+/// How many elements of lists and hashes are sampled to account for their memory,
+/// and by default by `MEMORY USAGE`, like redis' `OBJ_COMPUTE_SIZE_DEF_SAMPLES`
+pub const MEMORY_SAMPLES: usize = 5;
+
+/// Up to how many elements lists and hashes are reported as `listpack`
+/// by [Value::encoding], like redis' `*-max-listpack-entries`
+const LISTPACK_MAX_ENTRIES: usize = 128;
+/// Up to how long each field and value of hashes reported as `listpack`
+/// can be, like redis' `hash-max-listpack-value`
+const LISTPACK_MAX_VALUE: usize = 64;
+/// Up to how many bytes lists reported as `listpack` can
+/// hold, like redis' default `list-max-listpack-size` of -2
+const LISTPACK_MAX_BYTES: usize = 8 * 1024;
+/// Up to how long strings are reported as `embstr`, like redis' `OBJ_ENCODING_EMBSTR_SIZE_LIMIT`
+const EMBSTR_MAX_LEN: usize = 44;
+
+#[derive(Debug)]
 pub enum Value {
-    String(String),
+    /// Any bytes, like redis strings
+    String(Vec<u8>),
     List(Vec<String>),
     Hash(HashMap<String, String>),
+    /// A value of a type defined outside of this crate, e.g. by a server module
+    Custom(Box<dyn CustomValue>),
+}
+
+/// Implemented by values of types that this crate doesn't know about.
+/// They can be stored like any other value, and downcast back to their
+/// concrete type with [Value::downcast_ref] and [Value::downcast_mut].
+pub trait CustomValue: Any + Debug + Send + Sync {
+    /// Name of the type, which tells what can (de)serialize it
+    fn type_name(&self) -> &'static str;
+    fn clone_box(&self) -> Box<dyn CustomValue>;
+    /// Bytes the value uses, including what it allocated. Used to enforce `maxmemory`.
+    fn memory_usage(&self) -> usize {
+        std::mem::size_of_val(self)
+    }
+    /// The encoding reported by `OBJECT ENCODING`, e.g. `skiplist` for a sorted set
+    fn encoding(&self) -> &'static str {
+        "raw"
+    }
+}
+
+impl Value {
+    /// The type's name, as reported by redis' `TYPE`
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Value::String(_) => "string",
+            Value::List(_) => "list",
+            Value::Hash(_) => "hash",
+            Value::Custom(value) => value.type_name(),
+        }
+    }
+
+    /// The encoding redis would use for the value as it is now, as reported by
+    /// `OBJECT ENCODING`. Values are always stored the same way here, but clients
+    /// use it to tell whether they fit redis' compact encodings.
+    pub fn encoding(&self) -> &'static str {
+        match self {
+            Value::String(s) if is_canonical_integer(s) => "int",
+            Value::String(s) if s.len() <= EMBSTR_MAX_LEN => "embstr",
+            Value::String(_) => "raw",
+            Value::List(list)
+                if list.len() <= LISTPACK_MAX_ENTRIES
+                    && list.iter().map(String::len).sum::<usize>() <= LISTPACK_MAX_BYTES =>
+            {
+                "listpack"
+            }
+            Value::List(_) => "quicklist",
+            Value::Hash(map)
+                if map.len() <= LISTPACK_MAX_ENTRIES
+                    && map.iter().all(|(k, v)| {
+                        k.len() <= LISTPACK_MAX_VALUE && v.len() <= LISTPACK_MAX_VALUE
+                    }) =>
+            {
+                "listpack"
+            }
+            Value::Hash(_) => "hashtable",
+            Value::Custom(value) => value.encoding(),
+        }
+    }
+
+    /// An estimate of the bytes the value allocated. Lists and hashes are estimated
+    /// from the average size of `samples` of their elements, so that it's cheap
+    /// enough to do after every change even for big ones, or from all of them if
+    /// `samples` is 0.
+    pub fn memory_usage(&self, samples: usize) -> usize {
+        match self {
+            Value::String(s) => s.capacity(),
+            Value::List(list) => {
+                list.capacity() * size_of::<String>()
+                    + sampled_size(list.len(), samples, list.iter().map(String::capacity))
+            }
+            Value::Hash(map) => {
+                // Like hashbrown, a control byte per bucket, and buckets 1/8th empty
+                map.capacity() * 8 / 7 * (size_of::<(String, String)>() + 1)
+                    + sampled_size(
+                        map.len(),
+                        samples,
+                        map.iter().map(|(k, v)| k.capacity() + v.capacity()),
+                    )
+            }
+            Value::Custom(value) => value.memory_usage(),
+        }
+    }
+
+    pub fn downcast_ref<T: CustomValue>(&self) -> Option<&T> {
+        match self {
+            Value::Custom(value) => (value.as_ref() as &dyn Any).downcast_ref(),
+            _ => None,
+        }
+    }
+
+    pub fn downcast_mut<T: CustomValue>(&mut self) -> Option<&mut T> {
+        match self {
+            Value::Custom(value) => (value.as_mut() as &mut dyn Any).downcast_mut(),
+            _ => None,
+        }
+    }
+}
+
+/// Whether `s` is an integer written the way it would be formatted, like redis'
+/// `string2ll`, which rejects e.g. `+5`, `007` and `-0`
+fn is_canonical_integer(s: &[u8]) -> bool {
+    s.len() <= 20
+        && std::str::from_utf8(s)
+            .ok()
+            .and_then(|s| s.parse::<i64>().ok().map(|n| n.to_string() == s))
+            .unwrap_or(false)
+}
+
+/// The size of `len` elements, from the average size of the first `samples` of `sizes`
+fn sampled_size(len: usize, samples: usize, sizes: impl Iterator<Item = usize>) -> usize {
+    let samples = if samples == 0 { len } else { samples };
+    let (samples, total) = sizes
+        .take(samples)
+        .fold((0, 0), |(samples, total), size| (samples + 1, total + size));
+    if samples == 0 {
+        return 0;
+    }
+    total * len / samples
+}
+
+impl Clone for Value {
+    fn clone(&self) -> Self {
+        match self {
+            Value::String(s) => Value::String(s.clone()),
+            Value::List(list) => Value::List(list.clone()),
+            Value::Hash(map) => Value::Hash(map.clone()),
+            Value::Custom(value) => Value::Custom(value.clone_box()),
+        }
+    }
 }
+
 impl From<&str> for Value {
     fn from(s: &str) -> Self {
-        Value::String(s.to_owned())
+        Value::String(s.as_bytes().to_vec())
     }
 }
 impl From<String> for Value {
     fn from(s: String) -> Self {
-        Value::String(s)
+        Value::String(s.into_bytes())
+    }
+}
+impl From<Vec<u8>> for Value {
+    fn from(bytes: Vec<u8>) -> Self {
+        Value::String(bytes)
     }
 }
 impl From<Vec<String>> for Value {
@@ -26,3 +179,77 @@ impl From<HashMap<String, String>> for Value {
         Value::Hash(s)
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[derive(Debug, Clone, PartialEq)]
+    struct Counter(i64);
+
+    impl CustomValue for Counter {
+        fn type_name(&self) -> &'static str {
+            "counter"
+        }
+
+        fn clone_box(&self) -> Box<dyn CustomValue> {
+            Box::new(self.clone())
+        }
+    }
+
+    #[test]
+    fn custom_values_can_be_downcast() {
+        let mut value = Value::Custom(Box::new(Counter(1)));
+        value.downcast_mut::<Counter>().unwrap().0 += 1;
+        assert_eq!(value.clone().downcast_ref::<Counter>(), Some(&Counter(2)));
+        assert_eq!(Value::from("a").downcast_ref::<Counter>(), None);
+    }
+
+    #[test]
+    fn estimates_memory_usage() {
+        assert_eq!(Value::from("a".repeat(100)).memory_usage(0), 100);
+        let mut list = vec!["a".repeat(10); 1000];
+        let value = Value::from(list.clone());
+        assert_eq!(value.memory_usage(5), 1000 * (size_of::<String>() + 10));
+        list[999] = "a".repeat(1010);
+        let value = Value::from(list);
+        assert_eq!(value.memory_usage(5), 1000 * (size_of::<String>() + 10));
+        assert_eq!(value.memory_usage(0), 1000 * (size_of::<String>() + 11));
+        let map: HashMap<String, String> = (0..1000)
+            .map(|i| (format!("{:04}", i), "a".repeat(10)))
+            .collect();
+        let usage = Value::from(map).memory_usage(MEMORY_SAMPLES);
+        assert!(usage > 1000 * (size_of::<(String, String)>() + 14));
+        assert!(usage < 2 * 1000 * (size_of::<(String, String)>() + 15));
+        assert_eq!(
+            Value::Custom(Box::new(Counter(1))).memory_usage(0),
+            size_of::<Counter>()
+        );
+    }
+
+    #[test]
+    fn reports_redis_encodings() {
+        assert_eq!(Value::from("-12345").encoding(), "int");
+        assert_eq!(Value::from("12345678901234567890").encoding(), "embstr");
+        for s in ["+5", "007", "-0", " 1"] {
+            assert_eq!(Value::from(s).encoding(), "embstr", "{}", s);
+        }
+        assert_eq!(Value::from("0").encoding(), "int");
+        assert_eq!(Value::from("a".repeat(44)).encoding(), "embstr");
+        assert_eq!(Value::from("a".repeat(45)).encoding(), "raw");
+        assert_eq!(
+            Value::from(vec!["a".to_string(); 128]).encoding(),
+            "listpack"
+        );
+        assert_eq!(
+            Value::from(vec!["a".to_string(); 129]).encoding(),
+            "quicklist"
+        );
+        assert_eq!(Value::from(vec!["a".repeat(9000)]).encoding(), "quicklist");
+        let mut map = HashMap::from([("field".to_string(), "a".repeat(64))]);
+        assert_eq!(Value::from(map.clone()).encoding(), "listpack");
+        map.insert("field".to_string(), "a".repeat(65));
+        assert_eq!(Value::from(map).encoding(), "hashtable");
+        assert_eq!(Value::Custom(Box::new(Counter(1))).encoding(), "raw");
+    }
+}
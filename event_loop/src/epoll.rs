@@ -0,0 +1,195 @@
+//! Linux backend: epoll for file descriptors, a timerfd per timer
+//! and an eventfd per waker, all registered in the same epoll instance.
+use std::{
+    cell::RefCell,
+    io,
+    os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd},
+    sync::Arc,
+    time::Duration,
+};
+
+use crate::{check, Event, Interest, Token};
+
+pub struct Poller {
+    epoll: OwnedFd,
+    /// Filled in by `epoll_wait`
+    buffer: RefCell<Vec<libc::epoll_event>>,
+}
+
+pub struct Timer {
+    fd: OwnedFd,
+}
+
+#[derive(Clone)]
+pub struct Waker {
+    fd: Arc<OwnedFd>,
+}
+
+impl Waker {
+    pub fn wake(&self) -> io::Result<()> {
+        let one: u64 = 1;
+        let result = unsafe {
+            libc::write(
+                self.fd.as_raw_fd(),
+                &one as *const u64 as *const libc::c_void,
+                8,
+            )
+        };
+        match check(result as libc::c_int) {
+            // The counter is saturated, so the loop is going to wake up anyway
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => Ok(()),
+            result => result.map(|_| ()),
+        }
+    }
+}
+
+impl Poller {
+    pub fn new() -> io::Result<Poller> {
+        let epoll = check(unsafe { libc::epoll_create1(libc::EPOLL_CLOEXEC) })?;
+        Ok(Poller {
+            epoll: unsafe { OwnedFd::from_raw_fd(epoll) },
+            buffer: RefCell::new(Vec::with_capacity(1024)),
+        })
+    }
+
+    pub fn add(&self, fd: RawFd, token: Token, interest: Interest) -> io::Result<()> {
+        self.ctl(libc::EPOLL_CTL_ADD, fd, token, interest)
+    }
+
+    pub fn modify(
+        &self,
+        fd: RawFd,
+        token: Token,
+        _old: Interest,
+        interest: Interest,
+    ) -> io::Result<()> {
+        self.ctl(libc::EPOLL_CTL_MOD, fd, token, interest)
+    }
+
+    pub fn delete(&self, fd: RawFd, _interest: Interest) -> io::Result<()> {
+        check(unsafe {
+            libc::epoll_ctl(
+                self.epoll.as_raw_fd(),
+                libc::EPOLL_CTL_DEL,
+                fd,
+                std::ptr::null_mut(),
+            )
+        })
+        .map(|_| ())
+    }
+
+    fn ctl(&self, op: libc::c_int, fd: RawFd, token: Token, interest: Interest) -> io::Result<()> {
+        let mut events = libc::EPOLLRDHUP;
+        if interest.is_readable() {
+            events |= libc::EPOLLIN;
+        }
+        if interest.is_writable() {
+            events |= libc::EPOLLOUT;
+        }
+        let mut event = libc::epoll_event {
+            events: events as u32,
+            u64: token as u64,
+        };
+        check(unsafe { libc::epoll_ctl(self.epoll.as_raw_fd(), op, fd, &mut event) }).map(|_| ())
+    }
+
+    pub fn add_timer(&self, token: Token, duration: Duration, repeat: bool) -> io::Result<Timer> {
+        let fd = check(unsafe {
+            libc::timerfd_create(
+                libc::CLOCK_MONOTONIC,
+                libc::TFD_NONBLOCK | libc::TFD_CLOEXEC,
+            )
+        })?;
+        let timer = Timer {
+            fd: unsafe { OwnedFd::from_raw_fd(fd) },
+        };
+        // A zero it_value would disarm the timer instead of firing it right away
+        let value = timespec(duration.max(Duration::from_nanos(1)));
+        let spec = libc::itimerspec {
+            it_value: value,
+            it_interval: if repeat {
+                value
+            } else {
+                timespec(Duration::ZERO)
+            },
+        };
+        check(unsafe { libc::timerfd_settime(fd, 0, &spec, std::ptr::null_mut()) })?;
+        self.add(fd, token, Interest::READABLE)?;
+        Ok(timer)
+    }
+
+    pub fn delete_timer(&self, timer: Timer) -> io::Result<()> {
+        self.delete(timer.fd.as_raw_fd(), Interest::READABLE)
+    }
+
+    /// Resets the timerfd's expiration count, otherwise it stays readable
+    pub fn ack_timer(&self, timer: &Timer) {
+        drain(timer.fd.as_raw_fd());
+    }
+
+    pub fn add_waker(&self, token: Token) -> io::Result<Waker> {
+        let fd = check(unsafe { libc::eventfd(0, libc::EFD_NONBLOCK | libc::EFD_CLOEXEC) })?;
+        let waker = Waker {
+            fd: Arc::new(unsafe { OwnedFd::from_raw_fd(fd) }),
+        };
+        self.add(fd, token, Interest::READABLE)?;
+        Ok(waker)
+    }
+
+    /// Wakers can outlive the loop's registration, and waking them then does nothing
+    pub fn delete_waker(&self, waker: Waker) -> io::Result<()> {
+        self.delete(waker.fd.as_raw_fd(), Interest::READABLE)
+    }
+
+    pub fn ack_waker(&self, waker: &Waker) {
+        drain(waker.fd.as_raw_fd());
+    }
+
+    pub fn wait(&self, events: &mut Vec<Event>, timeout: Option<Duration>) -> io::Result<()> {
+        let timeout = match timeout {
+            None => -1,
+            // Rounded up, so that waiting for less than a millisecond doesn't spin
+            Some(timeout) => timeout
+                .as_nanos()
+                .div_ceil(1_000_000)
+                .min(libc::c_int::MAX as u128) as libc::c_int,
+        };
+        let mut buffer = self.buffer.borrow_mut();
+        let capacity = buffer.capacity();
+        let result = unsafe {
+            libc::epoll_wait(
+                self.epoll.as_raw_fd(),
+                buffer.as_mut_ptr(),
+                capacity as libc::c_int,
+                timeout,
+            )
+        };
+        let n = match check(result) {
+            Ok(n) => n as usize,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => 0,
+            Err(e) => return Err(e),
+        };
+        unsafe { buffer.set_len(n) };
+        let closed = (libc::EPOLLHUP | libc::EPOLLRDHUP | libc::EPOLLERR) as u32;
+        events.extend(buffer.iter().map(|event| Event {
+            token: event.u64 as Token,
+            readable: event.events & (libc::EPOLLIN as u32 | closed) != 0,
+            writable: event.events & (libc::EPOLLOUT | libc::EPOLLERR) as u32 != 0,
+        }));
+        Ok(())
+    }
+}
+
+/// Reads the 8 byte counter of a timerfd or eventfd
+fn drain(fd: RawFd) {
+    let mut count: u64 = 0;
+    // Fails with EAGAIN if it was already drained, which is fine
+    unsafe { libc::read(fd, &mut count as *mut u64 as *mut libc::c_void, 8) };
+}
+
+fn timespec(duration: Duration) -> libc::timespec {
+    libc::timespec {
+        tv_sec: duration.as_secs() as libc::time_t,
+        tv_nsec: duration.subsec_nanos() as libc::c_long,
+    }
+}
@@ -0,0 +1,201 @@
+//! macOS and FreeBSD backend: everything is a kevent filter of the same kqueue,
+//! `EVFILT_TIMER` for timers and `EVFILT_USER` for wakers.
+use std::{
+    cell::RefCell,
+    io,
+    os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd},
+    sync::Arc,
+    time::Duration,
+};
+
+use crate::{check, Event, Interest, Token};
+
+pub struct Poller {
+    /// Shared with the wakers, which trigger events from other threads
+    kq: Arc<OwnedFd>,
+    /// Filled in by `kevent`
+    buffer: RefCell<Vec<libc::kevent>>,
+}
+
+pub struct Timer {
+    ident: usize,
+}
+
+#[derive(Clone)]
+pub struct Waker {
+    kq: Arc<OwnedFd>,
+    ident: usize,
+}
+
+impl Waker {
+    pub fn wake(&self) -> io::Result<()> {
+        let mut event = kevent(self.ident, libc::EVFILT_USER, 0, self.ident);
+        event.fflags = libc::NOTE_TRIGGER;
+        match apply(&self.kq, &[event]) {
+            // The loop cancelled the waker, so there's no one left to wake up
+            Err(e) if e.raw_os_error() == Some(libc::ENOENT) => Ok(()),
+            result => result,
+        }
+    }
+}
+
+impl Poller {
+    pub fn new() -> io::Result<Poller> {
+        let kq = check(unsafe { libc::kqueue() })?;
+        Ok(Poller {
+            kq: Arc::new(unsafe { OwnedFd::from_raw_fd(kq) }),
+            buffer: RefCell::new(Vec::with_capacity(1024)),
+        })
+    }
+
+    pub fn add(&self, fd: RawFd, token: Token, interest: Interest) -> io::Result<()> {
+        self.modify(fd, token, Interest(0), interest)
+    }
+
+    pub fn modify(
+        &self,
+        fd: RawFd,
+        token: Token,
+        old: Interest,
+        interest: Interest,
+    ) -> io::Result<()> {
+        let mut changes = vec![];
+        for (filter, was, is) in [
+            (libc::EVFILT_READ, old.is_readable(), interest.is_readable()),
+            (
+                libc::EVFILT_WRITE,
+                old.is_writable(),
+                interest.is_writable(),
+            ),
+        ] {
+            if is {
+                // Adding a filter again updates it
+                changes.push(kevent(fd as usize, filter, libc::EV_ADD, token));
+            } else if was {
+                changes.push(kevent(fd as usize, filter, libc::EV_DELETE, token));
+            }
+        }
+        apply(&self.kq, &changes)
+    }
+
+    pub fn delete(&self, fd: RawFd, interest: Interest) -> io::Result<()> {
+        self.modify(fd, 0, interest, Interest(0))
+    }
+
+    pub fn add_timer(&self, token: Token, duration: Duration, repeat: bool) -> io::Result<Timer> {
+        let flags = if repeat {
+            libc::EV_ADD
+        } else {
+            libc::EV_ADD | libc::EV_ONESHOT
+        };
+        let mut event = kevent(token, libc::EVFILT_TIMER, flags, token);
+        event.fflags = libc::NOTE_USECONDS;
+        event.data = duration.as_micros().min(isize::MAX as u128) as _;
+        apply(&self.kq, &[event])?;
+        Ok(Timer { ident: token })
+    }
+
+    pub fn delete_timer(&self, timer: Timer) -> io::Result<()> {
+        let event = kevent(
+            timer.ident,
+            libc::EVFILT_TIMER,
+            libc::EV_DELETE,
+            timer.ident,
+        );
+        match apply(&self.kq, &[event]) {
+            // Timeouts are removed by kqueue once they fire
+            Err(e) if e.raw_os_error() == Some(libc::ENOENT) => Ok(()),
+            result => result,
+        }
+    }
+
+    /// Timers don't need to be rearmed with kqueue
+    pub fn ack_timer(&self, _timer: &Timer) {}
+
+    pub fn add_waker(&self, token: Token) -> io::Result<Waker> {
+        let event = kevent(
+            token,
+            libc::EVFILT_USER,
+            libc::EV_ADD | libc::EV_CLEAR,
+            token,
+        );
+        apply(&self.kq, &[event])?;
+        Ok(Waker {
+            kq: self.kq.clone(),
+            ident: token,
+        })
+    }
+
+    pub fn delete_waker(&self, waker: Waker) -> io::Result<()> {
+        let event = kevent(waker.ident, libc::EVFILT_USER, libc::EV_DELETE, waker.ident);
+        apply(&self.kq, &[event])
+    }
+
+    /// `EV_CLEAR` already resets the event when it's delivered
+    pub fn ack_waker(&self, _waker: &Waker) {}
+
+    pub fn wait(&self, events: &mut Vec<Event>, timeout: Option<Duration>) -> io::Result<()> {
+        let timeout = timeout.map(|timeout| libc::timespec {
+            tv_sec: timeout.as_secs() as libc::time_t,
+            tv_nsec: timeout.subsec_nanos() as libc::c_long,
+        });
+        let mut buffer = self.buffer.borrow_mut();
+        let capacity = buffer.capacity();
+        let result = unsafe {
+            libc::kevent(
+                self.kq.as_raw_fd(),
+                std::ptr::null(),
+                0,
+                buffer.as_mut_ptr(),
+                capacity as libc::c_int,
+                timeout
+                    .as_ref()
+                    .map_or(std::ptr::null(), |timeout| timeout as *const _),
+            )
+        };
+        let n = match check(result) {
+            Ok(n) => n as usize,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => 0,
+            Err(e) => return Err(e),
+        };
+        unsafe { buffer.set_len(n) };
+        events.extend(buffer.iter().map(|event| {
+            let error = event.flags & libc::EV_ERROR != 0;
+            Event {
+                token: event.udata as Token,
+                readable: error || event.filter != libc::EVFILT_WRITE,
+                writable: error || event.filter == libc::EVFILT_WRITE,
+            }
+        }));
+        Ok(())
+    }
+}
+
+fn kevent(ident: usize, filter: i16, flags: u16, token: Token) -> libc::kevent {
+    // Zeroed rather than built field by field, because the
+    // struct has extra fields on some platforms
+    let mut event: libc::kevent = unsafe { std::mem::zeroed() };
+    event.ident = ident as _;
+    event.filter = filter as _;
+    event.flags = flags as _;
+    event.udata = token as _;
+    event
+}
+
+/// Submits changes to the kqueue, without waiting for events
+fn apply(kq: &OwnedFd, changes: &[libc::kevent]) -> io::Result<()> {
+    if changes.is_empty() {
+        return Ok(());
+    }
+    check(unsafe {
+        libc::kevent(
+            kq.as_raw_fd(),
+            changes.as_ptr(),
+            changes.len() as libc::c_int,
+            std::ptr::null_mut(),
+            0,
+            std::ptr::null(),
+        )
+    })
+    .map(|_| ())
+}
@@ -0,0 +1,428 @@
+//! A single threaded event loop, which calls back when file descriptors become
+//! readable or writable, when timers fire, or when another thread wakes it up.
+//!
+//! It's backed by epoll on Linux, and by kqueue on macOS and FreeBSD.
+use std::{
+    cell::{Cell, RefCell},
+    collections::HashMap,
+    io,
+    os::fd::RawFd,
+    time::Duration,
+};
+
+#[cfg(target_os = "linux")]
+mod epoll;
+#[cfg(target_os = "linux")]
+use epoll as sys;
+
+#[cfg(any(target_os = "macos", target_os = "freebsd"))]
+mod kqueue;
+#[cfg(any(target_os = "macos", target_os = "freebsd"))]
+use kqueue as sys;
+
+/// Identifies a registration, so that it can be changed or cancelled
+pub type Token = usize;
+
+/// Called with the loop itself, so that callbacks can register or cancel other callbacks
+pub type Callback = Box<dyn FnMut(&EventLoop, Event)>;
+
+/// Which readiness of a file descriptor to wait for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Interest(u8);
+
+impl Interest {
+    pub const READABLE: Interest = Interest(1 << 0);
+    pub const WRITABLE: Interest = Interest(1 << 1);
+
+    pub fn is_readable(self) -> bool {
+        self.0 & Self::READABLE.0 != 0
+    }
+
+    pub fn is_writable(self) -> bool {
+        self.0 & Self::WRITABLE.0 != 0
+    }
+}
+
+impl std::ops::BitOr for Interest {
+    type Output = Interest;
+    fn bitor(self, rhs: Self) -> Self::Output {
+        Interest(self.0 | rhs.0)
+    }
+}
+
+/// What woke a callback up. Timers and wakers are always reported as readable.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Event {
+    pub token: Token,
+    /// Also set on hangups and errors, so that the next read reports them
+    pub readable: bool,
+    pub writable: bool,
+}
+
+/// Wakes an [EventLoop] up from any thread, running the callback
+/// that was given to [EventLoop::waker]
+#[derive(Clone)]
+pub struct Waker(sys::Waker);
+
+impl Waker {
+    pub fn wake(&self) -> io::Result<()> {
+        self.0.wake()
+    }
+}
+
+pub struct EventLoop {
+    poller: sys::Poller,
+    next_token: Cell<Token>,
+    registrations: RefCell<HashMap<Token, Registration>>,
+    stopped: Cell<bool>,
+    /// Reused between iterations, to avoid allocating on every wakeup
+    events: RefCell<Vec<Event>>,
+}
+
+struct Registration {
+    source: Source,
+    /// Taken out while the callback runs, so that it can use the loop
+    callback: Option<Callback>,
+}
+
+enum Source {
+    Fd { fd: RawFd, interest: Interest },
+    Timer { timer: sys::Timer, repeat: bool },
+    Waker(sys::Waker),
+}
+
+impl EventLoop {
+    pub fn new() -> io::Result<EventLoop> {
+        Ok(EventLoop {
+            poller: sys::Poller::new()?,
+            next_token: Cell::new(1),
+            registrations: RefCell::new(HashMap::new()),
+            stopped: Cell::new(false),
+            events: RefCell::new(Vec::with_capacity(1024)),
+        })
+    }
+
+    /// Calls `cb` whenever `fd` is ready for `interest`, until the registration
+    /// is cancelled. Readiness is level triggered, so `cb` keeps getting called
+    /// for as long as `fd` stays ready.
+    ///
+    /// The registration must be cancelled before `fd` is closed.
+    pub fn register(
+        &self,
+        fd: RawFd,
+        interest: Interest,
+        cb: impl FnMut(&EventLoop, Event) + 'static,
+    ) -> io::Result<Token> {
+        let token = self.next_token();
+        self.poller.add(fd, token, interest)?;
+        self.insert(token, Source::Fd { fd, interest }, Box::new(cb));
+        Ok(token)
+    }
+
+    /// Changes what a file descriptor registered with [EventLoop::register] waits for
+    pub fn reregister(&self, token: Token, interest: Interest) -> io::Result<()> {
+        let mut registrations = self.registrations.borrow_mut();
+        match registrations.get_mut(&token) {
+            Some(Registration {
+                source: Source::Fd { fd, interest: old },
+                ..
+            }) => {
+                self.poller.modify(*fd, token, *old, interest)?;
+                *old = interest;
+                Ok(())
+            }
+            _ => Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                "No file descriptor is registered with this token",
+            )),
+        }
+    }
+
+    /// Calls `cb` once, after `after`
+    pub fn timeout(
+        &self,
+        after: Duration,
+        cb: impl FnMut(&EventLoop, Event) + 'static,
+    ) -> io::Result<Token> {
+        self.timer(after, false, Box::new(cb))
+    }
+
+    /// Calls `cb` every `every`, until the registration is cancelled
+    pub fn interval(
+        &self,
+        every: Duration,
+        cb: impl FnMut(&EventLoop, Event) + 'static,
+    ) -> io::Result<Token> {
+        self.timer(every, true, Box::new(cb))
+    }
+
+    fn timer(&self, duration: Duration, repeat: bool, cb: Callback) -> io::Result<Token> {
+        let token = self.next_token();
+        let timer = self.poller.add_timer(token, duration, repeat)?;
+        self.insert(token, Source::Timer { timer, repeat }, cb);
+        Ok(token)
+    }
+
+    /// Returns a [Waker] that runs `cb` on the loop's thread when woken up.
+    /// Several wakeups that happen before the loop gets to them only run `cb` once.
+    pub fn waker(&self, cb: impl FnMut(&EventLoop, Event) + 'static) -> io::Result<Waker> {
+        let token = self.next_token();
+        let waker = self.poller.add_waker(token)?;
+        self.insert(token, Source::Waker(waker.clone()), Box::new(cb));
+        Ok(Waker(waker))
+    }
+
+    /// Removes a registration. Does nothing if it was already removed,
+    /// e.g. because it was a timeout that already fired.
+    pub fn cancel(&self, token: Token) -> io::Result<()> {
+        let Some(registration) = self.registrations.borrow_mut().remove(&token) else {
+            return Ok(());
+        };
+        match registration.source {
+            Source::Fd { fd, interest } => self.poller.delete(fd, interest),
+            Source::Timer { timer, .. } => self.poller.delete_timer(timer),
+            Source::Waker(waker) => self.poller.delete_waker(waker),
+        }
+    }
+
+    /// Whether `token` still refers to a registration
+    pub fn is_registered(&self, token: Token) -> bool {
+        self.registrations.borrow().contains_key(&token)
+    }
+
+    /// Runs callbacks until [EventLoop::stop] is called
+    pub fn run(&self) -> io::Result<()> {
+        self.stopped.set(false);
+        while !self.stopped.get() {
+            self.run_once(None)?;
+        }
+        Ok(())
+    }
+
+    /// Makes [EventLoop::run] return, after the callbacks that are ready have run
+    pub fn stop(&self) {
+        self.stopped.set(true);
+    }
+
+    /// Waits for events for up to `timeout`, or until one happens if it's None,
+    /// and runs their callbacks. Returns how many callbacks ran.
+    pub fn run_once(&self, timeout: Option<Duration>) -> io::Result<usize> {
+        let mut events = std::mem::take(&mut *self.events.borrow_mut());
+        events.clear();
+        let result = self.poller.wait(&mut events, timeout);
+        let mut ran = 0;
+        for event in &events {
+            if self.dispatch(*event) {
+                ran += 1;
+            }
+        }
+        *self.events.borrow_mut() = events;
+        result.map(|_| ran)
+    }
+
+    fn dispatch(&self, event: Event) -> bool {
+        let (mut callback, oneshot) = {
+            let mut registrations = self.registrations.borrow_mut();
+            // It may have been cancelled by a callback that ran before
+            let Some(registration) = registrations.get_mut(&event.token) else {
+                return false;
+            };
+            let oneshot = match &registration.source {
+                Source::Fd { .. } => false,
+                Source::Timer { timer, repeat } => {
+                    self.poller.ack_timer(timer);
+                    !repeat
+                }
+                Source::Waker(waker) => {
+                    self.poller.ack_waker(waker);
+                    false
+                }
+            };
+            match registration.callback.take() {
+                Some(callback) => (callback, oneshot),
+                None => return false,
+            }
+        };
+        if oneshot {
+            // A timeout that fired can't be cancelled anymore
+            let _ = self.cancel(event.token);
+        }
+        callback(self, event);
+        if let Some(registration) = self.registrations.borrow_mut().get_mut(&event.token) {
+            registration.callback = Some(callback);
+        }
+        true
+    }
+
+    fn next_token(&self) -> Token {
+        let token = self.next_token.get();
+        self.next_token.set(token + 1);
+        token
+    }
+
+    fn insert(&self, token: Token, source: Source, callback: Callback) {
+        self.registrations.borrow_mut().insert(
+            token,
+            Registration {
+                source,
+                callback: Some(callback),
+            },
+        );
+    }
+}
+
+/// Converts the result of a libc call that returns -1 on error
+fn check(result: libc::c_int) -> io::Result<libc::c_int> {
+    if result < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(result)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        io::{Read, Write},
+        os::{fd::AsRawFd, unix::net::UnixStream},
+        rc::Rc,
+        thread,
+        time::Instant,
+    };
+
+    use super::*;
+
+    #[test]
+    fn fires_timeouts_once_and_intervals_until_cancelled() {
+        let event_loop = EventLoop::new().unwrap();
+        let fired = Rc::new(Cell::new(0));
+        let ticks = Rc::new(Cell::new(0));
+        let timeout = {
+            let fired = fired.clone();
+            event_loop
+                .timeout(Duration::from_millis(5), move |_, _| {
+                    fired.set(fired.get() + 1)
+                })
+                .unwrap()
+        };
+        {
+            let ticks = ticks.clone();
+            event_loop
+                .interval(Duration::from_millis(1), move |event_loop, event| {
+                    ticks.set(ticks.get() + 1);
+                    if ticks.get() == 3 {
+                        event_loop.cancel(event.token).unwrap();
+                    }
+                })
+                .unwrap();
+        }
+        let start = Instant::now();
+        while fired.get() == 0 {
+            event_loop.run_once(None).unwrap();
+        }
+        assert!(start.elapsed() >= Duration::from_millis(5));
+        assert!(!event_loop.is_registered(timeout));
+        assert_eq!(
+            event_loop
+                .run_once(Some(Duration::from_millis(20)))
+                .unwrap(),
+            0
+        );
+        assert_eq!((fired.get(), ticks.get()), (1, 3));
+    }
+
+    #[test]
+    fn waits_for_readable_and_writable_fds() {
+        let event_loop = EventLoop::new().unwrap();
+        let (mut a, b) = UnixStream::pair().unwrap();
+        let received = Rc::new(RefCell::new(vec![]));
+        let reader = {
+            let received = received.clone();
+            let mut b = b.try_clone().unwrap();
+            event_loop
+                .register(b.as_raw_fd(), Interest::READABLE, move |_, event| {
+                    assert!(event.readable);
+                    let mut buf = [0; 16];
+                    let n = b.read(&mut buf).unwrap();
+                    received.borrow_mut().extend_from_slice(&buf[..n]);
+                })
+                .unwrap()
+        };
+        assert_eq!(
+            event_loop
+                .run_once(Some(Duration::from_millis(10)))
+                .unwrap(),
+            0
+        );
+        a.write_all(b"hello").unwrap();
+        event_loop.run_once(None).unwrap();
+        assert_eq!(&*received.borrow(), b"hello");
+
+        let writable = Rc::new(Cell::new(false));
+        {
+            let writable = writable.clone();
+            event_loop
+                .register(a.as_raw_fd(), Interest::READABLE, move |_, event| {
+                    writable.set(event.writable)
+                })
+                .map(|token| event_loop.reregister(token, Interest::WRITABLE))
+                .unwrap()
+                .unwrap();
+        }
+        event_loop.run_once(None).unwrap();
+        assert!(writable.get());
+        event_loop.cancel(reader).unwrap();
+    }
+
+    #[test]
+    fn wakes_up_from_other_threads() {
+        let event_loop = EventLoop::new().unwrap();
+        let woken = Rc::new(Cell::new(0));
+        let waker = {
+            let woken = woken.clone();
+            event_loop
+                .waker(move |event_loop, _| {
+                    woken.set(woken.get() + 1);
+                    event_loop.stop();
+                })
+                .unwrap()
+        };
+        let thread = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(10));
+            waker.wake().unwrap();
+            waker.wake().unwrap();
+        });
+        event_loop.run().unwrap();
+        thread.join().unwrap();
+        assert_eq!(woken.get(), 1);
+        assert_eq!(
+            event_loop
+                .run_once(Some(Duration::from_millis(10)))
+                .unwrap(),
+            0
+        );
+    }
+
+    #[test]
+    fn callbacks_can_register_more_callbacks() {
+        let event_loop = EventLoop::new().unwrap();
+        let order = Rc::new(RefCell::new(vec![]));
+        {
+            let order = order.clone();
+            event_loop
+                .timeout(Duration::from_millis(1), move |event_loop, _| {
+                    order.borrow_mut().push("first");
+                    let order = order.clone();
+                    event_loop
+                        .timeout(Duration::from_millis(1), move |event_loop, _| {
+                            order.borrow_mut().push("second");
+                            event_loop.stop();
+                        })
+                        .unwrap();
+                })
+                .unwrap();
+        }
+        event_loop.run().unwrap();
+        assert_eq!(*order.borrow(), ["first", "second"]);
+    }
+}
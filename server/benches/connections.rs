@@ -0,0 +1,148 @@
+//! Compares the `threads`, `reactor` and `io_uring` io models with many idle
+//! clients: 10k connections that never send anything, while 100 active clients
+//! send GET and SET commands one at a time and wait for each reply.
+//!
+//! Run with `cargo bench -p dkv --bench connections --features io-uring`, without
+//! which `io_uring` falls back to the reactor. The benchmark needs to be allowed
+//! more open files than there are clients, e.g. with `ulimit -n 20000`, and the
+//! server raises its own limit up to the hard limit.
+//! The number of clients and how long each model runs can be changed with
+//! `DKV_BENCH_IDLE`, `DKV_BENCH_ACTIVE` and `DKV_BENCH_SECONDS`.
+use std::{
+    io::{BufRead, BufReader, Write},
+    net::TcpStream,
+    process::{Child, Command, Stdio},
+    thread::sleep,
+    time::{Duration, Instant},
+};
+
+const ADDRESS: &str = "127.0.0.1:6543";
+
+struct Server(Child);
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
+
+fn start_server(io_model: &str, clients: usize) -> Server {
+    let server = Server(
+        Command::new(env!("CARGO_BIN_EXE_dkv"))
+            .args(["--io-model", io_model])
+            .args(["--maxclients", &(clients + 100).to_string()])
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+            .expect("Unable to start dkv"),
+    );
+    for _ in 0..100 {
+        if TcpStream::connect(ADDRESS).is_ok() {
+            return server;
+        }
+        sleep(Duration::from_millis(50));
+    }
+    panic!("dkv didn't start listening on {}", ADDRESS);
+}
+
+fn env_or(name: &str, default: usize) -> usize {
+    std::env::var(name)
+        .map(|it| {
+            it.parse()
+                .unwrap_or_else(|_| panic!("{} must be a number", name))
+        })
+        .unwrap_or(default)
+}
+
+/// Sends commands one at a time for `duration`, returning the latency of each
+fn run_active_client(id: usize, duration: Duration) -> Vec<Duration> {
+    let stream = TcpStream::connect(ADDRESS).unwrap();
+    stream.set_nodelay(true).unwrap();
+    let mut writer = stream.try_clone().unwrap();
+    let mut reader = BufReader::new(stream);
+    let key = format!("key:{}", id);
+    let set = format!(
+        "*3\r\n$3\r\nSET\r\n${}\r\n{}\r\n$5\r\nvalue\r\n",
+        key.len(),
+        key
+    );
+    let get = format!("*2\r\n$3\r\nGET\r\n${}\r\n{}\r\n", key.len(), key);
+    let mut latencies = vec![];
+    let mut line = String::new();
+    let start = Instant::now();
+    while start.elapsed() < duration {
+        for command in [&set, &get] {
+            let sent = Instant::now();
+            writer.write_all(command.as_bytes()).unwrap();
+            line.clear();
+            reader.read_line(&mut line).unwrap();
+            if line.starts_with('$') {
+                line.clear();
+                reader.read_line(&mut line).unwrap();
+            }
+            latencies.push(sent.elapsed());
+        }
+    }
+    latencies
+}
+
+/// Resident memory and threads of the server, from procfs
+fn server_usage(server: &Server) -> Option<(usize, usize)> {
+    let status = std::fs::read_to_string(format!("/proc/{}/status", server.0.id())).ok()?;
+    let field = |name: &str| -> Option<usize> {
+        let line = status.lines().find(|line| line.starts_with(name))?;
+        line.split_whitespace().nth(1)?.parse().ok()
+    };
+    Some((field("VmRSS:")? / 1024, field("Threads:")?))
+}
+
+fn main() {
+    let idle = env_or("DKV_BENCH_IDLE", 10_000);
+    let active = env_or("DKV_BENCH_ACTIVE", 100);
+    let duration = Duration::from_secs(env_or("DKV_BENCH_SECONDS", 5) as u64);
+
+    println!(
+        "{:>8} {:>8} {:>8} {:>14} {:>8} {:>8} {:>8} {:>8}",
+        "model", "idle", "active", "commands/sec", "p50 us", "p99 us", "RSS MB", "threads"
+    );
+    for io_model in ["threads", "reactor", "io_uring"] {
+        let server = start_server(io_model, idle + active);
+        let idle_clients: Vec<TcpStream> = (0..idle)
+            .map(|_| TcpStream::connect(ADDRESS).expect("Connecting an idle client"))
+            .collect();
+
+        let start = Instant::now();
+        let handles: Vec<_> = (0..active)
+            .map(|id| std::thread::spawn(move || run_active_client(id, duration)))
+            .collect();
+        sleep(duration / 2);
+        let usage = server_usage(&server);
+        let mut latencies: Vec<Duration> = handles
+            .into_iter()
+            .flat_map(|handle| handle.join().unwrap())
+            .collect();
+        let elapsed = start.elapsed();
+        latencies.sort();
+        let percentile = |p: f64| {
+            latencies[((latencies.len() as f64 * p) as usize).min(latencies.len() - 1)].as_micros()
+        };
+        let (rss, threads) = usage.map_or(("-".to_owned(), "-".to_owned()), |(rss, threads)| {
+            (rss.to_string(), threads.to_string())
+        });
+        println!(
+            "{:>8} {:>8} {:>8} {:>14.0} {:>8} {:>8} {:>8} {:>8}",
+            io_model,
+            idle_clients.len(),
+            active,
+            latencies.len() as f64 / elapsed.as_secs_f64(),
+            percentile(0.5),
+            percentile(0.99),
+            rss,
+            threads,
+        );
+        drop(idle_clients);
+        drop(server);
+        // Lets the port be reused by the next server
+        sleep(Duration::from_millis(200));
+    }
+}
@@ -0,0 +1,140 @@
+//! Measures how `io-threads` scales with large values: clients SET and then GET
+//! their own key, with the reactor using 1, 2, 4 and 8 io threads, and with a
+//! thread per client for comparison.
+//!
+//! Whether it scales is still unverified: it has only been run on a single CPU
+//! machine so far, where extra io threads can only add hand-off overhead.
+//!
+//! Run with `cargo bench -p dkv --bench io_threads`. The number of clients, the
+//! value sizes and how long each run takes can be changed with `DKV_BENCH_CLIENTS`,
+//! `DKV_BENCH_VALUE_SIZES` (comma separated, in bytes) and `DKV_BENCH_SECONDS`.
+use std::{
+    io::{BufRead, BufReader, Read, Write},
+    net::TcpStream,
+    process::{Child, Command, Stdio},
+    thread::sleep,
+    time::{Duration, Instant},
+};
+
+const ADDRESS: &str = "127.0.0.1:6543";
+
+struct Server(Child);
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
+
+fn start_server(args: &[&str]) -> Server {
+    let server = Server(
+        Command::new(env!("CARGO_BIN_EXE_dkv"))
+            .args(args)
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+            .expect("Unable to start dkv"),
+    );
+    for _ in 0..100 {
+        if TcpStream::connect(ADDRESS).is_ok() {
+            return server;
+        }
+        sleep(Duration::from_millis(50));
+    }
+    panic!("dkv didn't start listening on {}", ADDRESS);
+}
+
+fn env_or(name: &str, default: &str) -> String {
+    std::env::var(name).unwrap_or_else(|_| default.to_owned())
+}
+
+/// Sets and gets a key of its own until `duration` is over,
+/// returning how many commands it sent
+fn run_client(id: usize, value_size: usize, duration: Duration) -> usize {
+    let stream = TcpStream::connect(ADDRESS).unwrap();
+    stream.set_nodelay(true).unwrap();
+    let mut writer = stream.try_clone().unwrap();
+    let mut reader = BufReader::new(stream);
+    let key = format!("key:{}", id);
+    let set = [
+        format!(
+            "*3\r\n$3\r\nSET\r\n${}\r\n{}\r\n${}\r\n",
+            key.len(),
+            key,
+            value_size
+        )
+        .as_bytes(),
+        &vec![b'x'; value_size],
+        b"\r\n",
+    ]
+    .concat();
+    let get = format!("*2\r\n$3\r\nGET\r\n${}\r\n{}\r\n", key.len(), key);
+    let mut value = vec![0; value_size + 2];
+    let mut line = String::new();
+    let mut commands = 0;
+    let start = Instant::now();
+    while start.elapsed() < duration {
+        writer.write_all(&set).unwrap();
+        line.clear();
+        reader.read_line(&mut line).unwrap();
+        assert_eq!(line, "+OK\r\n");
+        writer.write_all(get.as_bytes()).unwrap();
+        line.clear();
+        reader.read_line(&mut line).unwrap();
+        assert_eq!(line, format!("${}\r\n", value_size));
+        reader.read_exact(&mut value).unwrap();
+        commands += 2;
+    }
+    commands
+}
+
+fn main() {
+    let clients: usize = env_or("DKV_BENCH_CLIENTS", "50")
+        .parse()
+        .expect("DKV_BENCH_CLIENTS must be a number");
+    let value_sizes: Vec<usize> = env_or("DKV_BENCH_VALUE_SIZES", "16384,262144")
+        .split(',')
+        .map(|size| size.parse().expect("DKV_BENCH_VALUE_SIZES must be numbers"))
+        .collect();
+    let duration = Duration::from_secs(
+        env_or("DKV_BENCH_SECONDS", "5")
+            .parse()
+            .expect("DKV_BENCH_SECONDS must be a number"),
+    );
+
+    let setups: &[(&str, &[&str])] = &[
+        ("threads", &["--io-model", "threads"]),
+        ("reactor/1", &["--io-model", "reactor", "--io-threads", "1"]),
+        ("reactor/2", &["--io-model", "reactor", "--io-threads", "2"]),
+        ("reactor/4", &["--io-model", "reactor", "--io-threads", "4"]),
+        ("reactor/8", &["--io-model", "reactor", "--io-threads", "8"]),
+    ];
+    println!(
+        "{:>10} {:>10} {:>14} {:>10}",
+        "setup", "value", "commands/sec", "MB/sec"
+    );
+    for value_size in value_sizes {
+        for (name, args) in setups {
+            let server = start_server(args);
+            let start = Instant::now();
+            let handles: Vec<_> = (0..clients)
+                .map(|id| std::thread::spawn(move || run_client(id, value_size, duration)))
+                .collect();
+            let commands: usize = handles
+                .into_iter()
+                .map(|handle| handle.join().unwrap())
+                .sum();
+            let throughput = commands as f64 / start.elapsed().as_secs_f64();
+            println!(
+                "{:>10} {:>10} {:>14.0} {:>10.0}",
+                name,
+                value_size,
+                throughput,
+                throughput * value_size as f64 / (1024.0 * 1024.0)
+            );
+            drop(server);
+            // Lets the port be reused by the next server
+            sleep(Duration::from_millis(200));
+        }
+    }
+}
@@ -0,0 +1,90 @@
+//! Measures throughput of pipelined SET commands against a freshly started server,
+//! first with tiny values and growing batches, then with growing values.
+//!
+//! Run with `cargo bench -p dkv --bench pipeline`. The number of commands sent
+//! for each batch size can be changed with `DKV_BENCH_COMMANDS`.
+use std::{
+    io::{Read, Write},
+    net::TcpStream,
+    process::{Child, Command, Stdio},
+    thread::sleep,
+    time::{Duration, Instant},
+};
+
+const ADDRESS: &str = "127.0.0.1:6543";
+const DEFAULT_COMMANDS_PER_RUN: usize = 200_000;
+/// Caps how much data is sent when benchmarking big values
+const MAX_BYTES_PER_RUN: usize = 1024 * 1024 * 1024;
+
+struct Server(Child);
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
+
+fn start_server() -> (Server, TcpStream) {
+    let server = Server(
+        Command::new(env!("CARGO_BIN_EXE_dkv"))
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+            .expect("Unable to start dkv"),
+    );
+    for _ in 0..100 {
+        if let Ok(stream) = TcpStream::connect(ADDRESS) {
+            stream.set_nodelay(true).unwrap();
+            return (server, stream);
+        }
+        sleep(Duration::from_millis(50));
+    }
+    panic!("dkv didn't start listening on {}", ADDRESS);
+}
+
+fn run(stream: &mut TcpStream, commands: usize, batch_size: usize, value: &[u8]) -> f64 {
+    let command = [
+        format!("*3\r\n$3\r\nSET\r\n$3\r\nkey\r\n${}\r\n", value.len()).as_bytes(),
+        value,
+        b"\r\n",
+    ]
+    .concat();
+    let reply = b"+OK\r\n";
+    let batch = command.repeat(batch_size);
+    let mut replies = vec![0; reply.len() * batch_size];
+
+    let batches = (commands / batch_size).max(1);
+    let start = Instant::now();
+    for _ in 0..batches {
+        stream.write_all(&batch).unwrap();
+        stream.read_exact(&mut replies).unwrap();
+    }
+    let elapsed = start.elapsed();
+    assert_eq!(&replies[..reply.len()], reply);
+    (batches * batch_size) as f64 / elapsed.as_secs_f64()
+}
+
+fn main() {
+    let commands = std::env::var("DKV_BENCH_COMMANDS")
+        .map(|it| it.parse().expect("DKV_BENCH_COMMANDS must be a number"))
+        .unwrap_or(DEFAULT_COMMANDS_PER_RUN);
+    let (_server, mut stream) = start_server();
+    println!("{:>10} {:>14}", "batch", "commands/sec");
+    for batch_size in [1, 16, 100, 1000] {
+        let throughput = run(&mut stream, commands, batch_size, b"value");
+        println!("{:>10} {:>14.0}", batch_size, throughput);
+    }
+    println!();
+    println!("{:>10} {:>14} {:>10}", "value", "commands/sec", "MB/sec");
+    for value_size in [1024, 64 * 1024, 1024 * 1024] {
+        let value = vec![b'x'; value_size];
+        let commands = commands.min(MAX_BYTES_PER_RUN / value_size);
+        let throughput = run(&mut stream, commands, 16, &value);
+        println!(
+            "{:>10} {:>14.0} {:>10.0}",
+            value_size,
+            throughput,
+            throughput * value_size as f64 / (1024.0 * 1024.0)
+        );
+    }
+}
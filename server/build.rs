@@ -0,0 +1,16 @@
+use std::{env, process::Command};
+
+/// Makes the compiler's version available to `module::ABI_VERSION`, since
+/// dynamically loaded modules have to be built with the same one
+fn main() {
+    let rustc = env::var("RUSTC").unwrap_or_else(|_| "rustc".to_owned());
+    let version = Command::new(rustc)
+        .arg("--version")
+        .output()
+        .ok()
+        .and_then(|output| String::from_utf8(output.stdout).ok())
+        .map(|version| version.trim().to_owned())
+        .unwrap_or_else(|| "unknown rustc".to_owned());
+    println!("cargo:rustc-env=DKV_RUSTC_VERSION={}", version);
+    println!("cargo:rerun-if-env-changed=RUSTC");
+}
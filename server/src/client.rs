@@ -0,0 +1,204 @@
+use std::{
+    collections::HashMap,
+    io,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use crate::{
+    config::ConfigRegistry, error::Error, log, pubsub::OutputBuffer, stats::Stats, value::Value,
+};
+
+pub type ClientId = u64;
+
+/// How a client's connection finds out that other threads sent it something.
+/// Only the connection writes to the client's socket.
+pub enum ClientOutput {
+    /// The connection's own thread checks for messages every millisecond while
+    /// waiting for input, if the client may get any, see [ClientHandle::receives_pushes]
+    Polled,
+    /// The reactor, which owns the client's non blocking socket, gets woken up
+    Queued(Arc<ReadyClients>),
+}
+
+impl ClientOutput {
+    pub fn queued(ready: Arc<ReadyClients>) -> ClientOutput {
+        ClientOutput::Queued(ready)
+    }
+}
+
+/// Clients that other threads have something for, waiting for the reactor to get to them
+pub struct ReadyClients {
+    ids: Mutex<Vec<ClientId>>,
+    wake: Box<dyn Fn() -> io::Result<()> + Send + Sync>,
+}
+
+impl ReadyClients {
+    /// `wake` gets the reactor to call [ReadyClients::take]
+    pub fn new(wake: impl Fn() -> io::Result<()> + Send + Sync + 'static) -> ReadyClients {
+        ReadyClients {
+            ids: Mutex::new(vec![]),
+            wake: Box::new(wake),
+        }
+    }
+
+    fn add(&self, id: ClientId) {
+        let mut ids = self.ids.lock().unwrap();
+        // Otherwise the reactor was already woken up, and hasn't taken the ids yet
+        if ids.is_empty() {
+            if let Err(e) = (self.wake)() {
+                log::warning(format!("Waking the reactor up: {}", e));
+            }
+        }
+        ids.push(id);
+    }
+
+    pub fn take(&self) -> Vec<ClientId> {
+        std::mem::take(&mut *self.ids.lock().unwrap())
+    }
+}
+
+/// The part of a connection that other connections are allowed to touch,
+/// e.g. to deliver client side caching invalidations.
+pub struct ClientHandle {
+    pub id: ClientId,
+    output: ClientOutput,
+    /// Messages sent from other threads, like the ones published to the client's
+    /// channels and invalidations, waiting for the connection to write them
+    /// between replies
+    pushes: OutputBuffer,
+    resp3: AtomicBool,
+    subscribed: AtomicBool,
+    /// Set once another client redirects its invalidations to this one
+    redirect_target: AtomicBool,
+    /// Set with `CLIENT SETNAME` or `HELLO ... SETNAME`
+    name: Mutex<Option<String>>,
+}
+
+impl ClientHandle {
+    pub fn name(&self) -> Option<String> {
+        self.name.lock().unwrap().clone()
+    }
+
+    /// Like in redis, an empty name removes the name
+    pub fn set_name(&self, name: &str) -> Result<(), Error> {
+        if name.bytes().any(|b| !(b'!'..=b'~').contains(&b)) {
+            return Err(Error::generic(
+                "Client names cannot contain spaces, newlines or special characters.",
+                name,
+            ));
+        }
+        *self.name.lock().unwrap() = (!name.is_empty()).then(|| name.to_owned());
+        Ok(())
+    }
+
+    /// Queues a message for the client's connection to write. Like in redis, the
+    /// messages waiting are limited by `client-output-buffer-limit pubsub` while
+    /// the client is subscribed, and by `normal` otherwise. The client gets
+    /// disconnected when it goes past its limit, instead of blocking the sender.
+    pub fn send(&self, value: Value, config: &ConfigRegistry) {
+        let limit = {
+            let config = config.read();
+            if self.subscribed.load(Ordering::Relaxed) {
+                config.client_output_buffer_limit_pubsub
+            } else {
+                config.client_output_buffer_limit_normal
+            }
+        };
+        self.pushes.push(value, limit);
+        self.notify();
+    }
+
+    /// Takes the messages sent by other threads, or returns None if
+    /// they went past the client's limit and it should be disconnected
+    pub fn take_pushes(&self) -> Option<Vec<Value>> {
+        self.pushes.drain()
+    }
+
+    /// Lets the reactor know that the client's connection has something to do,
+    /// like writing the messages published to its channels. Clients with their
+    /// own thread find out by themselves.
+    pub fn notify(&self) {
+        if let ClientOutput::Queued(ready) = &self.output {
+            ready.add(self.id);
+        }
+    }
+
+    /// Whether other threads may send the client something without it asking,
+    /// besides what's published to its channels and its own invalidations
+    pub fn receives_pushes(&self) -> bool {
+        self.redirect_target.load(Ordering::Relaxed)
+    }
+
+    pub fn set_redirect_target(&self) {
+        self.redirect_target.store(true, Ordering::Relaxed)
+    }
+
+    pub fn is_resp3(&self) -> bool {
+        self.resp3.load(Ordering::Relaxed)
+    }
+
+    pub fn set_resp3(&self, resp3: bool) {
+        self.resp3.store(resp3, Ordering::Relaxed)
+    }
+
+    pub fn set_subscribed(&self, subscribed: bool) {
+        self.subscribed.store(subscribed, Ordering::Relaxed)
+    }
+
+    /// Sends an out of band message, like a client side caching invalidation.
+    ///
+    /// RESP3 clients receive it as a push. RESP2 clients can't receive pushes,
+    /// so they only get it as a pubsub message on `channel` while they're
+    /// in subscribe mode. Returns false if the message couldn't be delivered.
+    pub fn push(&self, channel: &str, values: Vec<Value>, config: &ConfigRegistry) -> bool {
+        let value = if self.is_resp3() {
+            Value::Push(values)
+        } else if self.subscribed.load(Ordering::Relaxed) {
+            let payload = values.into_iter().last().unwrap_or(Value::Null);
+            Value::Array(vec![Value::from("message"), Value::from(channel), payload])
+        } else {
+            return false;
+        };
+        self.send(value, config);
+        true
+    }
+}
+
+/// All connected clients, by id
+#[derive(Default)]
+pub struct ClientRegistry {
+    next_id: AtomicU64,
+    clients: Mutex<HashMap<ClientId, Arc<ClientHandle>>>,
+}
+
+impl ClientRegistry {
+    pub fn register(&self, output: ClientOutput, stats: Arc<Stats>) -> Arc<ClientHandle> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed) + 1;
+        let handle = Arc::new(ClientHandle {
+            id,
+            output,
+            pushes: OutputBuffer::new(stats),
+            resp3: AtomicBool::new(false),
+            subscribed: AtomicBool::new(false),
+            redirect_target: AtomicBool::new(false),
+            name: Mutex::new(None),
+        });
+        self.clients.lock().unwrap().insert(id, handle.clone());
+        handle
+    }
+
+    pub fn unregister(&self, id: ClientId) {
+        self.clients.lock().unwrap().remove(&id);
+    }
+
+    pub fn len(&self) -> usize {
+        self.clients.lock().unwrap().len()
+    }
+
+    pub fn get(&self, id: ClientId) -> Option<Arc<ClientHandle>> {
+        self.clients.lock().unwrap().get(&id).cloned()
+    }
+}
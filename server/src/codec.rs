@@ -1,203 +1,287 @@
-use std::{
-    collections::HashMap,
-    io::{self, Read, Write},
-};
+use std::io::{self, Write};
 
-use crate::{
-    error::{BadMessageError, Error},
-    value::Value,
-};
+use crate::{error::Error, value::Value};
 
 pub type Result<T> = std::result::Result<T, Error>;
 
-pub fn read<T: Read>(stream: &mut T) -> Result<Value> {
-    let mut buf = [0];
-    stream.read_exact(&mut buf)?;
-    match buf[0] {
-        b'$' => Ok(Value::String(read_bulk_string_tail(stream)?)),
-        b'_' => {
-            expect_newline(stream)?;
-            Ok(Value::Null)
-        }
-        b'-' => {
-            expect_newline(stream)?;
-            Ok(Value::Null)
-        }
-        b'+' => {
-            let mut value = vec![];
-            let mut b = [0];
-            loop {
-                stream.read_exact(&mut b)?;
-                if b[0] == b'\r' {
-                    break;
-                }
-                value.push(b[0]);
-            }
-            stream.read_exact(&mut b)?;
-            assert!(b[0] == b'\n');
-            let value = String::from_utf8(value)
-                .map_err(|it| Error::BadMessage(BadMessageError::Utf8(it)))?;
-            Ok(Value::String(value))
-        }
-        b'*' => {
-            let len = parse_length(stream)?;
-            let mut values = vec![];
-            for _ in 0..len {
-                values.push(read(stream)?);
-            }
-            Ok(Value::Array(values))
-        }
-        b'%' => {
-            let len = parse_length(stream)?;
-            let mut map = HashMap::new();
-            for _ in 0..len {
-                let key = read_bulk_string(stream)?;
-                let value = read(stream)?;
-                map.insert(key, value);
-            }
-            Ok(Value::Map(map))
-        }
-        b':' => {
-            let len = parse_length(stream)?;
-            Ok(Value::Integer(len as i64))
-        }
-        c => {
-            let mut buf = vec![c];
-            stream.read_to_end(&mut buf).unwrap();
-            panic!("Error: {}", String::from_utf8_lossy(&buf))
+/// Longest line accepted for an inline command, same as redis
+pub const INLINE_MAX_SIZE: usize = 64 * 1024;
+
+/// Which version of the protocol a client speaks. RESP3 types that
+/// don't exist in RESP2 are downgraded when writing to RESP2 clients.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Protocol {
+    RESP2,
+    RESP3,
+}
+
+/// Limits on the size of what clients may send, so that a single
+/// request can't make the server allocate unbounded amounts of memory.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ProtocolLimits {
+    /// Longest accepted bulk string, redis' `proto-max-bulk-len`
+    pub max_bulk_len: usize,
+    /// Most elements accepted in an array, set, map or push
+    pub max_multibulk_len: usize,
+}
+
+impl Default for ProtocolLimits {
+    fn default() -> Self {
+        ProtocolLimits {
+            max_bulk_len: 512 * 1024 * 1024,
+            max_multibulk_len: 1024 * 1024,
         }
     }
 }
 
-fn expect_newline<T: Read>(stream: &mut T) -> Result<()> {
-    let mut b = [0];
-    stream.read_exact(&mut b)?;
-    assert!(b[0] == b'\r');
-    stream.read_exact(&mut b)?;
-    assert!(b[0] == b'\n');
-    Ok(())
+/// Writes a value in RESP3
+pub fn write<T: Write>(value: &Value, stream: &mut T) -> io::Result<()> {
+    write_with_protocol(value, Protocol::RESP3, stream)
 }
 
-pub fn write<T: Write>(value: &Value, stream: &mut T) -> io::Result<()> {
+pub fn write_with_protocol<T: Write>(
+    value: &Value,
+    protocol: Protocol,
+    stream: &mut T,
+) -> io::Result<()> {
+    let resp3 = protocol == Protocol::RESP3;
     match value {
         Value::String(s) => {
-            write_bulk_string(stream, s.as_str())?;
+            write_bulk_string(stream, s)?;
+        }
+        Value::Bytes(bytes) => {
+            write_bulk_string(stream, bytes)?;
         }
         Value::Null => {
-            stream.write_all(b"_\r\n")?;
+            if resp3 {
+                stream.write_all(b"_\r\n")?;
+            } else {
+                stream.write_all(b"$-1\r\n")?;
+            }
         }
         Value::Array(values) => {
-            write!(stream, "*{}\r\n", values.len())?;
-            for value in values {
-                write(value, stream)?;
-            }
+            write_values(stream, '*', values, protocol)?;
+        }
+        Value::Set(values) => {
+            write_values(stream, if resp3 { '~' } else { '*' }, values, protocol)?;
+        }
+        Value::Push(values) => {
+            write_values(stream, if resp3 { '>' } else { '*' }, values, protocol)?;
         }
         Value::Map(map) => {
-            write!(stream, "%{}\r\n", map.len())?;
+            if resp3 {
+                write!(stream, "%{}\r\n", map.len())?;
+            } else {
+                write!(stream, "*{}\r\n", map.len() * 2)?;
+            }
             for (key, value) in map {
                 write_bulk_string(stream, key.as_str())?;
-                write(value, stream)?;
+                write_with_protocol(value, protocol, stream)?;
+            }
+        }
+        Value::Attribute(attributes, value) => {
+            // RESP2 clients don't know about attributes, so they only get the value
+            if resp3 {
+                write!(stream, "|{}\r\n", attributes.len())?;
+                for (key, value) in attributes {
+                    write_bulk_string(stream, key.as_str())?;
+                    write_with_protocol(value, protocol, stream)?;
+                }
             }
+            write_with_protocol(value, protocol, stream)?;
         }
         Value::Integer(i) => {
             write!(stream, ":{}\r\n", i)?;
         }
+        Value::Double(d) => {
+            if resp3 {
+                write!(stream, ",{}\r\n", format_double(*d))?;
+            } else {
+                write_bulk_string(stream, format_double(*d))?;
+            }
+        }
+        Value::Boolean(b) => {
+            if resp3 {
+                write!(stream, "#{}\r\n", if *b { 't' } else { 'f' })?;
+            } else {
+                write!(stream, ":{}\r\n", *b as i64)?;
+            }
+        }
+        Value::BigNumber(n) => {
+            if resp3 {
+                write!(stream, "({}\r\n", n)?;
+            } else {
+                write_bulk_string(stream, n)?;
+            }
+        }
+        Value::Verbatim { format, text } => {
+            if resp3 {
+                write!(stream, "={}\r\n{}:", format.len() + 1 + text.len(), format)?;
+                stream.write_all(text.as_bytes())?;
+                stream.write_all(b"\r\n")?;
+            } else {
+                write_bulk_string(stream, text)?;
+            }
+        }
+        Value::SimpleError(e) => {
+            write!(stream, "-{}\r\n", e)?;
+        }
+        Value::BlobError(e) => {
+            if resp3 {
+                write!(stream, "!{}\r\n", e.len())?;
+                stream.write_all(e.as_bytes())?;
+                stream.write_all(b"\r\n")?;
+            } else {
+                write!(stream, "-{}\r\n", e.replace(['\r', '\n'], " "))?;
+            }
+        }
     }
     Ok(())
 }
 
-fn parse_length<T: Read>(stream: &mut T) -> Result<usize> {
-    let mut len = vec![];
-    let mut b = [0];
-    loop {
-        stream.read_exact(&mut b)?;
-        if b[0] == b'\r' {
-            break;
-        }
-        len.push(b[0]);
+fn write_values<T: Write>(
+    stream: &mut T,
+    prefix: char,
+    values: &[Value],
+    protocol: Protocol,
+) -> io::Result<()> {
+    write!(stream, "{}{}\r\n", prefix, values.len())?;
+    for value in values {
+        write_with_protocol(value, protocol, stream)?;
+    }
+    Ok(())
+}
+
+/// Formats a double the way redis does, with the fewest digits that parse
+/// back to it, e.g. `1.5`, `inf`, `1e+300` or `1.5e-10`
+pub fn format_double(d: f64) -> String {
+    if d.is_nan() {
+        return String::from("nan");
+    } else if d.is_infinite() {
+        return String::from(if d > 0.0 { "inf" } else { "-inf" });
+    } else if d == 0.0 {
+        return format!("{}", d);
+    }
+    // Both of Rust's formats use the fewest digits too, only
+    // when to switch to scientific notation differs
+    let scientific = format!("{:e}", d);
+    let (mantissa, exponent) = scientific.split_once('e').unwrap();
+    let exponent: i32 = exponent.parse().unwrap();
+    let digits = mantissa.bytes().filter(u8::is_ascii_digit).count() as i32;
+    // The exponent of the last digit, same cut-offs as redis' `fpconv_dtoa`
+    let last = exponent - (digits - 1);
+    let plain = if last >= 0 {
+        exponent < digits + 7
+    } else {
+        last > -7 || exponent.abs() < 4
+    };
+    if plain {
+        format!("{}", d)
+    } else {
+        format!("{}e{:+}", mantissa, exponent)
     }
-    stream.read_exact(&mut b)?;
-    assert!(b[0] == b'\n');
-    let len = String::from_utf8(len).map_err(|it| Error::BadMessage(BadMessageError::Utf8(it)))?;
-    len.parse::<usize>()
-        .map_err(|_| Error::BadMessage(BadMessageError::InvalidLength(len)))
 }
 
-pub fn write_bulk_string<T: Write>(stream: &mut T, s: &str) -> io::Result<()> {
+pub fn write_bulk_string<T: Write>(stream: &mut T, s: impl AsRef<[u8]>) -> io::Result<()> {
+    let s = s.as_ref();
     write!(stream, "${}\r\n", s.len())?;
-    stream.write_all(s.as_bytes())?;
+    stream.write_all(s)?;
     stream.write_all(b"\r\n")?;
     Ok(())
 }
-fn read_bulk_string_tail<T: Read>(stream: &mut T) -> Result<String> {
-    let len = parse_length(stream)?;
-    let mut value = vec![0; len];
-    stream.read_exact(&mut value)?;
-    expect_newline(stream)?;
-    String::from_utf8(value).map_err(|it| Error::BadMessage(BadMessageError::Utf8(it)))
-}
 
-fn read_bulk_string<T: Read>(stream: &mut T) -> Result<String> {
-    let mut buf = [0];
-    stream.read_exact(&mut buf)?;
-    if buf[0] != b'$' {
-        return Err(Error::generic("Expected a string", format!("{:?}", buf[0])));
+/// Splits an inline command or a config line into arguments the way redis'
+/// `sdssplitargs` does. Arguments are separated by whitespace, and can be wrapped
+/// in double quotes (supporting `\n`, `\r`, `\t`, `\b`, `\a` and `\xHH` escapes)
+/// or single quotes. Works on bytes, so that e.g. `"\xff"` is the byte 0xff.
+/// Returns None if the quotes are unbalanced.
+pub fn split_args(line: &[u8]) -> Option<Vec<Vec<u8>>> {
+    let mut args = vec![];
+    let mut bytes = line.iter().copied().peekable();
+    loop {
+        while bytes.next_if(|b| is_space(*b)).is_some() {}
+        if bytes.peek().is_none() {
+            return Some(args);
+        }
+        let mut arg = vec![];
+        let mut in_double_quotes = false;
+        let mut in_single_quotes = false;
+        loop {
+            if in_double_quotes {
+                match bytes.next()? {
+                    b'\\' => match bytes.next()? {
+                        b'n' => arg.push(b'\n'),
+                        b'r' => arg.push(b'\r'),
+                        b't' => arg.push(b'\t'),
+                        b'b' => arg.push(0x08),
+                        b'a' => arg.push(0x07),
+                        b'x' if bytes.clone().take(2).filter(u8::is_ascii_hexdigit).count()
+                            == 2 =>
+                        {
+                            let high = hex_digit(bytes.next()?);
+                            let low = hex_digit(bytes.next()?);
+                            arg.push(high << 4 | low);
+                        }
+                        b => arg.push(b),
+                    },
+                    b'"' => {
+                        // The closing quote must be followed by a space or nothing at all
+                        if bytes.peek().is_some_and(|b| !is_space(*b)) {
+                            return None;
+                        }
+                        break;
+                    }
+                    b => arg.push(b),
+                }
+            } else if in_single_quotes {
+                match bytes.next()? {
+                    b'\\' if bytes.peek() == Some(&b'\'') => {
+                        bytes.next();
+                        arg.push(b'\'');
+                    }
+                    b'\'' => {
+                        if bytes.peek().is_some_and(|b| !is_space(*b)) {
+                            return None;
+                        }
+                        break;
+                    }
+                    b => arg.push(b),
+                }
+            } else {
+                match bytes.peek() {
+                    None => break,
+                    Some(b) if is_space(*b) => break,
+                    Some(b'"') => in_double_quotes = true,
+                    Some(b'\'') => in_single_quotes = true,
+                    Some(&b) => arg.push(b),
+                }
+                bytes.next();
+            }
+        }
+        args.push(arg);
     }
-    read_bulk_string_tail(stream)
 }
 
-pub fn read_bulk_string_array(stream: &mut impl Read) -> Result<Vec<String>> {
-    let mut buf = [0];
-    stream.read_exact(&mut buf)?;
-    if buf[0] != b'*' {
-        return Err(Error::generic("Expected a string", format!("{:?}", buf[0])));
-    }
-    let len = parse_length(stream)?;
-    let mut values = vec![];
-    for _ in 0..len {
-        values.push(read_bulk_string(stream)?);
-    }
-    Ok(values)
+/// Same as C's `isspace`, which unlike [u8::is_ascii_whitespace] includes `\v`
+fn is_space(b: u8) -> bool {
+    matches!(b, b' ' | b'\t' | b'\n' | 0x0b | 0x0c | b'\r')
 }
 
-pub fn write_bulk_string_array(stream: &mut impl Write, values: &[&str]) -> io::Result<()> {
-    write!(stream, "*{}\r\n", values.len())?;
-    for value in values {
-        write_bulk_string(stream, value)?;
-    }
-    Ok(())
+fn hex_digit(b: u8) -> u8 {
+    (b as char).to_digit(16).unwrap_or(0) as u8
 }
 
 #[cfg(test)]
 mod test {
+    use std::collections::HashMap;
 
     use super::*;
-    #[test]
-    fn can_read_and_write_map() {
-        let mut map = std::collections::HashMap::new();
-        map.insert("hello".to_string(), Value::String("world".to_string()));
-        let mut buf = vec![];
-        write(&Value::Map(map), &mut buf).unwrap();
-
-        dbg!(String::from_utf8_lossy(&buf));
-        let value = read(&mut &buf[..]).expect("Foo");
-        if let Value::Map(map) = value {
-            assert_eq!(map.len(), 1);
-            assert_eq!(
-                map.get("hello").unwrap(),
-                &Value::String("world".to_string())
-            );
-        } else {
-            panic!("Expected a map");
-        }
-    }
 
     #[test]
-    fn can_parse_bulk_string() -> Result<()> {
-        let input = b"$5\r\nhello\r\n";
-        let result = read(&mut &input[..])?;
-        assert_eq!(Value::String("hello".to_string()), result);
-        Ok(())
+    fn can_write_map() -> Result<()> {
+        let mut map = HashMap::new();
+        map.insert("hello".to_string(), Value::String("world".to_string()));
+        assert_writes(Value::Map(map), b"%1\r\n$5\r\nhello\r\n$5\r\nworld\r\n")
     }
 
     #[test]
@@ -210,33 +294,158 @@ mod test {
     }
 
     #[test]
-    fn can_read_simple_strings() -> Result<()> {
-        let input = b"+OK\r\n";
-        let result = read(&mut &input[..])?;
-        assert_eq!(Value::from("OK"), result);
+    fn can_write_nil_and_arrays() -> Result<()> {
+        assert_writes(Value::Null, b"_\r\n")?;
+        assert_writes(
+            Value::Array(vec![Value::from("foo"), Value::from("bar"), Value::Null]),
+            b"*3\r\n$3\r\nfoo\r\n$3\r\nbar\r\n_\r\n",
+        )
+    }
+
+    fn assert_writes(value: Value, expected: &[u8]) -> Result<()> {
+        let mut output: Vec<u8> = vec![];
+        write(&value, &mut output)?;
+        assert_eq!(
+            String::from_utf8_lossy(&output),
+            String::from_utf8_lossy(expected)
+        );
         Ok(())
     }
 
+    fn resp2(value: &Value) -> String {
+        let mut output: Vec<u8> = vec![];
+        write_with_protocol(value, Protocol::RESP2, &mut output).unwrap();
+        String::from_utf8(output).unwrap()
+    }
+
     #[test]
-    fn can_read_and_write_nil() -> Result<()> {
-        let input = b"_\r\n";
-        let result = read(&mut &input[..])?;
-        assert_eq!(Value::Null, result);
+    fn formats_doubles_like_redis() {
+        assert_eq!(format_double(0.0), "0");
+        assert_eq!(format_double(10_000_000.0), "10000000");
+        assert_eq!(format_double(100_000_000.0), "1e+8");
+        assert_eq!(format_double(-1.25e20), "-1.25e+20");
+        assert_eq!(format_double(123_456_789.5), "123456789.5");
+        assert_eq!(format_double(0.0001), "0.0001");
+        assert_eq!(format_double(0.000001), "0.000001");
+        assert_eq!(format_double(1.5e-10), "1.5e-10");
+        assert_eq!(format_double(0.1 + 0.2), "0.30000000000000004");
+    }
 
-        let mut output: Vec<u8> = vec![];
-        write(&Value::Null, &mut output)?;
-        assert_eq!(output, b"_\r\n");
+    #[test]
+    fn can_write_resp3_types() -> Result<()> {
+        assert_writes(Value::Integer(-42), b":-42\r\n")?;
+        assert_writes(Value::Bytes(vec![0xff, 0, b'a']), b"$3\r\n\xff\0a\r\n")?;
+        assert_writes(Value::Double(1.5), b",1.5\r\n")?;
+        assert_writes(Value::Double(-3.0), b",-3\r\n")?;
+        assert_writes(Value::Double(1e300), b",1e+300\r\n")?;
+        assert_writes(Value::Double(f64::INFINITY), b",inf\r\n")?;
+        assert_writes(Value::Double(f64::NEG_INFINITY), b",-inf\r\n")?;
+        assert_writes(Value::Boolean(true), b"#t\r\n")?;
+        assert_writes(Value::Boolean(false), b"#f\r\n")?;
+        assert_writes(
+            Value::BigNumber("-3492890328409238509324850943850943825024385".to_string()),
+            b"(-3492890328409238509324850943850943825024385\r\n",
+        )?;
+        assert_writes(
+            Value::Verbatim {
+                format: "txt".to_string(),
+                text: "Some string".to_string(),
+            },
+            b"=15\r\ntxt:Some string\r\n",
+        )?;
+        assert_writes(
+            Value::Set(vec![Value::from("a"), Value::Integer(1)]),
+            b"~2\r\n$1\r\na\r\n:1\r\n",
+        )?;
+        assert_writes(
+            Value::SimpleError("ERR unknown command".to_string()),
+            b"-ERR unknown command\r\n",
+        )?;
+        assert_writes(
+            Value::BlobError("SYNTAX invalid\r\nsyntax".to_string()),
+            b"!22\r\nSYNTAX invalid\r\nsyntax\r\n",
+        )?;
+        let mut attributes = HashMap::new();
+        attributes.insert("ttl".to_string(), Value::Integer(3600));
+        assert_writes(
+            Value::Attribute(attributes, Box::new(Value::from("value"))),
+            b"|1\r\n$3\r\nttl\r\n:3600\r\n$5\r\nvalue\r\n",
+        )?;
         Ok(())
     }
 
     #[test]
-    fn can_read_and_write_arrays() -> Result<()> {
-        let input = b"*3\r\n$3\r\nfoo\r\n$3\r\nbar\r\n_\r\n";
-        let result = read(&mut &input[..])?;
+    fn downgrades_resp3_types_for_resp2() {
+        assert_eq!(resp2(&Value::Null), "$-1\r\n");
+        assert_eq!(resp2(&Value::Double(2.5)), "$3\r\n2.5\r\n");
+        assert_eq!(resp2(&Value::Boolean(true)), ":1\r\n");
+        assert_eq!(resp2(&Value::Boolean(false)), ":0\r\n");
+        assert_eq!(resp2(&Value::BigNumber("123".to_string())), "$3\r\n123\r\n");
         assert_eq!(
-            Value::Array(vec![Value::from("foo"), Value::from("bar"), Value::Null]),
-            result
+            resp2(&Value::Verbatim {
+                format: "txt".to_string(),
+                text: "hi".to_string()
+            }),
+            "$2\r\nhi\r\n"
         );
-        Ok(())
+        assert_eq!(
+            resp2(&Value::Set(vec![Value::from("a")])),
+            "*1\r\n$1\r\na\r\n"
+        );
+        assert_eq!(
+            resp2(&Value::Push(vec![Value::from("a")])),
+            "*1\r\n$1\r\na\r\n"
+        );
+        let mut map = HashMap::new();
+        map.insert("k".to_string(), Value::Null);
+        assert_eq!(resp2(&Value::Map(map.clone())), "*2\r\n$1\r\nk\r\n$-1\r\n");
+        assert_eq!(
+            resp2(&Value::Attribute(map, Box::new(Value::Integer(1)))),
+            ":1\r\n"
+        );
+        assert_eq!(
+            resp2(&Value::BlobError("ERR a\r\nb".to_string())),
+            "-ERR a  b\r\n"
+        );
+        assert_eq!(resp2(&Value::SimpleError("ERR".to_string())), "-ERR\r\n");
+    }
+
+    #[test]
+    fn splits_inline_arguments() {
+        let split = |s: &str| {
+            split_args(s.as_bytes())
+                .unwrap()
+                .into_iter()
+                .map(|arg| String::from_utf8(arg).unwrap())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(split("  get   foo "), vec!["get", "foo"]);
+        assert_eq!(
+            split(r#"set "a\tb\x41\"" 'it\'s' """#),
+            vec!["set", "a\tbA\"", "it's", ""]
+        );
+        assert_eq!(split(r#""\xZZ""#), vec!["xZZ"]);
+        assert_eq!(split("a\x0bb"), vec!["a", "b"]);
+        assert_eq!(split(""), Vec::<String>::new());
+        assert_eq!(split_args(br#"set "unterminated"#), None);
+        assert_eq!(split_args(br#"set "a"b"#), None);
+        assert_eq!(split_args(b"set 'a"), None);
+    }
+
+    #[test]
+    fn splits_binary_arguments() {
+        assert_eq!(
+            split_args(b"set \"\\xff\\x00\" \xc3"),
+            Some(vec![b"set".to_vec(), vec![0xff, 0x00], vec![0xc3]])
+        );
+    }
+
+    #[test]
+    fn can_write_pushes() -> Result<()> {
+        let value = Value::Push(vec![
+            Value::from("invalidate"),
+            Value::Array(vec![Value::from("key")]),
+        ]);
+        assert_writes(value, b">2\r\n$10\r\ninvalidate\r\n*1\r\n$3\r\nkey\r\n")
     }
 }
@@ -1,97 +1,1176 @@
-use std::io::Read;
+use std::collections::HashMap;
+
+use bytes::Bytes;
 
 use crate::{
-    codec::{read_bulk_string_array, Result},
-    serializable::Deserializable,
+    codec::Result,
+    connection::{Connection, HandleResult},
+    docs::{Arg, CommandDocs},
+    error::BadMessageError,
+    glob,
+    module::{ModuleHandler, ModuleRegistry},
+    parser::Request,
     Error, Value,
 };
 
-#[derive(Debug, PartialEq)]
-pub enum Command {
-    Set(String, String),
-    Get(String),
-    Del(String),
-    Exists(String),
-    Command(Vec<String>),
-    Config(Vec<String>),
-    Ping(String),
-    FlushAll,
-    ClientSetInfo(String, String),
-    Rename(String, String),
-    HSet {
-        key: String,
-        field: String,
-        value: String,
-    },
-    HGet {
-        key: String,
-        field: String,
-    },
-    HGetAll(String),
-    HLen(String),
-    HExists {
-        key: String,
-        field: String,
-    },
-    Hello(String),
-    Subscribe(Vec<String>),
-    Publish(String, String),
-    Unsubscribe(Vec<String>),
-    Quit,
+/// Runs a built in command on behalf of a connection. Gets every argument of
+/// the request, including the command's name, like `argv` in redis.
+pub(crate) type BuiltinHandler = fn(&mut Connection, &[Bytes]) -> Result<HandleResult>;
+
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum Handler {
+    Builtin(BuiltinHandler),
+    Module(ModuleHandler),
+}
+
+/// Command flags, as reported by `COMMAND INFO`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CommandFlag {
+    /// May modify the keyspace
+    Write,
+    /// Only reads from the keyspace
+    Readonly,
+    /// May grow memory usage, so it's refused when out of memory
+    Denyoom,
+    Admin,
+    Pubsub,
+    /// Not allowed from scripts
+    Noscript,
+    /// Allowed while the dataset is still being loaded
+    Loading,
+    /// Allowed on a replica with stale data
+    Stale,
+    /// Runs in constant or logarithmic time
+    Fast,
+    /// Allowed before the client authenticates
+    NoAuth,
+}
+
+impl CommandFlag {
+    pub fn name(self) -> &'static str {
+        match self {
+            CommandFlag::Write => "write",
+            CommandFlag::Readonly => "readonly",
+            CommandFlag::Denyoom => "denyoom",
+            CommandFlag::Admin => "admin",
+            CommandFlag::Pubsub => "pubsub",
+            CommandFlag::Noscript => "noscript",
+            CommandFlag::Loading => "loading",
+            CommandFlag::Stale => "stale",
+            CommandFlag::Fast => "fast",
+            CommandFlag::NoAuth => "no_auth",
+        }
+    }
+}
+
+/// Where a command's keys are in its arguments, and what it does with them.
+/// Only redis' `index` + `range` kind of key specs are supported.
+#[derive(Debug, Clone, Copy)]
+pub struct KeySpec {
+    /// Position of the first key
+    pub index: i32,
+    /// Position of the last key, relative to `index`. Negative positions
+    /// count from the end of the arguments, so -1 is the last argument.
+    pub last_key: i32,
+    pub step: i32,
+    /// e.g. `RW` and `access`, as reported by `COMMAND GETKEYSANDFLAGS`
+    pub flags: &'static [&'static str],
+}
+
+impl KeySpec {
+    /// A single key at `index`
+    pub const fn single(index: i32, flags: &'static [&'static str]) -> Self {
+        KeySpec::range(index, 0, 1, flags)
+    }
+
+    pub const fn range(
+        index: i32,
+        last_key: i32,
+        step: i32,
+        flags: &'static [&'static str],
+    ) -> Self {
+        KeySpec {
+            index,
+            last_key,
+            step,
+            flags,
+        }
+    }
+
+    /// The key arguments this spec finds in a request
+    fn positions(&self, args: usize) -> impl Iterator<Item = usize> {
+        let last = if self.last_key < 0 {
+            args as i32 + self.last_key
+        } else {
+            (self.index + self.last_key).min(args as i32 - 1)
+        };
+        (self.index..=last)
+            .step_by(self.step.max(1) as usize)
+            .map(|i| i as usize)
+    }
+
+    fn info(&self) -> Value {
+        let mut begin_search = HashMap::new();
+        begin_search.insert("type".to_owned(), Value::from("index"));
+        begin_search.insert(
+            "spec".to_owned(),
+            Value::Map(HashMap::from([(
+                "index".to_owned(),
+                Value::Integer(self.index as i64),
+            )])),
+        );
+        let mut find_keys = HashMap::new();
+        find_keys.insert("type".to_owned(), Value::from("range"));
+        find_keys.insert(
+            "spec".to_owned(),
+            Value::Map(HashMap::from([
+                ("lastkey".to_owned(), Value::Integer(self.last_key as i64)),
+                ("keystep".to_owned(), Value::Integer(self.step as i64)),
+                ("limit".to_owned(), Value::Integer(0)),
+            ])),
+        );
+        Value::Map(HashMap::from([
+            (
+                "flags".to_owned(),
+                Value::Set(self.flags.iter().map(|f| Value::from(*f)).collect()),
+            ),
+            ("begin_search".to_owned(), Value::Map(begin_search)),
+            ("find_keys".to_owned(), Value::Map(find_keys)),
+        ]))
+    }
+}
+
+/// An entry of the command table. Everything the server knows about a
+/// command comes from here: dispatch, arity checks and `COMMAND` replies.
+#[derive(Debug)]
+pub struct CommandSpec {
+    /// Lowercase. Subcommands are named after their container, e.g. `client|id`
+    pub name: &'static str,
+    /// Number of arguments, including the command's name (and the subcommand's).
+    /// A negative arity `-n` means at least `n` arguments.
+    pub arity: i32,
+    pub flags: &'static [CommandFlag],
+    pub key_specs: &'static [KeySpec],
+    /// Without the `@` prefix
+    pub acl_categories: &'static [&'static str],
+    pub docs: CommandDocs,
+    /// None for containers like `CLIENT`, which are only called with a subcommand
+    handler: Option<Handler>,
+    pub subcommands: &'static [CommandSpec],
 }
 
-impl Deserializable for Command {
-    type Error = Error;
-    fn read(stream: &mut impl Read) -> Result<Self> {
-        let command = read_bulk_string_array(stream)?;
-        use Command as c;
-        let c: Command = match (command[0].to_uppercase().as_str(), &command[1..]) {
-            ("CLIENT", [setinfo, key, value]) if setinfo.to_uppercase() == "SETINFO" => {
-                Command::ClientSetInfo(key.clone(), value.clone())
+impl CommandSpec {
+    const fn new(name: &'static str, arity: i32, handler: BuiltinHandler) -> Self {
+        CommandSpec::with_handler(name, arity, Handler::Builtin(handler))
+    }
+
+    /// A command implemented by a [crate::module::Module]. `name` should be
+    /// lowercase, and is usually prefixed with the module's name, like `json.get`.
+    pub const fn module(name: &'static str, arity: i32, handler: ModuleHandler) -> Self {
+        CommandSpec::with_handler(name, arity, Handler::Module(handler))
+    }
+
+    const fn with_handler(name: &'static str, arity: i32, handler: Handler) -> Self {
+        CommandSpec {
+            name,
+            arity,
+            flags: &[],
+            key_specs: &[],
+            acl_categories: &[],
+            docs: CommandDocs::EMPTY,
+            handler: Some(handler),
+            subcommands: &[],
+        }
+    }
+
+    const fn container(
+        name: &'static str,
+        acl_categories: &'static [&'static str],
+        subcommands: &'static [CommandSpec],
+    ) -> Self {
+        CommandSpec {
+            name,
+            arity: -2,
+            flags: &[],
+            key_specs: &[],
+            acl_categories,
+            docs: CommandDocs::EMPTY,
+            handler: None,
+            subcommands,
+        }
+    }
+
+    pub const fn flags(mut self, flags: &'static [CommandFlag]) -> Self {
+        self.flags = flags;
+        self
+    }
+
+    pub const fn keys(mut self, key_specs: &'static [KeySpec]) -> Self {
+        self.key_specs = key_specs;
+        self
+    }
+
+    pub const fn acl(mut self, acl_categories: &'static [&'static str]) -> Self {
+        self.acl_categories = acl_categories;
+        self
+    }
+
+    pub const fn docs(
+        mut self,
+        summary: &'static str,
+        since: &'static str,
+        group: &'static str,
+        complexity: &'static str,
+    ) -> Self {
+        self.docs = CommandDocs {
+            summary,
+            since,
+            group,
+            complexity,
+            arguments: self.docs.arguments,
+        };
+        self
+    }
+
+    pub const fn args(mut self, arguments: &'static [Arg]) -> Self {
+        self.docs.arguments = arguments;
+        self
+    }
+
+    pub const fn subcommands(mut self, subcommands: &'static [CommandSpec]) -> Self {
+        self.subcommands = subcommands;
+        self
+    }
+
+    /// Looks up a built in or module command by name, ignoring case
+    pub(crate) fn find(name: &[u8], modules: &ModuleRegistry) -> Option<&'static CommandSpec> {
+        find_in(COMMANDS, name).or_else(|| modules.find_command(name))
+    }
+
+    /// Like [CommandSpec::find], but also finds subcommands by their
+    /// full name, e.g. `config|get`, like `COMMAND INFO` and `COMMAND DOCS` do.
+    pub(crate) fn find_by_full_name(
+        name: &[u8],
+        modules: &ModuleRegistry,
+    ) -> Option<&'static CommandSpec> {
+        match name.iter().position(|&b| b == b'|') {
+            Some(i) => find_in(Self::find(&name[..i], modules)?.subcommands, &name[i + 1..]),
+            None => Self::find(name, modules),
+        }
+    }
+
+    /// Every built in and module command, without subcommands
+    pub(crate) fn top_level(modules: &ModuleRegistry) -> Vec<&'static CommandSpec> {
+        COMMANDS.iter().chain(modules.commands()).collect()
+    }
+
+    /// Every command, followed by its subcommands
+    pub(crate) fn all(modules: &ModuleRegistry) -> Vec<&'static CommandSpec> {
+        Self::top_level(modules)
+            .into_iter()
+            .flat_map(|spec| std::iter::once(spec).chain(spec.subcommands))
+            .collect()
+    }
+
+    /// Resolves a request to the command handling it, which is a subcommand
+    /// for containers like `CLIENT`, and checks the number of arguments.
+    pub(crate) fn resolve(
+        args: &[Bytes],
+        modules: &ModuleRegistry,
+    ) -> Result<&'static CommandSpec> {
+        let Some((name, rest)) = args.split_first() else {
+            return Err(Error::generic("Empty command", ""));
+        };
+        let spec = Self::find(name, modules).ok_or_else(|| Error::unknown_command(name, rest))?;
+        if !spec.accepts(args.len()) {
+            return Err(Error::wrong_arity(spec.name));
+        }
+        let spec = match rest.first() {
+            Some(subcommand) if !spec.subcommands.is_empty() => {
+                match find_in(spec.subcommands, subcommand) {
+                    Some(subcommand) => subcommand,
+                    None => {
+                        return Err(Error::unknown_subcommand(
+                            &spec.name.to_uppercase(),
+                            &String::from_utf8_lossy(subcommand),
+                        ))
+                    }
+                }
             }
-            ("CONFIG", args) => Command::Config(args.to_vec()),
-            ("HELLO", [version]) => c::Hello(version.clone()),
-            ("COMMAND", args) => c::Command(args.to_vec()),
-            ("FLUSHALL", _) => c::FlushAll,
-            ("PING", []) => c::Ping("PONG".into()),
-            ("PING", [value]) => c::Ping(value.into()),
-            ("SET", [key, value]) => c::Set(key.clone(), value.clone()),
-            ("GET", [key]) => c::Get(key.clone()),
-            ("DEL", [key]) => c::Del(key.clone()),
-            ("RENAME", [old, new]) => c::Rename(old.clone(), new.clone()),
-            ("EXISTS", [key]) => c::Exists(key.clone()),
-            ("HGET", [key, field]) => c::HGet {
-                key: key.clone(),
-                field: field.clone(),
-            },
-            ("HSET", [key, field, value]) => c::HSet {
-                key: key.clone(),
-                field: field.clone(),
-                value: value.clone(),
-            },
-            ("HGETALL", [key]) => c::HGetAll(key.clone()),
-            ("HLEN", [key]) => c::HLen(key.clone()),
-            ("HEXISTS", [key, field]) => c::HExists {
-                key: key.clone(),
-                field: field.clone(),
-            },
-            ("SUBSCRIBE", channels) => c::Subscribe(channels.to_vec()),
-            ("PUBLISH", [channel, message]) => c::Publish(channel.clone(), message.clone()),
-            ("UNSUBSCRIBE", channels) => c::Unsubscribe(channels.to_vec()),
-            ("QUIT", []) => c::Quit,
-            _ => return Err(Error::generic("Invalid command", format!("{:?}", command))),
+            _ => spec,
         };
-        Ok(c)
+        if !spec.accepts(args.len()) {
+            return Err(Error::wrong_arity(spec.name));
+        }
+        Ok(spec)
+    }
+
+    fn accepts(&self, args: usize) -> bool {
+        if self.arity >= 0 {
+            args == self.arity as usize
+        } else {
+            args >= self.arity.unsigned_abs() as usize
+        }
+    }
+
+    /// The name without its container, e.g. `id` for `client|id`
+    fn short_name(&self) -> &'static str {
+        self.name.rsplit('|').next().unwrap_or(self.name)
     }
+
+    /// The key arguments of a request for this command, with their flags
+    pub fn keys_of<'a>(&self, args: &'a [Bytes]) -> Vec<(&'a Bytes, &'static [&'static str])> {
+        self.key_specs
+            .iter()
+            .flat_map(|spec| spec.positions(args.len()).map(|i| (&args[i], spec.flags)))
+            .collect()
+    }
+
+    /// The first key, last key and step that clients used before key specs,
+    /// derived from the key specs the same way redis does.
+    pub fn legacy_key_range(&self) -> (i32, i32, i32) {
+        match self.key_specs {
+            [] => (0, 0, 0),
+            [spec] if spec.last_key < 0 => (spec.index, spec.last_key, spec.step),
+            [spec] => (spec.index, spec.index + spec.last_key, spec.step),
+            specs => {
+                let first = specs.iter().map(|spec| spec.index).min().unwrap_or(0);
+                let last = specs
+                    .iter()
+                    .map(|spec| spec.index + spec.last_key)
+                    .max()
+                    .unwrap_or(0);
+                (first, last, 1)
+            }
+        }
+    }
+
+    /// Whether `COMMAND LIST FILTERBY <filter> <value>` includes this command
+    pub(crate) fn matches_filter(
+        &self,
+        filter: &str,
+        value: &str,
+        modules: &ModuleRegistry,
+    ) -> Result<bool> {
+        match filter.to_uppercase().as_str() {
+            "MODULE" => Ok(modules.module_of(self) == Some(value)),
+            "ACLCAT" => Ok(self
+                .acl_categories
+                .iter()
+                .any(|category| category.eq_ignore_ascii_case(value))),
+            "PATTERN" => Ok(glob::matches(value.as_bytes(), self.name.as_bytes(), true)),
+            _ => Err(Error::generic("syntax error", filter)),
+        }
+    }
+
+    /// The reply to `COMMAND INFO` for this command
+    pub fn info(&self) -> Value {
+        let (first_key, last_key, key_step) = self.legacy_key_range();
+        Value::Array(vec![
+            Value::from(self.name),
+            Value::Integer(self.arity as i64),
+            Value::Set(self.flags.iter().map(|f| Value::from(f.name())).collect()),
+            Value::Integer(first_key as i64),
+            Value::Integer(last_key as i64),
+            Value::Integer(key_step as i64),
+            Value::Set(
+                self.acl_categories
+                    .iter()
+                    .map(|c| Value::from(format!("@{}", c)))
+                    .collect(),
+            ),
+            // Tips
+            Value::Array(vec![]),
+            Value::Array(self.key_specs.iter().map(KeySpec::info).collect()),
+            Value::Array(self.subcommands.iter().map(CommandSpec::info).collect()),
+        ])
+    }
+
+    /// The reply to `COMMAND DOCS` for this command
+    pub fn docs_reply(&self) -> Value {
+        let mut docs = self.docs.to_value();
+        if !self.subcommands.is_empty() {
+            let subcommands = self
+                .subcommands
+                .iter()
+                .map(|spec| (spec.name.to_owned(), spec.docs_reply()))
+                .collect();
+            docs.insert("subcommands".to_owned(), Value::Map(subcommands));
+        }
+        Value::Map(docs)
+    }
+}
+
+fn find_in(specs: &'static [CommandSpec], name: &[u8]) -> Option<&'static CommandSpec> {
+    specs
+        .iter()
+        .find(|spec| spec.short_name().as_bytes().eq_ignore_ascii_case(name))
+}
+
+/// A request, resolved to the command that handles it
+#[derive(Debug)]
+pub(crate) struct Command {
+    pub spec: &'static CommandSpec,
+    /// Every argument, starting with the command's name. They're views
+    /// into the request, so that values are only copied once they're stored.
+    pub args: Request,
+}
+
+impl Command {
+    /// Parses a request, as read by [crate::parser::RequestParser]
+    pub fn parse(request: Request, modules: &ModuleRegistry) -> Result<Self> {
+        Ok(Command {
+            spec: CommandSpec::resolve(&request, modules)?,
+            args: request,
+        })
+    }
+
+    pub fn handler(&self) -> Handler {
+        self.spec
+            .handler
+            .expect("Containers are always resolved to one of their subcommands")
+    }
+
+    /// Whether this is the command with the given lowercase name
+    pub fn is(&self, name: &str) -> bool {
+        self.spec.name == name
+    }
+}
+
+/// Copies an argument into a String. Values are only validated here, once
+/// they're stored, so that big values are only looked at once.
+pub fn to_string(arg: &[u8]) -> Result<String> {
+    std::str::from_utf8(arg)
+        .map(str::to_owned)
+        .map_err(|it| Error::BadMessage(BadMessageError::Utf8(it)))
 }
 
-pub fn make_command_docs() -> std::collections::HashMap<String, Value> {
-    let mut map = std::collections::HashMap::new();
-    let mut set_map = std::collections::HashMap::new();
-    set_map.insert("summary".to_owned(), Value::from("Set a key to a value"));
-    map.insert("SET".to_string(), Value::Map(set_map));
-    let mut get_map = std::collections::HashMap::new();
-    get_map.insert("summary".to_owned(), Value::from("Get a value by key"));
-    map.insert("GET".to_string(), Value::Map(get_map));
-    map
+pub fn strings(args: &[Bytes]) -> Result<Vec<String>> {
+    args.iter().map(|arg| to_string(arg)).collect()
+}
+
+use CommandFlag::*;
+
+const RO_ACCESS: &[&str] = &["RO", "access"];
+const RW_UPDATE: &[&str] = &["RW", "update"];
+
+/// Every command the server knows. Flags, key specs, ACL categories
+/// and docs are the same as in redis, for the arguments we support.
+pub static COMMANDS: &[CommandSpec] = &[
+    CommandSpec::container(
+        "client",
+        &["slow"],
+        &[
+            CommandSpec::new("client|setinfo", 4, Connection::client_setinfo)
+                .flags(&[Noscript, Loading, Stale])
+                .acl(&["slow", "connection"])
+                .docs(
+                    "Sets information specific to the client or connection.",
+                    "7.2.0",
+                    "connection",
+                    "O(1)",
+                )
+                .args(&[Arg::oneof(
+                    "attr",
+                    &[
+                        Arg::string("libname").token("LIB-NAME"),
+                        Arg::string("libver").token("LIB-VER"),
+                    ],
+                )]),
+            CommandSpec::new("client|id", 2, Connection::client_id)
+                .flags(&[Noscript, Loading, Stale])
+                .acl(&["slow", "connection"])
+                .docs(
+                    "Returns the unique client ID of the connection.",
+                    "5.0.0",
+                    "connection",
+                    "O(1)",
+                ),
+            CommandSpec::new("client|tracking", -3, Connection::client_tracking)
+                .flags(&[Noscript, Loading, Stale])
+                .acl(&["slow", "connection"])
+                .docs(
+                    "Controls server-assisted client-side caching for the connection.",
+                    "6.0.0",
+                    "connection",
+                    "O(1). Some options may introduce additional complexity.",
+                )
+                .args(&[
+                    Arg::oneof(
+                        "status",
+                        &[Arg::pure_token("on", "ON"), Arg::pure_token("off", "OFF")],
+                    ),
+                    Arg::integer("client-id").token("REDIRECT").optional(),
+                    Arg::string("prefix")
+                        .token("PREFIX")
+                        .optional()
+                        .multiple()
+                        .multiple_token(),
+                    Arg::pure_token("bcast", "BCAST").optional(),
+                    Arg::pure_token("optin", "OPTIN").optional(),
+                    Arg::pure_token("optout", "OPTOUT").optional(),
+                    Arg::pure_token("noloop", "NOLOOP").optional(),
+                ]),
+            CommandSpec::new("client|caching", 3, Connection::client_caching)
+                .flags(&[Noscript, Loading, Stale])
+                .acl(&["slow", "connection"])
+                .docs(
+                    "Instructs the server whether to track the keys in the next request.",
+                    "6.0.0",
+                    "connection",
+                    "O(1)",
+                )
+                .args(&[Arg::oneof(
+                    "mode",
+                    &[Arg::pure_token("yes", "YES"), Arg::pure_token("no", "NO")],
+                )]),
+            CommandSpec::new("client|setname", 3, Connection::client_setname)
+                .flags(&[Noscript, Loading, Stale])
+                .acl(&["slow", "connection"])
+                .docs("Sets the connection name.", "2.6.9", "connection", "O(1)")
+                .args(&[Arg::string("connection-name")]),
+            CommandSpec::new("client|getname", 2, Connection::client_getname)
+                .flags(&[Noscript, Loading, Stale])
+                .acl(&["slow", "connection"])
+                .docs(
+                    "Returns the name of the connection.",
+                    "2.6.9",
+                    "connection",
+                    "O(1)",
+                ),
+            CommandSpec::new("client|getredir", 2, Connection::client_getredir)
+                .flags(&[Noscript, Loading, Stale])
+                .acl(&["slow", "connection"])
+                .docs(
+                    "Returns the client ID to which the connection's tracking notifications are redirected.",
+                    "6.0.0",
+                    "connection",
+                    "O(1)",
+                ),
+        ],
+    )
+    .docs(
+        "A container for client connection commands.",
+        "2.4.0",
+        "connection",
+        "Depends on subcommand.",
+    ),
+    CommandSpec::container(
+        "config",
+        &["slow"],
+        &[
+            CommandSpec::new("config|get", -3, Connection::config_get)
+                .flags(&[Admin, Noscript, Loading, Stale])
+                .acl(&["admin", "slow", "dangerous"])
+                .docs(
+                    "Returns the effective values of configuration parameters.",
+                    "2.0.0",
+                    "server",
+                    "O(N) when N is the number of configuration parameters provided",
+                )
+                .args(&[Arg::string("parameter").multiple()]),
+            CommandSpec::new("config|set", -4, Connection::config_set)
+                .flags(&[Admin, Noscript, Loading, Stale])
+                .acl(&["admin", "slow", "dangerous"])
+                .docs(
+                    "Sets configuration parameters in-flight.",
+                    "2.0.0",
+                    "server",
+                    "O(N) when N is the number of configuration parameters provided",
+                )
+                .args(&[Arg::block(
+                    "data",
+                    &[Arg::string("parameter"), Arg::string("value")],
+                )
+                .multiple()]),
+            CommandSpec::new("config|resetstat", 2, Connection::config_resetstat)
+                .flags(&[Admin, Noscript, Loading, Stale])
+                .acl(&["admin", "slow", "dangerous"])
+                .docs("Resets the server's statistics.", "2.0.0", "server", "O(1)"),
+            CommandSpec::new("config|rewrite", 2, Connection::config_rewrite)
+                .flags(&[Admin, Noscript, Loading, Stale])
+                .acl(&["admin", "slow", "dangerous"])
+                .docs(
+                    "Persists the effective configuration to file.",
+                    "2.8.0",
+                    "server",
+                    "O(1)",
+                ),
+        ],
+    )
+    .docs(
+        "A container for server configuration commands.",
+        "2.0.0",
+        "server",
+        "Depends on subcommand.",
+    ),
+    CommandSpec::new("hello", -1, Connection::hello)
+        .flags(&[Noscript, Loading, Stale, Fast, NoAuth])
+        .acl(&["fast", "connection"])
+        .docs("Handshakes with the Redis server.", "6.0.0", "connection", "O(1)")
+        .args(&[Arg::block(
+            "arguments",
+            &[
+                Arg::integer("protover"),
+                Arg::block(
+                    "auth",
+                    &[Arg::string("username"), Arg::string("password")],
+                )
+                .token("AUTH")
+                .optional(),
+                Arg::string("clientname").token("SETNAME").optional(),
+            ],
+        )
+        .optional()]),
+    CommandSpec::new("auth", -2, Connection::auth)
+        .flags(&[Noscript, Loading, Stale, Fast, NoAuth])
+        .acl(&["fast", "connection"])
+        .docs(
+            "Authenticates the connection.",
+            "1.0.0",
+            "connection",
+            "O(N) where N is the number of passwords defined for the user",
+        )
+        .args(&[Arg::string("username").optional(), Arg::string("password")]),
+    CommandSpec::new("command", -1, Connection::command)
+        .flags(&[Loading, Stale])
+        .acl(&["slow", "connection"])
+        .docs(
+            "Returns detailed information about all commands.",
+            "2.8.13",
+            "server",
+            "O(N) where N is the total number of Redis commands",
+        )
+        .subcommands(&[
+            CommandSpec::new("command|count", 2, Connection::command_count)
+                .flags(&[Loading, Stale])
+                .acl(&["slow", "connection"])
+                .docs("Returns a count of commands.", "2.8.13", "server", "O(1)"),
+            CommandSpec::new("command|info", -2, Connection::command_info)
+                .flags(&[Loading, Stale])
+                .acl(&["slow", "connection"])
+                .docs(
+                    "Returns information about one, multiple or all commands.",
+                    "2.8.13",
+                    "server",
+                    "O(N) where N is the number of commands to look up",
+                )
+                .args(&[Arg::string("command-name").optional().multiple()]),
+            CommandSpec::new("command|list", -2, Connection::command_list)
+                .flags(&[Loading, Stale])
+                .acl(&["slow", "connection"])
+                .docs(
+                    "Returns a list of command names.",
+                    "7.0.0",
+                    "server",
+                    "O(N) where N is the total number of Redis commands",
+                )
+                .args(&[Arg::oneof(
+                    "filterby",
+                    &[
+                        Arg::string("module-name").token("MODULE"),
+                        Arg::string("category").token("ACLCAT"),
+                        Arg::pattern("pattern").token("PATTERN"),
+                    ],
+                )
+                .token("FILTERBY")
+                .optional()]),
+            CommandSpec::new("command|getkeys", -3, Connection::command_getkeys)
+                .flags(&[Loading, Stale])
+                .acl(&["slow", "connection"])
+                .docs(
+                    "Extracts the key names from an arbitrary command.",
+                    "2.8.13",
+                    "server",
+                    "O(N) where N is the number of arguments to the command",
+                )
+                .args(&[
+                    Arg::string("command"),
+                    Arg::string("arg").optional().multiple(),
+                ]),
+            CommandSpec::new(
+                "command|getkeysandflags",
+                -3,
+                Connection::command_getkeysandflags,
+            )
+            .flags(&[Loading, Stale])
+            .acl(&["slow", "connection"])
+            .docs(
+                "Extracts the key names and access flags for an arbitrary command.",
+                "7.0.0",
+                "server",
+                "O(N) where N is the number of arguments to the command",
+            )
+            .args(&[
+                Arg::string("command"),
+                Arg::string("arg").optional().multiple(),
+            ]),
+            CommandSpec::new("command|docs", -2, Connection::command_docs)
+                .flags(&[Loading, Stale])
+                .acl(&["slow", "connection"])
+                .docs(
+                    "Returns documentary information about one, multiple or all commands.",
+                    "7.0.0",
+                    "server",
+                    "O(N) where N is the number of commands to look up",
+                )
+                .args(&[Arg::string("command-name").optional().multiple()]),
+        ]),
+    CommandSpec::new("flushall", -1, Connection::flushall)
+        .flags(&[Write])
+        .acl(&["keyspace", "write", "slow", "dangerous"])
+        .docs(
+            "Removes all keys from all databases.",
+            "1.0.0",
+            "server",
+            "O(N) where N is the total number of keys in all databases",
+        )
+        .args(&[Arg::oneof(
+            "flush-type",
+            &[
+                Arg::pure_token("async", "ASYNC"),
+                Arg::pure_token("sync", "SYNC"),
+            ],
+        )
+        .optional()]),
+    CommandSpec::new("ping", -1, Connection::ping)
+        .flags(&[Fast])
+        .acl(&["fast", "connection"])
+        .docs(
+            "Returns the server's liveliness response.",
+            "1.0.0",
+            "connection",
+            "O(1)",
+        )
+        .args(&[Arg::string("message").optional()]),
+    CommandSpec::new("set", 3, Connection::set)
+        .flags(&[Write, Denyoom])
+        .keys(&[KeySpec::single(1, &["OW", "update"])])
+        .acl(&["write", "string", "slow"])
+        .docs(
+            "Sets the string value of a key, ignoring its type. The key is created if it doesn't exist.",
+            "1.0.0",
+            "string",
+            "O(1)",
+        )
+        .args(&[Arg::key("key", 0), Arg::string("value")]),
+    CommandSpec::new("get", 2, Connection::get)
+        .flags(&[Readonly, Fast])
+        .keys(&[KeySpec::single(1, RO_ACCESS)])
+        .acl(&["read", "string", "fast"])
+        .docs("Returns the string value of a key.", "1.0.0", "string", "O(1)")
+        .args(&[Arg::key("key", 0)]),
+    CommandSpec::new("del", 2, Connection::del)
+        .flags(&[Write])
+        .keys(&[KeySpec::single(1, &["RM", "delete"])])
+        .acl(&["keyspace", "write", "slow"])
+        .docs(
+            "Deletes a key.",
+            "1.0.0",
+            "generic",
+            "O(1) for strings, O(M) for values with M elements.",
+        )
+        .args(&[Arg::key("key", 0)]),
+    CommandSpec::new("rename", 3, Connection::rename)
+        .flags(&[Write])
+        .keys(&[
+            KeySpec::single(1, &["RW", "access", "delete"]),
+            KeySpec::single(2, &["OW", "update"]),
+        ])
+        .acl(&["keyspace", "write", "slow"])
+        .docs(
+            "Renames a key and overwrites the destination.",
+            "1.0.0",
+            "generic",
+            "O(1)",
+        )
+        .args(&[Arg::key("key", 0), Arg::key("newkey", 1)]),
+    CommandSpec::new("exists", 2, Connection::exists)
+        .flags(&[Readonly, Fast])
+        .keys(&[KeySpec::single(1, &["RO"])])
+        .acl(&["keyspace", "read", "fast"])
+        .docs("Determines whether a key exists.", "1.0.0", "generic", "O(1)")
+        .args(&[Arg::key("key", 0)]),
+    CommandSpec::new("scan", -2, Connection::scan)
+        .flags(&[Readonly])
+        .acl(&["keyspace", "read", "slow"])
+        .docs(
+            "Iterates over the key names in the database.",
+            "2.8.0",
+            "generic",
+            "O(1) for every call. O(N) for a complete iteration, including enough command calls for the cursor to return back to 0. N is the number of elements inside the collection.",
+        )
+        .args(&[
+            Arg::integer("cursor"),
+            Arg::pattern("pattern").token("MATCH").optional(),
+            Arg::integer("count").token("COUNT").optional(),
+            Arg::string("type").token("TYPE").optional(),
+        ]),
+    CommandSpec::new("hget", 3, Connection::hget)
+        .flags(&[Readonly, Fast])
+        .keys(&[KeySpec::single(1, RO_ACCESS)])
+        .acl(&["read", "hash", "fast"])
+        .docs("Returns the value of a field in a hash.", "2.0.0", "hash", "O(1)")
+        .args(&[Arg::key("key", 0), Arg::string("field")]),
+    CommandSpec::new("hset", 4, Connection::hset)
+        .flags(&[Write, Denyoom, Fast])
+        .keys(&[KeySpec::single(1, RW_UPDATE)])
+        .acl(&["write", "hash", "fast"])
+        .docs(
+            "Creates or modifies the value of a field in a hash.",
+            "2.0.0",
+            "hash",
+            "O(1)",
+        )
+        .args(&[
+            Arg::key("key", 0),
+            Arg::string("field"),
+            Arg::string("value"),
+        ]),
+    CommandSpec::new("hgetall", 2, Connection::hgetall)
+        .flags(&[Readonly])
+        .keys(&[KeySpec::single(1, RO_ACCESS)])
+        .acl(&["read", "hash", "slow"])
+        .docs(
+            "Returns all fields and values in a hash.",
+            "2.0.0",
+            "hash",
+            "O(N) where N is the size of the hash.",
+        )
+        .args(&[Arg::key("key", 0)]),
+    CommandSpec::new("hlen", 2, Connection::hlen)
+        .flags(&[Readonly, Fast])
+        .keys(&[KeySpec::single(1, &["RO"])])
+        .acl(&["read", "hash", "fast"])
+        .docs("Returns the number of fields in a hash.", "2.0.0", "hash", "O(1)")
+        .args(&[Arg::key("key", 0)]),
+    CommandSpec::new("hexists", 3, Connection::hexists)
+        .flags(&[Readonly, Fast])
+        .keys(&[KeySpec::single(1, &["RO"])])
+        .acl(&["read", "hash", "fast"])
+        .docs(
+            "Determines whether a field exists in a hash.",
+            "2.0.0",
+            "hash",
+            "O(1)",
+        )
+        .args(&[Arg::key("key", 0), Arg::string("field")]),
+    CommandSpec::new("subscribe", -2, Connection::subscribe)
+        .flags(&[Pubsub, Noscript, Loading, Stale])
+        .acl(&["pubsub", "slow"])
+        .docs(
+            "Listens for messages published to channels.",
+            "2.0.0",
+            "pubsub",
+            "O(N) where N is the number of channels to subscribe to.",
+        )
+        .args(&[Arg::string("channel").multiple()]),
+    CommandSpec::new("publish", 3, Connection::publish)
+        .flags(&[Pubsub, Loading, Stale, Fast])
+        .acl(&["pubsub", "fast"])
+        .docs(
+            "Posts a message to a channel.",
+            "2.0.0",
+            "pubsub",
+            "O(N) where N is the number of clients subscribed to the receiving channel.",
+        )
+        .args(&[Arg::string("channel"), Arg::string("message")]),
+    CommandSpec::new("unsubscribe", -1, Connection::unsubscribe)
+        .flags(&[Pubsub, Noscript, Loading, Stale])
+        .acl(&["pubsub", "slow"])
+        .docs(
+            "Stops listening to messages posted to channels.",
+            "2.0.0",
+            "pubsub",
+            "O(N) where N is the number of channels to unsubscribe.",
+        )
+        .args(&[Arg::string("channel").optional().multiple()]),
+    CommandSpec::new("info", -1, Connection::info)
+        .flags(&[Loading, Stale])
+        .acl(&["slow", "dangerous"])
+        .docs(
+            "Returns information and statistics about the server.",
+            "1.0.0",
+            "server",
+            "O(1)",
+        )
+        .args(&[Arg::string("section").optional().multiple()]),
+    CommandSpec::container(
+        "memory",
+        &["slow"],
+        &[
+            CommandSpec::new("memory|usage", -3, Connection::memory_usage)
+                .flags(&[Readonly])
+                .keys(&[KeySpec::single(2, &["RO"])])
+                .acl(&["read", "slow"])
+                .docs(
+                    "Estimates the memory usage of a key.",
+                    "4.0.0",
+                    "server",
+                    "O(N) where N is the number of samples.",
+                )
+                .args(&[
+                    Arg::key("key", 0),
+                    Arg::integer("count").token("SAMPLES").optional(),
+                ]),
+            CommandSpec::new("memory|stats", 2, Connection::memory_stats)
+                .acl(&["slow"])
+                .docs("Returns details about memory usage.", "4.0.0", "server", "O(1)"),
+            CommandSpec::new("memory|doctor", 2, Connection::memory_doctor)
+                .acl(&["slow"])
+                .docs("Outputs a memory problems report.", "4.0.0", "server", "O(1)"),
+            CommandSpec::new("memory|malloc-stats", 2, Connection::memory_malloc_stats)
+                .acl(&["slow"])
+                .docs(
+                    "Returns the allocator statistics.",
+                    "4.0.0",
+                    "server",
+                    "Depends on how much memory is allocated, could be slow",
+                ),
+            CommandSpec::new("memory|help", 2, Connection::memory_help)
+                .flags(&[Loading, Stale])
+                .acl(&["slow"])
+                .docs(
+                    "Returns helpful text about the different subcommands.",
+                    "4.0.0",
+                    "server",
+                    "O(1)",
+                ),
+        ],
+    )
+    .docs(
+        "A container for memory diagnostics commands.",
+        "4.0.0",
+        "server",
+        "Depends on subcommand.",
+    ),
+    CommandSpec::container(
+        "object",
+        &["slow"],
+        &[
+            CommandSpec::new("object|encoding", 3, Connection::object_encoding)
+                .flags(&[Readonly])
+                .keys(&[KeySpec::single(2, &["RO"])])
+                .acl(&["keyspace", "read", "slow"])
+                .docs(
+                    "Returns the internal encoding of a Redis object.",
+                    "2.2.3",
+                    "generic",
+                    "O(1)",
+                )
+                .args(&[Arg::key("key", 0)]),
+            CommandSpec::new("object|freq", 3, Connection::object_freq)
+                .flags(&[Readonly])
+                .keys(&[KeySpec::single(2, &["RO"])])
+                .acl(&["keyspace", "read", "slow"])
+                .docs(
+                    "Returns the logarithmic access frequency counter of a Redis object.",
+                    "4.0.0",
+                    "generic",
+                    "O(1)",
+                )
+                .args(&[Arg::key("key", 0)]),
+            CommandSpec::new("object|idletime", 3, Connection::object_idletime)
+                .flags(&[Readonly])
+                .keys(&[KeySpec::single(2, &["RO"])])
+                .acl(&["keyspace", "read", "slow"])
+                .docs(
+                    "Returns the time since the last access to a Redis object.",
+                    "2.2.3",
+                    "generic",
+                    "O(1)",
+                )
+                .args(&[Arg::key("key", 0)]),
+            CommandSpec::new("object|refcount", 3, Connection::object_refcount)
+                .flags(&[Readonly])
+                .keys(&[KeySpec::single(2, &["RO"])])
+                .acl(&["keyspace", "read", "slow"])
+                .docs(
+                    "Returns the reference count of a value of a key.",
+                    "2.2.3",
+                    "generic",
+                    "O(1)",
+                )
+                .args(&[Arg::key("key", 0)]),
+            CommandSpec::new("object|help", 2, Connection::object_help)
+                .flags(&[Loading, Stale])
+                .acl(&["keyspace", "slow"])
+                .docs(
+                    "Returns helpful text about the different subcommands.",
+                    "6.2.0",
+                    "generic",
+                    "O(1)",
+                ),
+        ],
+    )
+    .docs(
+        "A container for object introspection commands.",
+        "2.2.3",
+        "generic",
+        "Depends on subcommand.",
+    ),
+    CommandSpec::container(
+        "module",
+        &["slow"],
+        &[
+            CommandSpec::new("module|list", 2, Connection::module_list)
+                .flags(&[Admin, Noscript])
+                .acl(&["admin", "slow", "dangerous"])
+                .docs(
+                    "Returns all loaded modules.",
+                    "4.0.0",
+                    "server",
+                    "O(N) where N is the number of loaded modules.",
+                ),
+            CommandSpec::new("module|load", -3, Connection::module_load)
+                .flags(&[Admin, Noscript])
+                .acl(&["admin", "slow", "dangerous"])
+                .docs("Loads a module.", "4.0.0", "server", "O(1)")
+                .args(&[
+                    Arg::string("path"),
+                    Arg::string("arg").optional().multiple(),
+                ]),
+            CommandSpec::new("module|unload", 3, Connection::module_unload)
+                .flags(&[Admin, Noscript])
+                .acl(&["admin", "slow", "dangerous"])
+                .docs("Unloads a module.", "4.0.0", "server", "O(1)")
+                .args(&[Arg::string("name")]),
+        ],
+    )
+    .docs(
+        "A container for module commands.",
+        "4.0.0",
+        "server",
+        "Depends on subcommand.",
+    ),
+    CommandSpec::new("quit", 1, Connection::quit)
+        .flags(&[Noscript, Loading, Stale, Fast, NoAuth])
+        .acl(&["fast", "connection"])
+        .docs("Closes the connection.", "1.0.0", "connection", "O(1)"),
+];
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn request(args: &[&[u8]]) -> Request {
+        args.iter().map(|arg| Bytes::copy_from_slice(arg)).collect()
+    }
+
+    fn parse(args: &[&[u8]]) -> Result<Command> {
+        Command::parse(request(args), &ModuleRegistry::default())
+    }
+
+    fn find(name: &[u8]) -> Option<&'static CommandSpec> {
+        CommandSpec::find(name, &ModuleRegistry::default())
+    }
+
+    #[test]
+    fn values_share_the_request_buffer() -> Result<()> {
+        let request = request(&[b"set", b"key", b"value"]);
+        let value_ptr = request[2].as_ptr();
+        let command = Command::parse(request, &ModuleRegistry::default())?;
+        assert!(command.is("set"));
+        assert_eq!(command.args[2].as_ptr(), value_ptr);
+        Ok(())
+    }
+
+    #[test]
+    fn resolves_subcommands_ignoring_case() -> Result<()> {
+        let command = parse(&[b"Client", b"GetRedir"])?;
+        assert!(command.is("client|getredir"));
+        let command = parse(&[b"command"])?;
+        assert!(command.is("command"));
+        Ok(())
+    }
+
+    #[test]
+    fn finds_keys_from_key_positions() {
+        let args = request(&[b"rename", b"a", b"b"]);
+        let rename = find(b"RENAME").unwrap();
+        let keys = rename.keys_of(&args);
+        assert_eq!(keys.len(), 2);
+        assert_eq!(
+            keys[0],
+            (&Bytes::from("a"), &["RW", "access", "delete"][..])
+        );
+        assert_eq!(keys[1], (&Bytes::from("b"), &["OW", "update"][..]));
+        assert_eq!(rename.legacy_key_range(), (1, 2, 1));
+        let args = request(&[b"publish", b"channel", b"message"]);
+        assert!(find(b"publish").unwrap().keys_of(&args).is_empty());
+    }
+
+    #[test]
+    fn finds_subcommands_by_full_name() {
+        let spec =
+            CommandSpec::find_by_full_name(b"CONFIG|get", &ModuleRegistry::default()).unwrap();
+        assert_eq!(spec.name, "config|get");
+        assert!(
+            CommandSpec::find_by_full_name(b"config|nope", &ModuleRegistry::default()).is_none()
+        );
+        assert!(find(b"config|get").is_none());
+    }
+
+    #[test]
+    fn filters_commands() -> Result<()> {
+        let modules = ModuleRegistry::default();
+        let get = find(b"get").unwrap();
+        assert!(get.matches_filter("aclcat", "string", &modules)?);
+        assert!(!get.matches_filter("ACLCAT", "hash", &modules)?);
+        assert!(get.matches_filter("pattern", "G*", &modules)?);
+        assert!(!get.matches_filter("module", "json", &modules)?);
+        assert!(get.matches_filter("nope", "a", &modules).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn every_command_is_documented() {
+        for spec in CommandSpec::all(&ModuleRegistry::default()) {
+            assert!(!spec.docs.summary.is_empty(), "{} has no docs", spec.name);
+        }
+    }
+
+    #[test]
+    fn subcommands_are_named_after_their_container() {
+        for spec in COMMANDS {
+            if spec.handler.is_none() {
+                assert!(spec.arity == -2 && !spec.subcommands.is_empty());
+            }
+            for subcommand in spec.subcommands {
+                assert!(subcommand.name.starts_with(&format!("{}|", spec.name)));
+                assert!(subcommand.handler.is_some());
+                assert!(subcommand.arity >= 2 || subcommand.arity <= -2);
+            }
+        }
+    }
+
+    #[test]
+    fn tells_unknown_commands_from_wrong_arity() {
+        let reply = |args: &[&[u8]]| parse(args).unwrap_err().reply();
+        assert_eq!(
+            reply(&[b"GET"]),
+            "ERR wrong number of arguments for 'get' command"
+        );
+        assert_eq!(
+            reply(&[b"client", b"Caching"]),
+            "ERR wrong number of arguments for 'client|caching' command"
+        );
+        assert_eq!(
+            reply(&[b"CLIENT", b"nope"]),
+            "ERR unknown subcommand 'nope'. Try CLIENT HELP."
+        );
+        assert_eq!(
+            reply(&[b"NOPE", b"a"]),
+            "ERR unknown command 'NOPE', with args beginning with: 'a' "
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_utf8() {
+        assert!(to_string(b"\xff").is_err());
+        assert!(matches!(
+            strings(&request(&[b"GET", b"\xff"])),
+            Err(Error::BadMessage(BadMessageError::Utf8(_)))
+        ));
+    }
 }
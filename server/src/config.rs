@@ -0,0 +1,1082 @@
+use std::{
+    fs,
+    path::PathBuf,
+    sync::{RwLock, RwLockReadGuard},
+    time::Duration,
+};
+
+use dkv_db::{EvictionPolicy, KeyspaceEvents, MaxMemory, DB};
+
+use crate::{
+    codec::ProtocolLimits,
+    config_file,
+    error::Error,
+    glob,
+    log::{self, LogLevel},
+};
+
+/// Limits applied to the output buffer of a single client,
+/// mirroring redis' `client-output-buffer-limit <class> <hard> <soft> <soft-seconds>`.
+///
+/// A client is disconnected as soon as its buffer grows past `hard_limit_bytes`,
+/// or when it stays above `soft_limit_bytes` for `soft_limit_seconds` or longer.
+/// A limit of 0 disables that check.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OutputBufferLimit {
+    pub hard_limit_bytes: usize,
+    pub soft_limit_bytes: usize,
+    pub soft_limit_seconds: u64,
+}
+
+impl OutputBufferLimit {
+    pub const fn new(
+        hard_limit_bytes: usize,
+        soft_limit_bytes: usize,
+        soft_limit_seconds: u64,
+    ) -> Self {
+        OutputBufferLimit {
+            hard_limit_bytes,
+            soft_limit_bytes,
+            soft_limit_seconds,
+        }
+    }
+}
+
+/// How the server drives client connections, as configured by `io-model`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IoModel {
+    /// A thread per client, blocking on its socket
+    Threads,
+    /// A single thread multiplexing every client's non blocking socket, like redis
+    Reactor,
+    /// A single thread accepting, receiving and sending through io_uring,
+    /// when built with the `io-uring` feature on Linux, and like `Reactor` otherwise
+    IoUring,
+}
+
+impl IoModel {
+    pub fn parse(s: &str) -> Option<IoModel> {
+        match s.to_lowercase().as_str() {
+            "threads" => Some(IoModel::Threads),
+            "reactor" => Some(IoModel::Reactor),
+            "io_uring" => Some(IoModel::IoUring),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            IoModel::Threads => "threads",
+            IoModel::Reactor => "reactor",
+            IoModel::IoUring => "io_uring",
+        }
+    }
+}
+
+/// Who may run a command that's dangerous to expose, like `MODULE`,
+/// as configured by e.g. `enable-module-command`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProtectedAction {
+    No,
+    Yes,
+    /// Only clients connecting from the loopback interface
+    Local,
+}
+
+impl ProtectedAction {
+    pub fn parse(s: &str) -> Option<ProtectedAction> {
+        match s.to_lowercase().as_str() {
+            "no" => Some(ProtectedAction::No),
+            "yes" => Some(ProtectedAction::Yes),
+            "local" => Some(ProtectedAction::Local),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            ProtectedAction::No => "no",
+            ProtectedAction::Yes => "yes",
+            ProtectedAction::Local => "local",
+        }
+    }
+
+    /// Whether a client is allowed to do the action
+    pub fn allows(&self, local_client: bool) -> bool {
+        match self {
+            ProtectedAction::No => false,
+            ProtectedAction::Yes => true,
+            ProtectedAction::Local => local_client,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub client_output_buffer_limit_normal: OutputBufferLimit,
+    pub client_output_buffer_limit_replica: OutputBufferLimit,
+    pub client_output_buffer_limit_pubsub: OutputBufferLimit,
+    pub proto_max_bulk_len: usize,
+    /// Not a redis config, which only caps the number of arguments at `i32::MAX`
+    pub proto_max_multibulk_len: usize,
+    /// Password of the `default` user. Clients don't need to authenticate if it's None.
+    pub requirepass: Option<String>,
+    pub port: u16,
+    /// Addresses to listen on. `*` means every IPv4 address and `::*` every IPv6 one.
+    /// Addresses starting with `-` are skipped if they're not available.
+    pub bind: Vec<String>,
+    /// There's a single keyspace for now, so this is only reported by `CONFIG GET`
+    pub databases: usize,
+    pub maxclients: usize,
+    /// Not a redis config
+    pub io_model: IoModel,
+    /// Threads that read requests and write replies with `io-model reactor`,
+    /// counting the one running commands
+    pub io_threads: usize,
+    /// Seconds a client can stay idle before it gets disconnected, 0 to never disconnect it
+    pub timeout: u64,
+    pub loglevel: LogLevel,
+    /// Empty to log to stdout
+    pub logfile: String,
+    /// The working directory, where files like `dbfilename` are saved
+    pub dir: PathBuf,
+    pub dbfilename: String,
+    /// Bytes the keyspace may use, 0 for no limit
+    pub maxmemory: usize,
+    pub maxmemory_policy: EvictionPolicy,
+    pub maxmemory_samples: usize,
+    /// Whether the keyspace keeps resizing its tables while the server is idle,
+    /// rather than only as keys are accessed
+    pub activerehashing: bool,
+    pub notify_keyspace_events: KeyspaceEvents,
+    /// Off by default like in redis, since a module can run any code in the server
+    pub enable_module_command: ProtectedAction,
+    /// Modules loaded at startup by `loadmodule <path> [args...]` directives,
+    /// as paths followed by the module's arguments
+    pub loadmodule: Vec<(String, Vec<String>)>,
+    /// The file the config was loaded from, if any
+    pub config_file: Option<PathBuf>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            client_output_buffer_limit_normal: OutputBufferLimit::new(0, 0, 0),
+            client_output_buffer_limit_replica: OutputBufferLimit::new(
+                256 * 1024 * 1024,
+                64 * 1024 * 1024,
+                60,
+            ),
+            client_output_buffer_limit_pubsub: OutputBufferLimit::new(
+                32 * 1024 * 1024,
+                8 * 1024 * 1024,
+                60,
+            ),
+            proto_max_bulk_len: ProtocolLimits::default().max_bulk_len,
+            proto_max_multibulk_len: ProtocolLimits::default().max_multibulk_len,
+            requirepass: None,
+            port: 6543,
+            bind: vec!["*".to_owned()],
+            databases: 16,
+            maxclients: 10000,
+            io_model: IoModel::Threads,
+            io_threads: 1,
+            timeout: 0,
+            loglevel: LogLevel::Notice,
+            logfile: String::new(),
+            dir: PathBuf::from("."),
+            dbfilename: "dump.rdb".to_owned(),
+            maxmemory: MaxMemory::default().limit,
+            maxmemory_policy: MaxMemory::default().policy,
+            maxmemory_samples: MaxMemory::default().samples,
+            activerehashing: true,
+            notify_keyspace_events: KeyspaceEvents::NONE,
+            enable_module_command: ProtectedAction::No,
+            loadmodule: vec![],
+            config_file: None,
+        }
+    }
+}
+
+impl Config {
+    /// Applies a directive from a config file or the command line, like `port 7000`.
+    /// Returns false if the directive isn't known, in which case nothing changes.
+    pub fn apply(&mut self, name: &str, args: &[String]) -> Result<bool, Error> {
+        // Not a param, since it can be repeated and `CONFIG GET` doesn't report it
+        if name.eq_ignore_ascii_case("loadmodule") {
+            let Some((path, args)) = args.split_first() else {
+                return Err(Error::generic("wrong number of arguments", name));
+            };
+            self.loadmodule.push((path.clone(), args.to_vec()));
+            return Ok(true);
+        }
+        let Some(param) = ConfigParam::find(name) else {
+            return Ok(false);
+        };
+        match args {
+            [value] => (param.set)(self, value)?,
+            _ if param.multi_arg && !args.is_empty() => (param.set)(self, &args.join(" "))?,
+            _ => return Err(Error::generic("wrong number of arguments", name)),
+        }
+        Ok(true)
+    }
+
+    /// How long a client can stay idle before it gets disconnected
+    pub fn idle_timeout(&self) -> Option<Duration> {
+        (self.timeout > 0).then(|| Duration::from_secs(self.timeout))
+    }
+
+    pub fn max_memory(&self) -> MaxMemory {
+        MaxMemory {
+            limit: self.maxmemory,
+            policy: self.maxmemory_policy,
+            samples: self.maxmemory_samples,
+        }
+    }
+
+    pub fn protocol_limits(&self) -> ProtocolLimits {
+        ProtocolLimits {
+            max_bulk_len: self.proto_max_bulk_len,
+            max_multibulk_len: self.proto_max_multibulk_len,
+        }
+    }
+
+    pub fn set_proto_max_bulk_len(&mut self, value: &str) -> Result<(), Error> {
+        let len = parse_memory(value)?;
+        // Same minimum as redis, so that clients can't be locked out by mistake
+        if len < 1024 * 1024 {
+            return Err(Error::generic(
+                "argument must be between 1048576 and 9223372036854775807 inclusive",
+                value,
+            ));
+        }
+        self.proto_max_bulk_len = len;
+        Ok(())
+    }
+
+    pub fn set_proto_max_multibulk_len(&mut self, value: &str) -> Result<(), Error> {
+        match value.parse::<usize>() {
+            Ok(len) if len > 0 => {
+                self.proto_max_multibulk_len = len;
+                Ok(())
+            }
+            _ => Err(Error::generic("argument must be a positive integer", value)),
+        }
+    }
+
+    /// Whether `username` and `password` are valid credentials. Only the
+    /// `default` user exists, and any password is valid if `requirepass` isn't set.
+    pub fn authenticate(&self, username: &str, password: &str) -> bool {
+        username == "default"
+            && self
+                .requirepass
+                .as_deref()
+                .is_none_or(|requirepass| requirepass == password)
+    }
+
+    /// Like in redis, setting an empty password removes it
+    pub fn set_requirepass(&mut self, value: &str) {
+        self.requirepass = (!value.is_empty()).then(|| value.to_owned());
+    }
+
+    /// Formats `client-output-buffer-limit` the way `CONFIG GET` reports it
+    pub fn client_output_buffer_limit(&self) -> String {
+        [
+            ("normal", self.client_output_buffer_limit_normal),
+            ("slave", self.client_output_buffer_limit_replica),
+            ("pubsub", self.client_output_buffer_limit_pubsub),
+        ]
+        .iter()
+        .map(|(class, limit)| {
+            format!(
+                "{} {} {} {}",
+                class, limit.hard_limit_bytes, limit.soft_limit_bytes, limit.soft_limit_seconds
+            )
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+    }
+
+    /// Parses the value of `client-output-buffer-limit`, which is a list of
+    /// `<class> <hard> <soft> <soft-seconds>` groups. The config is only
+    /// updated if every group is valid.
+    pub fn set_client_output_buffer_limit(&mut self, value: &str) -> Result<(), Error> {
+        let parts: Vec<&str> = value.split_whitespace().collect();
+        if parts.is_empty() || !parts.len().is_multiple_of(4) {
+            return Err(Error::generic(
+                "Wrong number of arguments in buffer limit configuration.",
+                value,
+            ));
+        }
+        let mut updates = vec![];
+        for group in parts.chunks(4) {
+            let limit = OutputBufferLimit::new(
+                parse_memory(group[1])?,
+                parse_memory(group[2])?,
+                group[3]
+                    .parse()
+                    .map_err(|_| Error::generic("Invalid soft limit seconds", group[3]))?,
+            );
+            let class = match group[0].to_lowercase().as_str() {
+                "normal" => ClientClass::Normal,
+                "slave" | "replica" => ClientClass::Replica,
+                "pubsub" => ClientClass::PubSub,
+                _ => {
+                    return Err(Error::generic(
+                        "Invalid client class specified in buffer limit configuration.",
+                        group[0],
+                    ))
+                }
+            };
+            updates.push((class, limit));
+        }
+        for (class, limit) in updates {
+            match class {
+                ClientClass::Normal => self.client_output_buffer_limit_normal = limit,
+                ClientClass::Replica => self.client_output_buffer_limit_replica = limit,
+                ClientClass::PubSub => self.client_output_buffer_limit_pubsub = limit,
+            }
+        }
+        Ok(())
+    }
+}
+
+enum ClientClass {
+    Normal,
+    Replica,
+    PubSub,
+}
+
+type Getter = fn(&Config) -> String;
+type Setter = fn(&mut Config, &str) -> Result<(), Error>;
+/// Makes a new value take effect outside of [Config], e.g. in the DB
+type Applier = fn(&Config, &DB) -> Result<(), Error>;
+
+/// A parameter that can be read with `CONFIG GET`, and set with
+/// `CONFIG SET` or in the config file
+pub struct ConfigParam {
+    pub name: &'static str,
+    /// Can only be set at startup, like `port`
+    pub immutable: bool,
+    /// Takes several arguments in the config file, like `bind 127.0.0.1 -::1`,
+    /// which `CONFIG SET` receives as a single space separated value
+    pub multi_arg: bool,
+    get: Getter,
+    set: Setter,
+    apply: Option<Applier>,
+}
+
+impl ConfigParam {
+    const fn new(name: &'static str, get: Getter, set: Setter) -> Self {
+        ConfigParam {
+            name,
+            immutable: false,
+            multi_arg: false,
+            get,
+            set,
+            apply: None,
+        }
+    }
+
+    const fn immutable(mut self) -> Self {
+        self.immutable = true;
+        self
+    }
+
+    const fn multi_arg(mut self) -> Self {
+        self.multi_arg = true;
+        self
+    }
+
+    const fn apply(mut self, apply: Applier) -> Self {
+        self.apply = Some(apply);
+        self
+    }
+
+    pub fn find(name: &str) -> Option<&'static ConfigParam> {
+        PARAMS
+            .iter()
+            .find(|param| param.name.eq_ignore_ascii_case(name))
+    }
+
+    /// The lines `CONFIG REWRITE` writes for the param's current value
+    fn lines(&self, config: &Config) -> Vec<String> {
+        let value = (self.get)(config);
+        if self.name == "client-output-buffer-limit" {
+            // One line per client class, like in redis.conf
+            let words: Vec<&str> = value.split_whitespace().collect();
+            return words
+                .chunks(4)
+                .map(|class| format!("{} {}", self.name, class.join(" ")))
+                .collect();
+        }
+        if self.multi_arg {
+            return vec![format!("{} {}", self.name, value)];
+        }
+        vec![format!("{} {}", self.name, config_file::quote(&value))]
+    }
+}
+
+/// Every parameter the server knows about
+pub static PARAMS: &[ConfigParam] = &[
+    ConfigParam::new(
+        "port",
+        |config| config.port.to_string(),
+        |config, value| {
+            config.port = parse_int(value, 0, u16::MAX as i64)? as u16;
+            Ok(())
+        },
+    )
+    .immutable(),
+    ConfigParam::new(
+        "bind",
+        |config| config.bind.join(" "),
+        |config, value| {
+            if value.trim().is_empty() {
+                return Err(Error::generic("bind requires at least one address", value));
+            }
+            config.bind = value.split_whitespace().map(str::to_owned).collect();
+            Ok(())
+        },
+    )
+    .immutable()
+    .multi_arg(),
+    ConfigParam::new(
+        "databases",
+        |config| config.databases.to_string(),
+        |config, value| {
+            config.databases = parse_int(value, 1, i32::MAX as i64)? as usize;
+            Ok(())
+        },
+    )
+    .immutable(),
+    ConfigParam::new(
+        "maxclients",
+        |config| config.maxclients.to_string(),
+        |config, value| {
+            config.maxclients = parse_int(value, 1, u32::MAX as i64)? as usize;
+            Ok(())
+        },
+    ),
+    ConfigParam::new(
+        "io-model",
+        |config| config.io_model.name().to_owned(),
+        |config, value| {
+            config.io_model = IoModel::parse(value).ok_or_else(|| {
+                Error::generic(
+                    "argument(s) must be one of the following: threads, reactor, io_uring",
+                    value,
+                )
+            })?;
+            Ok(())
+        },
+    )
+    .immutable(),
+    ConfigParam::new(
+        "io-threads",
+        |config| config.io_threads.to_string(),
+        |config, value| {
+            config.io_threads = parse_int(value, 1, 128)? as usize;
+            Ok(())
+        },
+    )
+    .immutable(),
+    ConfigParam::new(
+        "timeout",
+        |config| config.timeout.to_string(),
+        |config, value| {
+            config.timeout = parse_int(value, 0, i32::MAX as i64)? as u64;
+            Ok(())
+        },
+    ),
+    ConfigParam::new(
+        "loglevel",
+        |config| config.loglevel.name().to_owned(),
+        |config, value| {
+            config.loglevel = LogLevel::parse(value)
+                .ok_or_else(|| Error::generic("argument(s) must be one of the following: debug, verbose, notice, warning, nothing", value))?;
+            Ok(())
+        },
+    )
+    .apply(|config, _| {
+        log::set_level(config.loglevel);
+        Ok(())
+    }),
+    ConfigParam::new(
+        "logfile",
+        |config| config.logfile.clone(),
+        |config, value| {
+            config.logfile = value.to_owned();
+            Ok(())
+        },
+    )
+    .immutable(),
+    ConfigParam::new(
+        "dir",
+        |config| config.dir.display().to_string(),
+        |config, value| {
+            config.dir = PathBuf::from(value);
+            Ok(())
+        },
+    )
+    .apply(|config, _| {
+        std::env::set_current_dir(&config.dir)
+            .map_err(|e| Error::generic(e.to_string().replace(['\r', '\n'], " "), ""))
+    }),
+    ConfigParam::new(
+        "dbfilename",
+        |config| config.dbfilename.clone(),
+        |config, value| {
+            if value.contains('/') {
+                return Err(Error::generic(
+                    "dbfilename can't be a path, just a filename",
+                    value,
+                ));
+            }
+            config.dbfilename = value.to_owned();
+            Ok(())
+        },
+    ),
+    ConfigParam::new(
+        "requirepass",
+        |config| config.requirepass.clone().unwrap_or_default(),
+        |config, value| {
+            config.set_requirepass(value);
+            Ok(())
+        },
+    ),
+    ConfigParam::new(
+        "maxmemory",
+        |config| config.maxmemory.to_string(),
+        |config, value| {
+            config.maxmemory = parse_memory(value)?;
+            Ok(())
+        },
+    )
+    .apply(apply_max_memory),
+    ConfigParam::new(
+        "maxmemory-policy",
+        |config| config.maxmemory_policy.name().to_owned(),
+        |config, value| {
+            config.maxmemory_policy = EvictionPolicy::parse(value).ok_or_else(|| {
+                Error::generic(
+                    format!(
+                        "argument(s) must be one of the following: {}",
+                        EvictionPolicy::names()
+                    ),
+                    value,
+                )
+            })?;
+            Ok(())
+        },
+    )
+    .apply(apply_max_memory),
+    ConfigParam::new(
+        "maxmemory-samples",
+        |config| config.maxmemory_samples.to_string(),
+        |config, value| {
+            config.maxmemory_samples = parse_int(value, 1, 64)? as usize;
+            Ok(())
+        },
+    )
+    .apply(apply_max_memory),
+    ConfigParam::new(
+        "activerehashing",
+        |config| yes_no(config.activerehashing),
+        |config, value| {
+            config.activerehashing = parse_bool(value)?;
+            Ok(())
+        },
+    ),
+    ConfigParam::new(
+        "notify-keyspace-events",
+        |config| config.notify_keyspace_events.to_string(),
+        |config, value| {
+            config.notify_keyspace_events = KeyspaceEvents::parse(value).ok_or_else(|| {
+                Error::generic("Invalid event class character. Use 'Ag$lshzxeKEtmn'.", value)
+            })?;
+            Ok(())
+        },
+    )
+    .apply(|config, db| {
+        db.set_keyspace_events(config.notify_keyspace_events);
+        Ok(())
+    }),
+    ConfigParam::new(
+        "enable-module-command",
+        |config| config.enable_module_command.name().to_owned(),
+        |config, value| {
+            config.enable_module_command = ProtectedAction::parse(value).ok_or_else(|| {
+                Error::generic(
+                    "argument(s) must be one of the following: no, yes, local",
+                    value,
+                )
+            })?;
+            Ok(())
+        },
+    )
+    .immutable(),
+    ConfigParam::new(
+        "client-output-buffer-limit",
+        |config| config.client_output_buffer_limit(),
+        |config, value| config.set_client_output_buffer_limit(value),
+    )
+    .multi_arg(),
+    ConfigParam::new(
+        "proto-max-bulk-len",
+        |config| config.proto_max_bulk_len.to_string(),
+        |config, value| config.set_proto_max_bulk_len(value),
+    ),
+    ConfigParam::new(
+        "proto-max-multibulk-len",
+        |config| config.proto_max_multibulk_len.to_string(),
+        |config, value| config.set_proto_max_multibulk_len(value),
+    ),
+];
+
+fn apply_max_memory(config: &Config, db: &DB) -> Result<(), Error> {
+    db.set_maxmemory(config.max_memory());
+    Ok(())
+}
+
+/// The server's config, shared by every connection
+pub struct ConfigRegistry {
+    config: RwLock<Config>,
+}
+
+impl ConfigRegistry {
+    pub fn new(config: Config) -> Self {
+        ConfigRegistry {
+            config: RwLock::new(config),
+        }
+    }
+
+    pub fn read(&self) -> RwLockReadGuard<'_, Config> {
+        self.config.read().unwrap()
+    }
+
+    /// The values of every param matching one of the glob `patterns`, like `CONFIG GET`
+    pub fn get(&self, patterns: &[String]) -> Vec<(&'static str, String)> {
+        let config = self.read();
+        PARAMS
+            .iter()
+            .filter(|param| {
+                patterns
+                    .iter()
+                    .any(|pattern| glob::matches(pattern.as_bytes(), param.name.as_bytes(), true))
+            })
+            .map(|param| (param.name, (param.get)(&config)))
+            .collect()
+    }
+
+    /// Sets every `(name, value)` pair, like `CONFIG SET`. Either all of them
+    /// are set, or none of them is if any value is invalid or can't be applied.
+    pub fn set(&self, pairs: &[(String, String)], db: &DB) -> Result<(), Error> {
+        let mut params: Vec<(&ConfigParam, &str)> = vec![];
+        for (name, value) in pairs {
+            let param = ConfigParam::find(name).ok_or_else(|| {
+                Error::generic(
+                    format!(
+                        "Unknown option or number of arguments for CONFIG SET - '{}'",
+                        name.replace(['\r', '\n'], " ")
+                    ),
+                    name.as_str(),
+                )
+            })?;
+            if params.iter().any(|(it, _)| it.name == param.name) {
+                return Err(set_failed(param, "duplicate parameter"));
+            }
+            if param.immutable {
+                return Err(set_failed(param, "can't set immutable config"));
+            }
+            params.push((param, value));
+        }
+
+        let mut config = self.config.write().unwrap();
+        let previous = config.clone();
+        for (param, value) in &params {
+            if let Err(e) = (param.set)(&mut config, value) {
+                *config = previous;
+                return Err(set_failed(param, &e.message()));
+            }
+        }
+        for (param, _) in &params {
+            if let Some(apply) = param.apply {
+                if let Err(e) = apply(&config, db) {
+                    // Like redis, values that were already applied are applied again
+                    *config = previous;
+                    for (param, _) in &params {
+                        if let Some(apply) = param.apply {
+                            let _ = apply(&config, db);
+                        }
+                    }
+                    return Err(set_failed(param, &e.message()));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes the current config back to the file it was loaded from, like
+    /// `CONFIG REWRITE`. Comments and unknown directives are kept, known
+    /// directives are updated in place, and params that aren't in the file yet
+    /// are appended if they differ from their default value.
+    ///
+    /// Files included by the config file aren't changed.
+    pub fn rewrite(&self) -> Result<(), Error> {
+        let config = self.read();
+        let path = config
+            .config_file
+            .as_ref()
+            .ok_or_else(|| Error::generic("The server is running without a config file", ""))?;
+        let io_error = |e: std::io::Error| {
+            Error::generic(
+                format!("Rewriting config file: {}", e).replace(['\r', '\n'], " "),
+                "",
+            )
+        };
+        let contents = fs::read_to_string(path).map_err(io_error)?;
+        let rewritten = rewrite_lines(&contents, &config);
+        // Writing to a temporary file first means a failure can't leave a truncated config behind
+        let tmp = path.with_extension(format!("tmp-{}", std::process::id()));
+        fs::write(&tmp, rewritten)
+            .and_then(|_| fs::rename(&tmp, path))
+            .map_err(|e| {
+                let _ = fs::remove_file(&tmp);
+                io_error(e)
+            })
+    }
+}
+
+fn set_failed(param: &ConfigParam, message: &str) -> Error {
+    Error::generic(
+        format!(
+            "CONFIG SET failed (possibly related to argument '{}') - {}",
+            param.name, message
+        ),
+        "",
+    )
+}
+
+/// Replaces the lines of known params in `contents` with their value in `config`
+fn rewrite_lines(contents: &str, config: &Config) -> String {
+    let defaults = Config::default();
+    // Lines still to be written for each param, in the order they were found
+    let mut pending: Vec<(&ConfigParam, Vec<String>)> = PARAMS
+        .iter()
+        .map(|param| (param, param.lines(config)))
+        .collect();
+    let mut seen = vec![false; PARAMS.len()];
+    let mut output = String::new();
+    for line in contents.lines() {
+        let name = config_file::split_args(line.trim())
+            .ok()
+            .and_then(|words| words.into_iter().next());
+        let index = name.and_then(|name| {
+            PARAMS
+                .iter()
+                .position(|param| param.name.eq_ignore_ascii_case(&name))
+        });
+        match index {
+            Some(index) => {
+                seen[index] = true;
+                let lines = &mut pending[index].1;
+                // Extra lines of a param, e.g. a repeated `port`, are dropped
+                if !lines.is_empty() {
+                    output.push_str(&lines.remove(0));
+                    output.push('\n');
+                }
+            }
+            None => {
+                output.push_str(line);
+                output.push('\n');
+            }
+        }
+    }
+    let mut appended = false;
+    for (index, (param, lines)) in pending.into_iter().enumerate() {
+        let is_default = (param.get)(config) == (param.get)(&defaults);
+        if lines.is_empty() || (!seen[index] && is_default) {
+            continue;
+        }
+        if !appended {
+            output.push_str("# Generated by CONFIG REWRITE\n");
+            appended = true;
+        }
+        for line in lines {
+            output.push_str(&line);
+            output.push('\n');
+        }
+    }
+    output
+}
+
+/// Parses an integer the way redis does for numeric params
+fn parse_int(value: &str, min: i64, max: i64) -> Result<i64, Error> {
+    let n: i64 = value
+        .parse()
+        .map_err(|_| Error::generic("argument couldn't be parsed into an integer", value))?;
+    if n < min || n > max {
+        return Err(Error::generic(
+            format!("argument must be between {} and {} inclusive", min, max),
+            value,
+        ));
+    }
+    Ok(n)
+}
+
+/// Parses a `yes` or `no` param
+fn parse_bool(value: &str) -> Result<bool, Error> {
+    match value.to_lowercase().as_str() {
+        "yes" => Ok(true),
+        "no" => Ok(false),
+        _ => Err(Error::generic("argument must be 'yes' or 'no'", value)),
+    }
+}
+
+fn yes_no(value: bool) -> String {
+    if value { "yes" } else { "no" }.to_owned()
+}
+
+/// Parses a memory amount like `1024`, `8mb` or `1gb` into bytes.
+/// Units are case insensitive, and as in redis, `k`/`m`/`g` are
+/// powers of 1000 while `kb`/`mb`/`gb` are powers of 1024.
+pub fn parse_memory(s: &str) -> Result<usize, Error> {
+    let lower = s.to_lowercase();
+    let split_at = lower
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(lower.len());
+    let (digits, unit) = lower.split_at(split_at);
+    let multiplier: usize = match unit {
+        "" | "b" => 1,
+        "k" => 1000,
+        "kb" => 1024,
+        "m" => 1000 * 1000,
+        "mb" => 1024 * 1024,
+        "g" => 1000 * 1000 * 1000,
+        "gb" => 1024 * 1024 * 1024,
+        _ => return Err(Error::generic("Invalid memory value", s)),
+    };
+    digits
+        .parse::<usize>()
+        .ok()
+        .and_then(|n| n.checked_mul(multiplier))
+        .ok_or_else(|| Error::generic("Invalid memory value", s))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parses_memory_units() {
+        assert_eq!(parse_memory("100").unwrap(), 100);
+        assert_eq!(parse_memory("1k").unwrap(), 1000);
+        assert_eq!(parse_memory("1kb").unwrap(), 1024);
+        assert_eq!(parse_memory("8MB").unwrap(), 8 * 1024 * 1024);
+        assert!(parse_memory("8xb").is_err());
+        assert!(parse_memory("mb").is_err());
+    }
+
+    #[test]
+    fn sets_client_output_buffer_limit() {
+        let mut config = Config::default();
+        config
+            .set_client_output_buffer_limit("pubsub 1mb 512kb 10")
+            .unwrap();
+        assert_eq!(
+            config.client_output_buffer_limit_pubsub,
+            OutputBufferLimit::new(1024 * 1024, 512 * 1024, 10)
+        );
+        assert!(config
+            .set_client_output_buffer_limit("pubsub 1mb 512kb")
+            .is_err());
+        assert!(config
+            .set_client_output_buffer_limit("normal 0 0 0 unknown 1 1 1")
+            .is_err());
+        assert_eq!(
+            config.client_output_buffer_limit_normal,
+            OutputBufferLimit::new(0, 0, 0)
+        );
+    }
+
+    #[test]
+    fn authenticates_the_default_user() {
+        let mut config = Config::default();
+        assert!(config.authenticate("default", "anything"));
+        assert!(!config.authenticate("someone", "anything"));
+        config.set_requirepass("secret");
+        assert!(config.authenticate("default", "secret"));
+        assert!(!config.authenticate("default", "anything"));
+        config.set_requirepass("");
+        assert!(config.authenticate("default", "anything"));
+    }
+
+    #[test]
+    fn applies_directives() {
+        let mut config = Config::default();
+        let args = |args: &[&str]| args.iter().map(|it| it.to_string()).collect::<Vec<_>>();
+        assert!(config.apply("PORT", &args(&["7000"])).unwrap());
+        assert!(config.apply("bind", &args(&["127.0.0.1", "-::1"])).unwrap());
+        assert!(config.apply("loglevel", &args(&["warning"])).unwrap());
+        assert!(config.apply("io-model", &args(&["Reactor"])).unwrap());
+        assert!(config.apply("io-threads", &args(&["4"])).unwrap());
+        assert!(config.apply("activerehashing", &args(&["No"])).unwrap());
+        assert!(config.apply("maxmemory", &args(&["100mb"])).unwrap());
+        assert!(config
+            .apply("maxmemory-policy", &args(&["allkeys-LFU"]))
+            .unwrap());
+        assert!(config
+            .apply(
+                "client-output-buffer-limit",
+                &args(&["pubsub", "1mb", "0", "0"])
+            )
+            .unwrap());
+        assert_eq!(config.port, 7000);
+        assert_eq!(config.bind, args(&["127.0.0.1", "-::1"]));
+        assert_eq!(config.loglevel, LogLevel::Warning);
+        assert_eq!(config.io_model, IoModel::Reactor);
+        assert_eq!(config.io_threads, 4);
+        assert!(!config.activerehashing);
+        assert_eq!(
+            config.max_memory(),
+            MaxMemory {
+                limit: 100 * 1024 * 1024,
+                policy: EvictionPolicy::AllKeysLfu,
+                samples: 5,
+            }
+        );
+        assert_eq!(
+            config.client_output_buffer_limit_pubsub,
+            OutputBufferLimit::new(1024 * 1024, 0, 0)
+        );
+        assert!(config
+            .apply("enable-module-command", &args(&["LOCAL"]))
+            .unwrap());
+        assert!(config
+            .apply("loadmodule", &args(&["/modules/a.so", "x", "1"]))
+            .unwrap());
+        assert!(config
+            .apply("loadmodule", &args(&["/modules/b.so"]))
+            .unwrap());
+        assert_eq!(config.enable_module_command, ProtectedAction::Local);
+        assert_eq!(
+            config.loadmodule,
+            vec![
+                ("/modules/a.so".to_owned(), args(&["x", "1"])),
+                ("/modules/b.so".to_owned(), vec![]),
+            ]
+        );
+        assert!(!config.apply("appendonly", &args(&["no"])).unwrap());
+        assert!(config.apply("loadmodule", &[]).is_err());
+        assert!(config
+            .apply("enable-module-command", &args(&["always"]))
+            .is_err());
+        assert!(config.apply("port", &args(&["70000"])).is_err());
+        assert!(config.apply("port", &args(&["1", "2"])).is_err());
+        assert!(config.apply("maxclients", &args(&["0"])).is_err());
+        assert!(config.apply("dbfilename", &args(&["a/dump.rdb"])).is_err());
+        assert!(config.apply("io-model", &args(&["fibers"])).is_err());
+        assert!(config.apply("io-model", &args(&["io_uring"])).unwrap());
+        assert_eq!(config.io_model, IoModel::IoUring);
+        assert!(config.apply("io-threads", &args(&["0"])).is_err());
+        assert!(config.apply("activerehashing", &args(&["maybe"])).is_err());
+        assert!(config.apply("maxmemory-policy", &args(&["lru"])).is_err());
+        assert!(config.apply("maxmemory-samples", &args(&["0"])).is_err());
+        assert_eq!(config.port, 7000);
+    }
+
+    #[test]
+    fn gets_params_matching_patterns() {
+        let registry = ConfigRegistry::new(Config::default());
+        let names = |patterns: &[&str]| {
+            let patterns: Vec<String> = patterns.iter().map(|it| it.to_string()).collect();
+            let mut names: Vec<&str> = registry
+                .get(&patterns)
+                .into_iter()
+                .map(|(name, _)| name)
+                .collect();
+            names.sort();
+            names
+        };
+        assert_eq!(
+            names(&["proto-*"]),
+            ["proto-max-bulk-len", "proto-max-multibulk-len"]
+        );
+        assert_eq!(names(&["PORT", "maxc*"]), ["maxclients", "port"]);
+        assert!(names(&["nope"]).is_empty());
+        assert_eq!(
+            registry.get(&["timeout".to_owned()]),
+            [("timeout", "0".to_owned())]
+        );
+    }
+
+    #[test]
+    fn sets_every_param_or_none() {
+        let registry = ConfigRegistry::new(Config::default());
+        let db = DB::new();
+        let pairs = |pairs: &[(&str, &str)]| {
+            pairs
+                .iter()
+                .map(|(name, value)| (name.to_string(), value.to_string()))
+                .collect::<Vec<_>>()
+        };
+        registry
+            .set(
+                &pairs(&[("timeout", "30"), ("notify-keyspace-events", "KEA")]),
+                &db,
+            )
+            .unwrap();
+        assert_eq!(registry.read().timeout, 30);
+        assert_eq!(db.keyspace_events().to_string(), "AKE");
+
+        let e = registry
+            .set(&pairs(&[("timeout", "10"), ("maxclients", "-1")]), &db)
+            .unwrap_err();
+        assert_eq!(e.reply(), "ERR CONFIG SET failed (possibly related to argument 'maxclients') - argument must be between 1 and 4294967295 inclusive");
+        assert_eq!(registry.read().timeout, 30);
+
+        assert!(registry.set(&pairs(&[("port", "7000")]), &db).is_err());
+        assert!(registry
+            .set(&pairs(&[("timeout", "1"), ("TIMEOUT", "2")]), &db)
+            .is_err());
+        assert!(registry.set(&pairs(&[("nope", "1")]), &db).is_err());
+        assert_eq!(registry.read().timeout, 30);
+    }
+
+    #[test]
+    fn rewrites_known_params_in_place() {
+        let config = Config {
+            port: 7000,
+            timeout: 30,
+            requirepass: Some("a secret".to_owned()),
+            ..Config::default()
+        };
+        let contents = "# The port\nport 6543\n\nappendonly no\nport 6544\ntimeout 0 # bad\n";
+        assert_eq!(
+            rewrite_lines(contents, &config),
+            "# The port\nport 7000\n\nappendonly no\ntimeout 30\n\
+             # Generated by CONFIG REWRITE\nrequirepass \"a secret\"\n"
+        );
+        // A rewritten file is left as is
+        let contents = rewrite_lines(contents, &config);
+        assert_eq!(rewrite_lines(&contents, &config), contents);
+    }
+
+    #[test]
+    fn sets_protocol_limits() {
+        let mut config = Config::default();
+        config.set_proto_max_bulk_len("2mb").unwrap();
+        config.set_proto_max_multibulk_len("100").unwrap();
+        assert_eq!(
+            config.protocol_limits(),
+            ProtocolLimits {
+                max_bulk_len: 2 * 1024 * 1024,
+                max_multibulk_len: 100,
+            }
+        );
+        assert!(config.set_proto_max_bulk_len("1k").is_err());
+        assert!(config.set_proto_max_multibulk_len("0").is_err());
+        assert_eq!(config.protocol_limits().max_multibulk_len, 100);
+    }
+}
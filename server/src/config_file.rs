@@ -0,0 +1,316 @@
+//! Loads the config from a redis.conf style file and the command line
+
+use std::{
+    fmt::Display,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use crate::{codec, config::Config};
+
+/// Why the config couldn't be loaded, reported the same way redis does
+#[derive(Debug)]
+pub struct ConfigError {
+    /// Where the offending line comes from, None for the command line
+    pub file: Option<PathBuf>,
+    /// 1 based, 0 if the error isn't about a specific line
+    pub line: usize,
+    pub text: String,
+    pub message: String,
+}
+
+impl ConfigError {
+    fn new(file: Option<&Path>, line: usize, text: &str, message: impl Into<String>) -> Self {
+        ConfigError {
+            file: file.map(Path::to_path_buf),
+            line,
+            text: text.to_owned(),
+            message: message.into(),
+        }
+    }
+}
+
+impl Display for ConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "*** FATAL CONFIG FILE ERROR ***")?;
+        match &self.file {
+            Some(file) if self.line > 0 => {
+                writeln!(f, "Reading {}, at line {}", file.display(), self.line)?
+            }
+            Some(file) => writeln!(f, "Reading {}", file.display())?,
+            None => writeln!(f, "Reading the command line")?,
+        }
+        if !self.text.is_empty() {
+            writeln!(f, ">>> '{}'", self.text)?;
+        }
+        write!(f, "{}", self.message)
+    }
+}
+
+/// What the server was started with, e.g. `dkv /etc/dkv.conf --port 7000`
+#[derive(Debug, Default, PartialEq)]
+pub struct CommandLine {
+    /// The first positional argument, or the value of `--config`
+    pub config_file: Option<PathBuf>,
+    /// Directives given as `--name arg...`, which override the config file
+    pub overrides: Vec<Vec<String>>,
+}
+
+impl CommandLine {
+    pub fn parse(args: impl IntoIterator<Item = String>) -> Result<CommandLine, String> {
+        let mut command_line = CommandLine::default();
+        let mut args = args.into_iter().peekable();
+        while let Some(arg) = args.next() {
+            let Some(name) = arg.strip_prefix("--") else {
+                if command_line.config_file.is_some() || !command_line.overrides.is_empty() {
+                    return Err(format!(
+                        "Unexpected argument '{}'. Options start with --",
+                        arg
+                    ));
+                }
+                command_line.config_file = Some(PathBuf::from(arg));
+                continue;
+            };
+            let mut values = vec![];
+            while let Some(value) = args.next_if(|it| !it.starts_with("--")) {
+                values.push(value);
+            }
+            if name == "config" {
+                match values.as_slice() {
+                    [path] if command_line.config_file.is_none() => {
+                        command_line.config_file = Some(PathBuf::from(path))
+                    }
+                    [_] => return Err("The config file can only be given once".to_owned()),
+                    _ => return Err("--config takes the path of a config file".to_owned()),
+                }
+            } else {
+                let mut directive = vec![name.to_owned()];
+                directive.extend(values);
+                command_line.overrides.push(directive);
+            }
+        }
+        Ok(command_line)
+    }
+
+    /// Builds the config from the default one, the config file and then the overrides
+    pub fn load(&self) -> Result<Config, ConfigError> {
+        let mut config = Config::default();
+        if let Some(path) = &self.config_file {
+            let mut loader = Loader {
+                config: &mut config,
+                includes: vec![],
+            };
+            loader.load_file(path)?;
+            let path = fs::canonicalize(path)
+                .map_err(|e| ConfigError::new(Some(path), 0, "", e.to_string()))?;
+            config.config_file = Some(path);
+        }
+        for directive in &self.overrides {
+            let text = directive.join(" ");
+            apply(&mut config, &directive[0], &directive[1..])
+                .map_err(|message| ConfigError::new(None, 0, &text, message))?;
+        }
+        Ok(config)
+    }
+}
+
+struct Loader<'a> {
+    config: &'a mut Config,
+    /// Files being loaded, to catch files that include themselves
+    includes: Vec<PathBuf>,
+}
+
+impl Loader<'_> {
+    fn load_file(&mut self, path: &Path) -> Result<(), ConfigError> {
+        let error =
+            |line, text: &str, message: String| ConfigError::new(Some(path), line, text, message);
+        let canonical = fs::canonicalize(path)
+            .map_err(|e| error(0, "", format!("Can't open config file: {}", e)))?;
+        if self.includes.contains(&canonical) {
+            return Err(error(0, "", "The config file includes itself".to_owned()));
+        }
+        let contents = fs::read_to_string(path)
+            .map_err(|e| error(0, "", format!("Can't open config file: {}", e)))?;
+        self.includes.push(canonical);
+        for (i, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let words = split_args(line).map_err(|message| error(i + 1, line, message))?;
+            let Some((name, args)) = words.split_first() else {
+                continue;
+            };
+            if name.eq_ignore_ascii_case("include") {
+                match args {
+                    // Like redis, relative paths are relative to the working directory
+                    [include] => self.load_file(Path::new(include))?,
+                    _ => {
+                        return Err(error(
+                            i + 1,
+                            line,
+                            "Bad directive or wrong number of arguments".to_owned(),
+                        ))
+                    }
+                }
+            } else {
+                apply(self.config, name, args).map_err(|message| error(i + 1, line, message))?;
+            }
+        }
+        self.includes.pop();
+        Ok(())
+    }
+}
+
+/// Unknown directives are only warned about, so that existing redis.conf
+/// files can be used even though most of their directives aren't supported
+fn apply(config: &mut Config, name: &str, args: &[String]) -> Result<(), String> {
+    match config.apply(name, args) {
+        Ok(true) => Ok(()),
+        Ok(false) => {
+            crate::log::warning(format!("Ignoring unsupported config directive '{}'", name));
+            Ok(())
+        }
+        Err(e) => Err(e.message()),
+    }
+}
+
+/// Splits a config line into arguments, see [codec::split_args]
+pub fn split_args(line: &str) -> Result<Vec<String>, String> {
+    let args = codec::split_args(line.as_bytes())
+        .ok_or_else(|| "Unbalanced quotes in configuration line".to_owned())?;
+    args.into_iter()
+        .map(|arg| {
+            String::from_utf8(arg).map_err(|_| "Invalid UTF-8 in configuration line".to_owned())
+        })
+        .collect()
+}
+
+/// Quotes `value` if needed, so that [split_args] reads it back unchanged
+pub fn quote(value: &str) -> String {
+    let needs_quotes = value.is_empty()
+        || value
+            .chars()
+            .any(|c| c.is_whitespace() || c.is_control() || matches!(c, '"' | '\'' | '\\'));
+    if !needs_quotes {
+        return value.to_owned();
+    }
+    let mut quoted = String::from("\"");
+    for c in value.chars() {
+        match c {
+            '\\' => quoted.push_str("\\\\"),
+            '"' => quoted.push_str("\\\""),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            c if c.is_control() => {
+                // Escapes are bytes, so non-ASCII control characters take several
+                for byte in c.encode_utf8(&mut [0; 4]).bytes() {
+                    quoted.push_str(&format!("\\x{:02x}", byte));
+                }
+            }
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn strings(args: &[&str]) -> Vec<String> {
+        args.iter().map(|it| it.to_string()).collect()
+    }
+
+    #[test]
+    fn splits_quoted_arguments() {
+        assert_eq!(
+            split_args("  port   7000 ").unwrap(),
+            strings(&["port", "7000"])
+        );
+        assert_eq!(
+            split_args(r#"requirepass "a b\n\x41" 'it\'s'"#).unwrap(),
+            strings(&["requirepass", "a b\nA", "it's"])
+        );
+        assert_eq!(
+            split_args(r#"logfile """#).unwrap(),
+            strings(&["logfile", ""])
+        );
+        assert_eq!(
+            split_args(r#"requirepass "\xc3\xa9""#).unwrap(),
+            strings(&["requirepass", "é"])
+        );
+        assert!(split_args(r#"requirepass "\xff""#).is_err());
+        assert!(split_args(r#"logfile "a"b"#).is_err());
+        assert!(split_args("logfile 'a").is_err());
+    }
+
+    #[test]
+    fn quotes_values_that_need_it() {
+        assert_eq!(quote("dump.rdb"), "dump.rdb");
+        assert_eq!(quote(""), r#""""#);
+        for value in ["a b", "it's", "a\"b\\c\n\x01", "\u{85}"] {
+            assert_eq!(
+                split_args(&format!("requirepass {}", quote(value))).unwrap(),
+                strings(&["requirepass", value])
+            );
+        }
+    }
+
+    #[test]
+    fn parses_the_command_line() {
+        let command_line =
+            CommandLine::parse(strings(&["dkv.conf", "--port", "7000", "--bind", "a", "b"]))
+                .unwrap();
+        assert_eq!(
+            command_line,
+            CommandLine {
+                config_file: Some(PathBuf::from("dkv.conf")),
+                overrides: vec![strings(&["port", "7000"]), strings(&["bind", "a", "b"])],
+            }
+        );
+        let command_line = CommandLine::parse(strings(&["--config", "dkv.conf"])).unwrap();
+        assert_eq!(command_line.config_file, Some(PathBuf::from("dkv.conf")));
+        assert!(CommandLine::parse(strings(&["--port", "1", "dkv.conf"])).is_ok());
+        assert!(CommandLine::parse(strings(&["a.conf", "--config", "b.conf"])).is_err());
+        assert!(CommandLine::parse(strings(&["--config"])).is_err());
+    }
+
+    #[test]
+    fn loads_included_files_and_overrides() {
+        let dir = std::env::temp_dir().join(format!("dkv-config-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let main = dir.join("main.conf");
+        let included = dir.join("included.conf");
+        fs::write(
+            &main,
+            format!(
+                "# comment\nport 7000\ninclude {}\nappendonly no\ntimeout 5\n",
+                included.display()
+            ),
+        )
+        .unwrap();
+        fs::write(&included, "port 7001\nbind 127.0.0.1 -::1\n").unwrap();
+
+        let command_line = CommandLine {
+            config_file: Some(main.clone()),
+            overrides: vec![strings(&["timeout", "10"])],
+        };
+        let config = command_line.load().unwrap();
+        assert_eq!(config.port, 7001);
+        assert_eq!(config.bind, strings(&["127.0.0.1", "-::1"]));
+        assert_eq!(config.timeout, 10);
+        assert_eq!(config.config_file, Some(fs::canonicalize(&main).unwrap()));
+
+        fs::write(&included, "port 7001\nport abc\n").unwrap();
+        let error = command_line.load().unwrap_err();
+        assert_eq!((error.line, error.text.as_str()), (2, "port abc"));
+        assert_eq!(error.message, "argument couldn't be parsed into an integer");
+
+        fs::write(&included, format!("include {}\n", main.display())).unwrap();
+        assert!(command_line.load().is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
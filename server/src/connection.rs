@@ -1,444 +1,1254 @@
 use std::{
-    collections::HashMap,
-    io::{self, Write},
-    net::TcpStream,
-    sync::mpsc,
+    collections::{HashMap, VecDeque},
+    io::{self, Read, Write},
+    net::{SocketAddr, TcpStream},
+    sync::Arc,
+    time::{Duration, Instant},
 };
 
+use bytes::Bytes;
+
 use crate::{
-    codec::{self, write_bulk_string},
-    command::Command,
-    error::{BadMessageError, Error},
-    serializable::{Deserializable, Serializable},
-    server::Result,
+    client::{ClientHandle, ClientOutput},
+    codec::{self, Protocol},
+    command::{self, Command, CommandFlag, CommandSpec, Handler},
+    error::{Error, ErrorKind},
+    glob, log, memory,
+    module::{ModuleContext, ModuleHandler, ModuleRegistry},
+    parser::{Request, RequestParser},
+    replies::Replies,
+    server::{Result, ServerState},
+    stats::Stats,
+    tracking::TrackingOptions,
     value::Value,
 };
 
-use crate::command::make_command_docs;
-use db::DB;
+use db::{KeyspaceEvents, SubscriberId, DB, MEMORY_SAMPLES};
 use dkv_db as db;
 
-#[derive(Debug, Copy, Clone)]
-enum Protocol {
-    RESP2,
-    RESP3,
-}
+/// How much output we let pile up before writing it to the socket,
+/// while handling pipelined commands.
+const IO_BUFFER_SIZE: usize = 16 * 1024;
 
 pub struct Connection {
     db: DB,
-    tcp_stream: TcpStream,
+    state: Arc<ServerState>,
+    client: Arc<ClientHandle>,
+    parser: RequestParser,
+    /// Requests that were parsed ahead of running them, by [Connection::parse].
+    /// Parsing stops after an error, which is the last item.
+    parsed: VecDeque<Result<Request>>,
+    /// Replies are collected here and written to the client once the
+    /// command is handled, so that messages pushed by other clients
+    /// can't end up in the middle of a reply. When the client pipelines
+    /// commands, replies are only written once we've handled every
+    /// command that `parser` has already received.
+    output: Replies,
     protocol: Protocol,
+    tracking: Option<TrackingOptions>,
+    /// Set by `CLIENT CACHING yes|no`, applies to the next command only
+    caching: Option<bool>,
+    /// The `CLIENT CACHING` value for the command currently being handled
+    current_caching: Option<bool>,
+    /// Whether the client can run commands other than `AUTH` and `HELLO`.
+    /// Clients don't need to authenticate if there's no `requirepass`.
+    authenticated: bool,
+    /// Set while the client is in subscribe mode
+    subscriptions: Option<Subscriptions>,
+    /// Whether the client connected from the loopback interface,
+    /// for settings like `enable-module-command local`
+    local: bool,
 }
-enum HandleResult {
+pub enum HandleResult {
     Continue,
     Quit,
 }
 
+/// What a connection needs once it handled the requests it received
+pub enum Progress {
+    /// Every complete request was handled
+    NeedInput,
+    /// Output piled up, and should be written before handling more requests
+    OutputFull,
+    /// The client should be disconnected once its output is written
+    Quit,
+}
+
+/// The channels of a client in subscribe mode
+struct Subscriptions {
+    by_channel: HashMap<String, SubscriberId>,
+}
+
 impl Connection {
-    pub fn new(db: DB, stream: TcpStream) -> Connection {
+    /// A connection only buffers what the client sends and what it gets back,
+    /// reading and writing the socket is up to whoever drives it
+    /// `peer` is the client's address, if it's still known
+    pub fn new(
+        state: Arc<ServerState>,
+        output: ClientOutput,
+        peer: Option<SocketAddr>,
+    ) -> Connection {
+        let client = state.clients.register(output, state.stats.clone());
+        let limits = state.config.read().protocol_limits();
+        let authenticated = state.config.read().requirepass.is_none();
         Connection {
-            db,
-            tcp_stream: stream,
+            db: state.db.clone(),
+            state,
+            client,
+            parser: RequestParser::new(limits),
+            parsed: VecDeque::new(),
+            output: Replies::default(),
             protocol: Protocol::RESP2,
+            tracking: None,
+            caching: None,
+            current_caching: None,
+            authenticated,
+            subscriptions: None,
+            local: peer.is_some_and(|peer| peer.ip().is_loopback()),
         }
     }
-    pub fn handle(&mut self) -> std::io::Result<()> {
+
+    pub fn client(&self) -> &Arc<ClientHandle> {
+        &self.client
+    }
+
+    pub fn is_subscribed(&self) -> bool {
+        self.subscriptions.is_some()
+    }
+
+    /// Serves the client from its own thread, blocking on `stream`
+    pub fn handle(&mut self, mut stream: &TcpStream) -> io::Result<()> {
+        // Replies are already batched in `output`, so there's no point in delaying them further
+        stream.set_nodelay(true)?;
+        let mut last_interaction = Instant::now();
         loop {
-            match self._handle() {
+            let progress = self.process();
+            self.flush(stream)?;
+            match progress {
+                Progress::NeedInput => {}
+                Progress::OutputFull => continue,
+                Progress::Quit => return Ok(()),
+            }
+            // Clients that other clients send messages to, like subscribers and clients
+            // tracking keys, only wait a millisecond for input, so that they write the
+            // messages soon enough. Nothing else writes to the socket.
+            let idle_timeout = self.state.config.read().idle_timeout();
+            let polled = self.subscriptions.is_some()
+                || self.tracking.is_some()
+                || self.client.receives_pushes();
+            let timeout = match polled {
+                true => Some(Duration::from_millis(1)),
+                false => idle_timeout,
+            };
+            stream.set_read_timeout(timeout)?;
+            match self.receive(&mut stream) {
+                // When redis-cli quits, it just closes the connection
+                Ok(0) => return Ok(()),
+                Ok(_) => last_interaction = Instant::now(),
+                Err(e)
+                    if e.kind() == io::ErrorKind::WouldBlock
+                        || e.kind() == io::ErrorKind::TimedOut =>
+                {
+                    // Like redis, subscribers are never idle
+                    let idle = idle_timeout.is_some_and(|timeout| {
+                        self.subscriptions.is_none() && last_interaction.elapsed() >= timeout
+                    });
+                    if idle {
+                        log::verbose(format!("Closing idle client {}", self.client.id));
+                        return Ok(());
+                    }
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Reads whatever the client sent from `stream`, without handling it yet.
+    /// Returns 0 once the client closed the connection.
+    pub fn receive(&mut self, stream: &mut impl Read) -> io::Result<usize> {
+        self.parser.read_from(stream)
+    }
+
+    /// Handles every complete request received so far, appending the replies
+    /// to the output, and then the messages other clients sent to this one.
+    /// Stops early once the output grows past [IO_BUFFER_SIZE].
+    pub fn process(&mut self) -> Progress {
+        loop {
+            if self.output.len() >= IO_BUFFER_SIZE {
+                return Progress::OutputFull;
+            }
+            let result = match self.next_request() {
+                Ok(Some(request)) => self.handle_request(request),
+                Ok(None) => return self.write_messages(),
+                Err(e) => Err(e),
+            };
+            match result {
                 Ok(HandleResult::Continue) => {}
-                Ok(HandleResult::Quit) => break,
-                Err(Error::Io(ref e)) if e.kind() == std::io::ErrorKind::UnexpectedEof => {
-                    // When redis-cli quits, it just closes the connection,
-                    // which means when we try to read the next command, we get
-                    // an UnexpectedEof error. We should just break the loop to
-                    // handle this case.
-                    break;
+                Ok(HandleResult::Quit) => return Progress::Quit,
+                Err(Error::Io(e)) => {
+                    log::verbose(format!("Connection error: {}", e));
+                    return Progress::Quit;
                 }
-                Err(e) => {
-                    self.write_error(&to_simple_string(e))?;
+                Err(e) if e.is_protocol_error() => {
+                    // Like redis, we can't know where the next command starts
+                    // after a protocol error, so we reply with the error and
+                    // close the connection.
+                    self.write_error(e).expect("Writing to a Vec can't fail");
+                    return Progress::Quit;
                 }
+                Err(e) => self.write_error(e).expect("Writing to a Vec can't fail"),
             }
         }
-        Ok(())
     }
-    fn read_command(&mut self) -> codec::Result<Command> {
-        Command::read(&mut self.tcp_stream)
-    }
-
-    fn _handle(&mut self) -> Result<HandleResult> {
-        let command = self.read_command()?;
-        match command {
-            Command::Hello(version) => {
-                if version == "3" {
-                    self.protocol = Protocol::RESP3;
-                    let mut map = HashMap::new();
-                    {
-                        let mut put = |k, v| {
-                            map.insert(String::from(k), v);
-                        };
-                        put("server", Value::from("dkv"));
-                        put("version", Value::from("0.1.0"));
-                        put("proto", Value::Integer(3));
-                        put("id", Value::Integer(10));
-                        put("mode", Value::from("standalone"));
-                        put("role", Value::from("master"));
-                        put("modules", Value::Array(vec![]));
-                    }
 
-                    self.write_value(&Value::Map(map))?;
-                } else {
-                    self.write_error("Invalid protocol version")?;
-                }
+    /// Takes the replies, for drivers that write them themselves
+    pub fn take_replies(&mut self) -> Replies {
+        std::mem::take(&mut self.output)
+    }
+
+    /// Parses every complete request received so far, without running them.
+    /// Lets io threads do the parsing, while commands run on a single thread.
+    pub fn parse(&mut self) {
+        while !matches!(self.parsed.back(), Some(Err(_))) {
+            match self.parse_request().transpose() {
+                Some(request) => self.parsed.push_back(request),
+                None => break,
             }
-            Command::Set(key, value) => {
-                self.db.set(key, db::Value::from(value));
-                self.write_simple_string("OK")?;
+        }
+    }
+
+    fn next_request(&mut self) -> Result<Option<Request>> {
+        match self.parsed.pop_front() {
+            Some(request) => request.map(Some),
+            None => self.parse_request(),
+        }
+    }
+
+    fn parse_request(&mut self) -> Result<Option<Request>> {
+        let limits = self.state.config.read().protocol_limits();
+        self.parser.set_limits(limits);
+        self.parser.next_request()
+    }
+
+    fn handle_request(&mut self, request: Request) -> Result<HandleResult> {
+        let command = Command::parse(request, &self.state.modules)?;
+        if self.subscriptions.is_some() {
+            return self.handle_subscribed(command);
+        }
+        self.current_caching = self.caching.take();
+        if !self.authenticated && !command.spec.flags.contains(&CommandFlag::NoAuth) {
+            return Err(Error::no_auth());
+        }
+        // Like redis, keys are evicted before running any command, but only
+        // commands that may use more memory fail if that wasn't enough
+        if !self.free_memory() && command.spec.flags.contains(&CommandFlag::Denyoom) {
+            return Err(Error::oom());
+        }
+        match command.handler() {
+            Handler::Builtin(handler) => handler(self, &command.args),
+            Handler::Module(handler) => self.call_module(command.spec, handler, &command.args),
+        }
+    }
+
+    /// Runs a module command, doing the client side caching
+    /// bookkeeping for the keys in its key specs
+    fn call_module(
+        &mut self,
+        spec: &CommandSpec,
+        handler: ModuleHandler,
+        args: &[Bytes],
+    ) -> Result<HandleResult> {
+        let keys = command::strings(
+            &spec
+                .keys_of(args)
+                .into_iter()
+                .map(|(key, _)| key.clone())
+                .collect::<Vec<_>>(),
+        )?;
+        if spec.flags.contains(&CommandFlag::Readonly) {
+            for key in &keys {
+                self.track_key_read(key);
+            }
+        }
+        let reply = handler(&mut ModuleContext::new(&self.db, self.client.id), args)?;
+        if spec.flags.contains(&CommandFlag::Write) {
+            for key in &keys {
+                self.signal_modified_key(key);
             }
-            Command::Get(key) => match self.db.get_optional(&key) {
-                Some(value @ db::Value::String(_)) => {
-                    self.write(&value)?;
+        }
+        self.write_value(reply)?;
+        Ok(HandleResult::Continue)
+    }
+
+    pub fn hello(&mut self, args: &[Bytes]) -> Result<HandleResult> {
+        let mut protocol = self.protocol;
+        let mut credentials = None;
+        let mut name = None;
+        if let Some(version) = args.get(1) {
+            protocol = match command::to_string(version)?.parse::<i64>() {
+                Ok(2) => Protocol::RESP2,
+                Ok(3) => Protocol::RESP3,
+                Ok(_) => {
+                    return Err(Error::with_kind(
+                        ErrorKind::NoProto,
+                        "unsupported protocol version",
+                        "",
+                    ))
                 }
-                Some(_) => self.write_error("WRONGTYPE")?,
-                None => {
-                    self.write_null_response()?;
+                Err(_) => {
+                    return Err(Error::generic(
+                        "Protocol version is not an integer or out of range",
+                        "",
+                    ))
                 }
-            },
-            Command::Command(args) => {
-                if args[0].clone().as_str() == "DOCS" {
-                    let subcommand = args.get(1);
-                    if subcommand.is_none() {
-                        let command_docs = make_command_docs();
-                        self.write_value(&Value::Map(command_docs))?;
-                    } else {
-                        todo!("COMMAND DOCS is not implement for subcommands yet")
+            };
+            let mut options = &args[2..];
+            while let Some((option, rest)) = options.split_first() {
+                options = match rest {
+                    [username, password, rest @ ..] if option.eq_ignore_ascii_case(b"AUTH") => {
+                        credentials =
+                            Some((command::to_string(username)?, command::to_string(password)?));
+                        rest
                     }
-                } else {
-                    todo!("Unimplement COMMAND {:?}", args[0])
-                }
-            }
-            Command::Config(args) => {
-                if args[0] == "GET" {
-                    if let Some(key) = args.get(1) {
-                        let config = get_default_config();
-                        let default_reply = Value::Map(HashMap::new());
-                        if !config.contains_key(key.as_str()) {
-                            println!("invalid config key: {:?}", key);
-                        }
-                        let value = config.get(key.as_str()).unwrap_or(&default_reply);
-                        self.write_value(value)?;
-                    } else {
-                        todo!("Unimplement CONFIG GET {:?}", args[1])
+                    [client_name, rest @ ..] if option.eq_ignore_ascii_case(b"SETNAME") => {
+                        name = Some(command::to_string(client_name)?);
+                        rest
                     }
-                    self.write_value(&Value::Map(HashMap::new()))?
-                } else {
-                    todo!("Unimplement CONFIG {:?}", args[0])
+                    _ => {
+                        return Err(Error::generic(
+                            format!(
+                                "Syntax error in HELLO option '{}'",
+                                String::from_utf8_lossy(option).replace(['\r', '\n'], " ")
+                            ),
+                            "",
+                        ))
+                    }
+                };
+            }
+        }
+        match credentials {
+            Some((username, password)) => self.authenticate(&username, &password)?,
+            None if !self.authenticated => return Err(Error::with_kind(
+                ErrorKind::NoAuth,
+                "HELLO must be called with the client already authenticated, otherwise the HELLO <proto> AUTH <user> <pass> option can be used to authenticate the client and select the RESP protocol version at the same time",
+                "",
+            )),
+            None => {}
+        }
+        if let Some(name) = name {
+            self.client.set_name(&name)?;
+        }
+        self.protocol = protocol;
+        self.client.set_resp3(protocol == Protocol::RESP3);
+
+        let mut map = HashMap::new();
+        {
+            let mut put = |k, v| {
+                map.insert(String::from(k), v);
+            };
+            put("server", Value::from("dkv"));
+            put("version", Value::from("0.1.0"));
+            put(
+                "proto",
+                Value::Integer(if protocol == Protocol::RESP3 { 3 } else { 2 }),
+            );
+            put("id", Value::Integer(self.client.id as i64));
+            put("mode", Value::from("standalone"));
+            put("role", Value::from("master"));
+            put("modules", self.state.modules.list());
+        }
+        self.write_value(Value::Map(map))?;
+        Ok(HandleResult::Continue)
+    }
+
+    pub fn auth(&mut self, args: &[Bytes]) -> Result<HandleResult> {
+        let (username, password) = match args {
+            [_, password] => {
+                if self.state.config.read().requirepass.is_none() {
+                    return Err(Error::generic(
+                        "AUTH <password> called without any password configured for the default user. Are you sure your configuration is correct?",
+                        "",
+                    ));
                 }
+                ("default".to_owned(), command::to_string(password)?)
             }
-            Command::Ping(s) => self.write_value(&Value::from(s))?,
-            Command::FlushAll => {
-                self.db.flush_all();
-                self.write_simple_string("OK")?;
+            [_, username, password] => {
+                (command::to_string(username)?, command::to_string(password)?)
             }
-            Command::Del(key) => {
-                let num_keys_deleted = self.db.del(&key);
-                self.write_value(&Value::Integer(num_keys_deleted as i64))?
+            _ => return Err(Error::generic("syntax error", "AUTH")),
+        };
+        self.authenticate(&username, &password)?;
+        self.write_simple_string("OK")?;
+        Ok(HandleResult::Continue)
+    }
+
+    fn authenticate(&mut self, username: &str, password: &str) -> Result<()> {
+        if !self.state.config.read().authenticate(username, password) {
+            return Err(Error::wrong_pass());
+        }
+        self.authenticated = true;
+        Ok(())
+    }
+
+    pub fn set(&mut self, args: &[Bytes]) -> Result<HandleResult> {
+        let key = command::to_string(&args[1])?;
+        // Values are stored as they were sent, whether they're UTF-8 or not
+        self.db.set(key.clone(), db::Value::from(args[2].to_vec()));
+        self.signal_modified_key(&key);
+        self.db
+            .notify_keyspace_event(KeyspaceEvents::STRING, "set", &key);
+        self.write_simple_string("OK")?;
+        Ok(HandleResult::Continue)
+    }
+
+    pub fn get(&mut self, args: &[Bytes]) -> Result<HandleResult> {
+        let key = command::to_string(&args[1])?;
+        match self.read_key(&key) {
+            Some(value @ db::Value::String(_)) => {
+                self.write_value(Value::from(value))?;
             }
-            Command::ClientSetInfo(_, _) => {
-                self.write_simple_string("OK")?;
+            Some(_) => self.write_error(Error::wrong_type())?,
+            None => {
+                self.notify_key_miss(&key);
+                self.write_value(Value::Null)?;
             }
-            Command::Rename(old_key, new_key) => {
-                match self.db.get_optional(&old_key) {
-                    Some(value) => {
-                        self.db.set(new_key, value);
-                        self.db.del(&old_key);
-                        self.write_simple_string("OK")?;
-                    }
-                    None => {
-                        self.write_error("NO_SUCH_KEY")?;
-                    }
-                };
+        }
+        Ok(HandleResult::Continue)
+    }
+
+    pub fn command(&mut self, _args: &[Bytes]) -> Result<HandleResult> {
+        let infos = CommandSpec::top_level(&self.state.modules)
+            .into_iter()
+            .map(CommandSpec::info)
+            .collect();
+        self.write_value(Value::Array(infos))?;
+        Ok(HandleResult::Continue)
+    }
+
+    pub fn command_count(&mut self, _args: &[Bytes]) -> Result<HandleResult> {
+        self.write_value(Value::Integer(
+            CommandSpec::top_level(&self.state.modules).len() as i64,
+        ))?;
+        Ok(HandleResult::Continue)
+    }
+
+    pub fn command_info(&mut self, args: &[Bytes]) -> Result<HandleResult> {
+        let infos = if args.len() == 2 {
+            CommandSpec::top_level(&self.state.modules)
+                .into_iter()
+                .map(CommandSpec::info)
+                .collect()
+        } else {
+            args[2..]
+                .iter()
+                .map(|name| {
+                    CommandSpec::find_by_full_name(name, &self.state.modules)
+                        .map_or(Value::Null, CommandSpec::info)
+                })
+                .collect()
+        };
+        self.write_value(Value::Array(infos))?;
+        Ok(HandleResult::Continue)
+    }
+
+    pub fn command_list(&mut self, args: &[Bytes]) -> Result<HandleResult> {
+        let filter = match &args[2..] {
+            [] => None,
+            [filterby, filter, value] if filterby.eq_ignore_ascii_case(b"FILTERBY") => {
+                Some((command::to_string(filter)?, command::to_string(value)?))
             }
-            Command::HGet { key, field } => {
-                enum R {
-                    Found(String),
-                    NotFound,
-                    WrongType,
-                }
-                let result = self.db.view(&key, |v| match v {
-                    Some(db::Value::Hash(m)) => {
-                        if let Some(value) = m.get(&field) {
-                            R::Found(value.clone())
-                        } else {
-                            R::NotFound
-                        }
-                    }
-                    _ => R::WrongType,
-                });
-                match result {
-                    R::Found(value) => self.write_bulk_string(&value)?,
-                    R::NotFound => self.write_null_response()?,
-                    R::WrongType => self.write_error("WRONG_TYPE")?,
-                }
+            _ => return Err(Error::generic("syntax error", "COMMAND LIST")),
+        };
+        let mut names = vec![];
+        for spec in CommandSpec::all(&self.state.modules) {
+            let included = match &filter {
+                Some((filter, value)) => spec.matches_filter(filter, value, &self.state.modules)?,
+                None => true,
+            };
+            if included {
+                names.push(Value::from(spec.name));
             }
-            Command::HSet { key, field, value } => {
-                enum R {
-                    NewMap,
-                    Mutated,
-                    WrongKey,
-                }
-                let result = self.db.mutate(&key, |v| match v {
-                    None => R::NewMap,
-                    Some(db::Value::Hash(m)) => {
-                        m.insert(field.clone(), value.clone());
-                        R::Mutated
-                    }
+        }
+        self.write_value(Value::Array(names))?;
+        Ok(HandleResult::Continue)
+    }
 
-                    Some(_) => R::WrongKey,
-                });
+    pub fn command_getkeys(&mut self, args: &[Bytes]) -> Result<HandleResult> {
+        let keys = command_keys(&args[2..], &self.state.modules)?
+            .into_iter()
+            .map(|(key, _)| command::to_string(key).map(Value::from))
+            .collect::<Result<_>>()?;
+        self.write_value(Value::Array(keys))?;
+        Ok(HandleResult::Continue)
+    }
 
-                match result {
-                    R::Mutated => self.write_value(&Value::Integer(1))?,
-                    R::NewMap => {
-                        let mut map = HashMap::new();
-                        map.insert(field, value);
-                        self.db.set(key, db::Value::Hash(map));
-                        self.write_value(&Value::Integer(1))?
-                    }
-                    R::WrongKey => self.write_error("WRONG_KEY")?,
+    pub fn command_getkeysandflags(&mut self, args: &[Bytes]) -> Result<HandleResult> {
+        let keys = command_keys(&args[2..], &self.state.modules)?
+            .into_iter()
+            .map(|(key, flags)| {
+                Ok(Value::Array(vec![
+                    Value::from(command::to_string(key)?),
+                    Value::Set(flags.iter().map(|flag| Value::from(*flag)).collect()),
+                ]))
+            })
+            .collect::<Result<_>>()?;
+        self.write_value(Value::Array(keys))?;
+        Ok(HandleResult::Continue)
+    }
+
+    pub fn command_docs(&mut self, args: &[Bytes]) -> Result<HandleResult> {
+        let specs: Vec<_> = if args.len() == 2 {
+            CommandSpec::top_level(&self.state.modules)
+        } else {
+            args[2..]
+                .iter()
+                .filter_map(|name| CommandSpec::find_by_full_name(name, &self.state.modules))
+                .collect()
+        };
+        let docs = specs
+            .into_iter()
+            .map(|spec| {
+                let mut docs = spec.docs_reply();
+                if let (Value::Map(map), Some(module)) =
+                    (&mut docs, self.state.modules.module_of(spec))
+                {
+                    map.insert("module".to_owned(), Value::from(module));
                 }
+                (spec.name.to_owned(), docs)
+            })
+            .collect();
+        self.write_value(Value::Map(docs))?;
+        Ok(HandleResult::Continue)
+    }
+
+    pub fn config_get(&mut self, args: &[Bytes]) -> Result<HandleResult> {
+        let patterns = command::strings(&args[2..])?;
+        let values = self
+            .state
+            .config
+            .get(&patterns)
+            .into_iter()
+            .map(|(name, value)| (name.to_owned(), Value::from(value)))
+            .collect();
+        self.write_value(Value::Map(values))?;
+        Ok(HandleResult::Continue)
+    }
+
+    pub fn config_set(&mut self, args: &[Bytes]) -> Result<HandleResult> {
+        let args = command::strings(&args[2..])?;
+        if !args.len().is_multiple_of(2) {
+            return Err(Error::wrong_arity("config|set"));
+        }
+        let pairs: Vec<(String, String)> = args
+            .chunks(2)
+            .map(|pair| (pair[0].clone(), pair[1].clone()))
+            .collect();
+        self.state.config.set(&pairs, &self.db)?;
+        self.write_simple_string("OK")?;
+        Ok(HandleResult::Continue)
+    }
+
+    pub fn config_resetstat(&mut self, _args: &[Bytes]) -> Result<HandleResult> {
+        self.state.stats.reset();
+        self.write_simple_string("OK")?;
+        Ok(HandleResult::Continue)
+    }
+
+    pub fn config_rewrite(&mut self, _args: &[Bytes]) -> Result<HandleResult> {
+        self.state.config.rewrite()?;
+        self.write_simple_string("OK")?;
+        Ok(HandleResult::Continue)
+    }
+
+    pub fn ping(&mut self, args: &[Bytes]) -> Result<HandleResult> {
+        match args {
+            [_] => self.write_value(Value::from("PONG"))?,
+            [_, message] => self.write_value(Value::from(command::to_string(message)?))?,
+            _ => return Err(Error::wrong_arity("ping")),
+        }
+        Ok(HandleResult::Continue)
+    }
+
+    pub fn flushall(&mut self, _args: &[Bytes]) -> Result<HandleResult> {
+        self.db.flush_all();
+        self.state
+            .tracking
+            .invalidate_all(&self.state.clients, &self.state.config);
+        self.write_simple_string("OK")?;
+        Ok(HandleResult::Continue)
+    }
+
+    pub fn del(&mut self, args: &[Bytes]) -> Result<HandleResult> {
+        let key = command::to_string(&args[1])?;
+        let num_keys_deleted = self.db.del(&key);
+        if num_keys_deleted > 0 {
+            self.signal_modified_key(&key);
+            self.db
+                .notify_keyspace_event(KeyspaceEvents::GENERIC, "del", &key);
+        }
+        self.write_value(Value::Integer(num_keys_deleted as i64))?;
+        Ok(HandleResult::Continue)
+    }
+
+    pub fn client_setinfo(&mut self, args: &[Bytes]) -> Result<HandleResult> {
+        command::strings(&args[2..])?;
+        self.write_simple_string("OK")?;
+        Ok(HandleResult::Continue)
+    }
+
+    pub fn client_id(&mut self, _args: &[Bytes]) -> Result<HandleResult> {
+        self.write_value(Value::Integer(self.client.id as i64))?;
+        Ok(HandleResult::Continue)
+    }
+
+    pub fn client_tracking(&mut self, args: &[Bytes]) -> Result<HandleResult> {
+        let options = TrackingOptions::parse(&command::strings(&args[2..])?)?;
+        if let Some(id) = options.redirect {
+            let Some(target) = self.state.clients.get(id) else {
+                return Err(Error::generic(
+                    "The client ID you want redirect to does not exist",
+                    "",
+                ));
+            };
+            if options.enabled {
+                target.set_redirect_target();
             }
-            Command::Exists(key) => {
-                let exists = self.db.exists(&key);
-                if exists {
-                    self.write_value(&Value::Integer(1))?;
-                } else {
-                    self.write_value(&Value::Integer(0))?;
+        }
+        if options.enabled {
+            self.state.tracking.enable(self.client.id, options.clone());
+            self.tracking = Some(options);
+        } else {
+            self.state.tracking.disable(self.client.id);
+            self.tracking = None;
+        }
+        self.write_simple_string("OK")?;
+        Ok(HandleResult::Continue)
+    }
+
+    pub fn client_caching(&mut self, args: &[Bytes]) -> Result<HandleResult> {
+        let yes_no = command::to_string(&args[2])?;
+        let yes = match yes_no.to_uppercase().as_str() {
+            "YES" => true,
+            "NO" => false,
+            _ => return Err(Error::generic("syntax error", yes_no)),
+        };
+        match &self.tracking {
+            Some(options) if options.optin || options.optout => {
+                if yes && !options.optin {
+                    return Err(Error::generic(
+                        "CLIENT CACHING YES is only valid when tracking is enabled in OPTIN mode.",
+                        "",
+                    ));
+                } else if !yes && !options.optout {
+                    return Err(Error::generic(
+                        "CLIENT CACHING NO is only valid when tracking is enabled in OPTOUT mode.",
+                        "",
+                    ));
                 }
             }
-            Command::HGetAll(key) => {
-                let map = match self.db.get_optional(&key) {
-                    Some(db::Value::Hash(m)) => m,
-                    _ => HashMap::new(),
-                };
-                match self.protocol {
-                    Protocol::RESP2 => {
-                        let mut values = vec![];
-                        for (k, v) in &map {
-                            values.push(k.as_str());
-                            values.push(v.as_str());
-                        }
-                        println!("WRITE_ARRAY: {:?}", values);
-                        self.write_array(values.as_slice())?;
-                    }
-                    Protocol::RESP3 => {
-                        println!("WRITE_HASH: {:?}", map);
-                        self.write(&db::Value::Hash(map))?;
-                    }
+            _ => return Err(Error::generic("CLIENT CACHING can be called only when the client is in tracking mode with OPTIN or OPTOUT mode enabled", "")),
+        }
+        self.caching = Some(yes);
+        self.write_simple_string("OK")?;
+        Ok(HandleResult::Continue)
+    }
+
+    pub fn client_setname(&mut self, args: &[Bytes]) -> Result<HandleResult> {
+        self.client.set_name(&command::to_string(&args[2])?)?;
+        self.write_simple_string("OK")?;
+        Ok(HandleResult::Continue)
+    }
+
+    pub fn client_getname(&mut self, _args: &[Bytes]) -> Result<HandleResult> {
+        match self.client.name() {
+            Some(name) => self.write_bulk_string(&name)?,
+            None => self.write_value(Value::Null)?,
+        }
+        Ok(HandleResult::Continue)
+    }
+
+    pub fn client_getredir(&mut self, _args: &[Bytes]) -> Result<HandleResult> {
+        let redirect = match &self.tracking {
+            None => -1,
+            Some(options) => options.redirect.map(|id| id as i64).unwrap_or(0),
+        };
+        self.write_value(Value::Integer(redirect))?;
+        Ok(HandleResult::Continue)
+    }
+
+    pub fn rename(&mut self, args: &[Bytes]) -> Result<HandleResult> {
+        let old_key = command::to_string(&args[1])?;
+        let new_key = command::to_string(&args[2])?;
+        if self.db.rename(&old_key, new_key.clone()) {
+            self.signal_modified_key(&old_key);
+            self.signal_modified_key(&new_key);
+            self.db
+                .notify_keyspace_event(KeyspaceEvents::GENERIC, "rename_from", &old_key);
+            self.db
+                .notify_keyspace_event(KeyspaceEvents::GENERIC, "rename_to", &new_key);
+            self.write_simple_string("OK")?;
+        } else {
+            self.write_error(Error::no_such_key())?;
+        }
+        Ok(HandleResult::Continue)
+    }
+
+    pub fn hget(&mut self, args: &[Bytes]) -> Result<HandleResult> {
+        enum R {
+            Found(String),
+            NotFound,
+            WrongType,
+        }
+        let key = command::to_string(&args[1])?;
+        let field = command::to_string(&args[2])?;
+        self.track_key_read(&key);
+        let result = self.db.view(&key, |v| match v {
+            Some(db::Value::Hash(m)) => {
+                if let Some(value) = m.get(&field) {
+                    R::Found(value.clone())
+                } else {
+                    R::NotFound
                 }
             }
-            Command::HLen(ref key) => {
-                let len = self.db.view(key, |v| match v {
-                    Some(db::Value::Hash(m)) => m.len() as i64,
-                    _ => 0,
-                });
-                self.write_value(&Value::Integer(len))?;
+            None => R::NotFound,
+            Some(_) => R::WrongType,
+        });
+        match result {
+            R::Found(value) => self.write_bulk_string(&value)?,
+            R::NotFound => {
+                self.notify_key_miss(&key);
+                self.write_value(Value::Null)?
             }
-            Command::HExists { ref key, ref field } => {
-                let exists = self.db.view(key, |v| match v {
-                    Some(db::Value::Hash(m)) => m.contains_key(field),
-                    _ => false,
-                });
-                let result = if exists {
-                    Value::Integer(1)
-                } else {
-                    Value::Integer(0)
-                };
-                self.write_value(&result)?;
-            }
-            Command::Subscribe(channels) => {
-                self.handle_subscribe(channels)?;
+            R::WrongType => self.write_error(Error::wrong_type())?,
+        }
+        Ok(HandleResult::Continue)
+    }
+
+    pub fn hset(&mut self, args: &[Bytes]) -> Result<HandleResult> {
+        enum R {
+            NewMap(String, String),
+            Mutated,
+            WrongType,
+        }
+        let key = command::to_string(&args[1])?;
+        let field = command::to_string(&args[2])?;
+        let value = command::to_string(&args[3])?;
+        let result = self.db.mutate(&key, |v| match v {
+            None => R::NewMap(field, value),
+            Some(db::Value::Hash(m)) => {
+                m.insert(field, value);
+                R::Mutated
             }
-            Command::Publish(channel, message) => {
-                self.db.publish(&channel, &message);
-                self.write_value(&Value::Integer(1))?;
+
+            Some(_) => R::WrongType,
+        });
+
+        match result {
+            R::Mutated => {
+                self.signal_modified_key(&key);
+                self.db
+                    .notify_keyspace_event(KeyspaceEvents::HASH, "hset", &key);
+                self.write_value(Value::Integer(1))?
             }
-            Command::Unsubscribe(_) => {
-                self.write_error("Unsubscribe called outside of a subscription connection")?;
+            R::NewMap(field, value) => {
+                let mut map = HashMap::new();
+                map.insert(field, value);
+                self.db.set(key.clone(), db::Value::Hash(map));
+                self.signal_modified_key(&key);
+                self.db
+                    .notify_keyspace_event(KeyspaceEvents::HASH, "hset", &key);
+                self.write_value(Value::Integer(1))?
             }
-            Command::Quit => {
-                self.write_simple_string("OK")?;
-                return Ok(HandleResult::Quit);
+            R::WrongType => self.write_error(Error::wrong_type())?,
+        }
+        Ok(HandleResult::Continue)
+    }
+
+    pub fn scan(&mut self, args: &[Bytes]) -> Result<HandleResult> {
+        let cursor: u64 = command::to_string(&args[1])?
+            .parse()
+            .map_err(|_| Error::generic("invalid cursor", "SCAN"))?;
+        let mut pattern = None;
+        let mut count = 10;
+        let mut type_name = None;
+        for option in args[2..].chunks(2) {
+            match option {
+                [name, value] if name.eq_ignore_ascii_case(b"MATCH") => pattern = Some(value),
+                [name, value] if name.eq_ignore_ascii_case(b"COUNT") => {
+                    count = command::to_string(value)?.parse::<i64>().map_err(|_| {
+                        Error::generic("value is not an integer or out of range", "SCAN")
+                    })?;
+                    if count < 1 {
+                        return Err(Error::generic("syntax error", "SCAN"));
+                    }
+                }
+                [name, value] if name.eq_ignore_ascii_case(b"TYPE") => {
+                    type_name = Some(command::to_string(value)?)
+                }
+                _ => return Err(Error::generic("syntax error", "SCAN")),
             }
         }
+        let mut keys = vec![];
+        let cursor = self.db.scan(cursor, count as usize, |key, value| {
+            let included = pattern
+                .is_none_or(|pattern| glob::matches(pattern, key.as_bytes(), false))
+                && type_name
+                    .as_ref()
+                    .is_none_or(|type_name| value.type_name().eq_ignore_ascii_case(type_name));
+            if included {
+                keys.push(Value::from(key));
+            }
+        });
+        self.write_value(Value::Array(vec![
+            Value::from(cursor.to_string()),
+            Value::Array(keys),
+        ]))?;
         Ok(HandleResult::Continue)
     }
 
-    fn handle_subscribe(&mut self, channels: Vec<String>) -> Result<()> {
-        let mut subscriptions_by_channel = HashMap::new();
-        let (send_value, recv_value) = mpsc::channel();
+    pub fn exists(&mut self, args: &[Bytes]) -> Result<HandleResult> {
+        let key = command::to_string(&args[1])?;
+        self.track_key_read(&key);
+        let exists = self.db.exists(&key);
+        self.write_value(Value::Integer(exists as i64))?;
+        Ok(HandleResult::Continue)
+    }
+
+    pub fn hgetall(&mut self, args: &[Bytes]) -> Result<HandleResult> {
+        let key = command::to_string(&args[1])?;
+        let map = match self.read_key(&key) {
+            Some(db::Value::Hash(m)) => m,
+            _ => HashMap::new(),
+        };
+        self.write_value(Value::from(db::Value::Hash(map)))?;
+        Ok(HandleResult::Continue)
+    }
+
+    pub fn hlen(&mut self, args: &[Bytes]) -> Result<HandleResult> {
+        let key = command::to_string(&args[1])?;
+        self.track_key_read(&key);
+        let len = self.db.view(&key, |v| match v {
+            Some(db::Value::Hash(m)) => m.len() as i64,
+            _ => 0,
+        });
+        self.write_value(Value::Integer(len))?;
+        Ok(HandleResult::Continue)
+    }
+
+    pub fn hexists(&mut self, args: &[Bytes]) -> Result<HandleResult> {
+        let key = command::to_string(&args[1])?;
+        let field = command::to_string(&args[2])?;
+        self.track_key_read(&key);
+        let exists = self.db.view(&key, |v| match v {
+            Some(db::Value::Hash(m)) => m.contains_key(&field),
+            _ => false,
+        });
+        self.write_value(Value::Integer(exists as i64))?;
+        Ok(HandleResult::Continue)
+    }
+
+    pub fn subscribe(&mut self, args: &[Bytes]) -> Result<HandleResult> {
+        let channels = command::strings(&args[1..])?;
+        let subscriptions = self.subscriptions.get_or_insert_with(|| Subscriptions {
+            by_channel: HashMap::new(),
+        });
+        self.client.set_subscribed(true);
+        let mut replies = vec![];
         for channel in channels {
-            let send_value = send_value.clone();
-            let send_value_sub = send_value.clone();
-            let sub = self.db.subscribe(&channel, move |message| {
-                // deliberately ignore error because if we're unable to send a value
-                // that just means that the client has disconnected by calling
-                // unsubscribe
-                let _ = send_value_sub.send(Value::Array(vec![
-                    Value::from("message"),
-                    Value::from(message.channel.to_string()),
-                    Value::from(message.value.to_string()),
-                ]));
+            let client = self.client.clone();
+            let state = self.state.clone();
+            let id = self.db.subscribe(&channel, move |message| {
+                client.send(
+                    Value::Array(vec![
+                        Value::from("message"),
+                        Value::from(message.channel.to_string()),
+                        Value::from(message.value.to_string()),
+                    ]),
+                    &state.config,
+                );
             });
-            subscriptions_by_channel.insert(channel.clone(), sub);
-            send_value
-                .send(Value::Array(vec![
-                    Value::from("subscribe"),
-                    Value::from(channel),
-                    // TODO
-                    Value::from(subscriptions_by_channel.len() as i64),
-                ]))
-                .unwrap();
+            if let Some(previous) = subscriptions.by_channel.insert(channel.clone(), id) {
+                self.db.unsubscribe(previous);
+            }
+            replies.push(Value::Array(vec![
+                Value::from("subscribe"),
+                Value::from(channel),
+                Value::from(subscriptions.by_channel.len() as i64),
+            ]));
+        }
+        for reply in replies {
+            self.write_value(reply)?;
         }
+        Ok(HandleResult::Continue)
+    }
 
-        loop {
-            if let Ok(value) = recv_value.try_recv() {
-                self.write_value(&value)?;
+    pub fn publish(&mut self, args: &[Bytes]) -> Result<HandleResult> {
+        let channel = command::to_string(&args[1])?;
+        self.db.publish(&channel, &command::to_string(&args[2])?);
+        self.write_value(Value::Integer(1))?;
+        Ok(HandleResult::Continue)
+    }
+
+    pub fn unsubscribe(&mut self, _args: &[Bytes]) -> Result<HandleResult> {
+        Err(Error::generic(
+            "Unsubscribe called outside of a subscription connection",
+            "",
+        ))
+    }
+
+    pub fn info(&mut self, _args: &[Bytes]) -> Result<HandleResult> {
+        self.write_bulk_string(&self.state.stats.info())?;
+        Ok(HandleResult::Continue)
+    }
+
+    pub fn memory_usage(&mut self, args: &[Bytes]) -> Result<HandleResult> {
+        let key = command::to_string(&args[2])?;
+        let samples = match &args[3..] {
+            [] => MEMORY_SAMPLES,
+            [option, samples] if option.eq_ignore_ascii_case(b"SAMPLES") => {
+                let samples = command::to_string(samples)?.parse::<i64>().map_err(|_| {
+                    Error::generic("value is not an integer or out of range", "MEMORY USAGE")
+                })?;
+                // Like redis, 0 means every element
+                usize::try_from(samples)
+                    .map_err(|_| Error::generic("syntax error", "MEMORY USAGE"))?
             }
-            let command = self.try_read_command()?;
-            match command {
-                Some(Command::Unsubscribe(channels)) => {
-                    for channel in channels {
-                        if let Some(sub) = subscriptions_by_channel.remove(&channel) {
-                            self.db.unsubscribe(sub);
-                        }
-                        self.write_value(&Value::Array(vec![
-                            Value::from("unsubscribe"),
-                            Value::from(channel),
-                            Value::from(subscriptions_by_channel.len() as i64),
-                        ]))?;
-                    }
-                    if subscriptions_by_channel.is_empty() {
-                        break;
-                    }
-                }
-                Some(Command::Quit) => {
-                    for (_, id) in subscriptions_by_channel.drain() {
-                        self.db.unsubscribe(id);
-                    }
-                    self.write_simple_string("OK")?;
-                    break;
+            _ => return Err(Error::generic("syntax error", "MEMORY USAGE")),
+        };
+        match self.db.memory_usage(&key, samples) {
+            Some(bytes) => self.write_value(Value::Integer(bytes as i64))?,
+            None => self.write_value(Value::Null)?,
+        }
+        Ok(HandleResult::Continue)
+    }
+
+    pub fn memory_stats(&mut self, _args: &[Bytes]) -> Result<HandleResult> {
+        let stats = memory::stats(&self.db.memory_stats(), memory::resident_memory());
+        self.write_value(stats)?;
+        Ok(HandleResult::Continue)
+    }
+
+    pub fn memory_doctor(&mut self, _args: &[Bytes]) -> Result<HandleResult> {
+        let maxmemory = self.state.config.read().max_memory();
+        let report = memory::doctor(
+            &self.db.memory_stats(),
+            memory::resident_memory(),
+            maxmemory,
+        );
+        self.write_value(Value::Verbatim {
+            format: "txt".to_owned(),
+            text: report,
+        })?;
+        Ok(HandleResult::Continue)
+    }
+
+    pub fn memory_malloc_stats(&mut self, _args: &[Bytes]) -> Result<HandleResult> {
+        // Same as redis when it's not built with jemalloc
+        self.write_bulk_string("Stats not supported for the current allocator")?;
+        Ok(HandleResult::Continue)
+    }
+
+    pub fn memory_help(&mut self, _args: &[Bytes]) -> Result<HandleResult> {
+        self.write_help(
+            "MEMORY",
+            &[
+                "DOCTOR",
+                "    Return memory problems reports.",
+                "MALLOC-STATS",
+                "    Return internal statistics report from the memory allocator.",
+                "STATS",
+                "    Return information about the memory usage of the server.",
+                "USAGE <key> [SAMPLES <count>]",
+                "    Return memory in bytes used by <key> and its value. Nested values are",
+                "    sampled up to <count> times (default: 5, 0 means sample all).",
+            ],
+        )?;
+        Ok(HandleResult::Continue)
+    }
+
+    pub fn object_encoding(&mut self, args: &[Bytes]) -> Result<HandleResult> {
+        let info = self.object_info(&args[2])?;
+        self.write_value(info.map_or(Value::Null, |info| Value::from(info.encoding)))?;
+        Ok(HandleResult::Continue)
+    }
+
+    pub fn object_idletime(&mut self, args: &[Bytes]) -> Result<HandleResult> {
+        let info = self.object_info(&args[2])?;
+        self.write_value(info.map_or(Value::Null, |info| {
+            Value::Integer(info.idle.as_secs() as i64)
+        }))?;
+        Ok(HandleResult::Continue)
+    }
+
+    pub fn object_freq(&mut self, args: &[Bytes]) -> Result<HandleResult> {
+        let info = self.object_info(&args[2])?;
+        self.write_value(info.map_or(Value::Null, |info| Value::Integer(info.frequency as i64)))?;
+        Ok(HandleResult::Continue)
+    }
+
+    pub fn object_refcount(&mut self, args: &[Bytes]) -> Result<HandleResult> {
+        // Values are never shared between keys
+        let info = self.object_info(&args[2])?;
+        self.write_value(info.map_or(Value::Null, |_| Value::Integer(1)))?;
+        Ok(HandleResult::Continue)
+    }
+
+    pub fn object_help(&mut self, _args: &[Bytes]) -> Result<HandleResult> {
+        self.write_help(
+            "OBJECT",
+            &[
+                "ENCODING <key>",
+                "    Return the kind of internal representation used in order to store the value",
+                "    associated with a <key>.",
+                "FREQ <key>",
+                "    Return the access frequency index of the <key>. The returned integer is",
+                "    proportional to the logarithm of the recent access frequency of the key.",
+                "IDLETIME <key>",
+                "    Return the idle time of the <key>, that is the approximated number of",
+                "    seconds elapsed since the last access to the key.",
+                "REFCOUNT <key>",
+                "    Return the number of references of the value associated with the specified",
+                "    <key>.",
+            ],
+        )?;
+        Ok(HandleResult::Continue)
+    }
+
+    /// Looks at a key for `OBJECT`. Unlike redis, both its idle time
+    /// and its frequency are tracked whatever `maxmemory-policy` is.
+    fn object_info(&self, key: &Bytes) -> Result<Option<db::ObjectInfo>> {
+        let key = command::to_string(key)?;
+        self.track_key_read(&key);
+        Ok(self.db.object_info(&key))
+    }
+
+    pub fn module_list(&mut self, _args: &[Bytes]) -> Result<HandleResult> {
+        self.write_value(self.state.modules.list())?;
+        Ok(HandleResult::Continue)
+    }
+
+    pub fn module_load(&mut self, args: &[Bytes]) -> Result<HandleResult> {
+        let allowed = self.state.config.read().enable_module_command;
+        if !allowed.allows(self.local) {
+            return Err(Error::generic(
+                "MODULE command not allowed. If the enable-module-command option is set to \"local\", \
+                 you can run it from a local connection, otherwise you need to set this option \
+                 in the configuration file, and then restart the server.",
+                "",
+            ));
+        }
+        let path = command::to_string(&args[2])?;
+        let module_args = command::strings(&args[3..])?;
+        if let Err(e) = self.state.modules.load_dynamic(&path, module_args) {
+            // Like redis, the details only go to the server's logs
+            log::warning(format!("Module {} failed to load: {}", path, e.reply()));
+            return Err(Error::generic(
+                "Error loading the extension. Please check the server logs.",
+                path,
+            ));
+        }
+        self.write_simple_string("OK")?;
+        Ok(HandleResult::Continue)
+    }
+
+    pub fn module_unload(&mut self, args: &[Bytes]) -> Result<HandleResult> {
+        let name = command::to_string(&args[2])?;
+        self.state.modules.unload(&name)?;
+        self.write_simple_string("OK")?;
+        Ok(HandleResult::Continue)
+    }
+
+    pub fn quit(&mut self, _args: &[Bytes]) -> Result<HandleResult> {
+        self.write_simple_string("OK")?;
+        Ok(HandleResult::Quit)
+    }
+
+    /// Only a few commands are allowed in subscribe mode
+    fn handle_subscribed(&mut self, command: Command) -> Result<HandleResult> {
+        if command.is("subscribe") {
+            return self.subscribe(&command.args);
+        }
+        if command.is("unsubscribe") {
+            for channel in command::strings(&command.args[1..])? {
+                let subscriptions = self.subscriptions.as_mut().unwrap();
+                if let Some(id) = subscriptions.by_channel.remove(&channel) {
+                    self.db.unsubscribe(id);
                 }
-                Some(_) => {
-                    self.write_error("Only unsubscribe commands can be sent after SUBSCRIBE")?
+                let count = subscriptions.by_channel.len();
+                self.write_value(Value::Array(vec![
+                    Value::from("unsubscribe"),
+                    Value::from(channel),
+                    Value::from(count as i64),
+                ]))?;
+            }
+            if self
+                .subscriptions
+                .as_ref()
+                .is_some_and(|it| it.by_channel.is_empty())
+            {
+                self.unsubscribe_all();
+            }
+            return Ok(HandleResult::Continue);
+        }
+        if command.is("quit") {
+            self.unsubscribe_all();
+            self.write_simple_string("OK")?;
+            return Ok(HandleResult::Quit);
+        }
+        Err(Error::generic(
+            "Only unsubscribe commands can be sent after SUBSCRIBE",
+            "",
+        ))
+    }
+
+    /// Writes the messages other clients sent to this one since the last time,
+    /// like the ones published to its channels and invalidations
+    fn write_messages(&mut self) -> Progress {
+        match self.client.take_pushes() {
+            Some(values) => {
+                for value in values {
+                    self.output.push_value(value, self.protocol);
                 }
-                None => continue,
+                Progress::NeedInput
+            }
+            None => {
+                // The client couldn't keep up with the messages sent to it,
+                // so like redis, we disconnect it instead of buffering forever.
+                log::warning(format!(
+                    "Client id={} closed for overcoming of output buffer limits.",
+                    self.client.id
+                ));
+                self.unsubscribe_all();
+                Progress::Quit
             }
         }
+    }
 
-        Ok(())
+    fn unsubscribe_all(&mut self) {
+        if let Some(subscriptions) = self.subscriptions.take() {
+            for (_, id) in subscriptions.by_channel {
+                self.db.unsubscribe(id);
+            }
+            self.client.set_subscribed(false);
+        }
     }
 
-    /// Try to read a command from the tcp stream, but return None
-    /// in case the call would block.
-    fn try_read_command(&mut self) -> Result<Option<Command>> {
-        self.tcp_stream.set_nonblocking(true)?;
+    /// Evicts keys if the keyspace uses more than `maxmemory`.
+    /// Returns false if it still does.
+    fn free_memory(&self) -> bool {
+        let mut evicted = vec![];
+        let freed = self.db.evict(|key| {
+            Stats::incr(&self.state.stats.evicted_keys);
+            evicted.push(key.to_owned());
+        });
+        // Only once other clients can evict again, since sending invalidations
+        // reads the config, which `CONFIG SET maxmemory` holds while it waits
+        // for the eviction lock
+        for key in evicted {
+            self.state
+                .tracking
+                .invalidate_key(&key, None, &self.state.clients, &self.state.config);
+        }
+        freed
+    }
 
-        let value = match Command::read(&mut self.tcp_stream) {
-            Ok(command) => Ok(Some(command)),
-            Err(Error::Io(ref e)) if e.kind() == std::io::ErrorKind::WouldBlock => Ok(None),
-            Err(e) => Err(e),
+    /// Reads a key on behalf of the client, remembering it
+    /// for client side caching if needed.
+    fn read_key(&self, key: &str) -> Option<db::Value> {
+        self.track_key_read(key);
+        self.db.get_optional(key)
+    }
+
+    fn track_key_read(&self, key: &str) {
+        let Some(options) = &self.tracking else {
+            return;
         };
-        self.tcp_stream.set_nonblocking(true)?;
-        value
+        if options.bcast
+            || (options.optin && self.current_caching != Some(true))
+            || (options.optout && self.current_caching == Some(false))
+        {
+            return;
+        }
+        self.state.tracking.remember_key(self.client.id, key);
+    }
+
+    /// Invalidates `key` in the caches of clients that are tracking it
+    fn signal_modified_key(&self, key: &str) {
+        self.state.tracking.invalidate_key(
+            key,
+            Some(self.client.id),
+            &self.state.clients,
+            &self.state.config,
+        );
     }
 
-    fn write_error(&mut self, s: &str) -> io::Result<()> {
-        write!(self.tcp_stream, "-ERROR: {}\r\n", s)?;
+    fn flush(&mut self, mut stream: &TcpStream) -> io::Result<()> {
+        if !self.output.is_empty() {
+            let mut bytes = vec![];
+            self.output.write_to(&mut bytes);
+            stream.write_all(&bytes)?;
+        }
         Ok(())
     }
 
-    fn write_value(&mut self, value: &Value) -> io::Result<()> {
-        value.write(&mut self.tcp_stream)?;
+    fn write_error(&mut self, e: Error) -> io::Result<()> {
+        write!(self.output, "-{}\r\n", e.reply())?;
         Ok(())
     }
 
-    fn write(&mut self, value: &impl Serializable) -> io::Result<()> {
-        value.write(&mut self.tcp_stream)
+    fn write_value(&mut self, value: Value) -> io::Result<()> {
+        self.output.push_value(value, self.protocol);
+        Ok(())
     }
 
-    fn write_bulk_string(&mut self, value: &str) -> io::Result<()> {
-        codec::write_bulk_string(&mut self.tcp_stream, value)
+    /// Replies to `<command> HELP` like redis, with `lines` describing the subcommands
+    fn write_help(&mut self, command: &str, lines: &[&str]) -> io::Result<()> {
+        let mut help = vec![Value::from(format!(
+            "{} <subcommand> [<arg> [value] [opt] ...]. Subcommands are:",
+            command
+        ))];
+        help.extend(lines.iter().map(|line| Value::from(*line)));
+        help.push(Value::from("HELP"));
+        help.push(Value::from("    Print this help."));
+        self.write_value(Value::Array(help))
     }
 
-    fn write_array(&mut self, values: &[&str]) -> io::Result<()> {
-        codec::write_bulk_string_array(&mut self.tcp_stream, values)
+    fn write_bulk_string(&mut self, value: &str) -> io::Result<()> {
+        codec::write_bulk_string(&mut self.output, value)
     }
 
-    /// RESP2 doesn't have a Null representation
-    /// instead, it uses a bulk string/array with -1 length
-    /// depending on context
-    fn write_null_response(&mut self) -> io::Result<()> {
-        match self.protocol {
-            Protocol::RESP2 => {
-                write!(self.tcp_stream, "$-1\r\n")?;
-            }
-            Protocol::RESP3 => self.write_value(&Value::Null)?,
-        }
-        Ok(())
+    fn notify_key_miss(&self, key: &str) {
+        self.db
+            .notify_keyspace_event(KeyspaceEvents::KEY_MISS, "keymiss", key);
     }
 
     fn write_simple_string(&mut self, value: &str) -> Result<()> {
-        write!(self.tcp_stream, "+{}\r\n", value)?;
+        write!(self.output, "+{}\r\n", value)?;
         Ok(())
     }
 }
 
-fn get_default_config() -> HashMap<&'static str, Value> {
-    let mut config = HashMap::new();
-    config.insert("save", Value::from("3600 1 300 100 60 10000"));
-    config.insert("appendonly", Value::from("no"));
-    config.insert("bind", Value::from("localhost"));
-    config
+impl Drop for Connection {
+    fn drop(&mut self) {
+        self.unsubscribe_all();
+        self.state.tracking.disable(self.client.id);
+        self.state.clients.unregister(self.client.id);
+    }
 }
 
-fn to_simple_string(e: Error) -> String {
-    match e {
-        // there's no guarantee that io::Error contains characters that are safe
-        // to send as part of a simple string, so we'll just send a generic error
-        // Besides, this is treated as a server error, not client error.
-        Error::Io(_) => String::from("Internal server error"),
-        Error::BadMessage(BadMessageError::InvalidCommand(_)) => String::from("Invalid command"),
-        Error::BadMessage(BadMessageError::InvalidLength(_)) => {
-            String::from("Invalid length for a bulk string")
-        }
-        Error::BadMessage(BadMessageError::Generic(s, _)) => s,
-        Error::BadMessage(BadMessageError::Utf8(_)) => String::from("Invalid UTF-8"),
-        Error::UnexpectedStartOfValue(c) => {
-            format!("Unexpected start of value: {}", c)
-        }
+/// The keys of the command in `args`, for `COMMAND GETKEYS` and `COMMAND GETKEYSANDFLAGS`
+fn command_keys<'a>(
+    args: &'a [Bytes],
+    modules: &ModuleRegistry,
+) -> Result<Vec<(&'a Bytes, &'static [&'static str])>> {
+    if CommandSpec::find(&args[0], modules).is_none() {
+        return Err(Error::generic("Invalid command specified", ""));
     }
-}
-impl Serializable for db::Value {
-    fn write(&self, stream: &mut impl std::io::Write) -> std::io::Result<()> {
-        use db::Value;
-        match self {
-            Value::String(s) => {
-                write!(stream, "+{}\r\n", s)?;
-            }
-            Value::List(list) => {
-                write!(stream, "*{}\r\n", list.len())?;
-                for item in list {
-                    write_bulk_string(stream, item)?;
-                }
-            }
-            Value::Hash(map) => {
-                write!(stream, "%{}\r\n", map.len())?;
-                for (key, value) in map {
-                    write_bulk_string(stream, key.as_str())?;
-                    write_bulk_string(stream, value.as_str())?;
-                }
-            }
-        }
-        Ok(())
+    let spec = CommandSpec::resolve(args, modules)
+        .map_err(|_| Error::generic("Invalid number of arguments specified for command", ""))?;
+    let keys = spec.keys_of(args);
+    if keys.is_empty() {
+        return Err(Error::generic("The command has no key arguments", ""));
     }
+    Ok(keys)
 }
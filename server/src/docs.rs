@@ -0,0 +1,217 @@
+use std::collections::HashMap;
+
+use crate::Value;
+
+/// What `COMMAND DOCS` reports about a command
+#[derive(Debug, Clone, Copy)]
+pub struct CommandDocs {
+    pub summary: &'static str,
+    /// The redis version that introduced the command
+    pub since: &'static str,
+    /// e.g. `string`, `hash` or `connection`
+    pub group: &'static str,
+    pub complexity: &'static str,
+    pub arguments: &'static [Arg],
+}
+
+impl CommandDocs {
+    pub const EMPTY: CommandDocs = CommandDocs {
+        summary: "",
+        since: "",
+        group: "",
+        complexity: "",
+        arguments: &[],
+    };
+
+    pub fn to_value(self) -> HashMap<String, Value> {
+        let mut map = HashMap::new();
+        map.insert("summary".to_owned(), Value::from(self.summary));
+        map.insert("since".to_owned(), Value::from(self.since));
+        map.insert("group".to_owned(), Value::from(self.group));
+        map.insert("complexity".to_owned(), Value::from(self.complexity));
+        if !self.arguments.is_empty() {
+            map.insert("arguments".to_owned(), args_value(self.arguments));
+        }
+        map
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArgKind {
+    String,
+    Integer,
+    Key,
+    Pattern,
+    /// A token that's the whole argument, like `NOLOOP`
+    PureToken,
+    /// Exactly one of the nested arguments
+    Oneof,
+    /// All of the nested arguments, in order
+    Block,
+}
+
+impl ArgKind {
+    fn name(self) -> &'static str {
+        match self {
+            ArgKind::String => "string",
+            ArgKind::Integer => "integer",
+            ArgKind::Key => "key",
+            ArgKind::Pattern => "pattern",
+            ArgKind::PureToken => "pure-token",
+            ArgKind::Oneof => "oneof",
+            ArgKind::Block => "block",
+        }
+    }
+}
+
+/// A node of a command's argument tree, as reported by `COMMAND DOCS`
+#[derive(Debug, Clone, Copy)]
+pub struct Arg {
+    pub name: &'static str,
+    pub kind: ArgKind,
+    /// The literal that comes before the argument, like `REDIRECT`
+    pub token: Option<&'static str>,
+    /// Which of the command's key specs describes this key
+    pub key_spec_index: usize,
+    pub optional: bool,
+    pub multiple: bool,
+    /// Whether the token is repeated for every value, like `PREFIX a PREFIX b`
+    pub multiple_token: bool,
+    /// Nested arguments of `oneof` and `block` arguments
+    pub arguments: &'static [Arg],
+}
+
+impl Arg {
+    const fn new(name: &'static str, kind: ArgKind) -> Self {
+        Arg {
+            name,
+            kind,
+            token: None,
+            key_spec_index: 0,
+            optional: false,
+            multiple: false,
+            multiple_token: false,
+            arguments: &[],
+        }
+    }
+
+    pub const fn string(name: &'static str) -> Self {
+        Arg::new(name, ArgKind::String)
+    }
+
+    pub const fn integer(name: &'static str) -> Self {
+        Arg::new(name, ArgKind::Integer)
+    }
+
+    pub const fn key(name: &'static str, key_spec_index: usize) -> Self {
+        let mut arg = Arg::new(name, ArgKind::Key);
+        arg.key_spec_index = key_spec_index;
+        arg
+    }
+
+    pub const fn pattern(name: &'static str) -> Self {
+        Arg::new(name, ArgKind::Pattern)
+    }
+
+    pub const fn pure_token(name: &'static str, token: &'static str) -> Self {
+        Arg::new(name, ArgKind::PureToken).token(token)
+    }
+
+    pub const fn oneof(name: &'static str, arguments: &'static [Arg]) -> Self {
+        let mut arg = Arg::new(name, ArgKind::Oneof);
+        arg.arguments = arguments;
+        arg
+    }
+
+    pub const fn block(name: &'static str, arguments: &'static [Arg]) -> Self {
+        let mut arg = Arg::new(name, ArgKind::Block);
+        arg.arguments = arguments;
+        arg
+    }
+
+    pub const fn token(mut self, token: &'static str) -> Self {
+        self.token = Some(token);
+        self
+    }
+
+    pub const fn optional(mut self) -> Self {
+        self.optional = true;
+        self
+    }
+
+    pub const fn multiple(mut self) -> Self {
+        self.multiple = true;
+        self
+    }
+
+    pub const fn multiple_token(mut self) -> Self {
+        self.multiple_token = true;
+        self
+    }
+
+    fn to_value(self) -> Value {
+        let mut map = HashMap::new();
+        map.insert("name".to_owned(), Value::from(self.name));
+        map.insert("type".to_owned(), Value::from(self.kind.name()));
+        if !matches!(self.kind, ArgKind::Oneof | ArgKind::Block) {
+            map.insert("display_text".to_owned(), Value::from(self.name));
+        }
+        if self.kind == ArgKind::Key {
+            map.insert(
+                "key_spec_index".to_owned(),
+                Value::Integer(self.key_spec_index as i64),
+            );
+        }
+        if let Some(token) = self.token {
+            map.insert("token".to_owned(), Value::from(token));
+        }
+        let flags: Vec<Value> = [
+            ("optional", self.optional),
+            ("multiple", self.multiple),
+            ("multiple_token", self.multiple_token),
+        ]
+        .into_iter()
+        .filter(|(_, set)| *set)
+        .map(|(flag, _)| Value::from(flag))
+        .collect();
+        if !flags.is_empty() {
+            map.insert("flags".to_owned(), Value::Set(flags));
+        }
+        if !self.arguments.is_empty() {
+            map.insert("arguments".to_owned(), args_value(self.arguments));
+        }
+        Value::Map(map)
+    }
+}
+
+fn args_value(args: &[Arg]) -> Value {
+    Value::Array(args.iter().map(|arg| arg.to_value()).collect())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn describes_argument_trees() {
+        const STATUS: Arg = Arg::oneof(
+            "status",
+            &[Arg::pure_token("on", "ON"), Arg::pure_token("off", "OFF")],
+        )
+        .optional();
+        let Value::Map(map) = STATUS.to_value() else {
+            panic!("Expected a map");
+        };
+        assert_eq!(map["type"], Value::from("oneof"));
+        assert_eq!(map["flags"], Value::Set(vec![Value::from("optional")]));
+        assert!(!map.contains_key("display_text"));
+        let Value::Array(nested) = &map["arguments"] else {
+            panic!("Expected nested arguments");
+        };
+        let Value::Map(on) = &nested[0] else {
+            panic!("Expected a map");
+        };
+        assert_eq!(on["token"], Value::from("ON"));
+        assert_eq!(on["type"], Value::from("pure-token"));
+    }
+}
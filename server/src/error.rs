@@ -1,31 +1,194 @@
 use std::io;
 
+use bytes::Bytes;
+
+/// The first word of an error reply. Clients look at it to tell errors
+/// apart, e.g. to decide whether to retry, so these match redis exactly.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+// Scripting, cluster mode and transactions aren't implemented yet,
+// so some kinds are never produced
+#[allow(dead_code)]
+pub enum ErrorKind {
+    Err,
+    WrongType,
+    NoProto,
+    NoScript,
+    Moved,
+    Busy,
+    NoAuth,
+    WrongPass,
+    ExecAbort,
+    Oom,
+}
+
+impl ErrorKind {
+    pub fn prefix(self) -> &'static str {
+        match self {
+            ErrorKind::Err => "ERR",
+            ErrorKind::WrongType => "WRONGTYPE",
+            ErrorKind::NoProto => "NOPROTO",
+            ErrorKind::NoScript => "NOSCRIPT",
+            ErrorKind::Moved => "MOVED",
+            ErrorKind::Busy => "BUSY",
+            ErrorKind::NoAuth => "NOAUTH",
+            ErrorKind::WrongPass => "WRONGPASS",
+            ErrorKind::ExecAbort => "EXECABORT",
+            ErrorKind::Oom => "OOM",
+        }
+    }
+}
+
 #[derive(Debug)]
+// The payloads are only read through the Debug impl, when logging errors
+#[allow(dead_code)]
 pub enum BadMessageError {
     InvalidLength(String),
-    Utf8(std::string::FromUtf8Error),
-    InvalidCommand(String),
+    Utf8(std::str::Utf8Error),
     /**
-     * First argument is the error message sent to the client.
-     * Must be a simple string (i.e. no newlines)
-     * Second argument is only used by the server for debugging
+     * Second argument is the error message sent to the client, after the
+     * kind's prefix. Must be a simple string (i.e. no newlines)
+     * Third argument is only used by the server for debugging
      */
-    Generic(String, String),
+    Generic(ErrorKind, String, String),
+    /// The client sent something that isn't valid RESP. We can't tell where
+    /// the next command starts after that, so the connection gets closed.
+    Protocol(String),
 }
 #[derive(Debug)]
 pub enum Error {
     Io(io::Error),
     BadMessage(BadMessageError),
-    UnexpectedStartOfValue(char),
 }
 impl Error {
     pub fn generic<S: Into<String>, S2: Into<String>>(s: S, internal: S2) -> Error {
+        Error::with_kind(ErrorKind::Err, s, internal)
+    }
+
+    pub fn with_kind<S: Into<String>, S2: Into<String>>(
+        kind: ErrorKind,
+        s: S,
+        internal: S2,
+    ) -> Error {
         let string: String = s.into();
         assert!(
             !string.contains('\r') && !string.contains('\n'),
             "Generic error strings must not contain newlines"
         );
-        Error::BadMessage(BadMessageError::Generic(string, internal.into()))
+        Error::BadMessage(BadMessageError::Generic(kind, string, internal.into()))
+    }
+
+    pub fn protocol<S: Into<String>>(s: S) -> Error {
+        let string: String = s.into();
+        assert!(
+            !string.contains('\r') && !string.contains('\n'),
+            "Protocol error strings must not contain newlines"
+        );
+        Error::BadMessage(BadMessageError::Protocol(string))
+    }
+
+    pub fn wrong_type() -> Error {
+        Error::with_kind(
+            ErrorKind::WrongType,
+            "Operation against a key holding the wrong kind of value",
+            "",
+        )
+    }
+
+    /// `command` is lowercase, with subcommands written as `config|set`
+    pub fn wrong_arity(command: &str) -> Error {
+        Error::generic(
+            format!("wrong number of arguments for '{}' command", command),
+            "",
+        )
+    }
+
+    /// Like redis, echoes back the start of the arguments, so that
+    /// it's easier to tell what the client was trying to do
+    pub fn unknown_command(name: &[u8], args: &[Bytes]) -> Error {
+        let mut echoed = String::new();
+        for arg in args {
+            if echoed.len() >= 128 {
+                break;
+            }
+            let arg = truncate(arg, 128 - echoed.len());
+            echoed.push_str(&format!("'{}' ", String::from_utf8_lossy(arg)));
+        }
+        let message = format!(
+            "unknown command '{}', with args beginning with: {}",
+            String::from_utf8_lossy(truncate(name, 128)),
+            echoed
+        );
+        Error::generic(message.replace(['\r', '\n'], " "), "")
+    }
+
+    pub fn unknown_subcommand(command: &str, subcommand: &str) -> Error {
+        let subcommand = String::from_utf8_lossy(truncate(subcommand.as_bytes(), 128));
+        Error::generic(
+            format!(
+                "unknown subcommand '{}'. Try {} HELP.",
+                subcommand.replace(['\r', '\n'], " "),
+                command
+            ),
+            "",
+        )
+    }
+
+    pub fn no_auth() -> Error {
+        Error::with_kind(ErrorKind::NoAuth, "Authentication required.", "")
+    }
+
+    pub fn wrong_pass() -> Error {
+        Error::with_kind(
+            ErrorKind::WrongPass,
+            "invalid username-password pair or user is disabled.",
+            "",
+        )
+    }
+
+    /// For commands that may use more memory, once the keyspace uses
+    /// more than `maxmemory` and nothing can be evicted
+    pub fn oom() -> Error {
+        Error::with_kind(
+            ErrorKind::Oom,
+            "command not allowed when used memory > 'maxmemory'.",
+            "",
+        )
+    }
+
+    pub fn no_such_key() -> Error {
+        Error::generic("no such key", "")
+    }
+
+    pub fn is_protocol_error(&self) -> bool {
+        matches!(self, Error::BadMessage(BadMessageError::Protocol(_)))
+    }
+
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            Error::BadMessage(BadMessageError::Generic(kind, _, _)) => *kind,
+            _ => ErrorKind::Err,
+        }
+    }
+
+    /// The error as sent to the client, e.g. `ERR syntax error`
+    pub fn reply(&self) -> String {
+        format!("{} {}", self.kind().prefix(), self.message())
+    }
+
+    /// The error without its kind, e.g. `syntax error`
+    pub fn message(&self) -> String {
+        match self {
+            // there's no guarantee that io::Error contains characters that are safe
+            // to send as part of a simple string, so we'll just send a generic error
+            // Besides, this is treated as a server error, not client error.
+            Error::Io(_) => "Internal server error".to_string(),
+            Error::BadMessage(BadMessageError::InvalidLength(_)) => {
+                "Invalid length for a bulk string".to_string()
+            }
+            Error::BadMessage(BadMessageError::Generic(_, s, _)) => s.clone(),
+            Error::BadMessage(BadMessageError::Utf8(_)) => "Invalid UTF-8".to_string(),
+            Error::BadMessage(BadMessageError::Protocol(s)) => format!("Protocol error: {}", s),
+        }
     }
 }
 impl From<io::Error> for Error {
@@ -33,3 +196,48 @@ impl From<io::Error> for Error {
         Error::Io(e)
     }
 }
+
+fn truncate(s: &[u8], len: usize) -> &[u8] {
+    &s[..s.len().min(len)]
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn replies_start_with_the_error_kind() {
+        assert_eq!(
+            Error::wrong_type().reply(),
+            "WRONGTYPE Operation against a key holding the wrong kind of value"
+        );
+        assert_eq!(
+            Error::wrong_arity("get").reply(),
+            "ERR wrong number of arguments for 'get' command"
+        );
+        assert_eq!(
+            Error::protocol("invalid bulk length").reply(),
+            "ERR Protocol error: invalid bulk length"
+        );
+        assert_eq!(
+            Error::with_kind(ErrorKind::NoProto, "unsupported protocol version", "").reply(),
+            "NOPROTO unsupported protocol version"
+        );
+    }
+
+    #[test]
+    fn unknown_commands_echo_sanitized_arguments() {
+        let args = [Bytes::from("a"), Bytes::from("b\r\nc")];
+        assert_eq!(
+            Error::unknown_command(b"foo", &args).reply(),
+            "ERR unknown command 'foo', with args beginning with: 'a' 'b  c' "
+        );
+        let args = vec![Bytes::from(vec![b'x'; 200]); 3];
+        let reply = Error::unknown_command(b"foo", &args).reply();
+        assert!(reply.ends_with(&format!("'{}' ", "x".repeat(128))));
+        assert_eq!(
+            Error::unknown_subcommand("CONFIG", "foo").reply(),
+            "ERR unknown subcommand 'foo'. Try CONFIG HELP."
+        );
+    }
+}
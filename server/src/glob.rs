@@ -0,0 +1,95 @@
+/// Matches `s` against a glob-style pattern, the way redis' `stringmatchlen`
+/// does for `KEYS`, `CONFIG GET` and `COMMAND LIST FILTERBY PATTERN`.
+///
+/// Supports `*`, `?`, character classes like `[a-z]` or `[^abc]`,
+/// and `\` to escape the next character.
+pub fn matches(pattern: &[u8], s: &[u8], nocase: bool) -> bool {
+    let eq = |a: u8, b: u8| {
+        if nocase {
+            a.eq_ignore_ascii_case(&b)
+        } else {
+            a == b
+        }
+    };
+    let (mut p, mut i) = (0, 0);
+    // Where to resume from when the last `*` has to swallow one more byte
+    let mut backtrack: Option<(usize, usize)> = None;
+    while i < s.len() {
+        let matched = match pattern.get(p) {
+            Some(b'*') => {
+                backtrack = Some((p, i));
+                p += 1;
+                continue;
+            }
+            Some(b'?') => Some(p + 1),
+            Some(b'[') => match_class(pattern, p, s[i], nocase),
+            Some(b'\\') if p + 1 < pattern.len() => eq(pattern[p + 1], s[i]).then_some(p + 2),
+            Some(&c) => eq(c, s[i]).then_some(p + 1),
+            None => None,
+        };
+        match (matched, backtrack) {
+            (Some(next), _) => {
+                p = next;
+                i += 1;
+            }
+            (None, Some((star, start))) => {
+                p = star + 1;
+                i = start + 1;
+                backtrack = Some((star, start + 1));
+            }
+            (None, None) => return false,
+        }
+    }
+    pattern[p..].iter().all(|&c| c == b'*')
+}
+
+/// Matches `c` against the class starting at `pattern[start]`, which is a `[`.
+/// Returns where the rest of the pattern starts if it matched.
+fn match_class(pattern: &[u8], start: usize, c: u8, nocase: bool) -> Option<usize> {
+    let fold = |b: u8| if nocase { b.to_ascii_lowercase() } else { b };
+    let c = fold(c);
+    let mut p = start + 1;
+    let negate = pattern.get(p) == Some(&b'^');
+    if negate {
+        p += 1;
+    }
+    let mut matched = false;
+    while p < pattern.len() && pattern[p] != b']' {
+        if pattern[p] == b'\\' && p + 1 < pattern.len() {
+            matched |= fold(pattern[p + 1]) == c;
+            p += 2;
+        } else if p + 2 < pattern.len() && pattern[p + 1] == b'-' && pattern[p + 2] != b']' {
+            let (a, b) = (fold(pattern[p]), fold(pattern[p + 2]));
+            matched |= a.min(b) <= c && c <= a.max(b);
+            p += 3;
+        } else {
+            matched |= fold(pattern[p]) == c;
+            p += 1;
+        }
+    }
+    // Like redis, an unterminated class runs to the end of the pattern
+    (matched != negate).then_some((p + 1).min(pattern.len()))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn matches_wildcards_and_classes() {
+        let m = |pattern: &str, s: &str| matches(pattern.as_bytes(), s.as_bytes(), false);
+        assert!(m("*", ""));
+        assert!(m("h?llo", "hello"));
+        assert!(m("h*o", "hello"));
+        assert!(m("*ll*", "hello"));
+        assert!(!m("h*x", "hello"));
+        assert!(m("h[ae]llo", "hallo"));
+        assert!(!m("h[^e]llo", "hello"));
+        assert!(m("h[a-f]llo", "hello"));
+        assert!(m("h\\*llo", "h*llo"));
+        assert!(!m("h\\*llo", "hello"));
+        assert!(m("client|*", "client|id"));
+        assert!(!m("Get", "get"));
+        assert!(matches(b"Get", b"get", true));
+    }
+}
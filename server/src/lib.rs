@@ -0,0 +1,26 @@
+mod client;
+mod codec;
+pub mod command;
+pub mod config;
+pub mod config_file;
+mod connection;
+pub mod docs;
+pub mod error;
+mod glob;
+pub mod log;
+mod memory;
+pub mod module;
+mod parser;
+mod pubsub;
+mod reactor;
+mod replies;
+pub mod server;
+mod stats;
+mod tracking;
+#[cfg(all(target_os = "linux", feature = "io-uring"))]
+mod uring;
+pub mod value;
+
+pub use dkv_db as db;
+pub use error::Error;
+pub use value::Value;
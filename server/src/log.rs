@@ -0,0 +1,163 @@
+use std::{
+    fmt::Display,
+    fs::{File, OpenOptions},
+    io::{self, Write},
+    sync::Mutex,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// How verbose the server log is, as configured by `loglevel`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum LogLevel {
+    Debug,
+    Verbose,
+    Notice,
+    Warning,
+    /// Disables logging
+    Nothing,
+}
+
+impl LogLevel {
+    pub fn parse(s: &str) -> Option<LogLevel> {
+        match s.to_lowercase().as_str() {
+            "debug" => Some(LogLevel::Debug),
+            "verbose" => Some(LogLevel::Verbose),
+            "notice" => Some(LogLevel::Notice),
+            "warning" => Some(LogLevel::Warning),
+            "nothing" => Some(LogLevel::Nothing),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            LogLevel::Debug => "debug",
+            LogLevel::Verbose => "verbose",
+            LogLevel::Notice => "notice",
+            LogLevel::Warning => "warning",
+            LogLevel::Nothing => "nothing",
+        }
+    }
+
+    /// The character redis puts before each message of this level
+    fn marker(self) -> char {
+        match self {
+            LogLevel::Debug => '.',
+            LogLevel::Verbose => '-',
+            LogLevel::Notice => '*',
+            LogLevel::Warning | LogLevel::Nothing => '#',
+        }
+    }
+}
+
+struct Logger {
+    level: LogLevel,
+    /// None to log to stdout
+    file: Option<File>,
+}
+
+static LOGGER: Mutex<Logger> = Mutex::new(Logger {
+    level: LogLevel::Notice,
+    file: None,
+});
+
+/// Sends messages of `level` or above to `logfile`, or to stdout if it's empty
+pub fn init(level: LogLevel, logfile: &str) -> io::Result<()> {
+    let file = if logfile.is_empty() {
+        None
+    } else {
+        Some(OpenOptions::new().create(true).append(true).open(logfile)?)
+    };
+    *LOGGER.lock().unwrap() = Logger { level, file };
+    Ok(())
+}
+
+pub fn set_level(level: LogLevel) {
+    LOGGER.lock().unwrap().level = level;
+}
+
+/// Writes a line in the same format as redis,
+/// e.g. `1234:M 18 Oct 2026 10:15:42.123 * Ready to accept connections`
+pub fn log(level: LogLevel, message: impl Display) {
+    let mut logger = LOGGER.lock().unwrap();
+    if level < logger.level || level == LogLevel::Nothing {
+        return;
+    }
+    let line = format!(
+        "{}:M {} {} {}\n",
+        std::process::id(),
+        timestamp(),
+        level.marker(),
+        message
+    );
+    // There's nowhere left to report a failure to log to
+    let _ = match &mut logger.file {
+        Some(file) => file.write_all(line.as_bytes()),
+        None => io::stdout().write_all(line.as_bytes()),
+    };
+}
+
+pub fn debug(message: impl Display) {
+    log(LogLevel::Debug, message)
+}
+
+pub fn verbose(message: impl Display) {
+    log(LogLevel::Verbose, message)
+}
+
+pub fn notice(message: impl Display) {
+    log(LogLevel::Notice, message)
+}
+
+pub fn warning(message: impl Display) {
+    log(LogLevel::Warning, message)
+}
+
+/// The current UTC time, formatted like `18 Oct 2026 10:15:42.123`
+fn timestamp() -> String {
+    const MONTHS: [&str; 12] = [
+        "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+    ];
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    let secs = now.as_secs();
+    let (year, month, day) = civil_from_days((secs / 86400) as i64);
+    format!(
+        "{:02} {} {} {:02}:{:02}:{:02}.{:03}",
+        day,
+        MONTHS[month as usize - 1],
+        year,
+        secs % 86400 / 3600,
+        secs % 3600 / 60,
+        secs % 60,
+        now.subsec_millis()
+    )
+}
+
+/// Converts days since the unix epoch to a (year, month, day) date,
+/// using Howard Hinnant's `civil_from_days` algorithm
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn converts_days_to_dates() {
+        assert_eq!(civil_from_days(0), (1970, 1, 1));
+        assert_eq!(civil_from_days(11016), (2000, 2, 29));
+        assert_eq!(civil_from_days(20744), (2026, 10, 18));
+    }
+}
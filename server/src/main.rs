@@ -1,20 +1,60 @@
-use std::net::TcpListener;
-mod codec;
-mod command;
-mod connection;
-mod error;
-mod serializable;
-mod server;
-mod value;
+use std::process::exit;
 
-use error::Error;
-use value::Value;
+use dkv::{
+    config_file::CommandLine,
+    log,
+    server::{self, Server},
+};
 
-use crate::server::Server;
+const USAGE: &str =
+    "Usage: dkv [/path/to/dkv.conf] [--config /path/to/dkv.conf] [--option value...]
+
+Options are the same as config file directives, and override them, e.g.
+    dkv --port 7000 --bind 127.0.0.1 -::1
+    dkv /etc/dkv/dkv.conf --loglevel verbose";
 
 fn main() -> server::Result<()> {
-    let mut server = Server::new(TcpListener::bind("0.0.0.0:6543")?);
-    println!("Listening on port 6543");
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    match args.first().map(String::as_str) {
+        Some("-h" | "--help") => {
+            println!("{}", USAGE);
+            return Ok(());
+        }
+        Some("-v" | "--version") => {
+            println!("dkv v{}", env!("CARGO_PKG_VERSION"));
+            return Ok(());
+        }
+        _ => {}
+    }
+    let mut config = CommandLine::parse(args)
+        .map_err(|e| format!("{}\n\n{}", e, USAGE))
+        .and_then(|command_line| command_line.load().map_err(|e| e.to_string()))
+        .unwrap_or_else(|e| {
+            eprintln!("{}", e);
+            exit(1)
+        });
+    if let Err(e) = log::init(config.loglevel, &config.logfile) {
+        eprintln!("Can't open the log file {}: {}", config.logfile, e);
+        exit(1);
+    }
+    if let Err(e) = std::env::set_current_dir(&config.dir) {
+        log::warning(format!("Can't chdir to '{}': {}", config.dir.display(), e));
+        exit(1);
+    }
+    match &config.config_file {
+        Some(path) => log::notice(format!("Configuration loaded from {}", path.display())),
+        None => log::notice("No config file specified, using the default config"),
+    }
+
+    server::adjust_open_files_limit(&mut config);
+    let mut server = Server::with_config(config).unwrap_or_else(|e| {
+        log::warning(e);
+        exit(1)
+    });
+    for listener in server.listeners() {
+        log::notice(format!("Listening on {}", listener.local_addr()?));
+    }
+    log::notice("Ready to accept connections");
     server.start()?;
     Ok(())
 }
@@ -0,0 +1,184 @@
+//! What `MEMORY STATS` and `MEMORY DOCTOR` report, from the keyspace's
+//! own accounting and the resident memory of the process
+use std::collections::HashMap;
+
+use dkv_db::{EvictionPolicy, MaxMemory, MemoryStats};
+
+use crate::value::Value;
+
+/// Below this, the doctor doesn't look for issues, like redis
+const MIN_DIAGNOSED_MEMORY: usize = 5 * 1024 * 1024;
+
+/// The reply to `MEMORY STATS`. Fields are named like redis' when they mean
+/// the same, and the ones about allocators and replication are left out.
+pub fn stats(stats: &MemoryStats, rss: Option<usize>) -> Value {
+    let overhead = stats.table_overhead + stats.entry_overhead;
+    let mut map = HashMap::new();
+    let mut put = |name: &str, value: Value| {
+        map.insert(name.to_owned(), value);
+    };
+    put("peak.allocated", Value::Integer(stats.peak_memory as i64));
+    put("total.allocated", Value::Integer(stats.used_memory as i64));
+    put(
+        "db.0",
+        Value::Map(HashMap::from([
+            (
+                "overhead.hashtable.main".to_owned(),
+                Value::Integer(stats.table_overhead as i64),
+            ),
+            ("overhead.hashtable.expires".to_owned(), Value::Integer(0)),
+        ])),
+    );
+    put("overhead.total", Value::Integer(overhead as i64));
+    put("keys.count", Value::Integer(stats.keys as i64));
+    put(
+        "keys.bytes-per-key",
+        Value::Integer(stats.used_memory.checked_div(stats.keys).unwrap_or(0) as i64),
+    );
+    put("dataset.bytes", Value::Integer(stats.dataset() as i64));
+    put(
+        "dataset.percentage",
+        Value::Double(percentage(stats.dataset(), stats.used_memory)),
+    );
+    put(
+        "peak.percentage",
+        Value::Double(percentage(stats.used_memory, stats.peak_memory)),
+    );
+    if let Some(rss) = rss {
+        put(
+            "fragmentation",
+            Value::Double(rss as f64 / stats.used_memory.max(1) as f64),
+        );
+        put(
+            "fragmentation.bytes",
+            Value::Integer(rss as i64 - stats.used_memory as i64),
+        );
+    }
+    Value::Map(map)
+}
+
+/// The report of `MEMORY DOCTOR`, which looks for the usual
+/// reasons for the server to use more memory than expected
+pub fn doctor(stats: &MemoryStats, rss: Option<usize>, maxmemory: MaxMemory) -> String {
+    let used = stats.used_memory;
+    if used < MIN_DIAGNOSED_MEMORY {
+        return "This instance is empty or is using very little memory, so there's nothing \
+                for me to diagnose. Try again once it holds some data."
+            .to_owned();
+    }
+    let mut issues = vec![];
+    if stats.peak_memory as f64 > used as f64 * 1.5 {
+        issues.push(format!(
+            "High peak: the keyspace used up to {} in the past, {:.0}% more than the {} it \
+             uses now. Memory freed since may not have been returned to the OS, and \
+             counts towards the RSS.",
+            human(stats.peak_memory),
+            percentage(stats.peak_memory, used) - 100.0,
+            human(used)
+        ));
+    }
+    if let Some(rss) = rss.filter(|rss| *rss as f64 > used as f64 * 1.4) {
+        issues.push(format!(
+            "High RSS overhead: the process uses {}, but the keyspace is estimated at {}. \
+             The rest goes to client buffers, fragmentation, or lists and hashes whose \
+             elements vary in size a lot, which are estimated from a few samples. \
+             `MEMORY USAGE <key> SAMPLES 0` measures them exactly.",
+            human(rss),
+            human(used)
+        ));
+    }
+    let evicts = maxmemory.policy != EvictionPolicy::NoEviction && !maxmemory.policy.is_volatile();
+    if maxmemory.limit > 0 && !evicts && used as f64 > maxmemory.limit as f64 * 0.9 {
+        issues.push(format!(
+            "Close to maxmemory: the keyspace uses {} of its {}, and with maxmemory-policy \
+             {} nothing gets evicted, so commands that need more memory will fail with \
+             OOM errors.",
+            human(used),
+            human(maxmemory.limit),
+            maxmemory.policy.name()
+        ));
+    }
+    if issues.is_empty() {
+        return "I can't find any memory issue in this instance. I can only account for \
+                what the keyspace uses, and for the RSS of the process."
+            .to_owned();
+    }
+    let mut report = String::from("I found a few issues with the memory of this instance:\n\n");
+    for issue in issues {
+        report.push_str(&format!(" * {}\n\n", issue));
+    }
+    report
+}
+
+/// The resident set size of the process, where the OS tells it
+pub fn resident_memory() -> Option<usize> {
+    #[cfg(target_os = "linux")]
+    {
+        let statm = std::fs::read_to_string("/proc/self/statm").ok()?;
+        let pages: usize = statm.split_whitespace().nth(1)?.parse().ok()?;
+        let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) };
+        Some(pages * page_size.max(0) as usize)
+    }
+    #[cfg(not(target_os = "linux"))]
+    None
+}
+
+fn percentage(part: usize, total: usize) -> f64 {
+    part as f64 * 100.0 / total.max(1) as f64
+}
+
+/// Bytes with a unit, like redis' `bytesToHuman`
+fn human(bytes: usize) -> String {
+    let units = [("G", 1 << 30), ("M", 1 << 20), ("K", 1 << 10)];
+    match units.iter().find(|(_, size)| bytes >= *size) {
+        Some((unit, size)) => format!("{:.2}{}", bytes as f64 / *size as f64, unit),
+        None => format!("{}B", bytes),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const MB: usize = 1024 * 1024;
+
+    fn stats(used_memory: usize, peak_memory: usize) -> MemoryStats {
+        MemoryStats {
+            used_memory,
+            peak_memory,
+            keys: 1000,
+            ..MemoryStats::default()
+        }
+    }
+
+    #[test]
+    fn formats_bytes() {
+        assert_eq!(human(100), "100B");
+        assert_eq!(human(1536), "1.50K");
+        assert_eq!(human(3 * MB), "3.00M");
+    }
+
+    #[test]
+    fn diagnoses_memory_issues() {
+        let maxmemory = MaxMemory::default();
+        assert!(doctor(&stats(MB, MB), None, maxmemory).contains("very little memory"));
+        let healthy = doctor(&stats(100 * MB, 120 * MB), Some(110 * MB), maxmemory);
+        assert!(healthy.starts_with("I can't find any memory issue"));
+
+        let maxmemory = MaxMemory {
+            limit: 105 * MB,
+            ..maxmemory
+        };
+        let report = doctor(&stats(100 * MB, 200 * MB), Some(300 * MB), maxmemory);
+        assert!(report.contains("High peak: the keyspace used up to 200.00M in the past, 100%"));
+        assert!(report.contains("High RSS overhead: the process uses 300.00M"));
+        assert!(report.contains("Close to maxmemory: the keyspace uses 100.00M of its 105.00M"));
+
+        let maxmemory = MaxMemory {
+            policy: EvictionPolicy::AllKeysLru,
+            ..maxmemory
+        };
+        let report = doctor(&stats(100 * MB, 200 * MB), Some(300 * MB), maxmemory);
+        assert!(!report.contains("Close to maxmemory"));
+    }
+}
@@ -0,0 +1,431 @@
+//! Extending the server with commands and data types, without touching
+//! the built in command table.
+//!
+//! A module implements [Module] and is either linked into the server binary
+//! and registered with [crate::server::Server::load_module], or built as a
+//! `cdylib` that uses [crate::declare_module] and is loaded at startup with a
+//! `loadmodule <path>` directive, or at runtime with `MODULE LOAD <path>` if
+//! `enable-module-command` allows it. Dynamically loaded modules go through the
+//! Rust ABI, so they must be built with the same compiler and version of this crate.
+use std::{
+    ffi::{c_char, CStr},
+    sync::RwLock,
+};
+
+use bytes::Bytes;
+
+use crate::{
+    client::ClientId,
+    codec::Result,
+    command::{self, CommandSpec},
+    db::DB,
+    Error, Value,
+};
+
+/// Runs a module command. Gets every argument of the request, including
+/// the command's name, and returns the reply.
+pub type ModuleHandler = fn(&mut ModuleContext, &[Bytes]) -> Result<Value>;
+
+pub trait Module: Send + Sync {
+    /// Reported by `MODULE LIST`, and used to unload the module
+    fn name(&self) -> &'static str;
+
+    fn version(&self) -> i64 {
+        1
+    }
+
+    /// The module's commands, built with [CommandSpec::module]. Their names
+    /// must not clash with built in commands or other modules' commands.
+    ///
+    /// Keys are tracked for client side caching from the commands' key specs,
+    /// so module commands don't need to do it themselves: keys of `readonly`
+    /// commands are tracked, and keys of `write` commands are invalidated.
+    fn commands(&self) -> &'static [CommandSpec];
+
+    /// The [crate::db::CustomValue::type_name]s of the values that the module's commands
+    /// store. They must not clash with other modules' types, and like in redis,
+    /// a module with data types can't be unloaded.
+    fn data_types(&self) -> &'static [&'static str] {
+        &[]
+    }
+
+    /// Called before the module's commands are added, with the arguments
+    /// given to `MODULE LOAD`. Returning an error aborts loading.
+    fn on_load(&self, _args: &[String]) -> Result<()> {
+        Ok(())
+    }
+
+    fn on_unload(&self) {}
+}
+
+/// What module commands get to work with
+pub struct ModuleContext<'a> {
+    db: &'a DB,
+    client_id: ClientId,
+}
+
+impl<'a> ModuleContext<'a> {
+    pub(crate) fn new(db: &'a DB, client_id: ClientId) -> Self {
+        ModuleContext { db, client_id }
+    }
+
+    pub fn db(&self) -> &DB {
+        self.db
+    }
+
+    /// The id of the client that called the command, as returned by `CLIENT ID`
+    pub fn client_id(&self) -> u64 {
+        self.client_id
+    }
+}
+
+/// Identifies the Rust ABI that modules are called through: the version of
+/// this crate and the compiler it was built with. The server only calls a
+/// dynamically loaded module if the module was built for the same one.
+pub const ABI_VERSION: &CStr = match CStr::from_bytes_with_nul(
+    concat!(
+        env!("CARGO_PKG_VERSION"),
+        ", ",
+        env!("DKV_RUSTC_VERSION"),
+        "\0"
+    )
+    .as_bytes(),
+) {
+    Ok(version) => version,
+    Err(_) => panic!("the ABI version can't contain nul bytes"),
+};
+
+/// Exports a module from a `cdylib`, so that it can be loaded with `MODULE LOAD`.
+/// Takes an expression that creates the module.
+#[macro_export]
+macro_rules! declare_module {
+    ($module:expr) => {
+        /// Called through the C ABI, so that the server can check it
+        /// before trusting anything else the library exports
+        #[no_mangle]
+        pub extern "C" fn dkv_module_abi_version() -> *const ::std::ffi::c_char {
+            $crate::module::ABI_VERSION.as_ptr()
+        }
+
+        #[no_mangle]
+        pub fn dkv_module_init() -> Box<dyn $crate::module::Module> {
+            Box::new($module)
+        }
+    };
+}
+
+struct LoadedModule {
+    module: Box<dyn Module>,
+    /// None for modules linked into the server
+    path: Option<String>,
+    args: Vec<String>,
+}
+
+/// The modules loaded into the server
+#[derive(Default)]
+pub struct ModuleRegistry {
+    modules: RwLock<Vec<LoadedModule>>,
+}
+
+impl ModuleRegistry {
+    pub(crate) fn load(
+        &self,
+        module: Box<dyn Module>,
+        path: Option<String>,
+        args: Vec<String>,
+    ) -> Result<()> {
+        let mut modules = self.modules.write().unwrap();
+        if modules.iter().any(|it| it.module.name() == module.name()) {
+            return Err(Error::generic(
+                format!("module '{}' is already loaded", module.name()),
+                "",
+            ));
+        }
+        for spec in module.commands() {
+            let name = spec.name.as_bytes();
+            let clashes = command::COMMANDS
+                .iter()
+                .chain(modules.iter().flat_map(|it| it.module.commands()))
+                .any(|other| other.name.as_bytes().eq_ignore_ascii_case(name));
+            if clashes {
+                return Err(Error::generic(
+                    format!("command '{}' already exists", spec.name),
+                    "",
+                ));
+            }
+        }
+        for data_type in module.data_types() {
+            let clashes = modules
+                .iter()
+                .flat_map(|it| it.module.data_types())
+                .any(|other| other == data_type);
+            if clashes {
+                return Err(Error::generic(
+                    format!("data type '{}' already exists", data_type),
+                    "",
+                ));
+            }
+        }
+        module.on_load(&args)?;
+        modules.push(LoadedModule { module, path, args });
+        Ok(())
+    }
+
+    /// Loads a module from a `cdylib` that uses [crate::declare_module]
+    pub(crate) fn load_dynamic(&self, path: &str, args: Vec<String>) -> Result<()> {
+        let module = open_dynamic(path)?;
+        self.load(module, Some(path.to_owned()), args)
+    }
+
+    pub(crate) fn unload(&self, name: &str) -> Result<()> {
+        let mut modules = self.modules.write().unwrap();
+        let Some(index) = modules.iter().position(|it| it.module.name() == name) else {
+            return Err(Error::generic(
+                "Error unloading module: no such module with that name",
+                name,
+            ));
+        };
+        // Like redis, since keys of the module's types would become unusable
+        if !modules[index].module.data_types().is_empty() {
+            return Err(Error::generic(
+                "Error unloading module: the module exports one or more module-side data types, can't unload",
+                name,
+            ));
+        }
+        modules.remove(index).module.on_unload();
+        Ok(())
+    }
+
+    /// Looks up a module command by name, ignoring case
+    pub(crate) fn find_command(&self, name: &[u8]) -> Option<&'static CommandSpec> {
+        self.commands()
+            .into_iter()
+            .find(|spec| spec.name.as_bytes().eq_ignore_ascii_case(name))
+    }
+
+    /// The commands of every module, without their subcommands
+    pub(crate) fn commands(&self) -> Vec<&'static CommandSpec> {
+        let modules = self.modules.read().unwrap();
+        modules.iter().flat_map(|it| it.module.commands()).collect()
+    }
+
+    /// The name of the module that a command or subcommand comes from
+    pub(crate) fn module_of(&self, spec: &CommandSpec) -> Option<&'static str> {
+        let modules = self.modules.read().unwrap();
+        modules
+            .iter()
+            .find(|it| {
+                it.module.commands().iter().any(|command| {
+                    std::ptr::eq(command, spec)
+                        || command
+                            .subcommands
+                            .iter()
+                            .any(|sub| std::ptr::eq(sub, spec))
+                })
+            })
+            .map(|it| it.module.name())
+    }
+
+    /// The reply to `MODULE LIST`
+    pub(crate) fn list(&self) -> Value {
+        let modules = self.modules.read().unwrap();
+        Value::Array(
+            modules
+                .iter()
+                .map(|it| {
+                    let args = it.args.iter().map(Value::from).collect();
+                    Value::Map(
+                        [
+                            ("name", Value::from(it.module.name())),
+                            ("ver", Value::Integer(it.module.version())),
+                            ("path", Value::from(it.path.as_deref().unwrap_or(""))),
+                            ("args", Value::Array(args)),
+                        ]
+                        .into_iter()
+                        .map(|(key, value)| (key.to_owned(), value))
+                        .collect(),
+                    )
+                })
+                .collect(),
+        )
+    }
+}
+
+#[cfg(unix)]
+fn open_dynamic(path: &str) -> Result<Box<dyn Module>> {
+    let dl_error = || {
+        // SAFETY: dlerror returns either null or a valid C string
+        let message = unsafe { libc::dlerror() };
+        if message.is_null() {
+            String::from("unknown error")
+        } else {
+            // SAFETY: checked for null above
+            unsafe { std::ffi::CStr::from_ptr(message) }
+                .to_string_lossy()
+                .into_owned()
+        }
+    };
+    let c_path =
+        std::ffi::CString::new(path).map_err(|_| Error::generic("invalid module path", path))?;
+    // SAFETY: c_path is a valid C string. Loading runs the library's initializers,
+    // which is what loading a module is meant to do.
+    let handle = unsafe { libc::dlopen(c_path.as_ptr(), libc::RTLD_NOW | libc::RTLD_LOCAL) };
+    if handle.is_null() {
+        return Err(Error::generic(dl_error().replace(['\r', '\n'], " "), path));
+    }
+    let close = |message: String| {
+        // SAFETY: nothing the library exports has been kept
+        unsafe { libc::dlclose(handle) };
+        Err(Error::generic(message, path))
+    };
+    // SAFETY: handle was returned by dlopen, and the symbol names are valid C strings
+    let (abi_version, init) = unsafe {
+        (
+            libc::dlsym(handle, c"dkv_module_abi_version".as_ptr()),
+            libc::dlsym(handle, c"dkv_module_init".as_ptr()),
+        )
+    };
+    if abi_version.is_null() || init.is_null() {
+        return close("the library doesn't export a module, see dkv::declare_module".to_owned());
+    }
+    // SAFETY: dkv_module_abi_version is defined by declare_module with this signature,
+    // through the C ABI so that it can be called whatever the module was built with.
+    // It returns a pointer to the module's ABI_VERSION, a valid C string.
+    let abi_version = unsafe {
+        let abi_version =
+            std::mem::transmute::<*mut libc::c_void, extern "C" fn() -> *const c_char>(abi_version);
+        CStr::from_ptr(abi_version())
+    };
+    if abi_version != ABI_VERSION {
+        return close(format!(
+            "the module was built for dkv {}, but this is dkv {}",
+            abi_version.to_string_lossy(),
+            ABI_VERSION.to_string_lossy()
+        ));
+    }
+    // SAFETY: dkv_module_init is defined by declare_module with this signature, and
+    // the module uses the same Rust ABI as the server, as checked above.
+    // The library is never closed, since its code ends up in the command table.
+    let init = unsafe { std::mem::transmute::<*mut libc::c_void, fn() -> Box<dyn Module>>(init) };
+    Ok(init())
+}
+
+#[cfg(not(unix))]
+fn open_dynamic(path: &str) -> Result<Box<dyn Module>> {
+    Err(Error::generic(
+        "loading modules at runtime is only supported on unix",
+        path,
+    ))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        command::KeySpec,
+        db::{self, CustomValue},
+    };
+
+    #[derive(Debug, Clone)]
+    struct Counter(i64);
+
+    impl CustomValue for Counter {
+        fn type_name(&self) -> &'static str {
+            "counter"
+        }
+
+        fn clone_box(&self) -> Box<dyn CustomValue> {
+            Box::new(self.clone())
+        }
+    }
+
+    fn incr(context: &mut ModuleContext, args: &[Bytes]) -> Result<Value> {
+        let key = command::to_string(&args[1])?;
+        let value = context.db().mutate(&key, |value| match value {
+            Some(value) => value
+                .downcast_mut::<Counter>()
+                .map(|counter| {
+                    counter.0 += 1;
+                    counter.0
+                })
+                .ok_or_else(Error::wrong_type),
+            None => Ok(0),
+        })?;
+        if value == 0 {
+            context
+                .db()
+                .set(key, db::Value::Custom(Box::new(Counter(1))));
+        }
+        Ok(Value::Integer(value.max(1)))
+    }
+
+    struct CounterModule;
+
+    impl Module for CounterModule {
+        fn name(&self) -> &'static str {
+            "counter"
+        }
+
+        fn commands(&self) -> &'static [CommandSpec] {
+            static COMMANDS: &[CommandSpec] = &[CommandSpec::module("counter.incr", 2, incr)
+                .keys(&[KeySpec::single(1, &["RW", "update"])])];
+            COMMANDS
+        }
+    }
+
+    struct ClashingModule;
+
+    impl Module for ClashingModule {
+        fn name(&self) -> &'static str {
+            "clashing"
+        }
+
+        fn commands(&self) -> &'static [CommandSpec] {
+            static COMMANDS: &[CommandSpec] = &[CommandSpec::module("GET", 2, incr)];
+            COMMANDS
+        }
+    }
+
+    #[test]
+    fn registers_module_commands() -> Result<()> {
+        let modules = ModuleRegistry::default();
+        modules.load(Box::new(CounterModule), None, vec![])?;
+        let spec = modules.find_command(b"COUNTER.INCR").unwrap();
+        assert_eq!(modules.module_of(spec), Some("counter"));
+        assert!(modules.load(Box::new(CounterModule), None, vec![]).is_err());
+        assert!(modules
+            .load(Box::new(ClashingModule), None, vec![])
+            .is_err());
+
+        modules.unload("counter")?;
+        assert!(modules.find_command(b"counter.incr").is_none());
+        assert!(modules.unload("counter").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn module_commands_store_custom_values() -> Result<()> {
+        let db = DB::new();
+        let mut context = ModuleContext::new(&db, 1);
+        let args = [Bytes::from("counter.incr"), Bytes::from("key")];
+        assert_eq!(incr(&mut context, &args)?, Value::Integer(1));
+        assert_eq!(incr(&mut context, &args)?, Value::Integer(2));
+        db.set("key".to_owned(), db::Value::from("string"));
+        assert!(incr(&mut context, &args).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn rejects_libraries_that_cant_be_loaded() {
+        let modules = ModuleRegistry::default();
+        assert!(modules
+            .load_dynamic("/nonexistent/module.so", vec![])
+            .is_err());
+        #[cfg(target_os = "linux")]
+        assert!(modules
+            .load_dynamic("libc.so.6", vec![])
+            .unwrap_err()
+            .message()
+            .contains("doesn't export a module"));
+    }
+}
@@ -0,0 +1,409 @@
+use std::{
+    io::{self, Read},
+    ops::Range,
+};
+
+use bytes::{Bytes, BytesMut};
+
+use crate::{
+    codec::{split_args, ProtocolLimits, Result, INLINE_MAX_SIZE},
+    error::Error,
+};
+
+/// How much we try to read from the socket at once
+const READ_CHUNK_SIZE: usize = 16 * 1024;
+
+/// The arguments of a request. They're views into the buffer the request was
+/// received in, so that big values aren't copied until they're stored.
+pub type Request = Vec<Bytes>;
+
+/// Incrementally parses requests out of the bytes received from a client.
+///
+/// Unlike the blocking readers in [crate::codec], the parser never loses
+/// bytes when a request is only partially received: it remembers how far it
+/// got, and picks up from there once more data arrives. That makes it
+/// usable with non-blocking sockets, read timeouts and event loops.
+pub struct RequestParser {
+    /// Starts with the request currently being parsed. Parsed requests are
+    /// split off the front, which doesn't copy anything.
+    buffer: BytesMut,
+    /// Start of the bytes that haven't been parsed yet
+    position: usize,
+    state: State,
+    limits: ProtocolLimits,
+}
+
+enum State {
+    /// Waiting for the first byte of a request
+    Idle,
+    /// Reading the arguments of a multibulk request
+    Multibulk {
+        remaining: usize,
+        /// Where each argument read so far is in `buffer`
+        args: Vec<Range<usize>>,
+        /// Length of the next argument, once its `$<len>` line was read
+        bulk_len: Option<usize>,
+    },
+}
+
+impl RequestParser {
+    pub fn new(limits: ProtocolLimits) -> Self {
+        RequestParser {
+            buffer: BytesMut::new(),
+            position: 0,
+            state: State::Idle,
+            limits,
+        }
+    }
+
+    /// Updates the limits, which apply from the next request on
+    pub fn set_limits(&mut self, limits: ProtocolLimits) {
+        self.limits = limits;
+    }
+
+    /// Appends bytes received from the client
+    #[cfg(test)]
+    pub fn feed(&mut self, bytes: &[u8]) {
+        self.buffer.extend_from_slice(bytes);
+    }
+
+    /// Does a single read from `stream` into the parser's buffer.
+    /// Returns the number of bytes read, which is 0 at the end of the stream.
+    pub fn read_from(&mut self, stream: &mut impl Read) -> io::Result<usize> {
+        let start = self.buffer.len();
+        self.buffer.resize(start + READ_CHUNK_SIZE, 0);
+        let result = stream.read(&mut self.buffer[start..]);
+        self.buffer.truncate(start + *result.as_ref().unwrap_or(&0));
+        result
+    }
+
+    /// Parses the next request, either an array of bulk strings or an inline
+    /// command. Returns None if the buffer doesn't contain a full request yet.
+    ///
+    /// Like redis, empty arrays and empty lines are skipped. After a protocol
+    /// error, the state of the parser is unspecified.
+    pub fn next_request(&mut self) -> Result<Option<Request>> {
+        loop {
+            match &mut self.state {
+                State::Idle => {
+                    let Some(&first) = self.buffer.get(self.position) else {
+                        return Ok(None);
+                    };
+                    if first != b'*' {
+                        match self.parse_inline()? {
+                            None => return Ok(None),
+                            Some(args) if args.is_empty() => continue,
+                            Some(args) => return Ok(Some(args)),
+                        }
+                    }
+                    let Some(line) = self.line(self.position + 1)? else {
+                        return Ok(None);
+                    };
+                    let len = parse_length(
+                        &self.buffer[line.clone()],
+                        self.limits.max_multibulk_len,
+                        "invalid multibulk length",
+                    )?
+                    .unwrap_or(0);
+                    self.position = line.end + 2;
+                    if len == 0 {
+                        self.consume();
+                    } else {
+                        self.state = State::Multibulk {
+                            remaining: len,
+                            // The length comes from the client, so don't trust it too much
+                            args: Vec::with_capacity(len.min(1024)),
+                            bulk_len: None,
+                        };
+                    }
+                }
+                State::Multibulk { bulk_len: None, .. } => {
+                    let Some(&first) = self.buffer.get(self.position) else {
+                        return Ok(None);
+                    };
+                    if first != b'$' {
+                        return Err(Error::protocol(format!(
+                            "expected '$', got '{}'",
+                            (first as char).escape_default()
+                        )));
+                    }
+                    let Some(line) = self.line(self.position + 1)? else {
+                        return Ok(None);
+                    };
+                    let len = parse_length(
+                        &self.buffer[line.clone()],
+                        self.limits.max_bulk_len,
+                        "invalid bulk length",
+                    )?
+                    .ok_or_else(|| Error::protocol("invalid bulk length"))?;
+                    self.position = line.end + 2;
+                    if let State::Multibulk { bulk_len, .. } = &mut self.state {
+                        *bulk_len = Some(len);
+                    }
+                }
+                State::Multibulk {
+                    remaining,
+                    args,
+                    bulk_len: bulk_len @ Some(_),
+                } => {
+                    let len = bulk_len.unwrap();
+                    let end = self.position + len;
+                    if self.buffer.len() < end + 2 {
+                        // Make sure a big argument only needs a single allocation
+                        self.buffer.reserve(end + 2 - self.buffer.len());
+                        return Ok(None);
+                    }
+                    if &self.buffer[end..end + 2] != b"\r\n" {
+                        return Err(Error::protocol("expected '\\r\\n' after bulk string"));
+                    }
+                    args.push(self.position..end);
+                    self.position = end + 2;
+                    *bulk_len = None;
+                    *remaining -= 1;
+                    if *remaining == 0 {
+                        let args = std::mem::take(args);
+                        self.state = State::Idle;
+                        let request = self.consume();
+                        return Ok(Some(
+                            args.into_iter().map(|arg| request.slice(arg)).collect(),
+                        ));
+                    }
+                }
+            }
+        }
+    }
+
+    /// Parses an inline command (e.g. `SET key "hello world"`) typed into telnet
+    fn parse_inline(&mut self) -> Result<Option<Request>> {
+        let unparsed = &self.buffer[self.position..];
+        let Some(newline) = unparsed.iter().position(|b| *b == b'\n') else {
+            if unparsed.len() > INLINE_MAX_SIZE {
+                return Err(Error::protocol("too big inline request"));
+            }
+            return Ok(None);
+        };
+        self.position += newline + 1;
+        let line = self.consume();
+        let mut line = &line[..newline];
+        if line.last() == Some(&b'\r') {
+            line = &line[..line.len() - 1];
+        }
+        split_args(line)
+            .map(|args| Some(args.into_iter().map(Bytes::from).collect()))
+            .ok_or_else(|| Error::protocol("unbalanced quotes in request"))
+    }
+
+    /// Finds the line starting at `start`, returning the range of its content
+    /// without the terminating `\r\n`, or None if it wasn't fully received yet.
+    fn line(&self, start: usize) -> Result<Option<Range<usize>>> {
+        let unparsed = self.buffer.get(start..).unwrap_or_default();
+        match unparsed.iter().position(|b| *b == b'\r') {
+            None if unparsed.len() > INLINE_MAX_SIZE => Err(Error::protocol("too big line")),
+            None => Ok(None),
+            Some(end) => match unparsed.get(end + 1) {
+                None => Ok(None),
+                Some(b'\n') => Ok(Some(start..start + end)),
+                Some(_) => Err(Error::protocol("expected '\\n' after '\\r'")),
+            },
+        }
+    }
+
+    /// Splits the bytes that were parsed off the buffer
+    fn consume(&mut self) -> Bytes {
+        let parsed = self.buffer.split_to(self.position).freeze();
+        self.position = 0;
+        parsed
+    }
+}
+
+/// Parses a length where -1 means null
+fn parse_length(line: &[u8], max: usize, message: &str) -> Result<Option<usize>> {
+    if line == b"-1" {
+        return Ok(None);
+    }
+    std::str::from_utf8(line)
+        .ok()
+        .and_then(|it| it.parse::<usize>().ok())
+        .filter(|len| *len <= max)
+        .map(Some)
+        .ok_or_else(|| Error::protocol(message))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn parse_all(input: &[u8]) -> Result<Vec<Request>> {
+        let mut parser = RequestParser::new(ProtocolLimits::default());
+        parser.feed(input);
+        let mut requests = vec![];
+        while let Some(request) = parser.next_request()? {
+            requests.push(request);
+        }
+        Ok(requests)
+    }
+
+    #[test]
+    fn can_parse_pipelined_requests() -> Result<()> {
+        let input = b"*2\r\n$3\r\nGET\r\n$3\r\nkey\r\n\r\nSET key \"hello world\"\r\nPING\n";
+        assert_eq!(
+            parse_all(input)?,
+            vec![
+                vec!["GET", "key"],
+                vec!["SET", "key", "hello world"],
+                vec!["PING"]
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn resumes_partial_requests() -> Result<()> {
+        let input = b"*2\r\n$3\r\nGET\r\n$11\r\nhello world\r\nPING\r\n";
+        let mut parser = RequestParser::new(ProtocolLimits::default());
+        let mut requests = vec![];
+        for byte in input {
+            parser.feed(&[*byte]);
+            if let Some(request) = parser.next_request()? {
+                requests.push(request);
+            }
+        }
+        assert_eq!(requests, vec![vec!["GET", "hello world"], vec!["PING"]]);
+        assert!(parser.buffer.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn parses_binary_inline_arguments() -> Result<()> {
+        let input = b"SET key \"\\xff\\x00\" \xfe\r\n";
+        assert_eq!(
+            parse_all(input)?,
+            vec![vec![&b"SET"[..], b"key", &[0xff, 0x00], &[0xfe]]]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn skips_empty_requests() -> Result<()> {
+        let input = b"*0\r\n*-1\r\n\r\n\r\n*1\r\n$4\r\nPING\r\n";
+        assert_eq!(parse_all(input)?, vec![vec!["PING"]]);
+        Ok(())
+    }
+
+    #[test]
+    fn arguments_share_the_input_buffer() -> Result<()> {
+        let value = vec![b'x'; 1024 * 1024];
+        let header = b"*3\r\n$3\r\nSET\r\n$3\r\nkey\r\n$1048576\r\n";
+        let mut parser = RequestParser::new(ProtocolLimits::default());
+        parser.feed(&[&header[..], &value, b"\r\n"].concat());
+        let start = parser.buffer.as_ptr();
+        let request = parser.next_request()?.unwrap();
+        assert_eq!(request[2], value);
+        assert_eq!(request[2].as_ptr(), start.wrapping_add(header.len()));
+        Ok(())
+    }
+
+    #[test]
+    fn rejects_malformed_requests_with_protocol_errors() {
+        let limits = ProtocolLimits {
+            max_bulk_len: 8,
+            max_multibulk_len: 2,
+        };
+        for input in [
+            &b"*1\r\n$9\r\n"[..],
+            b"*3\r\n",
+            b"*1\r\n:1\r\n",
+            b"*x\r\n",
+            b"*1\r\n$-1\r\n",
+            b"*1\r\n$1\r\nab\r\n",
+            b"*1\r\r\n",
+            b"\"unbalanced\r\n",
+            &[b'a'; INLINE_MAX_SIZE + 2],
+            &[b'*'; INLINE_MAX_SIZE + 2],
+        ] {
+            let mut parser = RequestParser::new(limits);
+            parser.feed(input);
+            assert!(parser.next_request().unwrap_err().is_protocol_error());
+        }
+    }
+
+    /// Feeds mutated and random byte streams to the parser, in random chunks.
+    /// It must never panic, and must produce the same requests as when the
+    /// whole input is available at once.
+    #[test]
+    fn survives_fuzzed_input() {
+        let corpus: [&[u8]; 9] = [
+            b"*2\r\n$3\r\nGET\r\n$3\r\nkey\r\n",
+            b"SET key \"hello\\x41 world\" 'a'\r\n",
+            b"*-1\r\n*0\r\n*1\r\n$0\r\n\r\n",
+            b"*3\r\n$3\r\nSET\r\n$1\r\na\r\n$10\r\n0123456789\r\n",
+            b"\r\n\n PING \r\n",
+            // RESP3 types that clients aren't supposed to send
+            b"%2\r\n+a\r\n,1.5\r\n$1\r\nb\r\n#t\r\n",
+            b"*2\r\n|1\r\n+ttl\r\n:-10\r\n~2\r\n(123\r\n_\r\n",
+            b"*1\r\n*1\r\n*1\r\n*1\r\n:1\r\n",
+            b"",
+        ];
+        let alphabet = b"*$%~>|:,#(=!_+-\r\n0123456789ab\"' \\x";
+        let limits = ProtocolLimits {
+            max_bulk_len: 1024,
+            max_multibulk_len: 16,
+        };
+        // xorshift, so that every run uses the same inputs
+        let mut state = 0x2545f4914f6cdd1d_u64;
+        let mut random = move |n: usize| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            (state % n.max(1) as u64) as usize
+        };
+        let parse = |chunks: &[&[u8]]| {
+            let mut parser = RequestParser::new(limits);
+            let mut requests = vec![];
+            for chunk in chunks {
+                parser.feed(chunk);
+                loop {
+                    match parser.next_request() {
+                        Ok(Some(request)) => requests.push(Ok(request)),
+                        Ok(None) => break,
+                        Err(e) if e.is_protocol_error() => {
+                            requests.push(Err(()));
+                            return requests;
+                        }
+                        Err(_) => requests.push(Err(())),
+                    }
+                }
+            }
+            requests
+        };
+
+        for i in 0..20_000 {
+            let mut input = [corpus[i % corpus.len()], corpus[random(corpus.len())]].concat();
+            if i % 7 == 0 {
+                input = (0..random(64)).map(|_| random(256) as u8).collect();
+            }
+            for _ in 0..random(4) + 1 {
+                let position = random(input.len() + 1);
+                let byte = alphabet[random(alphabet.len())];
+                match random(4) {
+                    0 => input.insert(position, byte),
+                    1 if position < input.len() => input[position] = byte,
+                    2 => input.truncate(position),
+                    _ if position < input.len() => {
+                        input.remove(position);
+                    }
+                    _ => {}
+                }
+            }
+
+            let mut chunks = vec![];
+            let mut rest = &input[..];
+            while !rest.is_empty() {
+                let (chunk, tail) = rest.split_at(random(rest.len()) + 1);
+                chunks.push(chunk);
+                rest = tail;
+            }
+            assert_eq!(parse(&chunks), parse(&[&input]), "{:?}", input);
+        }
+    }
+}
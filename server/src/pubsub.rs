@@ -0,0 +1,166 @@
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex},
+    time::Instant,
+};
+
+use crate::{codec, config::OutputBufferLimit, stats::Stats, value::Value};
+
+/// Messages waiting to be written to a client, like the ones published to its
+/// channels and client side caching invalidations.
+///
+/// Other clients push into this buffer from their own threads, and the client's
+/// connection drains it. If the client can't keep up, the buffer is closed
+/// according to `client-output-buffer-limit` and the client gets disconnected
+/// instead of letting the queue grow without bound.
+pub struct OutputBuffer {
+    stats: Arc<Stats>,
+    inner: Mutex<OutputBufferInner>,
+}
+
+struct OutputBufferInner {
+    values: VecDeque<Value>,
+    size: usize,
+    soft_limit_reached_at: Option<Instant>,
+    closed: bool,
+}
+
+impl OutputBuffer {
+    pub fn new(stats: Arc<Stats>) -> OutputBuffer {
+        OutputBuffer {
+            stats,
+            inner: Mutex::new(OutputBufferInner {
+                values: VecDeque::new(),
+                size: 0,
+                soft_limit_reached_at: None,
+                closed: false,
+            }),
+        }
+    }
+
+    /// Queues a value for the subscriber, closing the buffer if that goes past
+    /// `limit`, which is passed every time so that `CONFIG SET` applies to
+    /// existing subscribers. Values pushed after the buffer has been closed are dropped.
+    pub fn push(&self, value: Value, limit: OutputBufferLimit) {
+        let mut inner = self.inner.lock().unwrap();
+        if inner.closed {
+            Stats::incr(&self.stats.pubsub_messages_dropped);
+            return;
+        }
+        inner.size += serialized_size(&value);
+        inner.values.push_back(value);
+        if self.limit_reached(&mut inner, limit) {
+            let dropped = inner.values.len() as u64;
+            inner.closed = true;
+            inner.values.clear();
+            inner.size = 0;
+            Stats::incr(&self.stats.client_output_buffer_limit_disconnections);
+            self.stats
+                .pubsub_messages_dropped
+                .fetch_add(dropped, std::sync::atomic::Ordering::Relaxed);
+        }
+    }
+
+    /// Takes every queued value, or returns None if the client
+    /// exceeded its limits and should be disconnected.
+    pub fn drain(&self) -> Option<Vec<Value>> {
+        let mut inner = self.inner.lock().unwrap();
+        if inner.closed {
+            return None;
+        }
+        inner.size = 0;
+        inner.soft_limit_reached_at = None;
+        Some(inner.values.drain(..).collect())
+    }
+
+    fn limit_reached(&self, inner: &mut OutputBufferInner, limit: OutputBufferLimit) -> bool {
+        let hard = limit.hard_limit_bytes > 0 && inner.size >= limit.hard_limit_bytes;
+        let mut soft = limit.soft_limit_bytes > 0 && inner.size >= limit.soft_limit_bytes;
+        if soft {
+            match inner.soft_limit_reached_at {
+                None => {
+                    inner.soft_limit_reached_at = Some(Instant::now());
+                    soft = false;
+                }
+                Some(reached_at) => {
+                    if reached_at.elapsed().as_secs() < limit.soft_limit_seconds {
+                        soft = false;
+                    }
+                }
+            }
+        } else {
+            inner.soft_limit_reached_at = None;
+        }
+        hard || soft
+    }
+}
+
+fn serialized_size(value: &Value) -> usize {
+    let mut buf = vec![];
+    codec::write(value, &mut buf).expect("Writing to a Vec can't fail");
+    buf.len()
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::atomic::Ordering;
+
+    use super::*;
+
+    fn message() -> Value {
+        Value::Array(vec![
+            Value::from("message"),
+            Value::from("channel"),
+            Value::from("hello"),
+        ])
+    }
+
+    #[test]
+    fn closes_buffer_after_hard_limit() {
+        let stats = Arc::new(Stats::default());
+        let buffer = OutputBuffer::new(stats.clone());
+        for _ in 0..10 {
+            buffer.push(message(), OutputBufferLimit::new(100, 0, 0));
+        }
+        assert!(buffer.drain().is_none());
+        assert_eq!(
+            stats
+                .client_output_buffer_limit_disconnections
+                .load(Ordering::Relaxed),
+            1
+        );
+        assert!(stats.pubsub_messages_dropped.load(Ordering::Relaxed) > 0);
+    }
+
+    #[test]
+    fn allows_soft_limit_for_soft_seconds() {
+        let stats = Arc::new(Stats::default());
+        let buffer = OutputBuffer::new(stats);
+        let limit = OutputBufferLimit::new(0, 10, 60);
+        buffer.push(message(), limit);
+        buffer.push(message(), limit);
+        assert_eq!(buffer.drain().map(|it| it.len()), Some(2));
+    }
+
+    #[test]
+    fn applies_limits_lowered_after_subscribing() {
+        let stats = Arc::new(Stats::default());
+        let buffer = OutputBuffer::new(stats);
+        buffer.push(message(), OutputBufferLimit::new(0, 0, 0));
+        buffer.push(message(), OutputBufferLimit::new(0, 0, 0));
+        assert_eq!(buffer.drain().map(|it| it.len()), Some(2));
+        buffer.push(message(), OutputBufferLimit::new(0, 0, 0));
+        buffer.push(message(), OutputBufferLimit::new(10, 0, 0));
+        assert!(buffer.drain().is_none());
+    }
+
+    #[test]
+    fn closes_buffer_once_soft_limit_is_exceeded_for_too_long() {
+        let stats = Arc::new(Stats::default());
+        let buffer = OutputBuffer::new(stats);
+        let limit = OutputBufferLimit::new(0, 10, 0);
+        buffer.push(message(), limit);
+        buffer.push(message(), limit);
+        assert!(buffer.drain().is_none());
+    }
+}
@@ -0,0 +1,496 @@
+//! The `io-model reactor` driver: a single thread multiplexes every client's
+//! non blocking socket with an [EventLoop], like redis does.
+//!
+//! Commands are still executed by [Connection], the reactor only moves bytes
+//! between the sockets and the connections' buffers. Other threads, e.g. clients
+//! publishing messages from a module thread, can't touch the sockets, so what
+//! they send is queued in the client's `ClientHandle` and the loop gets woken up for it.
+//!
+//! Clients that had events are handled in batches once the loop has dispatched
+//! them, like in redis 6: their sockets are read and their requests are parsed,
+//! then their commands run one client after the other, and finally their replies
+//! are serialized and written. With `io-threads` above 1, reading and writing
+//! are spread over [IoThreads], while commands keep running on the loop's thread.
+use std::{
+    cell::{OnceCell, RefCell},
+    collections::HashMap,
+    io::{self, Write},
+    net::{TcpListener, TcpStream},
+    os::fd::AsRawFd,
+    rc::Rc,
+    sync::{
+        mpsc::{self, Receiver, Sender},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use event_loop::{Event, EventLoop, Interest, Token};
+
+use crate::{
+    client::{ClientId, ClientOutput, ReadyClients},
+    connection::{Connection, Progress},
+    log,
+    replies::Replies,
+    server::{ServerState, CRON_INTERVAL},
+};
+
+/// How much is read from a client before moving on to the others
+const MAX_READ_PER_TURN: usize = 1024 * 1024;
+
+struct Reactor {
+    state: Arc<ServerState>,
+    clients: RefCell<HashMap<ClientId, Client>>,
+    /// Clients to handle once the loop dispatched the current events
+    pending: RefCell<Vec<ClientId>>,
+    io_threads: IoThreads<Client>,
+    /// Set once the loop's waker exists, which needs the reactor for its callback
+    ready: OnceCell<Arc<ReadyClients>>,
+}
+
+/// A client, along with the socket that only the reactor's threads touch
+struct Client {
+    stream: TcpStream,
+    token: Token,
+    interest: Interest,
+    connection: Connection,
+    /// Replies of the last commands, not serialized yet
+    replies: Replies,
+    /// What the socket didn't accept yet, starting at `written`
+    output: Vec<u8>,
+    written: usize,
+    /// The socket has something to read
+    readable: bool,
+    /// Waiting in [Reactor::pending]
+    scheduled: bool,
+    /// Some requests weren't handled yet, because output piled up
+    more: bool,
+    /// Set once the client has to be disconnected, after writing its output
+    closing: bool,
+    /// The client disconnected, or its socket failed
+    failed: bool,
+    last_interaction: Instant,
+}
+
+/// Serves every client from the current thread, until an error
+/// happens with the event loop itself
+pub fn run(state: Arc<ServerState>, listeners: &[TcpListener]) -> io::Result<()> {
+    let event_loop = EventLoop::new()?;
+    let io_threads = IoThreads::new(state.config.read().io_threads)?;
+    let reactor = Rc::new(Reactor {
+        state,
+        clients: RefCell::new(HashMap::new()),
+        pending: RefCell::new(vec![]),
+        io_threads,
+        ready: OnceCell::new(),
+    });
+
+    let waker = {
+        let reactor = reactor.clone();
+        event_loop.waker(move |_, _| {
+            for id in reactor.ready().take() {
+                reactor.schedule(id, false);
+            }
+        })?
+    };
+    let _ = reactor
+        .ready
+        .set(Arc::new(ReadyClients::new(move || waker.wake())));
+
+    for listener in listeners {
+        let listener = listener.try_clone()?;
+        listener.set_nonblocking(true)?;
+        let reactor = reactor.clone();
+        event_loop.register(
+            listener.as_raw_fd(),
+            Interest::READABLE,
+            move |event_loop, _| reactor.accept(event_loop, &listener),
+        )?;
+    }
+
+    {
+        let reactor = reactor.clone();
+        event_loop.interval(CRON_INTERVAL, move |event_loop, _| reactor.cron(event_loop))?;
+    }
+
+    let mut busy = false;
+    loop {
+        // Clients that stopped because their output piled up carry on without waiting
+        event_loop.run_once(busy.then_some(Duration::ZERO))?;
+        busy = reactor.handle_pending(&event_loop);
+    }
+}
+
+impl Reactor {
+    fn ready(&self) -> &Arc<ReadyClients> {
+        self.ready
+            .get()
+            .expect("The reactor's waker is set before serving clients")
+    }
+
+    fn accept(self: &Rc<Self>, event_loop: &EventLoop, listener: &TcpListener) {
+        loop {
+            let mut stream = match listener.accept() {
+                Ok((stream, _)) => stream,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => return,
+                Err(e) => {
+                    log::warning(format!("Accepting client connection: {}", e));
+                    return;
+                }
+            };
+            let maxclients = self.state.config.read().maxclients;
+            if self.state.clients.len() >= maxclients {
+                // Best effort, the connection gets closed either way
+                let _ = stream.write_all(b"-ERR max number of clients reached\r\n");
+                continue;
+            }
+            if let Err(e) = self.add_client(event_loop, stream) {
+                log::verbose(format!("Connection error: {}", e));
+            }
+        }
+    }
+
+    fn add_client(self: &Rc<Self>, event_loop: &EventLoop, stream: TcpStream) -> io::Result<()> {
+        stream.set_nonblocking(true)?;
+        // Replies are already batched, so there's no point in delaying them further
+        stream.set_nodelay(true)?;
+        let connection = Connection::new(
+            self.state.clone(),
+            ClientOutput::queued(self.ready().clone()),
+            stream.peer_addr().ok(),
+        );
+        let id = connection.client().id;
+        let reactor = self.clone();
+        let token = event_loop.register(
+            stream.as_raw_fd(),
+            Interest::READABLE,
+            move |_, event: Event| reactor.schedule(id, event.readable),
+        )?;
+        log::debug("Accepted new connection");
+        self.clients.borrow_mut().insert(
+            id,
+            Client {
+                stream,
+                token,
+                interest: Interest::READABLE,
+                connection,
+                replies: Replies::default(),
+                output: vec![],
+                written: 0,
+                readable: false,
+                scheduled: false,
+                more: false,
+                closing: false,
+                failed: false,
+                last_interaction: Instant::now(),
+            },
+        );
+        Ok(())
+    }
+
+    /// Queues the client to be handled with the others, after its socket became
+    /// ready or another thread sent it something
+    fn schedule(&self, id: ClientId, readable: bool) {
+        let mut clients = self.clients.borrow_mut();
+        let Some(client) = clients.get_mut(&id) else {
+            // Disconnected before the loop got to it
+            return;
+        };
+        client.readable |= readable;
+        if !client.scheduled {
+            client.scheduled = true;
+            self.pending.borrow_mut().push(id);
+        }
+    }
+
+    /// Handles the scheduled clients. Returns true if some of them
+    /// have more to do right away.
+    fn handle_pending(&self, event_loop: &EventLoop) -> bool {
+        let ids = std::mem::take(&mut *self.pending.borrow_mut());
+        if ids.is_empty() {
+            return false;
+        }
+        let mut batch: Vec<Client> = {
+            let mut clients = self.clients.borrow_mut();
+            ids.iter().filter_map(|id| clients.remove(id)).collect()
+        };
+        self.io_threads.run(&mut batch, Client::read);
+        for client in &mut batch {
+            client.run();
+        }
+        self.io_threads.run(&mut batch, Client::write);
+        for mut client in batch {
+            client.scheduled = false;
+            if client.failed || (client.closing && client.output.is_empty()) {
+                self.close(event_loop, client);
+                continue;
+            }
+            if let Err(e) = client.watch(event_loop) {
+                log::verbose(format!("Connection error: {}", e));
+                self.close(event_loop, client);
+                continue;
+            }
+            if client.more && client.output.is_empty() {
+                client.scheduled = true;
+                self.pending.borrow_mut().push(client.id());
+            }
+            self.clients.borrow_mut().insert(client.id(), client);
+        }
+        !self.pending.borrow().is_empty()
+    }
+
+    fn close(&self, event_loop: &EventLoop, client: Client) {
+        // The socket gets closed when `client` is dropped, which has to happen after this
+        if let Err(e) = event_loop.cancel(client.token) {
+            log::warning(format!("Unregistering client {}: {}", client.id(), e));
+        }
+        log::debug("Handled connection");
+    }
+
+    /// Runs the background tasks
+    fn cron(&self, event_loop: &EventLoop) {
+        self.state.cron();
+        let Some(timeout) = self.state.config.read().idle_timeout() else {
+            return;
+        };
+        let idle: Vec<ClientId> = self
+            .clients
+            .borrow()
+            .iter()
+            .filter(|(_, client)| {
+                !client.connection.is_subscribed() && client.last_interaction.elapsed() > timeout
+            })
+            .map(|(id, _)| *id)
+            .collect();
+        for id in idle {
+            log::verbose(format!("Closing idle client {}", id));
+            let client = self.clients.borrow_mut().remove(&id).unwrap();
+            self.close(event_loop, client);
+        }
+    }
+}
+
+impl Client {
+    fn id(&self) -> ClientId {
+        self.connection.client().id
+    }
+
+    /// Reads what the client sent and parses it, on any thread
+    fn read(&mut self) {
+        if !std::mem::take(&mut self.readable) {
+            return;
+        }
+        let mut received = 0;
+        // Big values take many reads, but other clients get their turn in between
+        while received < MAX_READ_PER_TURN {
+            match self.connection.receive(&mut self.stream) {
+                // What was received before gets handled first, the
+                // socket stays readable so the next turn sees the EOF
+                Ok(0) => {
+                    self.failed = received == 0;
+                    break;
+                }
+                Ok(n) => received += n,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => {
+                    log::verbose(format!("Connection error: {}", e));
+                    self.failed = true;
+                    break;
+                }
+            }
+        }
+        if received > 0 {
+            self.last_interaction = Instant::now();
+            self.connection.parse();
+        }
+    }
+
+    /// Runs the client's commands, on the loop's thread. Requests are only handled
+    /// once the previous replies are written, so that a client that doesn't read
+    /// can't make the server buffer without bound.
+    fn run(&mut self) {
+        if self.failed {
+            return;
+        }
+        if self.output.is_empty() && !self.closing {
+            let progress = self.connection.process();
+            self.closing = matches!(progress, Progress::Quit);
+            self.more = matches!(progress, Progress::OutputFull);
+            self.replies = self.connection.take_replies();
+        }
+    }
+
+    /// Serializes the replies and writes as much of the output
+    /// as the socket takes without blocking, on any thread
+    fn write(&mut self) {
+        if self.failed {
+            return;
+        }
+        self.replies.write_to(&mut self.output);
+        if let Err(e) = self.flush() {
+            log::verbose(format!("Connection error: {}", e));
+            self.failed = true;
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        while self.written < self.output.len() {
+            match self.stream.write(&self.output[self.written..]) {
+                Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+                Ok(n) => self.written += n,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        if self.written == self.output.len() {
+            self.output.clear();
+            self.written = 0;
+        }
+        Ok(())
+    }
+
+    /// Waits for the socket to be writable while there's output left,
+    /// and for the client to send more otherwise
+    fn watch(&mut self, event_loop: &EventLoop) -> io::Result<()> {
+        let interest = if self.output.is_empty() {
+            Interest::READABLE
+        } else {
+            Interest::WRITABLE
+        };
+        if interest != self.interest {
+            event_loop.reregister(self.token, interest)?;
+            self.interest = interest;
+        }
+        Ok(())
+    }
+}
+
+/// Threads that read, parse and write for the reactor, which hands
+/// them batches of clients and waits for them to be done
+struct IoThreads<T> {
+    /// The loop's thread does its share too, so there's one less of these than `io-threads`
+    workers: Vec<Worker<T>>,
+}
+
+type Job<T> = fn(&mut T);
+
+struct Worker<T> {
+    jobs: Sender<(Vec<T>, Job<T>)>,
+    done: Receiver<Vec<T>>,
+}
+
+impl<T: Send + 'static> IoThreads<T> {
+    fn new(count: usize) -> io::Result<IoThreads<T>> {
+        let mut workers = vec![];
+        for i in 1..count {
+            let (jobs, job_receiver) = mpsc::channel::<(Vec<T>, Job<T>)>();
+            let (done_sender, done) = mpsc::channel();
+            std::thread::Builder::new()
+                .name(format!("io_thd_{}", i))
+                .spawn(move || {
+                    // Ends once the reactor drops its side of the channel
+                    for (mut clients, job) in job_receiver {
+                        clients.iter_mut().for_each(job);
+                        if done_sender.send(clients).is_err() {
+                            break;
+                        }
+                    }
+                })?;
+            workers.push(Worker { jobs, done });
+        }
+        Ok(IoThreads { workers })
+    }
+
+    /// Runs `job` for every client, spread evenly over the threads.
+    /// The clients may come back in another order.
+    fn run(&self, clients: &mut Vec<T>, job: Job<T>) {
+        let threads = self.workers.len() + 1;
+        // Like redis, handing a few clients over to other threads isn't worth it
+        if clients.len() < threads * 2 {
+            clients.iter_mut().for_each(job);
+            return;
+        }
+        let per_thread = clients.len().div_ceil(threads);
+        let mut busy = vec![];
+        for worker in &self.workers {
+            let batch = clients.split_off(clients.len().saturating_sub(per_thread));
+            if batch.is_empty() {
+                break;
+            }
+            worker
+                .jobs
+                .send((batch, job))
+                .expect("io threads only stop with the reactor");
+            busy.push(worker);
+        }
+        clients.iter_mut().for_each(job);
+        for worker in busy {
+            clients.extend(worker.done.recv().expect("io thread panicked"));
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        collections::HashSet,
+        thread::{self, ThreadId},
+    };
+
+    use super::*;
+
+    #[derive(Default)]
+    struct Item {
+        id: usize,
+        runs: usize,
+        thread: Option<ThreadId>,
+    }
+
+    fn job(item: &mut Item) {
+        item.runs += 1;
+        item.thread = Some(thread::current().id());
+    }
+
+    fn items(count: usize) -> Vec<Item> {
+        (0..count)
+            .map(|id| Item {
+                id,
+                ..Item::default()
+            })
+            .collect()
+    }
+
+    /// The ids of the items, sorted, and the threads that ran them
+    fn ran(items: &[Item]) -> (Vec<usize>, HashSet<ThreadId>) {
+        assert!(items.iter().all(|item| item.runs == 1));
+        let mut ids: Vec<usize> = items.iter().map(|item| item.id).collect();
+        ids.sort();
+        (ids, items.iter().filter_map(|item| item.thread).collect())
+    }
+
+    #[test]
+    fn runs_small_batches_on_the_calling_thread() {
+        let io_threads = IoThreads::new(4).unwrap();
+        let mut batch = items(7);
+        io_threads.run(&mut batch, job);
+        assert_eq!(
+            ran(&batch),
+            ((0..7).collect(), HashSet::from([thread::current().id()]))
+        );
+    }
+
+    #[test]
+    fn spreads_batches_over_the_threads() {
+        let io_threads = IoThreads::new(4).unwrap();
+        for count in [8, 9, 100] {
+            let mut batch = items(count);
+            io_threads.run(&mut batch, job);
+            let (ids, threads) = ran(&batch);
+            assert_eq!(ids, (0..count).collect::<Vec<_>>());
+            // With 9 items, the 3 workers get 3 each and the calling thread none
+            assert!(threads.len() >= 3, "{} items ran on {:?}", count, threads);
+        }
+    }
+}
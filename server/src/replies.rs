@@ -0,0 +1,120 @@
+use std::{
+    collections::HashMap,
+    io::{self, Write},
+};
+
+use crate::{
+    codec::{self, Protocol},
+    value::Value,
+};
+
+/// Replies waiting to be written to a client, in order.
+///
+/// Values are kept as is, and only serialized once the replies are written,
+/// so that io threads can do it instead of the thread running commands.
+/// Replies that are cheap to format, like simple strings, are written
+/// as bytes right away through [Write].
+#[derive(Default)]
+pub struct Replies {
+    bytes: Vec<u8>,
+    /// Values to serialize, each with the offset in `bytes` it goes before
+    values: Vec<(usize, Value, Protocol)>,
+    /// Roughly how many bytes `values` take once serialized
+    values_len: usize,
+}
+
+impl Replies {
+    pub fn push_value(&mut self, value: Value, protocol: Protocol) {
+        self.values_len += estimated_len(&value);
+        self.values.push((self.bytes.len(), value, protocol));
+    }
+
+    /// Roughly how many bytes the replies take once serialized
+    pub fn len(&self) -> usize {
+        self.bytes.len() + self.values_len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.bytes.is_empty() && self.values.is_empty()
+    }
+
+    /// Serializes the replies at the end of `buffer`, leaving none behind
+    pub fn write_to(&mut self, buffer: &mut Vec<u8>) {
+        if buffer.is_empty() && self.values.is_empty() {
+            std::mem::swap(buffer, &mut self.bytes);
+            return;
+        }
+        let mut start = 0;
+        for (offset, value, protocol) in self.values.drain(..) {
+            buffer.extend_from_slice(&self.bytes[start..offset]);
+            codec::write_with_protocol(&value, protocol, buffer)
+                .expect("Writing to a Vec can't fail");
+            start = offset;
+        }
+        buffer.extend_from_slice(&self.bytes[start..]);
+        self.bytes.clear();
+        self.values_len = 0;
+    }
+}
+
+impl Write for Replies {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.bytes.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Roughly what a value's type marker, length and separators take
+const HEADER: usize = 16;
+
+/// Doesn't need to be exact, it's only used to decide
+/// when enough output piled up to write it
+fn estimated_len(value: &Value) -> usize {
+    match value {
+        Value::String(s) | Value::BigNumber(s) | Value::SimpleError(s) | Value::BlobError(s) => {
+            HEADER + s.len()
+        }
+        Value::Bytes(bytes) => HEADER + bytes.len(),
+        Value::Verbatim { text, .. } => HEADER + text.len(),
+        Value::Array(values) | Value::Push(values) | Value::Set(values) => {
+            HEADER + values.iter().map(estimated_len).sum::<usize>()
+        }
+        Value::Map(map) => estimated_map_len(map),
+        Value::Attribute(map, value) => estimated_map_len(map) + estimated_len(value),
+        Value::Integer(_) | Value::Null | Value::Double(_) | Value::Boolean(_) => HEADER,
+    }
+}
+
+fn estimated_map_len(map: &HashMap<String, Value>) -> usize {
+    HEADER
+        + map
+            .iter()
+            .map(|(key, value)| HEADER + key.len() + estimated_len(value))
+            .sum::<usize>()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn serializes_values_between_bytes() {
+        let mut replies = Replies::default();
+        replies.write_all(b"+OK\r\n").unwrap();
+        replies.push_value(Value::from("value"), Protocol::RESP2);
+        replies.push_value(Value::Null, Protocol::RESP3);
+        replies.write_all(b":1\r\n").unwrap();
+        replies.push_value(Value::Null, Protocol::RESP2);
+        assert!(replies.len() >= 24);
+
+        let mut buffer = b"-ERR\r\n".to_vec();
+        replies.write_to(&mut buffer);
+        assert_eq!(buffer, b"-ERR\r\n+OK\r\n$5\r\nvalue\r\n_\r\n:1\r\n$-1\r\n");
+        assert!(replies.is_empty());
+        assert_eq!(replies.len(), 0);
+    }
+}
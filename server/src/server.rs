@@ -1,16 +1,42 @@
 use std::{
-    collections::HashMap,
+    collections::{HashMap, HashSet},
+    io::{self, Write},
     net::TcpListener,
+    sync::{mpsc::Sender, Arc},
     thread::{JoinHandle, ThreadId},
+    time::Duration,
 };
 
 use dkv_db::DB;
 
-use crate::{codec, connection::Connection};
+use crate::{
+    client::{ClientOutput, ClientRegistry},
+    codec,
+    config::{Config, ConfigRegistry, IoModel},
+    connection::Connection,
+    log,
+    module::{Module, ModuleRegistry},
+    reactor,
+    stats::Stats,
+    tracking::Tracking,
+};
+
+#[cfg(all(target_os = "linux", feature = "io-uring"))]
+use crate::uring;
 
 pub struct Server {
-    listener: TcpListener,
-    db: DB,
+    listeners: Vec<TcpListener>,
+    state: Arc<ServerState>,
+}
+
+/// State shared by every connection
+pub struct ServerState {
+    pub db: DB,
+    pub config: ConfigRegistry,
+    pub stats: Arc<Stats>,
+    pub clients: ClientRegistry,
+    pub tracking: Tracking,
+    pub modules: ModuleRegistry,
 }
 enum HandleCommand {
     Start(JoinHandle<()>),
@@ -18,29 +44,222 @@ enum HandleCommand {
     Drain,
 }
 pub type Result<T> = codec::Result<T>;
+
+/// How often background tasks, like disconnecting idle clients, run
+pub(crate) const CRON_INTERVAL: Duration = Duration::from_millis(100);
+
+/// How long each cron run spends resizing the keyspace, like redis' `activerehashing`
+const ACTIVE_REHASHING_BUDGET: Duration = Duration::from_millis(1);
+
+/// File descriptors kept for things other than clients, like listeners and
+/// the log file. Same as redis' `CONFIG_MIN_RESERVED_FDS`.
+const RESERVED_FDS: usize = 32;
+
+/// Like redis, raises the open files limit so that `maxclients` clients fit,
+/// and lowers `maxclients` instead if the OS doesn't allow that many
+pub fn adjust_open_files_limit(config: &mut Config) {
+    let needed = config.maxclients + RESERVED_FDS;
+    let mut limit = libc::rlimit {
+        rlim_cur: 0,
+        rlim_max: 0,
+    };
+    if unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut limit) } != 0 {
+        log::warning(format!(
+            "Unable to obtain the current NOFILE limit ({}), assuming 1024",
+            io::Error::last_os_error()
+        ));
+        limit.rlim_cur = 1024;
+        limit.rlim_max = 1024;
+    }
+    let original = limit.rlim_cur as usize;
+    if original >= needed {
+        return;
+    }
+    let wanted = (needed as libc::rlim_t).min(limit.rlim_max);
+    let raised = libc::rlimit {
+        rlim_cur: wanted,
+        rlim_max: limit.rlim_max,
+    };
+    let current = if unsafe { libc::setrlimit(libc::RLIMIT_NOFILE, &raised) } == 0 {
+        wanted as usize
+    } else {
+        original
+    };
+    if current >= needed {
+        log::notice(format!(
+            "Increased maximum number of open files to {} (it was originally set to {}).",
+            current, original
+        ));
+        return;
+    }
+    let maxclients = current.saturating_sub(RESERVED_FDS).max(1);
+    log::warning(format!(
+        "You requested maxclients of {} requiring at least {} max file descriptors. \
+         maxclients has been reduced to {} to compensate for low ulimit. \
+         If you need higher maxclients increase 'ulimit -n'.",
+        config.maxclients, needed, maxclients
+    ));
+    config.maxclients = maxclients;
+}
+impl ServerState {
+    /// Background tasks that don't depend on how clients are served
+    pub fn cron(&self) {
+        if self.config.read().activerehashing {
+            self.db.rehash(ACTIVE_REHASHING_BUDGET);
+        }
+    }
+}
+
 impl Server {
     pub fn new(listener: TcpListener) -> Server {
+        Server::with_listeners(vec![listener], Config::default())
+    }
+
+    /// Listens on every address in `config.bind`
+    pub fn with_config(config: Config) -> io::Result<Server> {
+        let mut listeners = vec![];
+        for address in &config.bind {
+            let (optional, address) = match address.strip_prefix('-') {
+                Some(address) => (true, address),
+                None => (false, address.as_str()),
+            };
+            let host = match address {
+                "*" => "0.0.0.0",
+                "::*" => "::",
+                _ => address,
+            };
+            match TcpListener::bind((host, config.port)) {
+                Ok(listener) => listeners.push(listener),
+                Err(e) if optional => log::verbose(format!(
+                    "Skipping optional address {}:{}: {}",
+                    host, config.port, e
+                )),
+                Err(e) => {
+                    return Err(io::Error::new(
+                        e.kind(),
+                        format!("Could not listen on {}:{}: {}", host, config.port, e),
+                    ))
+                }
+            }
+        }
+        if listeners.is_empty() {
+            return Err(io::Error::other("None of the bind addresses is available"));
+        }
+        let modules = config.loadmodule.clone();
+        let server = Server::with_listeners(listeners, config);
+        // Like redis, the server doesn't start without the modules it's configured with
+        for (path, args) in modules {
+            if let Err(e) = server.state.modules.load_dynamic(&path, args) {
+                return Err(io::Error::other(format!(
+                    "Module {} failed to load: {}",
+                    path,
+                    e.message()
+                )));
+            }
+            log::notice(format!("Module loaded from {}", path));
+        }
+        Ok(server)
+    }
+
+    fn with_listeners(listeners: Vec<TcpListener>, config: Config) -> Server {
+        let db = DB::new();
+        db.set_keyspace_events(config.notify_keyspace_events);
+        db.set_maxmemory(config.max_memory());
         Server {
-            listener,
-            db: DB::new(),
+            listeners,
+            state: Arc::new(ServerState {
+                db,
+                config: ConfigRegistry::new(config),
+                stats: Arc::new(Stats::default()),
+                clients: ClientRegistry::default(),
+                tracking: Tracking::default(),
+                modules: ModuleRegistry::default(),
+            }),
         }
     }
 
+    pub fn listeners(&self) -> &[TcpListener] {
+        &self.listeners
+    }
+
+    #[cfg(all(test, target_os = "linux", feature = "io-uring"))]
+    pub(crate) fn state(&self) -> &Arc<ServerState> {
+        &self.state
+    }
+
+    /// Adds a module that's linked into the server, before or after starting it.
+    /// `args` are passed to [Module::on_load], like `MODULE LOAD` arguments.
+    pub fn load_module(&self, module: Box<dyn Module>, args: Vec<String>) -> Result<()> {
+        self.state.modules.load(module, None, args)
+    }
+
+    /// Serves clients the way `io-model` says, until the server fails
     pub fn start(&mut self) -> Result<()> {
+        let (io_model, io_threads) = {
+            let config = self.state.config.read();
+            (config.io_model, config.io_threads)
+        };
+        match io_model {
+            IoModel::Threads => {
+                if io_threads > 1 {
+                    log::warning("io-threads is ignored with io-model threads, where every client has its own thread");
+                }
+                self.start_threads()
+            }
+            IoModel::Reactor => Ok(reactor::run(self.state.clone(), &self.listeners)?),
+            IoModel::IoUring => {
+                if io_threads > 1 {
+                    log::warning("io-threads is ignored with io-model io_uring, where the kernel does the reading and writing");
+                }
+                self.start_io_uring()
+            }
+        }
+    }
+
+    #[cfg(all(target_os = "linux", feature = "io-uring"))]
+    fn start_io_uring(&mut self) -> Result<()> {
+        match uring::Driver::new(self.state.clone(), &self.listeners) {
+            Ok(driver) => Ok(driver.run()?),
+            Err(e) => {
+                log::warning(format!(
+                    "io_uring is unavailable ({}), falling back to io-model reactor",
+                    e
+                ));
+                Ok(reactor::run(self.state.clone(), &self.listeners)?)
+            }
+        }
+    }
+
+    #[cfg(not(all(target_os = "linux", feature = "io-uring")))]
+    fn start_io_uring(&mut self) -> Result<()> {
+        log::warning(
+            "dkv was built without the io-uring feature, falling back to io-model reactor",
+        );
+        Ok(reactor::run(self.state.clone(), &self.listeners)?)
+    }
+
+    fn start_threads(&mut self) -> Result<()> {
         let (handle_sender, handle_receiver) = std::sync::mpsc::channel::<HandleCommand>();
         let handle_manager = std::thread::spawn(move || {
             let mut handles = HashMap::new();
+            // Threads that stopped before the accepting thread got to send their handle
+            let mut stopped = HashSet::new();
             loop {
                 match handle_receiver.recv().unwrap() {
                     HandleCommand::Start(handle) => {
                         let thread_id = handle.thread().id();
-                        handles.insert(thread_id, handle);
-                    }
-                    HandleCommand::Stop(thread_id) => {
-                        let handle = handles.remove(&thread_id).unwrap();
-                        handle.join().unwrap();
-                        handles.remove(&thread_id);
+                        if stopped.remove(&thread_id) {
+                            handle.join().unwrap();
+                        } else {
+                            handles.insert(thread_id, handle);
+                        }
                     }
+                    HandleCommand::Stop(thread_id) => match handles.remove(&thread_id) {
+                        Some(handle) => handle.join().unwrap(),
+                        None => {
+                            stopped.insert(thread_id);
+                        }
+                    },
                     HandleCommand::Drain => break,
                 }
             }
@@ -48,20 +267,68 @@ impl Server {
                 handle.join().unwrap();
             }
         });
-        for stream in self.listener.incoming() {
-            let db = self.db.clone();
+        let state = self.state.clone();
+        std::thread::Builder::new()
+            .name("cron".to_owned())
+            .spawn(move || loop {
+                std::thread::sleep(CRON_INTERVAL);
+                state.cron();
+            })?;
+        let server = &*self;
+        std::thread::scope(|scope| {
+            for listener in &server.listeners {
+                let handle_sender = handle_sender.clone();
+                scope.spawn(move || server.accept(listener, handle_sender));
+            }
+        });
+        handle_sender.send(HandleCommand::Drain).unwrap();
+        handle_manager.join().unwrap();
+        Ok(())
+    }
+
+    fn accept(&self, listener: &TcpListener, handle_sender: Sender<HandleCommand>) {
+        for stream in listener.incoming() {
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(e) => {
+                    log::warning(format!("Accepting client connection: {}", e));
+                    continue;
+                }
+            };
+            // The client is registered before its thread starts, and counts itself,
+            // so that a burst of connections, even from several listeners, can't
+            // all see room for one more
+            let mut connection = Connection::new(
+                self.state.clone(),
+                ClientOutput::Polled,
+                stream.peer_addr().ok(),
+            );
+            let maxclients = self.state.config.read().maxclients;
+            if self.state.clients.len() > maxclients {
+                // Best effort, the connection gets closed either way
+                let _ = (&stream).write_all(b"-ERR max number of clients reached\r\n");
+                continue;
+            }
             let s = handle_sender.clone();
-            let handle = std::thread::spawn(move || {
-                dbg!("Accepted new connection");
-                Connection::new(db, stream.unwrap()).handle().unwrap();
-                dbg!("Handled connection");
+            let handle = std::thread::Builder::new().spawn(move || {
+                log::debug("Accepted new connection");
+                // A connection failing, e.g. because the client reset it,
+                // shouldn't take anything else down with it
+                if let Err(e) = connection.handle(&stream) {
+                    log::verbose(format!("Connection error: {}", e));
+                }
+                // Unregisters the client before the thread is reported as stopped
+                drop(connection);
+                log::debug("Handled connection");
                 s.send(HandleCommand::Stop(std::thread::current().id()))
                     .unwrap();
             });
-            handle_sender.send(HandleCommand::Start(handle)).unwrap();
+            match handle {
+                Ok(handle) => handle_sender.send(HandleCommand::Start(handle)).unwrap(),
+                // The client gets unregistered and disconnected, since its
+                // connection and stream were moved into the closure
+                Err(e) => log::warning(format!("Can't start a thread for a new client: {}", e)),
+            }
         }
-        handle_sender.send(HandleCommand::Drain).unwrap();
-        handle_manager.join().unwrap();
-        Ok(())
     }
 }
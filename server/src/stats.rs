@@ -0,0 +1,45 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Server wide counters reported by `INFO stats`
+#[derive(Default)]
+pub struct Stats {
+    pub client_output_buffer_limit_disconnections: AtomicU64,
+    /// Messages from other clients, like published messages and invalidations,
+    /// that were dropped when their receiver went past its output buffer limit
+    pub pubsub_messages_dropped: AtomicU64,
+    /// Keys evicted because of `maxmemory`
+    pub evicted_keys: AtomicU64,
+}
+
+impl Stats {
+    pub fn incr(counter: &AtomicU64) {
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Zeroes every counter, for `CONFIG RESETSTAT`
+    pub fn reset(&self) {
+        for counter in self.counters().map(|(_, counter)| counter) {
+            counter.store(0, Ordering::Relaxed);
+        }
+    }
+
+    fn counters(&self) -> [(&'static str, &AtomicU64); 3] {
+        [
+            (
+                "client_output_buffer_limit_disconnections",
+                &self.client_output_buffer_limit_disconnections,
+            ),
+            ("pubsub_messages_dropped", &self.pubsub_messages_dropped),
+            ("evicted_keys", &self.evicted_keys),
+        ]
+    }
+
+    /// Renders the stats section of `INFO`
+    pub fn info(&self) -> String {
+        let mut s = String::from("# Stats\r\n");
+        for (name, counter) in self.counters() {
+            s.push_str(&format!("{}:{}\r\n", name, counter.load(Ordering::Relaxed)));
+        }
+        s
+    }
+}
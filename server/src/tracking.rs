@@ -0,0 +1,318 @@
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Mutex,
+};
+
+use crate::{
+    client::{ClientId, ClientRegistry},
+    config::ConfigRegistry,
+    error::Error,
+    value::Value,
+};
+
+/// RESP2 clients receive invalidations on this channel, through a redirect client
+pub const INVALIDATE_CHANNEL: &str = "__redis__:invalidate";
+
+/// Options of `CLIENT TRACKING ON|OFF [REDIRECT id] [PREFIX prefix...] [BCAST] [OPTIN] [OPTOUT] [NOLOOP]`
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct TrackingOptions {
+    pub enabled: bool,
+    pub redirect: Option<ClientId>,
+    pub prefixes: Vec<String>,
+    pub bcast: bool,
+    pub optin: bool,
+    pub optout: bool,
+    pub noloop: bool,
+}
+
+impl TrackingOptions {
+    pub fn parse(args: &[String]) -> Result<TrackingOptions, Error> {
+        let mut options = TrackingOptions::default();
+        let (on_off, mut rest) = match args.split_first() {
+            Some(it) => it,
+            None => return Err(Error::generic("syntax error", "CLIENT TRACKING")),
+        };
+        options.enabled = match on_off.to_uppercase().as_str() {
+            "ON" => true,
+            "OFF" => false,
+            _ => return Err(Error::generic("syntax error", on_off.as_str())),
+        };
+        while let Some((option, tail)) = rest.split_first() {
+            rest = tail;
+            match option.to_uppercase().as_str() {
+                "BCAST" => options.bcast = true,
+                "OPTIN" => options.optin = true,
+                "OPTOUT" => options.optout = true,
+                "NOLOOP" => options.noloop = true,
+                "REDIRECT" | "PREFIX" => {
+                    let (value, tail) = rest
+                        .split_first()
+                        .ok_or_else(|| Error::generic("syntax error", option.as_str()))?;
+                    rest = tail;
+                    if option.eq_ignore_ascii_case("PREFIX") {
+                        options.prefixes.push(value.clone());
+                    } else {
+                        let id = value
+                            .parse()
+                            .map_err(|_| Error::generic("Invalid client ID", value.as_str()))?;
+                        options.redirect = Some(id);
+                    }
+                }
+                _ => return Err(Error::generic("syntax error", option.as_str())),
+            }
+        }
+
+        if !options.bcast && !options.prefixes.is_empty() {
+            return Err(Error::generic(
+                "PREFIX option requires BCAST mode to be enabled",
+                "",
+            ));
+        }
+        if options.optin && options.optout {
+            return Err(Error::generic("You can't use both OPTIN and OPTOUT", ""));
+        }
+        if options.bcast && (options.optin || options.optout) {
+            return Err(Error::generic(
+                "OPTIN and OPTOUT are not compatible with BCAST",
+                "",
+            ));
+        }
+        Ok(options)
+    }
+}
+
+/// Keeps track of which clients may have cached which keys, so that
+/// they can be told to invalidate them when the keys change.
+#[derive(Default)]
+pub struct Tracking {
+    inner: Mutex<TrackingInner>,
+}
+
+#[derive(Default)]
+struct TrackingInner {
+    clients: HashMap<ClientId, TrackingOptions>,
+    /// Clients which read each key, in the default tracking mode
+    keys: HashMap<String, HashSet<ClientId>>,
+    /// BCAST clients by prefix. An empty prefix matches every key.
+    prefixes: HashMap<String, HashSet<ClientId>>,
+}
+
+impl Tracking {
+    pub fn enable(&self, id: ClientId, options: TrackingOptions) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.remove_client(id);
+        if options.bcast {
+            let prefixes = if options.prefixes.is_empty() {
+                vec![String::new()]
+            } else {
+                options.prefixes.clone()
+            };
+            for prefix in prefixes {
+                inner.prefixes.entry(prefix).or_default().insert(id);
+            }
+        }
+        inner.clients.insert(id, options);
+    }
+
+    pub fn disable(&self, id: ClientId) {
+        self.inner.lock().unwrap().remove_client(id);
+    }
+
+    /// Remembers that `id` read `key`, so that it gets invalidated when `key` changes
+    pub fn remember_key(&self, id: ClientId, key: &str) {
+        let mut inner = self.inner.lock().unwrap();
+        match inner.clients.get(&id) {
+            Some(options) if !options.bcast => {}
+            _ => return,
+        }
+        match inner.keys.get_mut(key) {
+            Some(ids) => {
+                ids.insert(id);
+            }
+            None => {
+                inner.keys.insert(key.to_string(), HashSet::from([id]));
+            }
+        }
+    }
+
+    /// Sends invalidations for `key` to every client that may have cached it.
+    /// `writer` is the client that modified the key, which isn't notified if it
+    /// enabled tracking with NOLOOP.
+    pub fn invalidate_key(
+        &self,
+        key: &str,
+        writer: Option<ClientId>,
+        clients: &ClientRegistry,
+        config: &ConfigRegistry,
+    ) {
+        let targets = {
+            let mut inner = self.inner.lock().unwrap();
+            if inner.clients.is_empty() {
+                return;
+            }
+            let mut ids = inner.keys.remove(key).unwrap_or_default();
+            for (prefix, prefix_ids) in &inner.prefixes {
+                if key.starts_with(prefix.as_str()) {
+                    ids.extend(prefix_ids);
+                }
+            }
+            ids.into_iter()
+                .filter_map(|id| {
+                    let options = inner.clients.get(&id)?;
+                    if options.noloop && Some(id) == writer {
+                        None
+                    } else {
+                        Some((id, options.redirect))
+                    }
+                })
+                .collect::<Vec<_>>()
+        };
+        for (id, redirect) in targets {
+            send_invalidation(
+                id,
+                redirect,
+                Value::Array(vec![Value::from(key)]),
+                clients,
+                config,
+            );
+        }
+    }
+
+    /// Tells every tracking client to drop its whole cache, e.g. after FLUSHALL
+    pub fn invalidate_all(&self, clients: &ClientRegistry, config: &ConfigRegistry) {
+        let targets = {
+            let mut inner = self.inner.lock().unwrap();
+            inner.keys.clear();
+            inner
+                .clients
+                .iter()
+                .map(|(id, options)| (*id, options.redirect))
+                .collect::<Vec<_>>()
+        };
+        for (id, redirect) in targets {
+            send_invalidation(id, redirect, Value::Null, clients, config);
+        }
+    }
+}
+
+impl TrackingInner {
+    fn remove_client(&mut self, id: ClientId) {
+        self.clients.remove(&id);
+        self.prefixes.retain(|_, ids| {
+            ids.remove(&id);
+            !ids.is_empty()
+        });
+        // Keys read by this client are cleaned up lazily, when they get invalidated
+    }
+}
+
+fn send_invalidation(
+    id: ClientId,
+    redirect: Option<ClientId>,
+    keys: Value,
+    clients: &ClientRegistry,
+    config: &ConfigRegistry,
+) {
+    let message = vec![Value::from("invalidate"), keys];
+    match redirect {
+        None => {
+            if let Some(client) = clients.get(id) {
+                client.push(INVALIDATE_CHANNEL, message, config);
+            }
+        }
+        Some(redirect) => match clients.get(redirect) {
+            Some(target) => {
+                target.push(INVALIDATE_CHANNEL, message, config);
+            }
+            None => {
+                // Like redis, let RESP3 clients know that their invalidations are being lost
+                if let Some(client) = clients.get(id).filter(|it| it.is_resp3()) {
+                    client.push(
+                        INVALIDATE_CHANNEL,
+                        vec![
+                            Value::from("tracking-redir-broken"),
+                            Value::Integer(redirect as i64),
+                        ],
+                        config,
+                    );
+                }
+            }
+        },
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::{
+        client::ClientOutput,
+        config::{Config, OutputBufferLimit},
+        stats::Stats,
+    };
+
+    fn parse(args: &[&str]) -> Result<TrackingOptions, Error> {
+        TrackingOptions::parse(&args.iter().map(|it| it.to_string()).collect::<Vec<_>>())
+    }
+
+    #[test]
+    fn parses_tracking_options() {
+        let options = parse(&[
+            "on", "REDIRECT", "5", "BCAST", "PREFIX", "a:", "PREFIX", "b:", "NOLOOP",
+        ])
+        .unwrap();
+        assert_eq!(
+            options,
+            TrackingOptions {
+                enabled: true,
+                redirect: Some(5),
+                prefixes: vec!["a:".to_string(), "b:".to_string()],
+                bcast: true,
+                optin: false,
+                optout: false,
+                noloop: true,
+            }
+        );
+        assert!(!parse(&["OFF"]).unwrap().enabled);
+    }
+
+    #[test]
+    fn rejects_incompatible_tracking_options() {
+        assert!(parse(&[]).is_err());
+        assert!(parse(&["maybe"]).is_err());
+        assert!(parse(&["ON", "PREFIX", "a"]).is_err());
+        assert!(parse(&["ON", "OPTIN", "OPTOUT"]).is_err());
+        assert!(parse(&["ON", "BCAST", "OPTIN"]).is_err());
+        assert!(parse(&["ON", "REDIRECT"]).is_err());
+        assert!(parse(&["ON", "REDIRECT", "abc"]).is_err());
+    }
+
+    #[test]
+    fn queues_invalidations_up_to_the_output_buffer_limit() {
+        let config = ConfigRegistry::new(Config {
+            client_output_buffer_limit_normal: OutputBufferLimit::new(1024, 0, 0),
+            ..Config::default()
+        });
+        let clients = ClientRegistry::default();
+        let tracking = Tracking::default();
+        let client = clients.register(ClientOutput::Polled, Arc::new(Stats::default()));
+        client.set_resp3(true);
+        tracking.enable(client.id, parse(&["ON", "BCAST"]).unwrap());
+
+        tracking.invalidate_key("key", None, &clients, &config);
+        assert_eq!(
+            client.take_pushes(),
+            Some(vec![Value::Push(vec![
+                Value::from("invalidate"),
+                Value::Array(vec![Value::from("key")]),
+            ])])
+        );
+        // A client that doesn't read its invalidations gets disconnected,
+        // rather than blocking the clients writing keys
+        for i in 0..100 {
+            tracking.invalidate_key(&format!("key:{}", i), None, &clients, &config);
+        }
+        assert_eq!(client.take_pushes(), None);
+    }
+}
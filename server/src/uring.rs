@@ -0,0 +1,871 @@
+//! The `io-model io_uring` driver: like the reactor, a single thread serves every
+//! client, but instead of waiting for sockets to be ready to then read and write
+//! them, it hands accepts, receives and sends over to the kernel through io_uring,
+//! and carries on as they complete.
+//!
+//! Listeners are accepted from with multishot accepts, and clients are received
+//! from with multishot receives, which pick a buffer from a ring registered with
+//! the kernel once data arrives, so that idle clients don't hold a buffer each.
+//! Kernels without multishot accepts or receives get single shot ones instead,
+//! submitted again after each completion. The probe can't tell, since they use
+//! the same opcodes, so the driver finds out when the kernel rejects them.
+//!
+//! Commands still run through [Connection], in batches like with the reactor, and
+//! what other threads send to a client is queued the same way, with an eventfd
+//! waking the driver up.
+use std::{
+    alloc::{self, Layout},
+    collections::HashMap,
+    io::{self, Write},
+    net::{TcpListener, TcpStream},
+    os::fd::{AsRawFd, FromRawFd, OwnedFd},
+    ptr::NonNull,
+    sync::{
+        atomic::{AtomicU16, Ordering},
+        Arc,
+    },
+    time::Instant,
+};
+
+use io_uring::{cqueue, opcode, squeue, types, IoUring, Probe};
+
+use crate::{
+    client::{ClientId, ClientOutput, ReadyClients},
+    connection::{Connection, Progress},
+    log,
+    replies::Replies,
+    server::{ServerState, CRON_INTERVAL},
+};
+
+const ENTRIES: u32 = 4096;
+
+const BUFFER_GROUP: u16 = 0;
+/// Receive buffers shared by every client, a power of two as the kernel wants
+const BUFFERS: u16 = 512;
+const BUFFER_SIZE: usize = 16 * 1024;
+
+/// How much a client can send before it stops being received from,
+/// until its commands caught up
+const MAX_UNPROCESSED_INPUT: usize = 1024 * 1024;
+
+/// What a submission is for. Its user data carries this in the low bits,
+/// and the listener's index or the client's id in the others.
+#[derive(Clone, Copy)]
+enum Op {
+    Accept = 0,
+    Recv = 1,
+    Send = 2,
+    Wake = 3,
+    Cancel = 4,
+}
+
+fn user_data(op: Op, id: u64) -> u64 {
+    id << 3 | op as u64
+}
+
+fn decode(user_data: u64) -> (Option<Op>, u64) {
+    let op = match user_data & 0b111 {
+        0 => Op::Accept,
+        1 => Op::Recv,
+        2 => Op::Send,
+        3 => Op::Wake,
+        4 => Op::Cancel,
+        _ => return (None, user_data >> 3),
+    };
+    (Some(op), user_data >> 3)
+}
+
+pub struct Driver {
+    /// Dropped first, so that the kernel is done with the buffers
+    /// and the sockets by the time they're freed
+    ring: IoUring,
+    buffers: BufRing,
+    state: Arc<ServerState>,
+    listeners: Vec<TcpListener>,
+    clients: HashMap<ClientId, Client>,
+    /// Clients to handle once the current completions are
+    pending: Vec<ClientId>,
+    ready: Arc<ReadyClients>,
+    wake: Arc<OwnedFd>,
+    /// Where the kernel reads the eventfd's counter to
+    wake_count: Box<u64>,
+    multishot_accept: bool,
+    multishot_recv: bool,
+    /// Listeners that aren't accepted from until the next cron run, because
+    /// accepting failed, e.g. with EMFILE, and would fail again right away
+    accepts_paused: Vec<bool>,
+    /// Set once accepting failed, so that it's only logged again once it worked
+    accept_failed: bool,
+}
+
+#[derive(PartialEq)]
+enum Receive {
+    Idle,
+    Submitted,
+    /// Submitted, but the client sent too much for now
+    Cancelling,
+}
+
+struct Client {
+    stream: TcpStream,
+    connection: Connection,
+    /// Replies of the last commands, not serialized yet
+    replies: Replies,
+    /// What wasn't sent yet, starting at `written`. It can't be touched
+    /// while `sending`, the kernel is reading it.
+    output: Vec<u8>,
+    written: usize,
+    sending: bool,
+    receive: Receive,
+    /// Bytes received since the client's commands last caught up
+    unprocessed: usize,
+    scheduled: bool,
+    /// Some requests weren't handled yet, because output piled up
+    more: bool,
+    /// The client won't send anything anymore
+    eof: bool,
+    /// Set once the client has to be disconnected, after sending its output
+    closing: bool,
+    /// The client disconnected, or its socket failed
+    failed: bool,
+    /// The socket was shut down, and the client goes away once
+    /// the kernel is done with its submissions
+    shut_down: bool,
+    last_interaction: Instant,
+}
+
+impl Driver {
+    /// Sets io_uring up, failing if the kernel doesn't support
+    /// what the driver needs or doesn't allow io_uring
+    pub fn new(state: Arc<ServerState>, listeners: &[TcpListener]) -> io::Result<Driver> {
+        let ring = IoUring::new(ENTRIES)?;
+        if !ring.params().is_feature_ext_arg() {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "the kernel doesn't support waiting with a timeout",
+            ));
+        }
+        let mut probe = Probe::new();
+        ring.submitter().register_probe(&mut probe)?;
+        let ops = [
+            opcode::Accept::CODE,
+            opcode::Recv::CODE,
+            opcode::Send::CODE,
+            opcode::Read::CODE,
+            opcode::AsyncCancel::CODE,
+        ];
+        if !ops.iter().all(|&op| probe.is_supported(op)) {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "the kernel doesn't support accept, recv and send operations",
+            ));
+        }
+        let buffers = BufRing::new(&ring)?;
+
+        let wake = unsafe { libc::eventfd(0, libc::EFD_CLOEXEC) };
+        if wake < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let wake = Arc::new(unsafe { OwnedFd::from_raw_fd(wake) });
+        let ready = {
+            let wake = wake.clone();
+            Arc::new(ReadyClients::new(move || {
+                let one: u64 = 1;
+                let written =
+                    unsafe { libc::write(wake.as_raw_fd(), (&one as *const u64).cast(), 8) };
+                if written < 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                Ok(())
+            }))
+        };
+
+        let listeners = listeners
+            .iter()
+            .map(|listener| {
+                let listener = listener.try_clone()?;
+                // The kernel waits for clients itself
+                listener.set_nonblocking(false)?;
+                Ok(listener)
+            })
+            .collect::<io::Result<Vec<_>>>()?;
+        let accepts_paused = vec![false; listeners.len()];
+        Ok(Driver {
+            ring,
+            buffers,
+            state,
+            listeners,
+            clients: HashMap::new(),
+            pending: vec![],
+            ready,
+            wake,
+            wake_count: Box::new(0),
+            multishot_accept: true,
+            multishot_recv: true,
+            accepts_paused,
+            accept_failed: false,
+        })
+    }
+
+    /// Serves clients until an error happens with io_uring itself
+    pub fn run(mut self) -> io::Result<()> {
+        self.start()?;
+        let mut next_cron = Instant::now() + CRON_INTERVAL;
+        loop {
+            self.wait(next_cron)?;
+            self.handle_pending()?;
+            if Instant::now() >= next_cron {
+                self.cron()?;
+                next_cron = Instant::now() + CRON_INTERVAL;
+            }
+        }
+    }
+
+    fn start(&mut self) -> io::Result<()> {
+        for index in 0..self.listeners.len() {
+            self.accept(index)?;
+        }
+        self.wait_for_wake()
+    }
+
+    /// Submits what's queued, and handles the completions that arrive until
+    /// `deadline`, or the ones already there if clients are pending
+    fn wait(&mut self, deadline: Instant) -> io::Result<()> {
+        // Clients that stopped because their output piled up carry on without waiting
+        let result = if self.pending.is_empty() {
+            let timeout = types::Timespec::from(deadline.saturating_duration_since(Instant::now()));
+            let args = types::SubmitArgs::new().timespec(&timeout);
+            self.ring.submitter().submit_with_args(1, &args)
+        } else {
+            self.ring.submit()
+        };
+        match result {
+            Ok(_) => {}
+            Err(e)
+                if matches!(
+                    e.raw_os_error(),
+                    Some(libc::ETIME | libc::EINTR | libc::EBUSY)
+                ) => {}
+            Err(e) => return Err(e),
+        }
+        let completions: Vec<_> = self
+            .ring
+            .completion()
+            .map(|cqe| (cqe.user_data(), cqe.result(), cqe.flags()))
+            .collect();
+        for (user_data, result, flags) in completions {
+            self.complete(user_data, result, flags)?;
+        }
+        Ok(())
+    }
+
+    /// Queues a submission, making room by submitting the queued ones
+    /// if needed. The memory it points to must stay put until it completes.
+    fn push(&mut self, entry: squeue::Entry) -> io::Result<()> {
+        loop {
+            // The driver owns what its submissions point to, and only frees
+            // or moves it once they completed
+            if unsafe { self.ring.submission().push(&entry) }.is_ok() {
+                return Ok(());
+            }
+            self.ring.submit()?;
+        }
+    }
+
+    fn accept(&mut self, index: usize) -> io::Result<()> {
+        let fd = types::Fd(self.listeners[index].as_raw_fd());
+        let entry = if self.multishot_accept {
+            opcode::AcceptMulti::new(fd)
+                .flags(libc::SOCK_CLOEXEC)
+                .build()
+        } else {
+            opcode::Accept::new(fd, std::ptr::null_mut(), std::ptr::null_mut())
+                .flags(libc::SOCK_CLOEXEC)
+                .build()
+        };
+        self.push(entry.user_data(user_data(Op::Accept, index as u64)))
+    }
+
+    fn wait_for_wake(&mut self) -> io::Result<()> {
+        let fd = types::Fd(self.wake.as_raw_fd());
+        let count: *mut u64 = &mut *self.wake_count;
+        let entry = opcode::Read::new(fd, count.cast(), 8)
+            .build()
+            .user_data(user_data(Op::Wake, 0));
+        self.push(entry)
+    }
+
+    fn complete(&mut self, user_data: u64, result: i32, flags: u32) -> io::Result<()> {
+        match decode(user_data) {
+            (Some(Op::Accept), index) => {
+                let index = index as usize;
+                let rearm = !cqueue::more(flags);
+                match result {
+                    fd if fd >= 0 => {
+                        self.accept_failed = false;
+                        self.add_client(unsafe { TcpStream::from_raw_fd(fd) });
+                    }
+                    e if e == -libc::EINVAL && self.multishot_accept => {
+                        log::notice(
+                            "The kernel doesn't support multishot accepts, using single shot ones",
+                        );
+                        self.multishot_accept = false;
+                    }
+                    e => {
+                        if !self.accept_failed {
+                            log::warning(format!(
+                                "Accepting client connection: {}",
+                                io::Error::from_raw_os_error(-e)
+                            ));
+                            self.accept_failed = true;
+                        }
+                        if rearm {
+                            self.accepts_paused[index] = true;
+                            return Ok(());
+                        }
+                    }
+                }
+                if rearm {
+                    self.accept(index)?;
+                }
+            }
+            (Some(Op::Recv), id) => self.received(id, result, flags),
+            (Some(Op::Send), id) => self.sent(id, result),
+            (Some(Op::Wake), _) => {
+                for id in self.ready.take() {
+                    self.schedule(id);
+                }
+                self.wait_for_wake()?;
+            }
+            (Some(Op::Cancel), _) | (None, _) => {}
+        }
+        Ok(())
+    }
+
+    fn add_client(&mut self, mut stream: TcpStream) {
+        let maxclients = self.state.config.read().maxclients;
+        if self.state.clients.len() >= maxclients {
+            // Best effort, the connection gets closed either way
+            let _ = stream.write_all(b"-ERR max number of clients reached\r\n");
+            return;
+        }
+        // Replies are already batched, so there's no point in delaying them further
+        if let Err(e) = stream.set_nodelay(true) {
+            log::verbose(format!("Connection error: {}", e));
+            return;
+        }
+        let connection = Connection::new(
+            self.state.clone(),
+            ClientOutput::queued(self.ready.clone()),
+            stream.peer_addr().ok(),
+        );
+        let id = connection.client().id;
+        log::debug("Accepted new connection");
+        self.clients.insert(
+            id,
+            Client {
+                stream,
+                connection,
+                replies: Replies::default(),
+                output: vec![],
+                written: 0,
+                sending: false,
+                receive: Receive::Idle,
+                unprocessed: 0,
+                scheduled: false,
+                more: false,
+                eof: false,
+                closing: false,
+                failed: false,
+                shut_down: false,
+                last_interaction: Instant::now(),
+            },
+        );
+        self.schedule(id);
+    }
+
+    fn received(&mut self, id: ClientId, result: i32, flags: u32) {
+        let buffer = cqueue::buffer_select(flags);
+        if let Some(client) = self.clients.get_mut(&id) {
+            if !cqueue::more(flags) {
+                client.receive = Receive::Idle;
+            }
+            match result {
+                0 => client.eof = true,
+                n if n > 0 => {
+                    let buffer = buffer.expect("Receives always pick a buffer");
+                    let mut data = self.buffers.get(buffer, n as usize);
+                    while !data.is_empty() {
+                        // Reading from a slice can't fail
+                        let _ = client.connection.receive(&mut data);
+                    }
+                    client.unprocessed += n as usize;
+                    client.last_interaction = Instant::now();
+                    client.connection.parse();
+                }
+                // Ran out of buffers, or cancelled because the client sent
+                // too much, either way it gets received from again later
+                n if n == -libc::ENOBUFS || n == -libc::ECANCELED => {}
+                n if n == -libc::EINVAL && self.multishot_recv => {
+                    log::notice(
+                        "The kernel doesn't support multishot receives, using single shot ones",
+                    );
+                    self.multishot_recv = false;
+                }
+                n => {
+                    log::verbose(format!(
+                        "Connection error: {}",
+                        io::Error::from_raw_os_error(-n)
+                    ));
+                    client.failed = true;
+                }
+            }
+        }
+        if let Some(buffer) = buffer {
+            self.buffers.recycle(buffer);
+        }
+        self.schedule(id);
+    }
+
+    fn sent(&mut self, id: ClientId, result: i32) {
+        let Some(client) = self.clients.get_mut(&id) else {
+            return;
+        };
+        client.sending = false;
+        match result {
+            n if n > 0 => {
+                client.written += n as usize;
+                if client.written == client.output.len() {
+                    client.output.clear();
+                    client.written = 0;
+                }
+            }
+            0 => {
+                log::verbose(format!(
+                    "Connection error: {}",
+                    io::Error::from(io::ErrorKind::WriteZero)
+                ));
+                client.failed = true;
+            }
+            n => {
+                log::verbose(format!(
+                    "Connection error: {}",
+                    io::Error::from_raw_os_error(-n)
+                ));
+                client.failed = true;
+            }
+        }
+        self.schedule(id);
+    }
+
+    /// Queues the client to be handled with the others, after
+    /// something completed for it or another thread sent it something
+    fn schedule(&mut self, id: ClientId) {
+        let Some(client) = self.clients.get_mut(&id) else {
+            // Disconnected before the driver got to it
+            return;
+        };
+        if !client.scheduled {
+            client.scheduled = true;
+            self.pending.push(id);
+        }
+    }
+
+    /// Runs the scheduled clients' commands, and submits what they need next
+    fn handle_pending(&mut self) -> io::Result<()> {
+        for id in std::mem::take(&mut self.pending) {
+            let Some(client) = self.clients.get_mut(&id) else {
+                continue;
+            };
+            client.scheduled = false;
+            client.run();
+            if client.more && client.output.is_empty() {
+                client.scheduled = true;
+                self.pending.push(id);
+            }
+            self.submit(id)?;
+        }
+        Ok(())
+    }
+
+    /// Sends the client's output, receives from it if it can take more, or
+    /// disconnects it once it's done
+    fn submit(&mut self, id: ClientId) -> io::Result<()> {
+        let Some(client) = self.clients.get_mut(&id) else {
+            return Ok(());
+        };
+        if !client.sending {
+            client.replies.write_to(&mut client.output);
+        }
+        let fd = types::Fd(client.stream.as_raw_fd());
+        if client.failed || (client.closing && client.output.is_empty()) {
+            if !client.shut_down {
+                // Ends the client's submissions, it's dropped once they completed
+                unsafe { libc::shutdown(fd.0, libc::SHUT_RDWR) };
+                client.shut_down = true;
+            }
+            if client.receive == Receive::Idle && !client.sending {
+                self.clients.remove(&id);
+                log::debug("Handled connection");
+            }
+            return Ok(());
+        }
+
+        let mut entries = vec![];
+        if !client.sending && client.written < client.output.len() {
+            let unsent = &client.output[client.written..];
+            entries.push(
+                opcode::Send::new(fd, unsent.as_ptr(), unsent.len() as u32)
+                    .build()
+                    .user_data(user_data(Op::Send, id)),
+            );
+            client.sending = true;
+        }
+        let paused = client.unprocessed >= MAX_UNPROCESSED_INPUT;
+        match client.receive {
+            Receive::Idle if !paused && !client.eof => {
+                entries.push(
+                    if self.multishot_recv {
+                        opcode::RecvMulti::new(fd, BUFFER_GROUP).build()
+                    } else {
+                        opcode::Recv::new(fd, std::ptr::null_mut(), BUFFER_SIZE as u32)
+                            .buf_group(BUFFER_GROUP)
+                            .build()
+                            .flags(squeue::Flags::BUFFER_SELECT)
+                    }
+                    .user_data(user_data(Op::Recv, id)),
+                );
+                client.receive = Receive::Submitted;
+            }
+            Receive::Submitted if paused && self.multishot_recv => {
+                entries.push(
+                    opcode::AsyncCancel::new(user_data(Op::Recv, id))
+                        .build()
+                        .user_data(user_data(Op::Cancel, id)),
+                );
+                client.receive = Receive::Cancelling;
+            }
+            _ => {}
+        }
+        for entry in entries {
+            self.push(entry)?;
+        }
+        Ok(())
+    }
+
+    /// Runs the background tasks
+    fn cron(&mut self) -> io::Result<()> {
+        self.state.cron();
+        for index in 0..self.listeners.len() {
+            if std::mem::take(&mut self.accepts_paused[index]) {
+                self.accept(index)?;
+            }
+        }
+        let Some(timeout) = self.state.config.read().idle_timeout() else {
+            return Ok(());
+        };
+        let idle: Vec<ClientId> = self
+            .clients
+            .iter()
+            .filter(|(_, client)| {
+                !client.failed
+                    && !client.connection.is_subscribed()
+                    && client.last_interaction.elapsed() > timeout
+            })
+            .map(|(id, _)| *id)
+            .collect();
+        for id in idle {
+            log::verbose(format!("Closing idle client {}", id));
+            self.clients.get_mut(&id).unwrap().failed = true;
+            self.schedule(id);
+        }
+        Ok(())
+    }
+}
+
+impl Client {
+    /// Runs the client's commands. Requests are only handled once the previous
+    /// replies are sent, so that a client that doesn't read can't make the
+    /// server buffer without bound.
+    fn run(&mut self) {
+        if self.failed {
+            return;
+        }
+        if self.output.is_empty() && !self.closing {
+            let progress = self.connection.process();
+            self.closing = matches!(progress, Progress::Quit)
+                || (self.eof && matches!(progress, Progress::NeedInput));
+            self.more = matches!(progress, Progress::OutputFull);
+            if matches!(progress, Progress::NeedInput) {
+                self.unprocessed = 0;
+            }
+            self.replies = self.connection.take_replies();
+        }
+    }
+}
+
+/// Receive buffers registered with the kernel, which picks one of them
+/// for each receive, and gets it back once what it holds was parsed
+struct BufRing {
+    /// The ring of buffers the kernel can pick from, page aligned
+    entries: NonNull<types::BufRingEntry>,
+    entries_layout: Layout,
+    memory: NonNull<u8>,
+    memory_layout: Layout,
+    tail: u16,
+}
+
+impl BufRing {
+    fn new(ring: &IoUring) -> io::Result<BufRing> {
+        let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) }.max(4096) as usize;
+        let entries_layout = Layout::from_size_align(
+            BUFFERS as usize * std::mem::size_of::<types::BufRingEntry>(),
+            page_size,
+        )
+        .expect("The buffer ring's layout is valid");
+        let memory_layout = Layout::from_size_align(BUFFERS as usize * BUFFER_SIZE, page_size)
+            .expect("The buffers' layout is valid");
+        let entries = NonNull::new(unsafe { alloc::alloc_zeroed(entries_layout) })
+            .unwrap_or_else(|| alloc::handle_alloc_error(entries_layout));
+        let memory = NonNull::new(unsafe { alloc::alloc(memory_layout) })
+            .unwrap_or_else(|| alloc::handle_alloc_error(memory_layout));
+        let mut buffers = BufRing {
+            entries: entries.cast(),
+            entries_layout,
+            memory,
+            memory_layout,
+            tail: 0,
+        };
+        // The ring stays valid until it's dropped, after io_uring is
+        unsafe {
+            ring.submitter().register_buf_ring_with_flags(
+                buffers.entries.as_ptr() as u64,
+                BUFFERS,
+                BUFFER_GROUP,
+                0,
+            )?
+        };
+        for buffer in 0..BUFFERS {
+            buffers.recycle(buffer);
+        }
+        Ok(buffers)
+    }
+
+    /// What the kernel received into `buffer`
+    fn get(&self, buffer: u16, len: usize) -> &[u8] {
+        assert!(buffer < BUFFERS && len <= BUFFER_SIZE);
+        // The kernel is done with the buffer until it's recycled
+        unsafe {
+            std::slice::from_raw_parts(self.memory.as_ptr().add(buffer as usize * BUFFER_SIZE), len)
+        }
+    }
+
+    /// Gives `buffer` back to the kernel
+    fn recycle(&mut self, buffer: u16) {
+        unsafe {
+            let entry = &mut *self
+                .entries
+                .as_ptr()
+                .add((self.tail & (BUFFERS - 1)) as usize);
+            entry.set_addr(self.memory.as_ptr().add(buffer as usize * BUFFER_SIZE) as u64);
+            entry.set_len(BUFFER_SIZE as u32);
+            entry.set_bid(buffer);
+            self.tail = self.tail.wrapping_add(1);
+            let tail = &*types::BufRingEntry::tail(self.entries.as_ptr()).cast::<AtomicU16>();
+            tail.store(self.tail, Ordering::Release);
+        }
+    }
+}
+
+impl Drop for BufRing {
+    fn drop(&mut self) {
+        unsafe {
+            alloc::dealloc(self.entries.as_ptr().cast(), self.entries_layout);
+            alloc::dealloc(self.memory.as_ptr(), self.memory_layout);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        io::{ErrorKind, Read},
+        time::Duration,
+    };
+
+    use super::*;
+    use crate::server::Server;
+
+    /// A driver listening on a free port, or `None` where io_uring is unavailable
+    fn driver() -> Option<(Driver, TcpStream)> {
+        let server = Server::new(TcpListener::bind("127.0.0.1:0").unwrap());
+        let driver = match Driver::new(server.state().clone(), server.listeners()) {
+            Ok(driver) => driver,
+            Err(e) => {
+                eprintln!("Skipping, io_uring is unavailable: {}", e);
+                return None;
+            }
+        };
+        let client = TcpStream::connect(server.listeners()[0].local_addr().unwrap()).unwrap();
+        client.set_nonblocking(true).unwrap();
+        Some((driver, client))
+    }
+
+    fn turn(driver: &mut Driver) {
+        driver
+            .wait(Instant::now() + Duration::from_millis(10))
+            .unwrap();
+        driver.handle_pending().unwrap();
+    }
+
+    /// Reads `expected.len()` bytes from `client`, turning the driver while waiting
+    fn expect(driver: &mut Driver, client: &mut TcpStream, expected: &[u8]) {
+        let deadline = Instant::now() + Duration::from_secs(10);
+        let mut reply = vec![0; expected.len()];
+        let mut read = 0;
+        while read < reply.len() {
+            assert!(Instant::now() < deadline, "timed out waiting for a reply");
+            match client.read(&mut reply[read..]) {
+                Ok(0) => panic!("the connection was closed"),
+                Ok(n) => read += n,
+                Err(e) if e.kind() == ErrorKind::WouldBlock => turn(driver),
+                Err(e) => panic!("{}", e),
+            }
+        }
+        assert_eq!(reply, expected);
+    }
+
+    /// Writes `data` from another thread, while this one turns the driver
+    fn send_in_background(client: &TcpStream, data: Vec<u8>) -> std::thread::JoinHandle<()> {
+        let mut writer = client.try_clone().unwrap();
+        std::thread::spawn(move || {
+            let mut data = &data[..];
+            while !data.is_empty() {
+                match writer.write(data) {
+                    Ok(n) => data = &data[n..],
+                    // Shares the test's non-blocking socket
+                    Err(e) if e.kind() == ErrorKind::WouldBlock => {
+                        std::thread::sleep(Duration::from_millis(1))
+                    }
+                    Err(e) => panic!("{}", e),
+                }
+            }
+        })
+    }
+
+    fn ping_set_get(driver: &mut Driver, client: &mut TcpStream) {
+        client.write_all(b"PING\r\n").unwrap();
+        expect(driver, client, b"$4\r\nPONG\r\n");
+        client.write_all(b"SET key value\r\n").unwrap();
+        expect(driver, client, b"+OK\r\n");
+        client.write_all(b"GET key\r\n").unwrap();
+        expect(driver, client, b"$5\r\nvalue\r\n");
+    }
+
+    #[test]
+    fn serves_commands() {
+        let Some((mut driver, mut client)) = driver() else {
+            return;
+        };
+        driver.start().unwrap();
+        ping_set_get(&mut driver, &mut client);
+    }
+
+    #[test]
+    fn falls_back_to_single_shot_accepts() {
+        let Some((mut driver, mut client)) = driver() else {
+            return;
+        };
+        // What kernels without multishot accepts complete them with
+        driver
+            .complete(user_data(Op::Accept, 0), -libc::EINVAL, 0)
+            .unwrap();
+        assert!(!driver.multishot_accept);
+        driver.wait_for_wake().unwrap();
+        ping_set_get(&mut driver, &mut client);
+        // Each accept is submitted again once it completed
+        let mut other = TcpStream::connect(driver.listeners[0].local_addr().unwrap()).unwrap();
+        other.set_nonblocking(true).unwrap();
+        ping_set_get(&mut driver, &mut other);
+    }
+
+    #[test]
+    fn falls_back_to_single_shot_receives() {
+        let Some((mut driver, mut client)) = driver() else {
+            return;
+        };
+        driver.start().unwrap();
+        // Accepts the client without submitting its first receive
+        while driver.clients.is_empty() {
+            driver
+                .wait(Instant::now() + Duration::from_millis(10))
+                .unwrap();
+        }
+        let id = *driver.clients.keys().next().unwrap();
+        // What kernels without multishot receives complete them with
+        driver.received(id, -libc::EINVAL, 0);
+        assert!(!driver.multishot_recv);
+        ping_set_get(&mut driver, &mut client);
+    }
+
+    #[test]
+    fn stops_receiving_from_clients_that_are_too_far_ahead() {
+        let Some((mut driver, mut client)) = driver() else {
+            return;
+        };
+        driver.start().unwrap();
+        let value = vec![b'x'; 4 * 1024 * 1024];
+        let mut set = b"*3\r\n$3\r\nSET\r\n$3\r\nbig\r\n".to_vec();
+        set.extend_from_slice(format!("${}\r\n", value.len()).as_bytes());
+        set.extend_from_slice(&value);
+        set.extend_from_slice(b"\r\n");
+        let sent = send_in_background(&client, set);
+        expect(&mut driver, &mut client, b"+OK\r\n");
+        sent.join().unwrap();
+
+        // The reply to GET isn't read, so the pings pile up behind it
+        const PINGS: usize = 400_000;
+        let mut requests = b"GET big\r\n".to_vec();
+        requests.extend_from_slice(&b"PING\r\n".repeat(PINGS));
+        let sent = send_in_background(&client, requests);
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while !driver
+            .clients
+            .values()
+            .any(|client| client.receive == Receive::Cancelling)
+        {
+            assert!(Instant::now() < deadline, "the receive wasn't cancelled");
+            turn(&mut driver);
+        }
+
+        let mut expected = format!("${}\r\n", value.len()).into_bytes();
+        expected.extend_from_slice(&value);
+        expected.extend_from_slice(b"\r\n");
+        expected.extend_from_slice(&b"$4\r\nPONG\r\n".repeat(PINGS));
+        expect(&mut driver, &mut client, &expected);
+        sent.join().unwrap();
+    }
+
+    #[test]
+    fn accepts_again_on_the_next_cron_run_after_failing() {
+        let Some((mut driver, mut client)) = driver() else {
+            return;
+        };
+        // What an accept completes with once out of file descriptors
+        driver
+            .complete(user_data(Op::Accept, 0), -libc::EMFILE, 0)
+            .unwrap();
+        assert!(driver.accepts_paused[0]);
+        assert!(driver.accept_failed);
+        driver.wait_for_wake().unwrap();
+        for _ in 0..10 {
+            turn(&mut driver);
+        }
+        assert!(driver.clients.is_empty());
+
+        driver.cron().unwrap();
+        assert!(!driver.accepts_paused[0]);
+        ping_set_get(&mut driver, &mut client);
+        assert!(!driver.accept_failed);
+    }
+}
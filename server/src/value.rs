@@ -1,20 +1,34 @@
-use std::{
-    collections::HashMap,
-    io::{Read, Write},
-};
-
-use crate::{
-    codec,
-    serializable::{Deserializable, Serializable},
-};
+use std::collections::HashMap;
 
+/// A RESP3 value. Types that RESP2 doesn't have are downgraded
+/// by [crate::codec::write_with_protocol] when writing to RESP2 clients.
 #[derive(Debug, PartialEq, Clone)]
 pub enum Value {
     String(String),
+    /// A bulk string that may not be valid UTF-8, like the values of `SET`
+    Bytes(Vec<u8>),
     Array(Vec<Value>),
     Integer(i64),
     Map(HashMap<String, Value>),
+    /// Out of band data sent to RESP3 clients, like client side caching invalidations
+    Push(Vec<Value>),
     Null,
+    Double(f64),
+    Boolean(bool),
+    /// An integer of arbitrary size, kept as its decimal representation
+    BigNumber(String),
+    /// A string that's meant to be shown to users as is.
+    /// `format` is 3 characters long, e.g. `txt` or `mkd`.
+    Verbatim {
+        format: String,
+        text: String,
+    },
+    Set(Vec<Value>),
+    /// Auxiliary data about a reply, followed by the reply itself
+    Attribute(HashMap<String, Value>, Box<Value>),
+    SimpleError(String),
+    /// An error whose message may contain newlines
+    BlobError(String),
 }
 impl From<String> for Value {
     fn from(s: String) -> Self {
@@ -31,11 +45,36 @@ impl From<&str> for Value {
         Value::String(s.to_string())
     }
 }
+impl From<dkv_db::Value> for Value {
+    fn from(value: dkv_db::Value) -> Self {
+        match value {
+            dkv_db::Value::String(s) => Value::Bytes(s),
+            dkv_db::Value::List(list) => Value::Array(list.into_iter().map(Value::from).collect()),
+            dkv_db::Value::Hash(map) => Value::Map(
+                map.into_iter()
+                    .map(|(key, value)| (key, Value::from(value)))
+                    .collect(),
+            ),
+            // Module commands reply with values of their own making
+            dkv_db::Value::Custom(_) => Value::Null,
+        }
+    }
+}
 impl From<i64> for Value {
     fn from(i: i64) -> Self {
         Value::Integer(i)
     }
 }
+impl From<f64> for Value {
+    fn from(d: f64) -> Self {
+        Value::Double(d)
+    }
+}
+impl From<bool> for Value {
+    fn from(b: bool) -> Self {
+        Value::Boolean(b)
+    }
+}
 
 #[macro_export]
 macro_rules! dkv_array {
@@ -44,18 +83,6 @@ macro_rules! dkv_array {
     };
 }
 
-impl Serializable for Value {
-    fn write(&self, writer: &mut impl Write) -> std::io::Result<()> {
-        codec::write(self, writer)
-    }
-}
-impl Deserializable for Value {
-    type Error = crate::Error;
-    fn read(stream: &mut impl Read) -> std::result::Result<Self, Self::Error> {
-        codec::read(stream)
-    }
-}
-
 #[cfg(test)]
 mod test {
     use super::*;
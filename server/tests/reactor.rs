@@ -0,0 +1,232 @@
+//! Serves clients with `io-model reactor`, through real sockets
+use std::{
+    io::{Read, Write},
+    net::{SocketAddr, TcpStream},
+    sync::{Arc, Barrier},
+    thread,
+    time::{Duration, Instant},
+};
+
+use bytes::Bytes;
+use dkv::{
+    command::{self, CommandSpec},
+    config::{Config, IoModel},
+    module::{Module, ModuleContext},
+    server::{Result, Server},
+    Value,
+};
+
+fn server(config: Config) -> Server {
+    Server::with_config(Config {
+        port: 0,
+        bind: vec!["127.0.0.1".to_owned()],
+        io_model: IoModel::Reactor,
+        ..config
+    })
+    .unwrap()
+}
+
+fn run(mut server: Server) -> SocketAddr {
+    let address = server.listeners()[0].local_addr().unwrap();
+    thread::spawn(move || server.start());
+    address
+}
+
+fn start(config: Config) -> SocketAddr {
+    run(server(config))
+}
+
+fn connect(address: SocketAddr) -> TcpStream {
+    let stream = TcpStream::connect(address).unwrap();
+    stream
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+    stream
+}
+
+/// Reads until exactly `expected` was received
+fn expect(stream: &mut TcpStream, expected: &[u8]) {
+    let mut received = vec![0; expected.len()];
+    stream.read_exact(&mut received).unwrap();
+    assert_eq!(
+        String::from_utf8_lossy(&received),
+        String::from_utf8_lossy(expected)
+    );
+}
+
+/// Whether the server closed the connection, without it sending anything else
+fn is_closed(stream: &mut TcpStream) -> bool {
+    matches!(stream.read(&mut [0; 64]), Ok(0))
+}
+
+#[test]
+fn replies_to_pipelined_commands_in_order() {
+    let mut client = connect(start(Config::default()));
+    let mut requests = String::new();
+    let mut replies = String::new();
+    for i in 0..1000 {
+        requests.push_str(&format!("SET key:{} {}\r\nGET key:{}\r\n", i, i, i));
+        replies.push_str(&format!("+OK\r\n${}\r\n{}\r\n", i.to_string().len(), i));
+    }
+    client.write_all(requests.as_bytes()).unwrap();
+    expect(&mut client, replies.as_bytes());
+}
+
+#[test]
+fn serves_concurrent_clients_with_io_threads() {
+    // Enough clients for the batches to go past `io-threads * 2`,
+    // which is when the reactor hands them over to the io threads
+    let address = start(Config {
+        io_threads: 4,
+        ..Config::default()
+    });
+    let clients = 32;
+    let barrier = Arc::new(Barrier::new(clients));
+    let threads: Vec<_> = (0..clients)
+        .map(|client| {
+            let barrier = barrier.clone();
+            thread::spawn(move || {
+                let mut stream = connect(address);
+                let value = format!("{}", client).repeat(10_000);
+                let mut requests = String::new();
+                let mut replies = String::new();
+                for i in 0..20 {
+                    requests.push_str(&format!(
+                        "SET key:{}:{} {}\r\nGET key:{}:{}\r\n",
+                        client, i, value, client, i
+                    ));
+                    replies.push_str(&format!("+OK\r\n${}\r\n{}\r\n", value.len(), value));
+                }
+                barrier.wait();
+                stream.write_all(requests.as_bytes()).unwrap();
+                expect(&mut stream, replies.as_bytes());
+            })
+        })
+        .collect();
+    for thread in threads {
+        thread.join().unwrap();
+    }
+}
+
+#[test]
+fn waits_for_frames_split_across_reads() {
+    let mut client = connect(start(Config::default()));
+    let request =
+        b"*3\r\n$3\r\nSET\r\n$3\r\nkey\r\n$5\r\nvalue\r\n*2\r\n$3\r\nGET\r\n$3\r\nkey\r\n";
+    for byte in request {
+        client.write_all(&[*byte]).unwrap();
+        thread::sleep(Duration::from_millis(1));
+    }
+    expect(&mut client, b"+OK\r\n$5\r\nvalue\r\n");
+}
+
+#[test]
+fn flushes_replies_before_quitting() {
+    let mut client = connect(start(Config::default()));
+    let value = "x".repeat(1024 * 1024);
+    client
+        .write_all(format!("SET key {}\r\nGET key\r\nQUIT\r\nPING\r\n", value).as_bytes())
+        .unwrap();
+    expect(
+        &mut client,
+        format!("+OK\r\n${}\r\n{}\r\n+OK\r\n", value.len(), value).as_bytes(),
+    );
+    assert!(is_closed(&mut client));
+}
+
+#[test]
+fn closes_idle_clients_but_not_subscribers() {
+    let address = start(Config {
+        timeout: 1,
+        ..Config::default()
+    });
+    let mut idle = connect(address);
+    let mut subscriber = connect(address);
+    subscriber.write_all(b"SUBSCRIBE channel\r\n").unwrap();
+    expect(
+        &mut subscriber,
+        b"*3\r\n$9\r\nsubscribe\r\n$7\r\nchannel\r\n:1\r\n",
+    );
+    idle.write_all(b"SET key value\r\n").unwrap();
+    expect(&mut idle, b"+OK\r\n");
+
+    let started = Instant::now();
+    assert!(is_closed(&mut idle));
+    assert!(started.elapsed() >= Duration::from_millis(900));
+    connect(address)
+        .write_all(b"PUBLISH channel hello\r\n")
+        .unwrap();
+    expect(
+        &mut subscriber,
+        b"*3\r\n$7\r\nmessage\r\n$7\r\nchannel\r\n$5\r\nhello\r\n",
+    );
+}
+
+/// `LATER.PUBLISH <channel> <message>` publishes from another thread than the
+/// reactor's, once it replied
+fn publish_later(context: &mut ModuleContext, args: &[Bytes]) -> Result<Value> {
+    let channel = command::to_string(&args[1])?;
+    let message = command::to_string(&args[2])?;
+    let db = context.db().clone();
+    thread::spawn(move || {
+        thread::sleep(Duration::from_millis(10));
+        db.publish(&channel, &message);
+    });
+    Ok(Value::from("OK"))
+}
+
+struct LaterModule;
+
+impl Module for LaterModule {
+    fn name(&self) -> &'static str {
+        "later"
+    }
+
+    fn commands(&self) -> &'static [CommandSpec] {
+        static COMMANDS: &[CommandSpec] = &[CommandSpec::module("later.publish", 3, publish_later)];
+        COMMANDS
+    }
+}
+
+#[test]
+fn delivers_messages_sent_by_other_clients() {
+    let server = server(Config::default());
+    server.load_module(Box::new(LaterModule), vec![]).unwrap();
+    let address = run(server);
+    let mut subscriber = connect(address);
+    subscriber.write_all(b"SUBSCRIBE channel\r\n").unwrap();
+    expect(
+        &mut subscriber,
+        b"*3\r\n$9\r\nsubscribe\r\n$7\r\nchannel\r\n:1\r\n",
+    );
+    let mut tracker = connect(address);
+    tracker
+        .write_all(b"HELLO 3\r\nCLIENT TRACKING on\r\nGET key\r\n")
+        .unwrap();
+    // The HELLO reply's length depends on the server's version, so only what follows is checked
+    let mut received = vec![];
+    while !received.ends_with(b"+OK\r\n_\r\n") {
+        let mut buf = [0; 512];
+        let n = tracker.read(&mut buf).unwrap();
+        assert!(n > 0);
+        received.extend_from_slice(&buf[..n]);
+    }
+
+    let mut writer = connect(address);
+    writer
+        .write_all(b"SET key value\r\nPUBLISH channel hello\r\nLATER.PUBLISH channel later\r\n")
+        .unwrap();
+    expect(&mut writer, b"+OK\r\n:1\r\n$2\r\nOK\r\n");
+    expect(
+        &mut tracker,
+        b">2\r\n$10\r\ninvalidate\r\n*1\r\n$3\r\nkey\r\n",
+    );
+    expect(
+        &mut subscriber,
+        b"*3\r\n$7\r\nmessage\r\n$7\r\nchannel\r\n$5\r\nhello\r\n",
+    );
+    expect(
+        &mut subscriber,
+        b"*3\r\n$7\r\nmessage\r\n$7\r\nchannel\r\n$5\r\nlater\r\n",
+    );
+}
@@ -0,0 +1,46 @@
+from .util import make_redis
+
+
+def test_client_getredir():
+    r = make_redis(2)
+    assert r.execute_command("CLIENT", "GETREDIR") == -1
+    r.execute_command("CLIENT", "TRACKING", "ON")
+    assert r.execute_command("CLIENT", "GETREDIR") == 0
+    r.execute_command("CLIENT", "TRACKING", "OFF")
+    assert r.execute_command("CLIENT", "GETREDIR") == -1
+
+
+def test_invalidations_are_sent_to_redirect_client():
+    pubsub = make_redis(2).pubsub()
+    pubsub.execute_command("CLIENT", "ID")
+    redirect_id = pubsub.parse_response()
+    pubsub.subscribe("__redis__:invalidate")
+    assert pubsub.get_message(timeout=1)["type"] == "subscribe"
+
+    tracking = make_redis(2)
+    tracking.execute_command("CLIENT", "TRACKING", "ON", "REDIRECT", redirect_id)
+    tracking.get("tracked")
+
+    make_redis(2).set("tracked", "value")
+    message = pubsub.get_message(timeout=1)
+    assert message["channel"] == "__redis__:invalidate"
+    assert message["data"] == ["tracked"]
+
+
+def test_bcast_invalidations_match_prefixes():
+    pubsub = make_redis(2).pubsub()
+    pubsub.execute_command("CLIENT", "ID")
+    redirect_id = pubsub.parse_response()
+    pubsub.subscribe("__redis__:invalidate")
+    assert pubsub.get_message(timeout=1)["type"] == "subscribe"
+
+    tracking = make_redis(2)
+    tracking.execute_command(
+        "CLIENT", "TRACKING", "ON", "REDIRECT", redirect_id, "BCAST", "PREFIX", "user:"
+    )
+
+    writer = make_redis(2)
+    writer.set("other", "value")
+    writer.set("user:1", "value")
+    message = pubsub.get_message(timeout=1)
+    assert message["data"] == ["user:1"]
@@ -57,3 +57,9 @@ def test_hexists_with_existing_key(protocol):
     redis.hset("myhash4", "field", "value")
     assert redis.hexists("myhash4", "field")
     assert not redis.hexists("myhash4", "field2")
+
+
+@with_supported_protocols
+def test_hget_non_existent_hash(protocol):
+    redis = make_redis(protocol)
+    assert redis.hget("nonexistent", "field") is None
@@ -0,0 +1,38 @@
+from .util import make_redis, with_supported_protocols
+
+
+def next_message(pubsub):
+    message = pubsub.get_message(ignore_subscribe_messages=True, timeout=1)
+    assert message is not None
+    return message["channel"], message["data"]
+
+
+@with_supported_protocols
+def test_config_get_notify_keyspace_events(protocol):
+    r = make_redis(protocol)
+    try:
+        r.config_set("notify-keyspace-events", "KEA")
+        assert r.config_get("notify-keyspace-events") == {
+            "notify-keyspace-events": "AKE"
+        }
+    finally:
+        r.config_set("notify-keyspace-events", "")
+
+
+@with_supported_protocols
+def test_keyspace_notifications(protocol):
+    r = make_redis(protocol)
+    r.config_set("notify-keyspace-events", "KEg$h")
+    try:
+        pubsub = make_redis(protocol).pubsub()
+        pubsub.subscribe("__keyspace@0__:key", "__keyevent@0__:del")
+
+        r.set("key", "value")
+        assert next_message(pubsub) == ("__keyspace@0__:key", "set")
+        r.delete("key")
+        assert next_message(pubsub) == ("__keyspace@0__:key", "del")
+        assert next_message(pubsub) == ("__keyevent@0__:del", "key")
+        r.hset("key", "field", "value")
+        assert next_message(pubsub) == ("__keyspace@0__:key", "hset")
+    finally:
+        r.config_set("notify-keyspace-events", "")
@@ -0,0 +1,84 @@
+from test.util import make_redis, with_supported_protocols
+import pytest
+from redis.exceptions import ResponseError
+
+
+@pytest.fixture(autouse=True)
+def reset_maxmemory():
+    yield
+    r = make_redis(2)
+    r.config_set("maxmemory", "0")
+    r.config_set("maxmemory-policy", "noeviction")
+
+
+def keys(r):
+    return list(r.scan_iter(count=100))
+
+
+@with_supported_protocols
+def test_noeviction_refuses_writes(protocol):
+    r = make_redis(protocol)
+    for i in range(100):
+        r.set(f"key:{i}", "x" * 100)
+    r.config_set("maxmemory", "1000")
+    with pytest.raises(ResponseError, match="OOM command not allowed"):
+        r.set("other", "value")
+    with pytest.raises(ResponseError, match="OOM command not allowed"):
+        r.hset("hash", "field", "value")
+    # Reads and deletes still work
+    assert r.get("key:0") == "x" * 100
+    assert r.delete("key:0") == 1
+    assert len(keys(r)) == 99
+
+
+@with_supported_protocols
+def test_allkeys_lru_evicts_keys_that_were_not_used(protocol):
+    r = make_redis(protocol)
+    r.config_set("maxmemory-policy", "allkeys-lru")
+    r.config_set("maxmemory", "100kb")
+    evicted_before = int(r.info()["evicted_keys"])
+    for i in range(1000):
+        r.set(f"key:{i}", "x" * 1000)
+        # Keeps using the first keys
+        r.get("key:0")
+        r.get("key:1")
+    remaining = keys(r)
+    assert 0 < len(remaining) < 100
+    assert "key:0" in remaining and "key:1" in remaining
+    assert "key:999" in remaining
+    assert int(r.info()["evicted_keys"]) - evicted_before == 1000 - len(remaining)
+
+
+@with_supported_protocols
+def test_lowering_maxmemory_evicts_on_the_next_command(protocol):
+    r = make_redis(protocol)
+    for i in range(100):
+        r.set(f"key:{i}", "x" * 1000)
+    r.config_set("maxmemory-policy", "allkeys-random")
+    r.config_set("maxmemory", "10kb")
+    r.ping()
+    assert 0 < len(keys(r)) < 10
+
+
+@with_supported_protocols
+def test_volatile_policies_have_nothing_to_evict(protocol):
+    r = make_redis(protocol)
+    for i in range(100):
+        r.set(f"key:{i}", "x" * 100)
+    r.config_set("maxmemory-policy", "volatile-lru")
+    r.config_set("maxmemory", "1000")
+    with pytest.raises(ResponseError, match="OOM"):
+        r.set("other", "value")
+    assert len(keys(r)) == 100
+
+
+def test_maxmemory_config():
+    r = make_redis(2)
+    assert r.config_get("maxmemory-*") == {
+        "maxmemory-policy": "noeviction",
+        "maxmemory-samples": "5",
+    }
+    with pytest.raises(ResponseError, match="must be one of the following"):
+        r.config_set("maxmemory-policy", "lru")
+    with pytest.raises(ResponseError, match="must be between 1 and 64"):
+        r.config_set("maxmemory-samples", "0")
@@ -0,0 +1,50 @@
+from test.util import make_redis, with_supported_protocols
+import pytest
+from redis.exceptions import ResponseError
+
+
+@with_supported_protocols
+def test_memory_usage(protocol):
+    r = make_redis(protocol)
+    r.set("small", "x")
+    r.set("big", "x" * 10000)
+    assert r.memory_usage("small") < r.memory_usage("big")
+    assert r.memory_usage("big") > 10000
+    assert r.memory_usage("missing") is None
+
+    for i in range(100):
+        r.hset("hash", f"field:{i}", "x" * (i * 100))
+    exact = r.memory_usage("hash", samples=0)
+    assert exact > sum(i * 100 for i in range(100))
+    assert r.memory_usage("hash", samples=100) == exact
+
+    with pytest.raises(ResponseError, match="syntax error"):
+        r.execute_command("MEMORY", "USAGE", "big", "SAMPLES", "-1")
+    with pytest.raises(ResponseError, match="not an integer"):
+        r.execute_command("MEMORY", "USAGE", "big", "SAMPLES", "many")
+    with pytest.raises(ResponseError, match="syntax error"):
+        r.execute_command("MEMORY", "USAGE", "big", "FOO")
+
+
+@with_supported_protocols
+def test_memory_stats(protocol):
+    r = make_redis(protocol)
+    r.set("key", "x" * 1000)
+    stats = r.memory_stats()
+    assert stats["keys.count"] >= 1
+    assert stats["peak.allocated"] >= stats["total.allocated"]
+    assert stats["dataset.bytes"] >= 1000
+    assert stats["total.allocated"] >= stats["dataset.bytes"] + stats["overhead.total"]
+
+
+@with_supported_protocols
+def test_memory_doctor_and_help(protocol):
+    r = make_redis(protocol)
+    assert "memory" in r.memory_doctor()
+    assert (
+        r.execute_command("MEMORY", "MALLOC-STATS")
+        == "Stats not supported for the current allocator"
+    )
+    help = r.execute_command("MEMORY", "HELP")
+    assert help[0].startswith("MEMORY <subcommand>")
+    assert "USAGE <key> [SAMPLES <count>]" in help
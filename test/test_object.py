@@ -0,0 +1,42 @@
+from test.util import make_redis, with_supported_protocols
+import time
+
+
+@with_supported_protocols
+def test_object_encoding(protocol):
+    r = make_redis(protocol)
+    r.set("int", "12345")
+    r.set("embstr", "x" * 44)
+    r.set("raw", "x" * 45)
+    r.hset("small", "field", "value")
+    r.hset("big", "field", "x" * 65)
+    assert r.object("encoding", "int") == "int"
+    assert r.object("encoding", "embstr") == "embstr"
+    assert r.object("encoding", "raw") == "raw"
+    assert r.object("encoding", "small") == "listpack"
+    assert r.object("encoding", "big") == "hashtable"
+    assert r.object("encoding", "missing") is None
+
+
+@with_supported_protocols
+def test_object_idletime_and_freq(protocol):
+    r = make_redis(protocol)
+    r.set("key", "value")
+    time.sleep(1.1)
+    assert r.object("idletime", "key") >= 1
+    # Looking at the key doesn't count as an access
+    assert r.object("idletime", "key") >= 1
+    assert r.object("freq", "key") == 5
+    for _ in range(100):
+        r.get("key")
+    assert r.object("idletime", "key") == 0
+    assert r.object("freq", "key") > 5
+    assert r.object("refcount", "key") == 1
+    assert r.object("freq", "missing") is None
+
+
+def test_object_help():
+    r = make_redis(2)
+    help = r.execute_command("OBJECT", "HELP")
+    assert help[0].startswith("OBJECT <subcommand>")
+    assert "FREQ <key>" in help
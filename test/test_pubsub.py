@@ -26,3 +26,23 @@ def test_pubsub(protocol):
     r.publish("foo", "hello3")
 
     t.join()
+
+
+@with_supported_protocols
+def test_slow_subscriber_is_disconnected(protocol):
+    r = make_redis(protocol)
+    r.config_set("client-output-buffer-limit", "pubsub 64kb 0 0")
+    try:
+        before = r.info("stats")["client_output_buffer_limit_disconnections"]
+
+        # Subscribe, but never read any of the published messages
+        pubsub = r.pubsub()
+        pubsub.subscribe("slow")
+        payload = "x" * 1024
+        for _ in range(10000):
+            r.publish("slow", payload)
+
+        after = r.info("stats")["client_output_buffer_limit_disconnections"]
+        assert after == before + 1
+    finally:
+        r.config_set("client-output-buffer-limit", "pubsub 32mb 8mb 60")
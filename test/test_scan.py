@@ -0,0 +1,53 @@
+from test.util import make_redis, with_supported_protocols
+import pytest
+from redis.exceptions import ResponseError
+
+
+@with_supported_protocols
+def test_scan_returns_every_key(protocol):
+    r = make_redis(protocol)
+    for i in range(1000):
+        r.set(f"key:{i}", "value")
+    assert sorted(r.scan_iter(count=7)) == sorted(f"key:{i}" for i in range(1000))
+
+
+@with_supported_protocols
+def test_scan_keeps_its_cursor_while_keys_are_added(protocol):
+    r = make_redis(protocol)
+    for i in range(1000):
+        r.set(f"key:{i}", "value")
+    seen = set()
+    cursor = 0
+    added = 0
+    while True:
+        cursor, keys = r.scan(cursor, count=20)
+        seen.update(keys)
+        for _ in range(100):
+            r.set(f"new:{added}", "value")
+            added += 1
+        if cursor == 0:
+            break
+    assert {f"key:{i}" for i in range(1000)} <= seen
+
+
+@with_supported_protocols
+def test_scan_filters_by_pattern_and_type(protocol):
+    r = make_redis(protocol)
+    r.set("foo", "bar")
+    r.set("food", "bar")
+    r.hset("fool", "key", "value")
+    r.set("bar", "foo")
+    assert sorted(r.scan_iter(match="foo*")) == ["foo", "food", "fool"]
+    assert list(r.scan_iter(_type="hash")) == ["fool"]
+    assert sorted(r.scan_iter(match="foo*", _type="string")) == ["foo", "food"]
+
+
+@with_supported_protocols
+def test_scan_rejects_invalid_arguments(protocol):
+    r = make_redis(protocol)
+    with pytest.raises(ResponseError, match="invalid cursor"):
+        r.execute_command("SCAN", "abc")
+    with pytest.raises(ResponseError, match="syntax error"):
+        r.execute_command("SCAN", "0", "COUNT", "0")
+    with pytest.raises(ResponseError, match="syntax error"):
+        r.execute_command("SCAN", "0", "MATCH")
@@ -10,6 +10,14 @@ def test_get_after_set(protocol):
     assert r.get("foo") == "bar"
 
 
+@with_supported_protocols
+def test_get_after_set_binary(protocol):
+    r = make_redis(protocol, decode_responses=False)
+    blob = bytes(range(256)) * 100
+    r.set("foo", blob)
+    assert r.get("foo") == blob
+
+
 @with_supported_protocols
 def test_get_non_existent(protocol):
     r = make_redis(protocol)
@@ -47,3 +55,22 @@ def test_get_with_integer_value(protocol):
     r = make_redis(protocol)
     r.set("foo", 1)
     assert r.get("foo") == "1"
+
+
+@with_supported_protocols
+def test_rename_non_existent(protocol):
+    r = make_redis(protocol)
+    with pytest.raises(ResponseError) as ex:
+        r.rename("foo", "bar")
+    assert str(ex.value) == "no such key"
+
+
+@with_supported_protocols
+def test_errors_have_redis_prefixes(protocol):
+    r = make_redis(protocol)
+    with pytest.raises(ResponseError) as ex:
+        r.execute_command("GET")
+    assert str(ex.value) == "wrong number of arguments for 'get' command"
+    with pytest.raises(ResponseError) as ex:
+        r.execute_command("NOPE", "a")
+    assert str(ex.value) == "unknown command 'NOPE', with args beginning with: 'a' "
@@ -4,12 +4,12 @@ import pytest
 import os
 
 
-def make_redis(protocol):
+def make_redis(protocol, decode_responses=True):
     r = Redis(
         host="localhost",
         port=int(os.environ.get("DKV_PORT", "6543")),
         protocol=protocol,
-        decode_responses=True,
+        decode_responses=decode_responses,
     )
     r.flushall()
     return r
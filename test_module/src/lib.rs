@@ -0,0 +1,48 @@
+//! A small module built as a `cdylib`, so that loading modules at runtime
+//! gets tested through a real shared library
+use std::sync::RwLock;
+
+use bytes::Bytes;
+use dkv::{
+    command::{self, CommandSpec},
+    declare_module,
+    module::{Module, ModuleContext},
+    server::Result,
+    Value,
+};
+
+/// Greets with the word given to `MODULE LOAD`, if any
+static GREETING: RwLock<String> = RwLock::new(String::new());
+
+fn greet(_context: &mut ModuleContext, args: &[Bytes]) -> Result<Value> {
+    let name = command::to_string(&args[1])?;
+    Ok(Value::from(format!(
+        "{}, {}",
+        GREETING.read().unwrap(),
+        name
+    )))
+}
+
+struct HelloModule;
+
+impl Module for HelloModule {
+    fn name(&self) -> &'static str {
+        "hello"
+    }
+
+    fn version(&self) -> i64 {
+        2
+    }
+
+    fn commands(&self) -> &'static [CommandSpec] {
+        static COMMANDS: &[CommandSpec] = &[CommandSpec::module("hello.greet", 2, greet)];
+        COMMANDS
+    }
+
+    fn on_load(&self, args: &[String]) -> Result<()> {
+        *GREETING.write().unwrap() = args.first().cloned().unwrap_or_else(|| "Hello".to_owned());
+        Ok(())
+    }
+}
+
+declare_module!(HelloModule);
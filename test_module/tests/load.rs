@@ -0,0 +1,65 @@
+//! Loads the module's shared library into a server, like `loadmodule` and `MODULE LOAD` do
+#![cfg(unix)]
+
+use std::{
+    env::consts::{DLL_PREFIX, DLL_SUFFIX},
+    io::{BufRead, BufReader, Write},
+    net::TcpStream,
+    path::PathBuf,
+};
+
+use dkv::{
+    config::{Config, ProtectedAction},
+    server::Server,
+};
+
+/// Cargo builds the library next to the test binary, in `target/<profile>/deps`
+fn library_path() -> String {
+    let exe = std::env::current_exe().unwrap();
+    let path: PathBuf = exe
+        .parent()
+        .unwrap()
+        .join(format!("{}dkv_test_module{}", DLL_PREFIX, DLL_SUFFIX));
+    assert!(path.exists(), "{} wasn't built", path.display());
+    path.display().to_string()
+}
+
+/// Sends a command and reads a single line reply
+fn call(stream: &mut BufReader<TcpStream>, command: &str) -> String {
+    stream
+        .get_mut()
+        .write_all(format!("{}\r\n", command).as_bytes())
+        .unwrap();
+    let mut line = String::new();
+    stream.read_line(&mut line).unwrap();
+    if line.starts_with('$') {
+        line.clear();
+        stream.read_line(&mut line).unwrap();
+    }
+    line.trim_end().to_owned()
+}
+
+#[test]
+fn loads_the_module_at_startup_and_at_runtime() {
+    let path = library_path();
+    let config = Config {
+        port: 0,
+        bind: vec!["127.0.0.1".to_owned()],
+        enable_module_command: ProtectedAction::Local,
+        loadmodule: vec![(path.clone(), vec!["Hi".to_owned()])],
+        ..Config::default()
+    };
+    let mut server = Server::with_config(config).unwrap();
+    let address = server.listeners()[0].local_addr().unwrap();
+    std::thread::spawn(move || server.start());
+
+    let mut client = BufReader::new(TcpStream::connect(address).unwrap());
+    assert_eq!(call(&mut client, "HELLO.GREET world"), "Hi, world");
+    assert_eq!(call(&mut client, "MODULE UNLOAD hello"), "+OK");
+    assert!(call(&mut client, "HELLO.GREET world").starts_with("-ERR unknown command"));
+    assert_eq!(
+        call(&mut client, &format!("MODULE LOAD {} Hello", path)),
+        "+OK"
+    );
+    assert_eq!(call(&mut client, "HELLO.GREET world"), "Hello, world");
+}